@@ -0,0 +1,93 @@
+use anyhow::Context;
+use quantum_spacetime_shogi::export;
+use quantum_spacetime_shogi::frontend::run_script;
+use quantum_spacetime_shogi::prelude::*;
+
+// GUI を起動せずに使う道具。局面図の書き出しと、対局の統計の集計
+//   render <手順ファイル> [--world N] [--turn T] [-o out.svg]
+//   stats <手順ファイル>...
+// 手順ファイルは端末版の save で保存したもの (コマンド欄と同じ書式)。
+// --turn はその世界線の時間 (t) で、省略すると現在の局面。-o を省略すると標準出力へ書く
+const USAGE: &str =
+    "使い方: render <手順ファイル> [--world N] [--turn T] [-o out.svg] | stats <手順ファイル>...";
+
+fn load(path: &str) -> anyhow::Result<Game> {
+    let script = std::fs::read_to_string(path).with_context(|| format!("{path} を読めない"))?;
+    let mut game = Game::new(Settings::default());
+    run_script(&mut game, &script).with_context(|| format!("{path} を再生できない"))?;
+    Ok(game)
+}
+
+fn render(args: &[String]) -> anyhow::Result<()> {
+    let mut path = None;
+    let mut world = WorldId(0);
+    let mut turn = None;
+    let mut output = None;
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        let mut value = || it.next().with_context(|| format!("{arg} の値がない"));
+        match arg.as_str() {
+            "--world" | "-w" => {
+                let v = value()?;
+                world = WorldId(
+                    v.parse()
+                        .with_context(|| format!("世界線の指定が不正: {v}"))?,
+                );
+            }
+            "--turn" | "-t" => {
+                let v = value()?;
+                turn = Some(
+                    v.parse::<usize>()
+                        .with_context(|| format!("時間の指定が不正: {v}"))?,
+                );
+            }
+            "-o" | "--output" => output = Some(value()?.clone()),
+            _ if path.is_none() => path = Some(arg.clone()),
+            _ => anyhow::bail!("不明な引数: {arg}\n{USAGE}"),
+        }
+    }
+    let game = load(&path.context(USAGE)?)?;
+
+    let wl = game
+        .world(world)
+        .or_else(|| game.archived().get(&world))
+        .with_context(|| format!("世界線 {world} がない"))?;
+    let t = turn.unwrap_or(wl.present_t().0);
+    let snap = wl
+        .history()
+        .get(t)
+        .with_context(|| format!("{world} に t{t} の局面がない (現在 {})", wl.present_t()))?;
+    let svg = export::board_svg(snap, &format!("{world} t{t}"));
+    match output {
+        Some(out) => std::fs::write(&out, svg).with_context(|| format!("{out} に書けない"))?,
+        None => println!("{svg}"),
+    }
+    Ok(())
+}
+
+// 手順ファイルごとに、世界線の使われ方 (Δw/Δt の分布など) と駒種の確定の統計を並べる
+fn stats(args: &[String]) -> anyhow::Result<()> {
+    anyhow::ensure!(!args.is_empty(), "{USAGE}");
+    for path in args {
+        let game = load(path)?;
+        println!("# {path}");
+        for line in game
+            .stats()
+            .summary_lines()
+            .into_iter()
+            .chain(game.collapse_stats().summary_lines())
+        {
+            println!("{line}");
+        }
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("render") => render(&args[1..]),
+        Some("stats") => stats(&args[1..]),
+        _ => anyhow::bail!("{USAGE}"),
+    }
+}
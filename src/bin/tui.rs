@@ -0,0 +1,173 @@
+use std::io;
+
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use crossterm::ExecutableCommand;
+use quantum_spacetime_shogi::frontend::{describe, dispatch, parse_command, Frontend};
+use quantum_spacetime_shogi::prelude::*;
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{Block, Borders, Paragraph, Tabs, Wrap};
+use ratatui::{Frame, Terminal};
+
+// 端末用フロントエンド。Tab/←→ で世界線を切り替え、下のコマンド欄に手を入力する。
+struct Tui {
+    world: WorldId,
+    input: String,
+    log: Vec<String>,
+    // 通ったコマンドを世界線付きで残し、save で手順ファイルとして書き出す
+    script: Vec<String>,
+    failed: bool,
+}
+
+impl Frontend for Tui {
+    fn on_events(&mut self, _game: &Game, events: &[GameEvent]) {
+        self.log.extend(events.iter().map(describe));
+    }
+
+    fn on_error(&mut self, _game: &Game, message: &str) {
+        self.log.push(format!("エラー: {message}"));
+        self.failed = true;
+    }
+}
+
+impl Tui {
+    fn shift_world(&mut self, game: &Game, step: isize) {
+        let ids: Vec<WorldId> = game.worlds().keys().copied().collect();
+        let cur = ids.iter().position(|w| *w == self.world).unwrap_or(0) as isize;
+        let next = (cur + step).rem_euclid(ids.len() as isize) as usize;
+        self.world = ids[next];
+    }
+
+    fn submit(&mut self, game: &mut Game) {
+        let line = std::mem::take(&mut self.input);
+        if line.trim().is_empty() {
+            return;
+        }
+        self.log.push(format!("> {line}"));
+        if let Some(path) = line.trim().strip_prefix("save ") {
+            let text = self.script.join("\n") + "\n";
+            match std::fs::write(path.trim(), text) {
+                Ok(()) => self.log.push(format!("{} に保存しました", path.trim())),
+                Err(e) => self.on_error(game, &format!("保存失敗: {e}")),
+            }
+            return;
+        }
+        self.failed = false;
+        match parse_command(&line, game, self.world) {
+            Ok(cmd) => dispatch(game, cmd, self),
+            Err(e) => self.on_error(game, &e.to_string()),
+        }
+        if !self.failed {
+            self.script.push(format!("{} {}", self.world, line.trim()));
+        }
+        if !game.worlds().contains_key(&self.world) {
+            self.world = *game.worlds().keys().next().unwrap();
+        }
+    }
+
+    fn draw(&self, f: &mut Frame, game: &Game) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(3),
+                Constraint::Min(14),
+                Constraint::Length(3),
+            ])
+            .split(f.size());
+        let ids: Vec<WorldId> = game.worlds().keys().copied().collect();
+        let titles: Vec<String> = game
+            .worlds()
+            .values()
+            .map(|wl| {
+                let mark = if wl.staged().is_some() { "*" } else { "" };
+                format!("{} {}{mark}", wl.w(), wl.present_t())
+            })
+            .collect();
+        let tabs = Tabs::new(titles)
+            .select(ids.iter().position(|w| *w == self.world).unwrap_or(0))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(format!("量子時空将棋 — 手番: {}", game.turn().label())),
+            );
+        f.render_widget(tabs, rows[0]);
+
+        let cols = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Length(34), Constraint::Min(20)])
+            .split(rows[1]);
+        let mut board = game
+            .present(self.world)
+            .map(Snapshot::to_ascii)
+            .unwrap_or_default();
+        if let Some(pm) = game.world(self.world).and_then(|wl| wl.staged()) {
+            board.push_str(&format!("入力済: {}", pm.notation(game.files())));
+        }
+        f.render_widget(
+            Paragraph::new(board).block(Block::default().borders(Borders::ALL).title("盤面")),
+            cols[0],
+        );
+        let height = cols[1].height.saturating_sub(2) as usize;
+        let log = self.log[self.log.len().saturating_sub(height)..].join("\n");
+        f.render_widget(
+            Paragraph::new(log)
+                .wrap(Wrap { trim: false })
+                .block(Block::default().borders(Borders::ALL).title("ログ")),
+            cols[1],
+        );
+
+        f.render_widget(
+            Paragraph::new(format!("{}_", self.input)).block(
+                Block::default().borders(Borders::ALL).title(
+                    "mv 77 76 [Δw Δt] [+] / drop n 55 / obs 55 / pass / merge 0 1 / claim rep / resign / draw / accept / clear / commit / undo / redo / save file / Esc で終了",
+                ),
+            ),
+            rows[2],
+        );
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut game = Game::new(Settings::default());
+    let mut tui = Tui {
+        world: WorldId(0),
+        input: String::new(),
+        log: Vec::new(),
+        script: Vec::new(),
+        failed: false,
+    };
+
+    enable_raw_mode()?;
+    io::stdout().execute(EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+    let result = (|| -> anyhow::Result<()> {
+        loop {
+            terminal.draw(|f| tui.draw(f, &game))?;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Esc => return Ok(()),
+                KeyCode::Tab | KeyCode::Right => tui.shift_world(&game, 1),
+                KeyCode::BackTab | KeyCode::Left => tui.shift_world(&game, -1),
+                KeyCode::Enter => tui.submit(&mut game),
+                KeyCode::Backspace => {
+                    tui.input.pop();
+                }
+                KeyCode::Char(c) => tui.input.push(c),
+                _ => {}
+            }
+        }
+    })();
+    disable_raw_mode()?;
+    io::stdout().execute(LeaveAlternateScreen)?;
+    result
+}
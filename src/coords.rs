@@ -0,0 +1,96 @@
+use std::fmt;
+
+pub(crate) const RANK_KANJI: [&str; 9] = ["一", "二", "三", "四", "五", "六", "七", "八", "九"];
+
+// file/rank は盤面配列の添字 (board[rank][file])。
+// 表示上の筋は右から数えるので 盤の筋数 - file になる (5五将棋なら左上が5一)。
+// 盤の広さで表記が変わるので Display は持たず、notation に筋数を渡す。
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Square {
+    pub file: usize,
+    pub rank: usize,
+}
+
+impl Square {
+    pub const fn new(file: usize, rank: usize) -> Self {
+        Self { file, rank }
+    }
+
+    // size は (筋数, 段数)
+    pub fn is_on_board(self, (files, ranks): (usize, usize)) -> bool {
+        self.file < files && self.rank < ranks
+    }
+
+    pub fn offset(self, dx: i32, dy: i32, (files, ranks): (usize, usize)) -> Option<Self> {
+        let file = self.file as i32 + dx;
+        let rank = self.rank as i32 + dy;
+        if !(0..files as i32).contains(&file) || !(0..ranks as i32).contains(&rank) {
+            return None;
+        }
+        Some(Self::new(file as usize, rank as usize))
+    }
+
+    // 筋数 files の盤での表記 (例: 9筋の盤の file 2, rank 6 は 7七)
+    pub fn notation(self, files: usize) -> String {
+        match RANK_KANJI.get(self.rank) {
+            Some(r) if self.file < files => format!("{}{}", files - self.file, r),
+            _ => format!("({},{})", self.file, self.rank),
+        }
+    }
+
+    pub fn delta_to(self, to: Square) -> (i32, i32) {
+        (
+            to.file as i32 - self.file as i32,
+            to.rank as i32 - self.rank as i32,
+        )
+    }
+}
+
+impl From<(usize, usize)> for Square {
+    fn from((file, rank): (usize, usize)) -> Self {
+        Self::new(file, rank)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct WorldId(pub i32);
+
+impl WorldId {
+    pub fn offset(self, delta_w: i32) -> Self {
+        Self(self.0 + delta_w)
+    }
+}
+
+impl From<i32> for WorldId {
+    fn from(w: i32) -> Self {
+        Self(w)
+    }
+}
+
+impl fmt::Display for WorldId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "w{}", self.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimeIdx(pub usize);
+
+impl TimeIdx {
+    pub fn offset(self, delta_t: i32) -> Option<Self> {
+        let t = self.0 as i32 + delta_t;
+        (t >= 0).then_some(Self(t as usize))
+    }
+}
+
+impl From<usize> for TimeIdx {
+    fn from(t: usize) -> Self {
+        Self(t)
+    }
+}
+
+impl fmt::Display for TimeIdx {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "t{}", self.0)
+    }
+}
@@ -1,4 +1,11 @@
 use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Arc;
+
+use itertools::Itertools;
+
+use crate::coords::{Square, TimeIdx, WorldId};
+use crate::stats::{CollapseCause, CollapseStats, WorldUsageStats};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum Player {
@@ -25,6 +32,12 @@ impl Player {
             Self::White => "後手",
         }
     }
+    pub fn mark(self) -> &'static str {
+        match self {
+            Self::Black => "▲",
+            Self::White => "△",
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
@@ -66,33 +79,302 @@ impl PieceType {
             Self::King => "王",
         }
     }
+    // 成れる駒種。成った駒の候補はこの中に限る
+    pub fn can_promote(self) -> bool {
+        !matches!(self, Self::Gold | Self::King)
+    }
+    // 成った後の駒種の略称 (成れない駒種はそのまま)
+    pub fn promoted_short(self) -> &'static str {
+        match self {
+            Self::Pawn => "と",
+            Self::Lance => "杏",
+            Self::Knight => "圭",
+            Self::Silver => "全",
+            Self::Rook => "竜",
+            Self::Bishop => "馬",
+            Self::Gold | Self::King => self.short(),
+        }
+    }
+    // CSA 形式の2文字コード
+    pub fn csa(self, promoted: bool) -> &'static str {
+        match (self, promoted) {
+            (Self::Pawn, false) => "FU",
+            (Self::Pawn, true) => "TO",
+            (Self::Lance, false) => "KY",
+            (Self::Lance, true) => "NY",
+            (Self::Knight, false) => "KE",
+            (Self::Knight, true) => "NK",
+            (Self::Silver, false) => "GI",
+            (Self::Silver, true) => "NG",
+            (Self::Gold, _) => "KI",
+            (Self::Rook, false) => "HI",
+            (Self::Rook, true) => "RY",
+            (Self::Bishop, false) => "KA",
+            (Self::Bishop, true) => "UM",
+            (Self::King, _) => "OU",
+        }
+    }
 }
 
+const PIECE_LIMITS: [(PieceType, usize); 8] = [
+    (PieceType::King, 1),
+    (PieceType::Rook, 1),
+    (PieceType::Bishop, 1),
+    (PieceType::Gold, 2),
+    (PieceType::Silver, 2),
+    (PieceType::Knight, 2),
+    (PieceType::Lance, 2),
+    (PieceType::Pawn, 9),
+];
+
+// 駒の id は対局を通して一意で、世界線が分岐しても同じ駒は同じ id を持つ
+pub type PieceId = u64;
+
 #[derive(Clone, Debug)]
 pub struct Piece {
-    pub id: u64,
-    pub owner: Player,
-    pub candidates: BTreeSet<PieceType>,
-    pub promoted: bool,
+    pub(crate) id: PieceId,
+    pub(crate) owner: Player,
+    pub(crate) candidates: BTreeSet<PieceType>,
+    pub(crate) promoted: bool,
+    // 持ち駒の出自 (捕獲した世界線, 手数)。同じ駒でも世界線ごとに別の持ち駒として扱う
+    pub(crate) captured_at: Option<(WorldId, usize)>,
+    // 過去の世界線で捕獲され、現在にも残っている駒 (CaptureAcrossTime::Paradoxed)。動かせない
+    pub(crate) paradoxed: bool,
 }
 
 impl Piece {
-    pub fn new(id: u64, owner: Player) -> Self {
+    pub(crate) fn new(id: PieceId, owner: Player) -> Self {
         Self {
             id,
             owner,
             candidates: PieceType::all(),
             promoted: false,
+            captured_at: None,
+            paradoxed: false,
         }
     }
+    pub fn id(&self) -> PieceId {
+        self.id
+    }
+    pub fn owner(&self) -> Player {
+        self.owner
+    }
+    pub fn candidates(&self) -> &BTreeSet<PieceType> {
+        &self.candidates
+    }
+    pub fn is_promoted(&self) -> bool {
+        self.promoted
+    }
+    pub fn captured_at(&self) -> Option<(WorldId, usize)> {
+        self.captured_at
+    }
+    pub fn is_paradoxed(&self) -> bool {
+        self.paradoxed
+    }
+    // 捕獲された駒を取った側の持ち駒の形に戻す。成りを解き、候補は成る前の駒種として数え直す
+    pub(crate) fn demote_captured(&mut self, captor: Player, at: (WorldId, usize)) {
+        self.owner = captor;
+        self.promoted = false;
+        self.paradoxed = false;
+        self.captured_at = Some(at);
+    }
+    // 候補の略称。成った駒は成った後の駒種 (と・竜など) として別の名前で数える
+    pub fn candidate_labels(&self) -> Vec<&'static str> {
+        self.candidates
+            .iter()
+            .map(|c| {
+                if self.promoted {
+                    c.promoted_short()
+                } else {
+                    c.short()
+                }
+            })
+            .collect()
+    }
+    pub fn display_text(&self) -> String {
+        let body = if self.candidates.len() == 1 {
+            self.candidate_labels()[0].to_string()
+        } else {
+            format!("{}候補", self.candidates.len())
+        };
+        let mark = if self.paradoxed { "※" } else { "" };
+        format!("{}{}{mark}", self.owner.mark(), body)
+    }
 }
 
-pub type Board = Vec<Vec<Option<Piece>>>;
+// 駒は Arc で共有し、局面を複製しても書き換えるまでは同じ実体を指す (copy-on-write)。
+// 履歴の各局面に全駒を複製していた頃に比べ、変化のない駒の分だけメモリが減る。
+pub type Board = Vec<Vec<Option<Arc<Piece>>>>;
+pub type CandidateSet = BTreeSet<PieceType>;
 
 #[derive(Clone)]
 pub struct Snapshot {
-    pub board: Board,
-    pub hands: HashMap<Player, Vec<Piece>>,
+    pub(crate) board: Board,
+    pub(crate) hands: HashMap<Player, Vec<Arc<Piece>>>,
+}
+
+impl Snapshot {
+    pub(crate) fn empty((files, ranks): (usize, usize)) -> Self {
+        Self {
+            board: vec![vec![None; files]; ranks],
+            hands: HashMap::from([(Player::Black, Vec::new()), (Player::White, Vec::new())]),
+        }
+    }
+    pub fn board(&self) -> &Board {
+        &self.board
+    }
+    pub fn files(&self) -> usize {
+        self.board.first().map_or(0, Vec::len)
+    }
+    pub fn ranks(&self) -> usize {
+        self.board.len()
+    }
+    pub fn contains(&self, sq: Square) -> bool {
+        sq.file < self.files() && sq.rank < self.ranks()
+    }
+    pub fn piece_at(&self, sq: Square) -> Option<&Piece> {
+        self.board.get(sq.rank)?.get(sq.file)?.as_deref()
+    }
+    pub(crate) fn take(&mut self, sq: Square) -> Option<Piece> {
+        self.board
+            .get_mut(sq.rank)?
+            .get_mut(sq.file)?
+            .take()
+            .map(Arc::unwrap_or_clone)
+    }
+    pub(crate) fn put(&mut self, sq: Square, piece: Option<Piece>) {
+        self.board[sq.rank][sq.file] = piece.map(Arc::new);
+    }
+    pub(crate) fn piece_mut(&mut self, sq: Square) -> Option<&mut Piece> {
+        self.board
+            .get_mut(sq.rank)?
+            .get_mut(sq.file)?
+            .as_mut()
+            .map(Arc::make_mut)
+    }
+    pub(crate) fn add_to_hand(&mut self, piece: Piece) {
+        self.hands
+            .entry(piece.owner)
+            .or_default()
+            .push(Arc::new(piece));
+    }
+    pub fn hand(&self, player: Player) -> &[Arc<Piece>] {
+        self.hands.get(&player).map(Vec::as_slice).unwrap_or(&[])
+    }
+    pub fn pieces(&self) -> impl Iterator<Item = (Square, &Piece)> {
+        self.board.iter().enumerate().flat_map(|(rank, row)| {
+            row.iter().enumerate().filter_map(move |(file, cell)| {
+                cell.as_deref().map(|p| (Square::new(file, rank), p))
+            })
+        })
+    }
+    pub fn hand_pieces(&self) -> impl Iterator<Item = &Piece> {
+        self.hands.values().flatten().map(Arc::as_ref)
+    }
+    pub fn hand_groups(&self, player: Player) -> BTreeMap<CandidateSet, Vec<u64>> {
+        let mut groups: BTreeMap<CandidateSet, Vec<u64>> = BTreeMap::new();
+        for p in self.hand(player) {
+            groups.entry(p.candidates.clone()).or_default().push(p.id);
+        }
+        groups
+    }
+    // 局面の比較用のハッシュ。駒の id は含めず、候補と成りと持ち駒を含める
+    pub fn position_hash(&self) -> u64 {
+        let mut h = DefaultHasher::new();
+        (self.files(), self.ranks()).hash(&mut h);
+        for cell in self.board.iter().flatten() {
+            cell.as_deref()
+                .map(|p| (p.owner, p.promoted, &p.candidates))
+                .hash(&mut h);
+        }
+        for pl in [Player::Black, Player::White] {
+            let mut hand: Vec<&CandidateSet> =
+                self.hand(pl).iter().map(|p| &p.candidates).collect();
+            hand.sort();
+            hand.hash(&mut h);
+        }
+        h.finish()
+    }
+    pub(crate) fn hand_index_of(&self, player: Player, group: &CandidateSet) -> Option<usize> {
+        self.hand(player)
+            .iter()
+            .position(|p| &p.candidates == group)
+    }
+    pub(crate) fn contains_piece(&self, id: PieceId) -> bool {
+        self.pieces()
+            .map(|(_, p)| p)
+            .chain(self.hand_pieces())
+            .any(|p| p.id == id)
+    }
+    // id の駒を盤上・持ち駒から (同じ局面に複数あればすべて) 取り除く
+    pub(crate) fn remove_piece(&mut self, id: PieceId) {
+        for cell in self.board.iter_mut().flatten() {
+            if cell.as_ref().is_some_and(|p| p.id == id) {
+                *cell = None;
+            }
+        }
+        for hand in self.hands.values_mut() {
+            hand.retain(|p| p.id != id);
+        }
+    }
+    fn all_pieces_mut(&mut self) -> impl Iterator<Item = &mut Arc<Piece>> {
+        let board = self.board.iter_mut().flatten().flatten();
+        board.chain(self.hands.values_mut().flatten())
+    }
+
+    // 盤上と持ち駒の player の駒のうち、候補に pt を含むものの数
+    pub fn count_candidates(&self, player: Player, pt: PieceType) -> usize {
+        self.pieces()
+            .map(|(_, p)| p)
+            .chain(self.hand(player).iter().map(Arc::as_ref))
+            .filter(|p| p.owner == player && p.candidates.contains(&pt))
+            .count()
+    }
+
+    // 全駒の候補を keep で絞り、候補が変わった駒の id を返す。
+    // 候補のあった駒が1枚でも空になるなら何も変えずにエラーにする。
+    // 分岐先へ送った駒は送り先で元の自分と同じ id で並ぶので、id ではなく駒ごとに扱う。
+    pub fn retain_candidates(
+        &mut self,
+        mut keep: impl FnMut(&Piece, PieceType) -> bool,
+    ) -> Result<Vec<PieceId>, MoveError> {
+        let mut updates = Vec::new();
+        for p in self.all_pieces_mut() {
+            let kept: CandidateSet = p
+                .candidates
+                .iter()
+                .copied()
+                .filter(|t| keep(p, *t))
+                .collect();
+            if kept.is_empty() && !p.candidates.is_empty() {
+                return Err(MoveError::CandidatesExhausted(p.id));
+            }
+            updates.push((kept != p.candidates).then_some(kept));
+        }
+        let mut ids = Vec::new();
+        for (p, kept) in self.all_pieces_mut().zip(updates) {
+            if let Some(kept) = kept {
+                ids.push(p.id);
+                Arc::make_mut(p).candidates = kept;
+            }
+        }
+        Ok(ids)
+    }
+
+    // id の駒を (同じ局面に複数あればすべて) pt に確定させる。pt が候補にない場合はエラー
+    pub fn collapse_piece(&mut self, id: PieceId, pt: PieceType) -> Result<(), MoveError> {
+        let copies: Vec<&mut Arc<Piece>> = self.all_pieces_mut().filter(|p| p.id == id).collect();
+        if copies.is_empty() {
+            return Err(MoveError::PieceNotFound(id));
+        }
+        if copies.iter().any(|p| !p.candidates.contains(&pt)) {
+            return Err(MoveError::NotACandidate(id, pt));
+        }
+        for p in copies.into_iter().filter(|p| p.candidates.len() > 1) {
+            Arc::make_mut(p).candidates = BTreeSet::from([pt]);
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -107,594 +389,4835 @@ pub enum CheckAttackMode {
     Certain,
 }
 
-#[derive(Clone)]
-pub struct Settings {
-    pub max_worlds: usize,
-    pub max_time_jump: i32,
-    pub hand_mode: HandMode,
-    pub check_attack_mode: CheckAttackMode,
-    pub past_only: bool,
+// 推論と枚数制約以外に、駒種を乱数で確定させる場面
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CollapseMode {
+    // 乱数では確定させない (観測の手も指せない)
+    CountOnly,
+    // 観測の手でのみ確定させる
+    RandomOnObserve,
+    // 観測に加え、捕獲されて持ち駒になった時にも確定させる
+    RandomOnCapture,
 }
 
-impl Default for Settings {
-    fn default() -> Self {
-        Self {
-            max_worlds: 7,
-            max_time_jump: 5,
-            hand_mode: HandMode::PerWorld,
-            check_attack_mode: CheckAttackMode::Possible,
-            past_only: true,
-        }
-    }
+// 過去へ送った駒が、着地した局面にまだいる自分自身 (同じ id の駒) と出会った時の扱い
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParadoxRule {
+    // 過去の自分は盤上・持ち駒から消える
+    RemovePastSelf,
+    // 両方残し、同じ駒として候補を共通部分にそろえる
+    MergeCandidates,
+    // そのような手は指せない
+    Forbid,
 }
 
-#[derive(Clone)]
-pub struct WorldLine {
-    pub w: i32,
-    pub history: Vec<Snapshot>,
-    pub staged: Option<PlannedMove>,
-    pub lost: bool,
+// 分岐先 (過去) で駒を取った時、分岐元の現在に残っている同じ駒の扱い
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaptureAcrossTime {
+    // 取った世界線だけの出来事とし、分岐元の現在には手を付けない
+    Local,
+    // 分岐元の現在の盤上からも取り除く
+    RemoveFromSource,
+    // 分岐元の現在には残すが、矛盾した駒として以後動かせなくする
+    Paradoxed,
 }
 
-#[derive(Clone, Debug)]
-pub enum MoveKind {
-    Move {
-        from: (usize, usize),
-        to: (usize, usize),
-        promote: bool,
-    },
-    Drop {
-        piece_index: usize,
-        to: (usize, usize),
-    },
+// 1ターンに手を指す世界線の決め方
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TurnMode {
+    // 全世界線に1手ずつ入力してから同時に確定する
+    Simultaneous,
+    // 好きな世界線を1つ選んで指す
+    Sequential,
+    // 世界線番号順に1つずつ。先手・後手が1手ずつ指したら次の世界線へ進む
+    RoundRobin,
 }
 
-#[derive(Clone, Debug)]
-pub struct PlannedMove {
-    pub kind: MoveKind,
-    pub delta_w: i32,
-    pub delta_t: i32,
+// 分岐で作られる世界線の番号の決め方
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorldIdPolicy {
+    // w+Δw ちょうど。使われていれば分岐できない
+    Exact,
+    // w+Δw に最も近い空き番号 (同じ距離なら Δw の向きを先に見る)
+    NearestFree,
+    // w+Δw から Δw の向きに進んで最初の空き番号
+    SignedDirection,
+    // 親 w の子を 3w±1 に置く木の番号。使われていれば Δw の向きに進む
+    Tree,
 }
 
-pub struct Game {
-    pub settings: Settings,
-    pub worlds: BTreeMap<i32, WorldLine>,
-    pub turn: Player,
-    pub selected_world: i32,
-    pub message: String,
-    next_id: u64,
+// 開始局面の駒の並べ方と候補
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StartPosition {
+    // 自陣の3段すべてに、どの駒種にもなりうる駒を置く
+    FullQuantum,
+    // 本将棋の初形。駒種はすべて確定している
+    Classical,
+    // 本将棋の初形の升に置き、候補を段ごとに絞る (1段目は香桂銀金王、2段目は飛角、3段目は歩)
+    RowConstrained,
 }
 
-impl Game {
-    pub fn new(settings: Settings) -> Self {
-        let mut g = Self {
-            settings,
-            worlds: BTreeMap::new(),
-            turn: Player::Black,
-            selected_world: 0,
-            message: String::new(),
-            next_id: 1,
-        };
-        let snapshot = g.initial_snapshot();
-        g.worlds.insert(
-            0,
-            WorldLine {
-                w: 0,
-                history: vec![snapshot],
-                staged: None,
-                lost: false,
-            },
-        );
-        g
-    }
+// 盤の広さと駒の一式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Variant {
+    // 9×9 の本将棋
+    Standard,
+    // 5×5 の5五将棋。王・飛・角・金・銀・歩が1枚ずつで、敵陣は最奥の1段
+    Minishogi,
+}
 
-    fn initial_snapshot(&mut self) -> Snapshot {
-        let mut board = vec![vec![None; 9]; 9];
-        for y in 0..3 {
-            for x in 0..9 {
-                board[y][x] = Some(Piece::new(self.alloc_id(), Player::White));
-            }
+const MINISHOGI_LIMITS: [(PieceType, usize); 6] = [
+    (PieceType::King, 1),
+    (PieceType::Rook, 1),
+    (PieceType::Bishop, 1),
+    (PieceType::Gold, 1),
+    (PieceType::Silver, 1),
+    (PieceType::Pawn, 1),
+];
+
+impl Variant {
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Standard => "本将棋",
+            Self::Minishogi => "5五将棋",
         }
-        for y in 6..9 {
-            for x in 0..9 {
-                board[y][x] = Some(Piece::new(self.alloc_id(), Player::Black));
-            }
+    }
+    // (筋の数, 段の数)
+    pub fn board_size(self) -> (usize, usize) {
+        match self {
+            Self::Standard => (9, 9),
+            Self::Minishogi => (5, 5),
         }
-        let mut hands = HashMap::new();
-        hands.insert(Player::Black, Vec::new());
-        hands.insert(Player::White, Vec::new());
-        Snapshot { board, hands }
     }
-
-    fn alloc_id(&mut self) -> u64 {
-        let id = self.next_id;
-        self.next_id += 1;
-        id
+    // 成れる敵陣の段数
+    pub fn camp_depth(self) -> usize {
+        match self {
+            Self::Standard => 3,
+            Self::Minishogi => 1,
+        }
     }
-
-    pub fn present(&self, w: i32) -> Option<&Snapshot> {
-        self.worlds.get(&w).and_then(|wl| wl.history.last())
+    // 開始局面で駒を置く自陣の段数
+    fn home_rows(self) -> usize {
+        match self {
+            Self::Standard => 3,
+            Self::Minishogi => 2,
+        }
     }
-
-    fn mut_present(&mut self, w: i32) -> Option<&mut Snapshot> {
-        self.worlds.get_mut(&w).and_then(|wl| wl.history.last_mut())
+    // 1人分の駒種ごとの枚数
+    fn army(self) -> &'static [(PieceType, usize)] {
+        match self {
+            Self::Standard => &PIECE_LIMITS,
+            Self::Minishogi => &MINISHOGI_LIMITS,
+        }
     }
+}
 
-    pub fn stage_move(&mut self, w: i32, mv: PlannedMove) {
-        if let Some(wl) = self.worlds.get_mut(&w) {
-            wl.staged = Some(mv);
+// 駒落ち。上手 (後手の側に並ぶ) が駒を落とし、先に指す
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Handicap {
+    None,
+    Lance,
+    Bishop,
+    Rook,
+    TwoPiece,
+    FourPiece,
+    SixPiece,
+}
+
+impl Handicap {
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::None => "平手",
+            Self::Lance => "香落ち",
+            Self::Bishop => "角落ち",
+            Self::Rook => "飛車落ち",
+            Self::TwoPiece => "二枚落ち",
+            Self::FourPiece => "四枚落ち",
+            Self::SixPiece => "六枚落ち",
         }
     }
+    // 上手が落とす駒の (筋, 段) と、本将棋の初形でそこにある駒種
+    pub fn removed(self) -> &'static [(usize, usize, PieceType)] {
+        use PieceType::*;
+        match self {
+            Self::None => &[],
+            Self::Lance => &[(8, 0, Lance)],
+            Self::Bishop => &[(7, 1, Bishop)],
+            Self::Rook => &[(1, 1, Rook)],
+            Self::TwoPiece => &[(1, 1, Rook), (7, 1, Bishop)],
+            Self::FourPiece => &[(1, 1, Rook), (7, 1, Bishop), (0, 0, Lance), (8, 0, Lance)],
+            Self::SixPiece => &[
+                (1, 1, Rook),
+                (7, 1, Bishop),
+                (0, 0, Lance),
+                (8, 0, Lance),
+                (1, 0, Knight),
+                (7, 0, Knight),
+            ],
+        }
+    }
+}
 
-    pub fn clear_staged(&mut self) {
-        for wl in self.worlds.values_mut() {
-            wl.staged = None;
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuantumModel {
+    // 駒種は観測で候補が1つに絞られた時に初めて決まる
+    Lazy,
+    // 駒種は開始時に seed から決まっていて、観測はそれを明かすだけ
+    Hidden,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PromotionZone {
+    // 移動元か移動先のどちらかが敵陣 (盤上の段) なら成れる
+    PerWorld,
+    // Δw/Δt を使った手は到着した時間層での位置だけで判定する
+    PerTimeLayer,
+}
+
+// 各世界線の勝敗をどう集計して対局の勝敗とするか
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WinCondition {
+    AllWorlds,
+    Majority,
+    AnyWorld,
+}
+
+// 持ち時間を使い切った後の扱い
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockMode {
+    // 1手 secs 秒の秒読みを periods 回まで。1手を secs 秒以内に指せば回数は減らない
+    Byoyomi { secs: u64, periods: u32 },
+    // 1手指すごとに持ち時間へ increment_secs 秒を加える。使い切れば時間切れ
+    Fischer { increment_secs: u64 },
+}
+
+// 持ち時間の設定。対局の開始時に Clock を作る
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeControl {
+    pub main_secs: u64,
+    pub mode: ClockMode,
+}
+
+impl Default for TimeControl {
+    fn default() -> Self {
+        Self {
+            main_secs: 600,
+            mode: ClockMode::Byoyomi {
+                secs: 30,
+                periods: 1,
+            },
         }
     }
+}
 
-    pub fn commit_turn(&mut self) {
-        let world_ids: Vec<i32> = self.worlds.keys().copied().collect();
-        for w in &world_ids {
-            if self
-                .worlds
-                .get(w)
-                .and_then(|wl| wl.staged.as_ref())
-                .is_none()
-            {
-                self.message = format!("世界線 {} の手が未入力です", w);
-                return;
-            }
+// 対局時計。アプリが計った経過時間を Game::tick_clock で手番の側に渡す
+#[derive(Clone, Debug)]
+pub struct Clock {
+    control: TimeControl,
+    // 残りの持ち時間
+    main: BTreeMap<Player, std::time::Duration>,
+    // 残りの秒読みの回数 (今読まれている回を含む)
+    periods: BTreeMap<Player, u32>,
+    // 今の手で読まれている秒読みの残り
+    period_left: BTreeMap<Player, std::time::Duration>,
+}
+
+impl Clock {
+    pub fn new(control: TimeControl) -> Self {
+        let main = std::time::Duration::from_secs(control.main_secs);
+        let (periods, period) = match control.mode {
+            ClockMode::Byoyomi { secs, periods } => (periods, std::time::Duration::from_secs(secs)),
+            ClockMode::Fischer { .. } => (0, std::time::Duration::ZERO),
+        };
+        let both = [Player::Black, Player::White];
+        Self {
+            control,
+            main: both.into_iter().map(|p| (p, main)).collect(),
+            periods: both.into_iter().map(|p| (p, periods)).collect(),
+            period_left: both.into_iter().map(|p| (p, period)).collect(),
         }
+    }
 
-        let staged: Vec<(i32, PlannedMove)> = world_ids
-            .iter()
-            .map(|w| (*w, self.worlds[w].staged.clone().unwrap()))
-            .collect();
+    pub fn control(&self) -> TimeControl {
+        self.control
+    }
+
+    pub fn main_left(&self, player: Player) -> std::time::Duration {
+        self.main[&player]
+    }
 
-        let mut global_consumption: HashMap<PieceType, usize> = HashMap::new();
+    pub fn periods_left(&self, player: Player) -> u32 {
+        self.periods[&player]
+    }
+
+    pub fn period_left(&self, player: Player) -> std::time::Duration {
+        self.period_left[&player]
+    }
+
+    // 持ち時間も秒読みも使い切った
+    pub fn flagged(&self, player: Player) -> bool {
+        self.main[&player].is_zero() && self.periods[&player] == 0
+    }
 
-        for (w, pm) in staged {
-            if let Err(e) = self.apply_one_world(w, pm, &mut global_consumption) {
-                self.message = format!("不合法手: {}", e);
-                return;
+    // player の時計から経過時間を引く。持ち時間を使い切った分は秒読みから引き、
+    // 秒読みを1回使い切るごとに回数を1つ減らす。時間切れになれば true
+    pub fn tick(&mut self, player: Player, elapsed: std::time::Duration) -> bool {
+        let main = self.main.get_mut(&player).unwrap();
+        let mut over = elapsed.saturating_sub(*main);
+        *main = main.saturating_sub(elapsed);
+        if let ClockMode::Byoyomi { secs, .. } = self.control.mode {
+            let periods = self.periods.get_mut(&player).unwrap();
+            let left = self.period_left.get_mut(&player).unwrap();
+            while !over.is_zero() && *periods > 0 {
+                if over < *left {
+                    *left -= over;
+                    break;
+                }
+                over -= *left;
+                *periods -= 1;
+                *left = if *periods > 0 {
+                    std::time::Duration::from_secs(secs)
+                } else {
+                    std::time::Duration::ZERO
+                };
             }
         }
+        self.flagged(player)
+    }
 
-        if self.settings.hand_mode == HandMode::Global {
-            let mut total: HashMap<PieceType, usize> = HashMap::new();
-            for wl in self.worlds.values() {
-                if let Some(s) = wl.history.last() {
-                    for p in s.hands.get(&self.turn).into_iter().flatten() {
-                        for c in &p.candidates {
-                            *total.entry(*c).or_default() += 1;
-                        }
-                    }
+    // player が手を確定した。秒読みは読み直し、フィッシャーは加算する
+    fn on_move(&mut self, player: Player) {
+        match self.control.mode {
+            ClockMode::Byoyomi { secs, .. } => {
+                if self.periods[&player] > 0 {
+                    self.period_left
+                        .insert(player, std::time::Duration::from_secs(secs));
                 }
             }
-            for (pt, used) in global_consumption {
-                if used > *total.get(&pt).unwrap_or(&0) {
-                    self.message = format!("global hand不足: {}", pt.short());
-                    return;
-                }
+            ClockMode::Fischer { increment_secs } => {
+                *self.main.get_mut(&player).unwrap() +=
+                    std::time::Duration::from_secs(increment_secs);
             }
         }
+    }
 
-        for wl in self.worlds.values_mut() {
-            wl.staged = None;
-            if let Some(s) = wl.history.last_mut() {
-                Self::collapse_by_count(s);
-                wl.lost = Self::king_candidates(s, self.turn).is_empty()
-                    || Self::king_candidates(s, self.turn.opposite()).is_empty();
-            }
+    // 表示用。例: "9:58"、"秒読み 27秒 (残り3回)"
+    pub fn label(&self, player: Player) -> String {
+        let main = self.main[&player].as_secs();
+        if main > 0 || !matches!(self.control.mode, ClockMode::Byoyomi { .. }) {
+            return format!("{}:{:02}", main / 60, main % 60);
         }
+        match self.periods[&player] {
+            0 => "時間切れ".to_string(),
+            n => format!(
+                "秒読み {}秒 (残り{n}回)",
+                self.period_left[&player].as_secs()
+            ),
+        }
+    }
+}
 
-        self.turn = self.turn.opposite();
-        self.message = "同時確定しました".into();
+// 引き分けになった理由
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DrawReason {
+    // 引き分けの提案が受け入れられた
+    Agreement,
+    // 千日手
+    Repetition,
+    // 持将棋
+    Impasse,
+    // 各世界線の勝敗を集計した結果
+    Worlds,
+}
+
+impl DrawReason {
+    pub fn label(self) -> &'static str {
+        match self {
+            DrawReason::Agreement => "合意",
+            DrawReason::Repetition => "千日手",
+            DrawReason::Impasse => "持将棋",
+            DrawReason::Worlds => "世界線の勝敗",
+        }
     }
+}
 
-    fn apply_one_world(
-        &mut self,
-        w: i32,
-        pm: PlannedMove,
-        global_cons: &mut HashMap<PieceType, usize>,
-    ) -> anyhow::Result<()> {
-        let present_idx = self.worlds.get(&w).unwrap().history.len() as i32 - 1;
-        if self.settings.past_only && pm.delta_t > 0 {
-            anyhow::bail!("未来移動は無効");
+// 対局の状態。commit_turn や UI は終局したかをこれで見る
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GameStatus {
+    Ongoing,
+    BlackWins,
+    WhiteWins,
+    Draw { reason: DrawReason },
+}
+
+impl GameStatus {
+    // 引き分けになりうる結果。reason は引き分けの時だけ使う
+    fn from_result(result: GameResult, reason: DrawReason) -> Self {
+        match result {
+            GameResult::Win(player) => Self::won(player),
+            GameResult::Draw => Self::Draw { reason },
         }
-        if pm.delta_t.abs() > self.settings.max_time_jump {
-            anyhow::bail!("時間逆行幅が上限超え");
+    }
+    // 投了・時間切れ・反則の申告など、勝ちにしかならない終局
+    fn won(player: Player) -> Self {
+        match player {
+            Player::Black => Self::BlackWins,
+            Player::White => Self::WhiteWins,
+        }
+    }
+    pub fn is_over(self) -> bool {
+        self != Self::Ongoing
+    }
+    pub fn result(self) -> Option<GameResult> {
+        match self {
+            Self::Ongoing => None,
+            Self::BlackWins => Some(GameResult::Win(Player::Black)),
+            Self::WhiteWins => Some(GameResult::Win(Player::White)),
+            Self::Draw { .. } => Some(GameResult::Draw),
         }
-        let t_base = present_idx + pm.delta_t;
-        if t_base < 0 {
-            anyhow::bail!("履歴範囲外");
+    }
+    pub fn label(self) -> String {
+        match self {
+            Self::Ongoing => "対局中".into(),
+            Self::BlackWins => format!("{}の勝ち", Player::Black.label()),
+            Self::WhiteWins => format!("{}の勝ち", Player::White.label()),
+            Self::Draw { reason } => format!("引き分け ({})", reason.label()),
         }
+    }
+}
 
-        let branching = pm.delta_w != 0 || pm.delta_t < 0;
+// 同じ局面が4回現れた時の扱い
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RepetitionRule {
+    // 引き分け
+    Draw,
+    // 引き分け。ただし繰り返しの間ずっと王手をかけ続けた側の負け (連続王手の千日手)
+    PerpetualCheckLoses,
+}
 
-        if branching {
-            let w_new = w + pm.delta_w;
-            if self.worlds.len() >= self.settings.max_worlds {
-                anyhow::bail!("MAX_WORLDS");
-            }
-            if self.worlds.contains_key(&w_new) {
-                anyhow::bail!("world衝突");
+// 千日手の判定用に、確定のたびに局面を記録したもの
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct PositionMark {
+    // 局面と手番のハッシュ
+    hash: u64,
+    to_move: Player,
+    // 手番の側が王手されているか
+    in_check: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GameResult {
+    Win(Player),
+    Draw,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Settings {
+    pub max_worlds: usize,
+    pub max_time_jump: i32,
+    pub hand_mode: HandMode,
+    pub check_attack_mode: CheckAttackMode,
+    pub past_only: bool,
+    // 変則: 各プレイヤー1局1回、世界線の生成時にその世界へ駒を召喚できる
+    pub summon_variant: bool,
+    // N ターン何も起きなかった世界線を凍結して盤から外す
+    pub archive_idle_turns: Option<usize>,
+    pub world_destruction: bool,
+    pub teleport_drop: bool,
+    pub quantum_model: QuantumModel,
+    pub variant: Variant,
+    pub start_position: StartPosition,
+    pub handicap: Handicap,
+    pub promotion_zone: PromotionZone,
+    pub win_condition: WinCondition,
+    pub repetition_rule: RepetitionRule,
+    // 分岐の起点に祖先・兄弟の世界線の局面を選べる
+    pub cross_world_base: bool,
+    // 1ターンに分岐させてよい手の数
+    pub max_branches_per_turn: Option<usize>,
+    // 指した後に自玉が取られうる手を認めない
+    pub forbid_self_check: bool,
+    // 両者の王が敵陣に入った世界線を27点法で判定する (本将棋のみ)
+    pub entering_king: bool,
+    // 分岐しても同じ id の駒は同じ駒とみなし、どこかの世界線で消えた候補は全世界線から消す
+    pub entangle_worlds: bool,
+    // ターンの終わりに、局面が食い違わない世界線どうしを自動でまとめる
+    pub auto_merge_worlds: bool,
+    // 王を失った世界線を凍結し、以後の入力と MAX_WORLDS の勘定から外す
+    pub prune_lost_worlds: bool,
+    pub collapse_mode: CollapseMode,
+    pub paradox_rule: ParadoxRule,
+    pub capture_across_time: CaptureAcrossTime,
+    pub world_id_policy: WorldIdPolicy,
+    pub turn_mode: TurnMode,
+    // 同じ世界線でのパスは自分の手番 N 回につき1回まで。None なら制限なし
+    pub pass_interval: Option<usize>,
+    // 全世界線の手を解決した後、指した側の王が取られうる世界線が残れば確定ごと拒否する
+    pub strict_commit_check: bool,
+    // 角が1手で同時に動かせる軸の数 (2〜4)。2 なら x/y/w/t のうち2軸の平面上の斜めだけ
+    pub bishop_axes: usize,
+    // 持ち時間と秒読み・フィッシャー加算。None なら時計を使わない
+    pub time_control: Option<TimeControl>,
+    // 反則の手も確定させ、相手の申告で負けにする (王手放置の判定は CheckAttackMode に従う)
+    pub arbiter_mode: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            max_worlds: 7,
+            max_time_jump: 5,
+            hand_mode: HandMode::PerWorld,
+            check_attack_mode: CheckAttackMode::Possible,
+            past_only: true,
+            summon_variant: false,
+            archive_idle_turns: None,
+            world_destruction: false,
+            teleport_drop: false,
+            quantum_model: QuantumModel::Lazy,
+            variant: Variant::Standard,
+            start_position: StartPosition::FullQuantum,
+            handicap: Handicap::None,
+            promotion_zone: PromotionZone::PerWorld,
+            win_condition: WinCondition::Majority,
+            repetition_rule: RepetitionRule::Draw,
+            cross_world_base: false,
+            max_branches_per_turn: None,
+            forbid_self_check: false,
+            entering_king: true,
+            entangle_worlds: false,
+            auto_merge_worlds: false,
+            prune_lost_worlds: false,
+            collapse_mode: CollapseMode::RandomOnObserve,
+            paradox_rule: ParadoxRule::MergeCandidates,
+            capture_across_time: CaptureAcrossTime::Local,
+            world_id_policy: WorldIdPolicy::Exact,
+            turn_mode: TurnMode::Simultaneous,
+            pass_interval: None,
+            strict_commit_check: false,
+            bishop_axes: 4,
+            time_control: None,
+            arbiter_mode: false,
+        }
+    }
+}
+
+impl Settings {
+    // 組み合わせとして意味をなさない設定を弾く。最初に見つかった問題を返す
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        if self.max_worlds == 0 {
+            return Err(SettingsError::NoWorlds);
+        }
+        if self.max_time_jump < 0 {
+            return Err(SettingsError::NegativeTimeJump(self.max_time_jump));
+        }
+        if self.teleport_drop && self.hand_mode != HandMode::Global {
+            return Err(SettingsError::TeleportNeedsGlobalHand);
+        }
+        if self.summon_variant && self.max_worlds < 2 {
+            return Err(SettingsError::SummonNeedsBranching);
+        }
+        if self.archive_idle_turns == Some(0) {
+            return Err(SettingsError::ZeroIdleTurns);
+        }
+        if !(2..=4).contains(&self.bishop_axes) {
+            return Err(SettingsError::BishopAxes(self.bishop_axes));
+        }
+        if self.handicap != Handicap::None && self.variant != Variant::Standard {
+            return Err(SettingsError::HandicapNeedsStandard);
+        }
+        if let Some(tc) = self.time_control {
+            let extra = match tc.mode {
+                ClockMode::Byoyomi { secs, periods } => secs * u64::from(periods),
+                ClockMode::Fischer { increment_secs } => increment_secs,
+            };
+            if tc.main_secs == 0 && extra == 0 {
+                return Err(SettingsError::NoThinkingTime);
+            }
+        }
+        Ok(())
+    }
+
+    // validate が通るように値を丸め、成り立たない変則は外す
+    pub fn clamp(&mut self) {
+        self.max_worlds = self.max_worlds.max(1);
+        self.max_time_jump = self.max_time_jump.max(0);
+        self.teleport_drop &= self.hand_mode == HandMode::Global;
+        self.summon_variant &= self.max_worlds >= 2;
+        if let Some(n) = &mut self.archive_idle_turns {
+            *n = (*n).max(1);
+        }
+        self.bishop_axes = self.bishop_axes.clamp(2, 4);
+        if self.variant != Variant::Standard {
+            self.handicap = Handicap::None;
+        }
+        if let Some(tc) = &mut self.time_control {
+            tc.main_secs = tc.main_secs.max(1);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SettingsError {
+    NoWorlds,
+    NegativeTimeJump(i32),
+    TeleportNeedsGlobalHand,
+    SummonNeedsBranching,
+    ZeroIdleTurns,
+    BishopAxes(usize),
+    HandicapNeedsStandard,
+    NoThinkingTime,
+    FixedAtStart,
+}
+
+impl std::fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SettingsError::NoWorlds => write!(f, "MAX_WORLDS は1以上"),
+            SettingsError::NegativeTimeJump(n) => write!(f, "MAX_TIME_JUMP が負: {n}"),
+            SettingsError::TeleportNeedsGlobalHand => {
+                write!(f, "量子トンネル打ちには global hand が必要")
+            }
+            SettingsError::SummonNeedsBranching => {
+                write!(f, "召喚ルールには MAX_WORLDS が2以上必要")
+            }
+            SettingsError::ZeroIdleTurns => write!(f, "凍結までのターン数は1以上"),
+            SettingsError::BishopAxes(n) => write!(f, "角の同時に動く軸の数は2〜4: {n}"),
+            SettingsError::HandicapNeedsStandard => write!(f, "駒落ちは本将棋の盤でのみ選べる"),
+            SettingsError::NoThinkingTime => write!(f, "持ち時間・秒読み・加算がすべて0秒"),
+            SettingsError::FixedAtStart => {
+                write!(f, "盤・駒の配置・持ち時間は対局の途中で変えられない")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+// 配置が規則上ありえない理由 (Game::validate_position と PositionBuilder)。
+// at が None の駒は持ち駒
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PositionViolation {
+    OffBoard(Square),
+    NothingToPromote(Square),
+    NoSourceWorld(WorldId),
+    // 盤の広さを変える前に置いた駒がある
+    PlacedBeforeResize,
+    DuplicateId {
+        owner: Player,
+        at: Option<Square>,
+        id: PieceId,
+    },
+    NoCandidates {
+        owner: Player,
+        at: Option<Square>,
+    },
+    PromotedUnpromotable(Square),
+    // 成らずに行き所のない駒
+    Stuck(Square),
+    NoKing(Player),
+    // 王で確定した駒が2枚以上
+    ManyKings {
+        player: Player,
+        count: usize,
+    },
+    TooMany {
+        player: Player,
+        piece: PieceType,
+        count: usize,
+        limit: usize,
+    },
+    DoublePawn {
+        player: Player,
+        file: usize,
+    },
+    NotInVariant {
+        id: PieceId,
+        piece: PieceType,
+        variant: Variant,
+    },
+}
+
+impl PositionViolation {
+    // files は盤の筋数 (マスの表記に使う)
+    pub fn describe(&self, files: usize) -> String {
+        let place = |owner: &Player, at: &Option<Square>| match at {
+            Some(sq) => sq.notation(files),
+            None => format!("{}持駒", owner.mark()),
+        };
+        match self {
+            PositionViolation::OffBoard(sq) => format!("{}: 盤の外", sq.notation(files)),
+            PositionViolation::NothingToPromote(sq) => {
+                format!("{}: 成らせる駒がない", sq.notation(files))
+            }
+            PositionViolation::NoSourceWorld(w) => format!("写し元の世界線 {w} がない"),
+            PositionViolation::PlacedBeforeResize => "盤の広さを変える前に置いた駒がある".into(),
+            PositionViolation::DuplicateId { owner, at, id } => {
+                format!("{}: 駒ID #{id} が重複", place(owner, at))
+            }
+            PositionViolation::NoCandidates { owner, at } => {
+                format!("{}: 候補が空", place(owner, at))
+            }
+            PositionViolation::PromotedUnpromotable(sq) => {
+                format!("{}: 成った駒に成れない候補がある", sq.notation(files))
+            }
+            PositionViolation::Stuck(sq) => format!("{}: 行き所のない駒", sq.notation(files)),
+            PositionViolation::NoKing(pl) => format!("{}: 玉になりうる駒がない", pl.label()),
+            PositionViolation::ManyKings { player, count } => {
+                format!("{}: 玉で確定した駒が{count}枚ある", player.label())
+            }
+            PositionViolation::TooMany {
+                player,
+                piece,
+                count,
+                limit,
+            } => format!(
+                "{}: {}が{limit}枚を超える ({count}枚)",
+                player.label(),
+                piece.short()
+            ),
+            PositionViolation::DoublePawn { player, file } => {
+                format!("{}: {}筋に二歩", player.label(), files - file)
+            }
+            PositionViolation::NotInVariant { id, piece, variant } => {
+                format!("駒 #{id}: {}に{}はない", variant.label(), piece.short())
+            }
+        }
+    }
+}
+
+// PositionBuilder から対局を作れない理由
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PositionError {
+    Settings(SettingsError),
+    NoWorlds,
+    TooManyWorlds(usize),
+    // files は盤の筋数 (違反の表記に使う)
+    Invalid {
+        w: WorldId,
+        files: usize,
+        problems: Vec<PositionViolation>,
+    },
+}
+
+impl std::fmt::Display for PositionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PositionError::Settings(e) => write!(f, "{e}"),
+            PositionError::NoWorlds => write!(f, "世界線が1つもない"),
+            PositionError::TooManyWorlds(n) => write!(f, "世界線が MAX_WORLDS を超える ({n}本)"),
+            PositionError::Invalid { w, files, problems } => {
+                let problems: Vec<String> = problems.iter().map(|p| p.describe(*files)).collect();
+                write!(f, "{w} の局面が不正: {}", problems.join(" / "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for PositionError {}
+
+// play_one_world の結果。w の新しい現在局面、打ち先の世界線の差し替え、分岐でできた世界線
+struct WorldChange {
+    present: Snapshot,
+    dest: Option<(WorldId, Snapshot)>,
+    branch: Option<WorldLine>,
+    summoned: bool,
+}
+
+#[derive(Clone)]
+pub struct WorldLine {
+    pub(crate) w: WorldId,
+    pub(crate) history: Vec<Snapshot>,
+    pub(crate) staged: Option<PlannedMove>,
+    pub(crate) lost: bool,
+    // 王の喪失か詰みでこの世界線の勝敗が決まった場合の勝者
+    pub(crate) winner: Option<Player>,
+    // 持将棋でこの世界線が引き分けになった
+    pub(crate) drawn: bool,
+    pub(crate) parent: Option<WorldId>,
+    // 分岐の起点 (起点の世界線, 時間)。最初の世界線は None
+    pub(crate) branch_point: Option<(WorldId, TimeIdx)>,
+    // 分岐・捕獲・候補の絞り込みなど、この世界線に何かが起きた最後のターン
+    pub(crate) last_active: usize,
+}
+
+impl WorldLine {
+    pub fn w(&self) -> WorldId {
+        self.w
+    }
+    pub fn present_t(&self) -> TimeIdx {
+        TimeIdx(self.history.len() - 1)
+    }
+    pub fn history(&self) -> &[Snapshot] {
+        &self.history
+    }
+    pub fn present(&self) -> &Snapshot {
+        self.history.last().expect("world line without history")
+    }
+    pub fn staged(&self) -> Option<&PlannedMove> {
+        self.staged.as_ref()
+    }
+    pub fn is_lost(&self) -> bool {
+        self.lost
+    }
+    pub fn winner(&self) -> Option<Player> {
+        self.winner
+    }
+    pub fn is_drawn(&self) -> bool {
+        self.drawn
+    }
+    // 勝敗か持将棋の引き分けが決まった
+    pub fn is_decided(&self) -> bool {
+        self.winner.is_some() || self.drawn
+    }
+    pub fn parent(&self) -> Option<WorldId> {
+        self.parent
+    }
+    pub fn branch_point(&self) -> Option<(WorldId, TimeIdx)> {
+        self.branch_point
+    }
+    pub fn last_active(&self) -> usize {
+        self.last_active
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum MoveKind {
+    Move {
+        from: Square,
+        to: Square,
+        promote: bool,
+    },
+    Drop {
+        group: CandidateSet,
+        to: Square,
+    },
+    // global hand の持ち駒を別の世界線の現在へ打つ (Settings::teleport_drop)
+    TeleportDrop {
+        group: CandidateSet,
+        to: Square,
+        dest: WorldId,
+    },
+    // 自駒を1枚犠牲にして別の世界線を消滅させる (Settings::world_destruction)
+    CollapseWorld {
+        target: WorldId,
+        sacrifice: Square,
+    },
+    // 手番を使って盤上の駒1枚を観測し、駒種を1つに確定させる
+    Observe {
+        at: Square,
+    },
+    // この世界線では何もしない。時間だけ進む
+    Pass,
+}
+
+#[derive(Clone, Debug)]
+pub struct PlannedMove {
+    pub kind: MoveKind,
+    pub delta_w: i32,
+    pub delta_t: i32,
+    pub summon: Option<Square>,
+    pub base_world: Option<WorldId>,
+}
+
+impl PlannedMove {
+    // files は盤の筋数 (Game::files)
+    pub fn notation(&self, files: usize) -> String {
+        let mut out = match &self.kind {
+            MoveKind::Move { from, to, promote } => {
+                format!(
+                    "{from}→{to}{}",
+                    if *promote { "成" } else { "" },
+                    from = from.notation(files),
+                    to = to.notation(files)
+                )
+            }
+            MoveKind::Drop { group, to } => {
+                let cands: String = group.iter().map(|c| c.short()).collect();
+                format!("{to}打({cands})", to = to.notation(files))
+            }
+            MoveKind::TeleportDrop { group, to, dest } => {
+                let cands: String = group.iter().map(|c| c.short()).collect();
+                format!("{dest}:{to}打({cands})", to = to.notation(files))
+            }
+            MoveKind::CollapseWorld { target, sacrifice } => {
+                format!(
+                    "{target}破壊({sacrifice}犠牲)",
+                    sacrifice = sacrifice.notation(files)
+                )
+            }
+            MoveKind::Observe { at } => format!("{at}観測", at = at.notation(files)),
+            MoveKind::Pass => "パス".to_string(),
+        };
+        if self.delta_w != 0 {
+            out.push_str(&format!(" Δw{:+}", self.delta_w));
+        }
+        if self.delta_t != 0 {
+            out.push_str(&format!(" Δt{:+}", self.delta_t));
+        }
+        if let Some(b) = self.base_world {
+            out.push_str(&format!(" 起点{b}"));
+        }
+        if let Some(sq) = self.summon {
+            out.push_str(&format!(" 召喚{sq}", sq = sq.notation(files)));
+        }
+        out
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Deduction {
+    pub turn: usize,
+    pub world: WorldId,
+    pub piece_id: u64,
+    pub removed: CandidateSet,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GameEvent {
+    WorldCreated {
+        w: WorldId,
+        // 分岐させた手を指した世界線 (from は起点の局面がある世界線)
+        parent: WorldId,
+        from: WorldId,
+        t_base: TimeIdx,
+    },
+    WorldLost {
+        w: WorldId,
+    },
+    WorldArchived {
+        w: WorldId,
+    },
+    WorldPruned {
+        w: WorldId,
+    },
+    WorldDestroyed {
+        w: WorldId,
+        by: Player,
+    },
+    WorldDecided {
+        w: WorldId,
+        winner: Player,
+    },
+    // 両者の王が敵陣に入った。winner が None なら持将棋
+    Impasse {
+        w: WorldId,
+        winner: Option<Player>,
+    },
+    WorldsMerged {
+        kept: WorldId,
+        removed: WorldId,
+    },
+    // 同じ局面が4回現れた (w が None なら全世界線を合わせた状態)
+    Repetition {
+        w: Option<WorldId>,
+    },
+    Claimed {
+        by: Player,
+        claim: Claim,
+    },
+    // 持ち時間と秒読みを使い切った
+    Flagged {
+        player: Player,
+    },
+    Resigned {
+        by: Player,
+    },
+    DrawOffered {
+        by: Player,
+    },
+    DrawAccepted {
+        by: Player,
+    },
+    GameOver {
+        result: GameResult,
+    },
+    TurnCommitted {
+        player: Player,
+        turn: usize,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct TurnRecord {
+    pub player: Player,
+    pub moves: Vec<(WorldId, PlannedMove)>,
+    // このターンの確定で起きたこと (TurnCommitted を除く)
+    pub events: Vec<GameEvent>,
+}
+
+// 対局者が申し立てる終局。持ち時間切れは tick_clock がその場で終局にするので申告はない
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Claim {
+    // どれかの世界線か全世界線を合わせた状態で、同じ局面が4回現れた
+    Repetition,
+    // 相手が直前のターンに王手放置の手を確定した (Settings::arbiter_mode)
+    IllegalMove,
+}
+
+impl Claim {
+    pub fn label(self) -> &'static str {
+        match self {
+            Claim::Repetition => "千日手",
+            Claim::IllegalMove => "反則",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClaimError {
+    GameOver,
+    NoRepetition,
+    ArbiterModeOff,
+    NoPreviousTurn,
+    NoIllegalMove,
+}
+
+impl std::fmt::Display for ClaimError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClaimError::GameOver => write!(f, "対局は終了しています"),
+            ClaimError::NoRepetition => write!(f, "同じ局面が4回現れていない"),
+            ClaimError::ArbiterModeOff => write!(f, "反則の申告は裁定モードでのみできる"),
+            ClaimError::NoPreviousTurn => write!(f, "相手はまだ手を確定していない"),
+            ClaimError::NoIllegalMove => write!(f, "相手の直前の手に反則はない"),
+        }
+    }
+}
+
+impl std::error::Error for ClaimError {}
+
+// 取り消し・やり直し用に、確定したターンの手とその時の設定を残す
+#[derive(Clone)]
+pub(crate) struct JournalEntry {
+    settings: Settings,
+    moves: Vec<(WorldId, PlannedMove)>,
+    // 確定後に手動で行った世界線の統合 (残す側, 消す側)
+    merges: Vec<(WorldId, WorldId)>,
+}
+
+#[derive(Clone, Default)]
+pub struct GameBuilder {
+    settings: Settings,
+    // settings と呼ぶ順番によらず、指定があれば設定の開始局面より優先する
+    start_position: Option<StartPosition>,
+    seed: u64,
+}
+
+impl GameBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn settings(mut self, settings: Settings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    pub fn start_position(mut self, start: StartPosition) -> Self {
+        self.start_position = Some(start);
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn try_build(mut self) -> Result<Game, SettingsError> {
+        if let Some(start) = self.start_position {
+            self.settings.start_position = start;
+        }
+        self.settings.validate()?;
+        Ok(self.build())
+    }
+
+    // 設定は clamp してから使う。問題を知りたい場合は try_build
+    pub fn build(mut self) -> Game {
+        if let Some(start) = self.start_position {
+            self.settings.start_position = start;
+        }
+        self.settings.clamp();
+        let clock = self.settings.time_control.map(Clock::new);
+        let mut g = Game {
+            initial_settings: self.settings.clone(),
+            settings: self.settings,
+            worlds: BTreeMap::new(),
+            archived: BTreeMap::new(),
+            turn: Player::Black,
+            stats: WorldUsageStats::new(),
+            collapse_stats: CollapseStats::default(),
+            record: Vec::new(),
+            deductions: BTreeMap::new(),
+            summon_used: BTreeSet::new(),
+            hidden: BTreeMap::new(),
+            status: GameStatus::Ongoing,
+            draw_offer: None,
+            seed: self.seed,
+            rng: self.seed,
+            next_id: 1,
+            journal: Vec::new(),
+            redo: Vec::new(),
+            resolving: BTreeMap::new(),
+            time_used: BTreeMap::new(),
+            clock,
+            army_size: 0,
+            start: None,
+            positions: BTreeMap::new(),
+            global_positions: Vec::new(),
+        };
+        if g.settings.handicap != Handicap::None {
+            g.turn = Player::White;
+        }
+        let snapshot = g.initial_snapshot();
+        g.army_size = snapshot.pieces().count();
+        if g.settings.quantum_model == QuantumModel::Hidden {
+            g.assign_hidden_types(&snapshot);
+        }
+        g.worlds.insert(
+            WorldId(0),
+            WorldLine {
+                w: WorldId(0),
+                history: vec![snapshot],
+                staged: None,
+                lost: false,
+                winner: None,
+                drawn: false,
+                parent: None,
+                branch_point: None,
+                last_active: 0,
+            },
+        );
+        g.log_positions(g.turn);
+        let u = g.average_uncertainty();
+        g.collapse_stats.record_uncertainty(u);
+        g
+    }
+}
+
+// 任意の局面から対局を始めるための配置 (Game::from_position)。
+// 駒の id は置いた順に振る。copy_world で写した世界線の駒は元と同じ id を持つ
+#[derive(Clone)]
+pub struct PositionBuilder {
+    settings: Settings,
+    seed: u64,
+    turn: Player,
+    next_id: u64,
+    worlds: BTreeMap<WorldId, Snapshot>,
+    parents: BTreeMap<WorldId, WorldId>,
+    problems: Vec<(WorldId, PositionViolation)>,
+}
+
+impl Default for PositionBuilder {
+    fn default() -> Self {
+        Self {
+            settings: Settings::default(),
+            seed: 0,
+            turn: Player::Black,
+            next_id: 1,
+            worlds: BTreeMap::from([(WorldId(0), Snapshot::empty(Variant::Standard.board_size()))]),
+            parents: BTreeMap::new(),
+            problems: Vec::new(),
+        }
+    }
+}
+
+impl PositionBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 盤の広さが変わる場合は、それまでに置いた駒を捨てて空の盤にする
+    pub fn settings(mut self, settings: Settings) -> Self {
+        let size = settings.variant.board_size();
+        if size != self.settings.variant.board_size() {
+            for (&w, s) in self.worlds.iter_mut() {
+                if s.pieces().next().is_some() || s.hand_pieces().next().is_some() {
+                    self.problems
+                        .push((w, PositionViolation::PlacedBeforeResize));
+                }
+                *s = Snapshot::empty(size);
+            }
+        }
+        self.settings = settings;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn turn(mut self, player: Player) -> Self {
+        self.turn = player;
+        self
+    }
+
+    // 空の盤の世界線を作る (すでにあれば何もしない)
+    pub fn world(mut self, w: WorldId) -> Self {
+        let size = self.settings.variant.board_size();
+        self.worlds
+            .entry(w)
+            .or_insert_with(|| Snapshot::empty(size));
+        self
+    }
+
+    // from の世界線の局面を写して w を作る。w の親は from になる
+    pub fn copy_world(mut self, from: WorldId, w: WorldId) -> Self {
+        match self.worlds.get(&from).cloned() {
+            Some(s) => {
+                self.worlds.insert(w, s);
+                self.parents.insert(w, from);
+            }
+            None => self
+                .problems
+                .push((w, PositionViolation::NoSourceWorld(from))),
+        }
+        self
+    }
+
+    // w の sq に駒を置く (あれば置き換える)。候補が空なら置かない
+    pub fn piece(
+        mut self,
+        w: WorldId,
+        sq: Square,
+        owner: Player,
+        candidates: CandidateSet,
+    ) -> Self {
+        let size = self.settings.variant.board_size();
+        if !sq.is_on_board(size) {
+            self.problems.push((w, PositionViolation::OffBoard(sq)));
+            return self;
+        }
+        let piece = self.new_piece(owner, candidates);
+        self.worlds
+            .entry(w)
+            .or_insert_with(|| Snapshot::empty(size))
+            .put(sq, Some(piece));
+        self
+    }
+
+    // w の sq にある駒を成った駒にする
+    pub fn promote(mut self, w: WorldId, sq: Square) -> Self {
+        match self.worlds.get_mut(&w).and_then(|s| s.piece_mut(sq)) {
+            Some(p) => p.promoted = true,
+            None => self
+                .problems
+                .push((w, PositionViolation::NothingToPromote(sq))),
+        }
+        self
+    }
+
+    pub fn hand(mut self, w: WorldId, owner: Player, candidates: CandidateSet) -> Self {
+        let size = self.settings.variant.board_size();
+        let piece = self.new_piece(owner, candidates);
+        self.worlds
+            .entry(w)
+            .or_insert_with(|| Snapshot::empty(size))
+            .add_to_hand(piece);
+        self
+    }
+
+    fn new_piece(&mut self, owner: Player, candidates: CandidateSet) -> Piece {
+        let mut piece = Piece::new(self.next_id, owner);
+        piece.candidates = candidates;
+        self.next_id += 1;
+        piece
+    }
+
+    // 設定・配置を検査せずに対局を作る (検査済みの配置を undo で並べ直す時など)
+    fn place(self) -> Game {
+        let mut g = GameBuilder::new()
+            .settings(self.settings.clone())
+            .seed(self.seed)
+            .build();
+        g.turn = self.turn;
+        g.next_id = self.next_id;
+        g.hidden.clear();
+        g.worlds.clear();
+        let first = self.worlds.values().next().cloned();
+        g.army_size = first
+            .as_ref()
+            .map_or(0, |s| s.pieces().count() + s.hand_pieces().count());
+        for (&w, s) in &self.worlds {
+            let parent = self.parents.get(&w).copied();
+            g.worlds.insert(
+                w,
+                WorldLine {
+                    w,
+                    history: vec![s.clone()],
+                    staged: None,
+                    lost: false,
+                    winner: None,
+                    drawn: false,
+                    parent,
+                    branch_point: parent.map(|p| (p, TimeIdx(0))),
+                    last_active: 0,
+                },
+            );
+        }
+        if g.settings.quantum_model == QuantumModel::Hidden {
+            if let Some(s) = first {
+                g.assign_hidden_types(&s);
+            }
+        }
+        // 置いた世界線はどれも開始時からある
+        g.stats.world_spans = g.worlds.keys().map(|w| (*w, (0, None))).collect();
+        let u = g.average_uncertainty();
+        g.collapse_stats = CollapseStats::default();
+        g.collapse_stats.record_uncertainty(u);
+        g.positions.clear();
+        g.global_positions.clear();
+        g.log_positions(g.turn);
+        g.start = Some(Box::new(self));
+        g
+    }
+}
+
+#[derive(Clone)]
+pub struct Game {
+    pub(crate) settings: Settings,
+    pub(crate) worlds: BTreeMap<WorldId, WorldLine>,
+    pub(crate) archived: BTreeMap<WorldId, WorldLine>,
+    pub(crate) turn: Player,
+    pub(crate) stats: WorldUsageStats,
+    pub(crate) collapse_stats: CollapseStats,
+    pub(crate) record: Vec<TurnRecord>,
+    pub(crate) deductions: BTreeMap<u64, Vec<Deduction>>,
+    pub(crate) summon_used: BTreeSet<Player>,
+    pub(crate) hidden: BTreeMap<u64, PieceType>,
+    pub(crate) status: GameStatus,
+    // 相手がまだ答えていない引き分けの提案。提案された側が手を確定すると取り下げになる
+    draw_offer: Option<Player>,
+    // 千日手の判定用に、確定のたびに記録した各世界線と全体の局面
+    positions: BTreeMap<WorldId, Vec<PositionMark>>,
+    global_positions: Vec<PositionMark>,
+    seed: u64,
+    // 乱数による確定に使う状態。seed から始め、ターンを確定するごとに1回進める
+    rng: u64,
+    next_id: u64,
+    // 開始局面の駒の総数 (両者合計)
+    army_size: usize,
+    // 開始時の設定。set_settings で後から変えても、undo はこの設定で開始局面を作り直す
+    initial_settings: Settings,
+    // Game::from_position で始めた対局の開始局面。undo はここから並べ直す
+    start: Option<Box<PositionBuilder>>,
+    pub(crate) journal: Vec<JournalEntry>,
+    pub(crate) redo: Vec<JournalEntry>,
+    // ターンの解決中だけ、解決前の各世界線の現在の添字を持つ (layer 参照)
+    resolving: BTreeMap<WorldId, usize>,
+    // プレイヤーごとの消費時間と対局時計。取り消しても戻さない
+    time_used: BTreeMap<Player, std::time::Duration>,
+    clock: Option<Clock>,
+}
+
+impl Game {
+    pub fn builder() -> GameBuilder {
+        GameBuilder::new()
+    }
+
+    pub fn new(settings: Settings) -> Self {
+        GameBuilder::new().settings(settings).build()
+    }
+
+    // 配置した局面から対局を始める。各世界線の局面は validate_position で検査する
+    pub fn from_position(builder: PositionBuilder) -> Result<Self, PositionError> {
+        let files = builder.settings.variant.board_size().0;
+        builder
+            .settings
+            .validate()
+            .map_err(PositionError::Settings)?;
+        if builder.worlds.is_empty() {
+            return Err(PositionError::NoWorlds);
+        }
+        if builder.worlds.len() > builder.settings.max_worlds {
+            return Err(PositionError::TooManyWorlds(builder.worlds.len()));
+        }
+        if let Some((w, problem)) = builder.problems.first() {
+            return Err(PositionError::Invalid {
+                w: *w,
+                files,
+                problems: vec![problem.clone()],
+            });
+        }
+        for (&w, s) in &builder.worlds {
+            let problems = Self::validate_position(s, builder.settings.variant);
+            if !problems.is_empty() {
+                return Err(PositionError::Invalid { w, files, problems });
+            }
+        }
+        Ok(builder.place())
+    }
+
+    fn initial_snapshot(&mut self) -> Snapshot {
+        let variant = self.settings.variant;
+        let mut snapshot = Snapshot::empty(variant.board_size());
+        let ranks = snapshot.ranks();
+        let home = variant.home_rows();
+        for (rank, row) in snapshot.board.iter_mut().enumerate() {
+            let (owner, depth) = if rank < home {
+                (Player::White, rank)
+            } else if rank >= ranks - home {
+                (Player::Black, ranks - 1 - rank)
+            } else {
+                continue;
+            };
+            for (file, cell) in row.iter_mut().enumerate() {
+                let dropped = owner == Player::White
+                    && self
+                        .settings
+                        .handicap
+                        .removed()
+                        .iter()
+                        .any(|&(f, r, _)| (f, r) == (file, rank));
+                if dropped {
+                    continue;
+                }
+                let classical = self.classical_type(owner, depth, file);
+                let mut candidates = match self.settings.start_position {
+                    // 本将棋は自陣3段を埋める。5五将棋は初形の升だけ
+                    StartPosition::FullQuantum => {
+                        if variant != Variant::Standard && classical.is_none() {
+                            continue;
+                        }
+                        PieceType::all()
+                    }
+                    StartPosition::Classical => {
+                        let Some(pt) = classical else {
+                            continue;
+                        };
+                        BTreeSet::from([pt])
+                    }
+                    StartPosition::RowConstrained => {
+                        if classical.is_none() {
+                            continue;
+                        }
+                        self.row_types(depth)
+                    }
+                };
+                // 落とした駒種が残らないなら、その駒種は候補から外す
+                candidates.retain(|t| self.army_limit(owner, *t) > 0);
+                let mut piece = Piece::new(self.alloc_id(), owner);
+                piece.candidates = candidates;
+                *cell = Some(Arc::new(piece));
+            }
+        }
+        snapshot
+    }
+
+    // 初形で、自陣の奥から depth 段目・file 筋目 (盤面配列の添字) に置かれる駒。
+    // 後手は先手の並びを左右反転したもの
+    fn classical_type(&self, owner: Player, depth: usize, file: usize) -> Option<PieceType> {
+        use PieceType::*;
+        let (files, _) = self.settings.variant.board_size();
+        let file = match owner {
+            Player::Black => file,
+            Player::White => files - 1 - file,
+        };
+        match (self.settings.variant, depth) {
+            (Variant::Standard, 0) => Some(
+                [
+                    Lance, Knight, Silver, Gold, King, Gold, Silver, Knight, Lance,
+                ][file],
+            ),
+            (Variant::Standard, 1) => match file {
+                1 => Some(Bishop),
+                7 => Some(Rook),
+                _ => None,
+            },
+            (Variant::Standard, 2) => Some(Pawn),
+            (Variant::Minishogi, 0) => Some([King, Gold, Silver, Bishop, Rook][file]),
+            (Variant::Minishogi, 1) => (file == 0).then_some(Pawn),
+            _ => None,
+        }
+    }
+
+    // StartPosition::RowConstrained で自陣の奥から depth 段目の駒に許す候補 (初形でその段にある駒種)
+    fn row_types(&self, depth: usize) -> CandidateSet {
+        let (files, _) = self.settings.variant.board_size();
+        (0..files)
+            .filter_map(|file| self.classical_type(Player::Black, depth, file))
+            .collect()
+    }
+
+    // 標準の20枚を割り当て、盤上の駒がそれより多い分は歩で埋める。
+    // 混ぜた一式と駒を、各駒の候補に含まれる駒種どうしで二部マッチングにする (増加路法)。
+    // 先に取った駒種も付け替えて探すので、割り当てられる組み方があれば必ず見つかる
+    fn assign_hidden_types(&mut self, s: &Snapshot) {
+        let mut state = self.seed;
+        for pl in [Player::Black, Player::White] {
+            let mut pieces: Vec<&Piece> = s
+                .pieces()
+                .map(|(_, p)| p)
+                .chain(s.hand_pieces())
+                .filter(|p| p.owner == pl)
+                .collect();
+            let mut types: Vec<PieceType> = PieceType::all()
+                .into_iter()
+                .flat_map(|pt| std::iter::repeat_n(pt, self.army_limit(pl, pt)))
+                .collect();
+            types.resize(types.len().max(pieces.len()), PieceType::Pawn);
+            for i in (1..types.len()).rev() {
+                let j = (splitmix64(&mut state) % (i as u64 + 1)) as usize;
+                types.swap(i, j);
+            }
+            pieces.sort_by_key(|p| p.candidates.len());
+            let mut holder: Vec<Option<usize>> = vec![None; types.len()];
+            for i in 0..pieces.len() {
+                let mut seen = vec![false; types.len()];
+                augment_hidden(i, &pieces, &types, &mut holder, &mut seen);
+            }
+            for (slot, i) in holder.iter().enumerate() {
+                if let Some(i) = i {
+                    self.hidden.insert(pieces[*i].id, types[slot]);
+                }
+            }
+            // 組にならなかった駒 (候補の駒種が一式に足りない) は候補から選ぶ
+            for p in pieces {
+                if self.hidden.contains_key(&p.id) {
+                    continue;
+                }
+                let n = p.candidates.len().max(1) as u64;
+                let k = (splitmix64(&mut state) % n) as usize;
+                if let Some(t) = p.candidates.iter().nth(k) {
+                    self.hidden.insert(p.id, *t);
+                }
+            }
+        }
+    }
+
+    // player の駒種 pt の枚数の上限 (Variant の一式から駒落ちで落とした分を引く)
+    pub fn army_limit(&self, player: Player, pt: PieceType) -> usize {
+        let lim = self
+            .settings
+            .variant
+            .army()
+            .iter()
+            .find(|(t, _)| *t == pt)
+            .map_or(0, |l| l.1);
+        let dropped = if player == Player::White {
+            self.settings
+                .handicap
+                .removed()
+                .iter()
+                .filter(|(_, _, t)| *t == pt)
+                .count()
+        } else {
+            0
+        };
+        lim - dropped
+    }
+
+    fn army_limits(&self) -> Vec<(Player, PieceType, usize)> {
+        [Player::Black, Player::White]
+            .into_iter()
+            .cartesian_product(PieceType::all().iter().copied())
+            .map(|(pl, pt)| (pl, pt, self.army_limit(pl, pt)))
+            .collect()
+    }
+
+    // 両者合わせた駒種 t の枚数の上限。開始局面の駒が標準の40枚より多い分は歩
+    // (assign_hidden_types と同じ)。召喚された金はその分だけ金の上限を増やす
+    fn type_budget(&self, t: PieceType) -> usize {
+        let standard =
+            |t: PieceType| self.army_limit(Player::Black, t) + self.army_limit(Player::White, t);
+        match t {
+            PieceType::Pawn => {
+                let others: usize = PieceType::all()
+                    .into_iter()
+                    .filter(|pt| *pt != PieceType::Pawn)
+                    .map(standard)
+                    .sum();
+                standard(t).max(self.army_size.saturating_sub(others))
+            }
+            PieceType::Gold => standard(t) + self.summon_used.len(),
+            _ => standard(t),
+        }
+    }
+
+    fn alloc_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn rng_state(&self) -> u64 {
+        self.rng
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    // 盤の筋数。マスの表記 (Square::notation) に使う
+    pub fn files(&self) -> usize {
+        self.settings.variant.board_size().0
+    }
+
+    // 対局中の設定変更。validate を通らない設定と、盤・駒の配置・持ち時間のように
+    // 開始時に決まる設定の変更は受け付けない (変えるなら対局を作り直す)
+    pub fn set_settings(&mut self, settings: Settings) -> Result<(), SettingsError> {
+        settings.validate()?;
+        let cur = &self.settings;
+        if settings.variant != cur.variant
+            || settings.start_position != cur.start_position
+            || settings.handicap != cur.handicap
+            || settings.quantum_model != cur.quantum_model
+            || settings.time_control != cur.time_control
+        {
+            return Err(SettingsError::FixedAtStart);
+        }
+        self.settings = settings;
+        Ok(())
+    }
+
+    pub fn worlds(&self) -> &BTreeMap<WorldId, WorldLine> {
+        &self.worlds
+    }
+
+    pub fn world(&self, w: WorldId) -> Option<&WorldLine> {
+        self.worlds.get(&w)
+    }
+
+    pub fn archived(&self) -> &BTreeMap<WorldId, WorldLine> {
+        &self.archived
+    }
+
+    pub fn turn(&self) -> Player {
+        self.turn
+    }
+
+    pub fn stats(&self) -> &WorldUsageStats {
+        &self.stats
+    }
+
+    pub fn collapse_stats(&self) -> &CollapseStats {
+        &self.collapse_stats
+    }
+
+    pub fn record(&self) -> &[TurnRecord] {
+        &self.record
+    }
+
+    // 各世界線の現在局面でその駒がどこにあるか (None は持ち駒)
+    pub fn locate_piece(&self, id: PieceId) -> Vec<(WorldId, Option<Square>)> {
+        let mut found = Vec::new();
+        for (w, wl) in &self.worlds {
+            let s = wl.present();
+            if let Some((sq, _)) = s.pieces().find(|(_, p)| p.id == id) {
+                found.push((*w, Some(sq)));
+            } else if [Player::Black, Player::White]
+                .into_iter()
+                .any(|pl| s.hand(pl).iter().any(|p| p.id == id))
+            {
+                found.push((*w, None));
+            }
+        }
+        found
+    }
+
+    // 全世界線の現在局面で制約伝播を不動点まで進め、新たに消えた候補を返す。確定のたびにも行う
+    pub fn propagate_constraints(&mut self) -> Result<Vec<Deduction>, EngineError> {
+        let mut notes = Vec::new();
+        self.propagate(&mut notes)?;
+        for d in &notes {
+            self.deductions
+                .entry(d.piece_id)
+                .or_default()
+                .push(d.clone());
+        }
+        Ok(notes)
+    }
+
+    pub fn deductions(&self, piece_id: PieceId) -> &[Deduction] {
+        self.deductions
+            .get(&piece_id)
+            .map(Vec::as_slice)
+            .unwrap_or(&[])
+    }
+
+    pub fn summon_available(&self, player: Player) -> bool {
+        self.settings.summon_variant && !self.summon_used.contains(&player)
+    }
+
+    pub fn present(&self, w: WorldId) -> Option<&Snapshot> {
+        self.worlds.get(&w).and_then(|wl| wl.history.last())
+    }
+
+    pub fn presents(&self) -> impl Iterator<Item = (WorldId, &Snapshot)> {
+        self.worlds.iter().map(|(w, wl)| (*w, wl.present()))
+    }
+
+    pub fn snapshots(&self) -> impl Iterator<Item = (WorldId, TimeIdx, &Snapshot)> {
+        self.worlds.iter().flat_map(|(w, wl)| {
+            wl.history
+                .iter()
+                .enumerate()
+                .map(move |(t, s)| (*w, TimeIdx(t), s))
+        })
+    }
+
+    pub fn pieces_of(&self, player: Player) -> impl Iterator<Item = (WorldId, Square, &Piece)> {
+        self.presents().flat_map(move |(w, s)| {
+            s.pieces()
+                .filter(move |(_, p)| p.owner == player)
+                .map(move |(sq, p)| (w, sq, p))
+        })
+    }
+
+    // 他の世界線の手とは独立に、ターン開始時の局面でこの手が指せるかを試す。
+    // 世界線どうしの衝突や持ち駒の合計は確定時 (staging_issues) に見る。
+    pub fn validate_move(&self, w: WorldId, pm: &PlannedMove) -> Result<(), MoveError> {
+        self.check_turn_mode(w)?;
+        self.play_one_world(w, pm, None, &mut Vec::new()).map(drop)
+    }
+
+    // 手の中身によらず、いま w に手を入力できるか (終局・TurnMode)
+    fn check_turn_mode(&self, w: WorldId) -> Result<(), MoveError> {
+        if self.status.is_over() {
+            return Err(MoveError::GameOver);
+        }
+        if !self.worlds.contains_key(&w) {
+            return Err(MoveError::NoSuchWorld(w));
+        }
+        match self.settings.turn_mode {
+            TurnMode::Simultaneous => {}
+            TurnMode::Sequential => {
+                if let Some(other) = self
+                    .worlds
+                    .values()
+                    .find(|wl| wl.w != w && wl.staged.is_some())
+                {
+                    return Err(MoveError::OtherWorldStaged(other.w));
+                }
+            }
+            TurnMode::RoundRobin => {
+                if let Some(a) = self.acting_world().filter(|a| *a != w) {
+                    return Err(MoveError::NotActingWorld(a));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stage_move(&mut self, w: WorldId, mv: PlannedMove) -> Result<(), MoveError> {
+        self.validate_move(w, &mv)?;
+        if let Some(wl) = self.worlds.get_mut(&w) {
+            wl.staged = Some(mv);
+        }
+        Ok(())
+    }
+
+    // 確定に必要な手がそろっているか (Settings::turn_mode)
+    pub fn all_staged(&self) -> bool {
+        match self.settings.turn_mode {
+            TurnMode::Simultaneous => self.worlds.values().all(|wl| wl.staged.is_some()),
+            TurnMode::Sequential => self.worlds.values().any(|wl| wl.staged.is_some()),
+            TurnMode::RoundRobin => self
+                .acting_world()
+                .and_then(|w| self.worlds.get(&w))
+                .is_some_and(|wl| wl.staged.is_some()),
+        }
+    }
+
+    // TurnMode::RoundRobin でこのターンに指す世界線。他のモードでは None。
+    // 確定済みのターン数から決めるので、取り消しや世界線の増減があっても順番がずれない
+    pub fn acting_world(&self) -> Option<WorldId> {
+        if self.settings.turn_mode != TurnMode::RoundRobin || self.worlds.is_empty() {
+            return None;
+        }
+        let round = self.stats.turns / 2;
+        self.worlds.keys().nth(round % self.worlds.len()).copied()
+    }
+
+    // モードで指せない世界線に手が入力されている場合の説明 (途中でモードを変えた時など)
+    pub fn staged_turn_mode_issue(&self) -> Option<StagingIssue> {
+        let staged: Vec<WorldId> = self
+            .worlds
+            .values()
+            .filter(|wl| wl.staged.is_some())
+            .map(|wl| wl.w)
+            .collect();
+        match self.settings.turn_mode {
+            TurnMode::Simultaneous => None,
+            TurnMode::Sequential => {
+                (staged.len() > 1).then_some(StagingIssue::ManyWorldsStaged(staged))
+            }
+            TurnMode::RoundRobin => {
+                let a = self.acting_world()?;
+                staged
+                    .iter()
+                    .any(|w| *w != a)
+                    .then_some(StagingIssue::NotActingWorld(a))
+            }
+        }
+    }
+
+    // 入力済みの手が全部分岐すると MAX_WORLDS を超える場合、その説明を返す
+    pub fn staged_world_overflow(&self) -> Option<StagingIssue> {
+        let branches = self
+            .worlds
+            .values()
+            .filter_map(|wl| wl.staged.as_ref())
+            .filter(|pm| pm.delta_w != 0 || pm.delta_t < 0)
+            .count();
+        let total = self.worlds.len() + branches;
+        (branches > 0 && total > self.settings.max_worlds).then_some(StagingIssue::TooManyWorlds {
+            branches,
+            total,
+            max: self.settings.max_worlds,
+        })
+    }
+
+    // 分岐先 w+Δw が既存の世界線や他の入力済みの手の分岐先と重なるものを列挙する。
+    // WorldIdPolicy::Exact 以外では空き番号へずらすので重ならない
+    pub fn staged_branch_collisions(&self) -> Vec<StagingIssue> {
+        if self.settings.world_id_policy != WorldIdPolicy::Exact {
+            return Vec::new();
+        }
+        let mut targets: BTreeMap<WorldId, Vec<WorldId>> = BTreeMap::new();
+        for wl in self.worlds.values() {
+            if let Some(pm) = wl.staged.as_ref() {
+                if pm.delta_w != 0 || pm.delta_t < 0 {
+                    targets
+                        .entry(wl.w.offset(pm.delta_w))
+                        .or_default()
+                        .push(wl.w);
+                }
+            }
+        }
+        targets
+            .into_iter()
+            .filter_map(|(target, from)| {
+                if self.worlds.contains_key(&target) || self.archived.contains_key(&target) {
+                    Some(StagingIssue::BranchExists { from, target })
+                } else if from.len() > 1 {
+                    Some(StagingIssue::BranchesOverlap { from, target })
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    // 確定前に分かる入力全体の問題 (個々の手の合法性は含まない)
+    pub fn staging_issues(&self) -> Vec<StagingIssue> {
+        self.staged_world_overflow()
+            .into_iter()
+            .chain(self.staged_turn_mode_issue())
+            .chain(self.staged_branch_limit())
+            .chain(self.staged_branch_collisions())
+            .collect()
+    }
+
+    fn staged_branch_limit(&self) -> Option<StagingIssue> {
+        let limit = self.settings.max_branches_per_turn?;
+        let branches = self
+            .worlds
+            .values()
+            .filter_map(|wl| wl.staged.as_ref())
+            .filter(|pm| pm.delta_w != 0 || pm.delta_t < 0)
+            .count();
+        (branches > limit).then_some(StagingIssue::TooManyBranches { branches, limit })
+    }
+
+    // w から Δw で分岐した時に作られる世界線の番号 (Settings::world_id_policy)。
+    // 稼働中・凍結中の世界線と重ならない番号が決まらなければ None
+    pub fn allocate_world(&self, w: WorldId, delta_w: i32) -> Option<WorldId> {
+        let taken = |id: WorldId| self.worlds.contains_key(&id) || self.archived.contains_key(&id);
+        let sign = if delta_w < 0 { -1 } else { 1 };
+        let target = w.offset(delta_w);
+        // 既存の世界線の数だけ進めば必ず空きがある
+        let span = (self.worlds.len() + self.archived.len()) as i32;
+        let scan = |start: WorldId| {
+            (0..=span)
+                .map(|d| start.offset(sign * d))
+                .find(|id| !taken(*id))
+        };
+        match self.settings.world_id_policy {
+            WorldIdPolicy::Exact => (!taken(target)).then_some(target),
+            WorldIdPolicy::NearestFree => (0..=span)
+                .flat_map(|d| [target.offset(sign * d), target.offset(-sign * d)])
+                .find(|id| !taken(*id)),
+            WorldIdPolicy::SignedDirection => scan(target),
+            WorldIdPolicy::Tree => scan(WorldId(w.0 * 3 + sign)),
+        }
+    }
+
+    pub fn clear_staged(&mut self) {
+        for wl in self.worlds.values_mut() {
+            wl.staged = None;
+        }
+    }
+
+    // まとめられる世界線の組 (小さい id が先)
+    pub fn mergeable_pairs(&self) -> Vec<(WorldId, WorldId)> {
+        let open: Vec<&WorldLine> = self
+            .worlds
+            .values()
+            .filter(|wl| !wl.lost && !wl.is_decided())
+            .collect();
+        open.iter()
+            .tuple_combinations()
+            .filter(|(a, b)| Self::merged_snapshot(a.present(), b.present()).is_some())
+            .map(|(a, b)| (a.w, b.w))
+            .collect()
+    }
+
+    // b の現在の局面を a に重ねて b を盤から外す。両方の局面で同じマスに同じ持ち主・成りの駒が
+    // あり、持ち駒の枚数も同じ場合だけまとめられ、各駒の候補は両方の共通部分になる。
+    // a の履歴と入力済みの手は残り、b の入力済みの手は捨てる。b から分岐した世界線の親は a になる
+    pub fn merge_worlds(&mut self, a: WorldId, b: WorldId) -> Result<Vec<GameEvent>, EngineError> {
+        if self.status.is_over() {
+            return Err(EngineError::GameOver);
+        }
+        let events = self.merge_into(a, b)?;
+        if let Some(last) = self.journal.last_mut() {
+            last.merges.push((a, b));
+        }
+        self.redo.clear();
+        Ok(events)
+    }
+
+    fn merge_into(&mut self, a: WorldId, b: WorldId) -> Result<Vec<GameEvent>, EngineError> {
+        let open = |w: WorldId| {
+            self.worlds
+                .get(&w)
+                .filter(|wl| !wl.lost && !wl.is_decided())
+                .ok_or(EngineError::MergeUnavailable(w))
+        };
+        let (wa, wb) = (open(a)?, open(b)?);
+        if a == b {
+            return Err(EngineError::MergeUnavailable(b));
+        }
+        let merged = Self::merged_snapshot(wa.present(), wb.present())
+            .ok_or(EngineError::MergeIncompatible { a, b })?;
+        let last_active = wa.last_active.max(wb.last_active);
+        let kept = self.worlds.get_mut(&a).unwrap();
+        *kept.history.last_mut().unwrap() = merged;
+        kept.last_active = last_active;
+        self.worlds.remove(&b);
+        for wl in self.worlds.values_mut().chain(self.archived.values_mut()) {
+            if wl.parent == Some(b) {
+                wl.parent = Some(a);
+            }
+        }
+        self.stats.record_merged(b);
+        Ok(vec![GameEvent::WorldsMerged {
+            kept: a,
+            removed: b,
+        }])
+    }
+
+    // Settings::auto_merge_worlds。まとめられる組がなくなるまで小さい id の組から順にまとめる
+    fn merge_all(&mut self) -> Result<Vec<GameEvent>, EngineError> {
+        let mut events = Vec::new();
+        while let Some(&(a, b)) = self.mergeable_pairs().first() {
+            events.extend(self.merge_into(a, b)?);
+        }
+        Ok(events)
+    }
+
+    // 1ターンの手は次の段階の順に解決する。
+    //   0: 通常の移動・打ち・分岐・破壊の犠牲駒。各世界線はターン開始時の履歴だけを読み
+    //      (base_world の起点も事前に決める)、書き込むのは自分と新しく生まれる世界線だけなので、
+    //      互いに干渉しない。
+    //      時間跳躍が複写する過去局面に、同じターンに別の世界線で起きた捕獲は反映されない。
+    //   1: 量子トンネル打ち。打ち先の世界線の、段階 0 を終えた局面に打つ。
+    //   その後に global 持ち駒の照合、世界線破壊 (段階 0 を終えた局面で判定) の順に行う。
+    fn resolution_phase(pm: &PlannedMove) -> u8 {
+        match pm.kind {
+            MoveKind::TeleportDrop { .. } => 1,
+            _ => 0,
+        }
+    }
+
+    pub fn result(&self) -> Option<GameResult> {
+        self.status.result()
+    }
+
+    pub fn status(&self) -> GameStatus {
+        self.status
+    }
+
+    pub fn draw_offer(&self) -> Option<Player> {
+        self.draw_offer
+    }
+
+    // 手番に関係なく投了できる
+    pub fn resign(&mut self, player: Player) -> Result<Vec<GameEvent>, EngineError> {
+        if self.status.is_over() {
+            return Err(EngineError::GameOver);
+        }
+        let result = GameResult::Win(player.opposite());
+        self.status = GameStatus::won(player.opposite());
+        self.draw_offer = None;
+        Ok(vec![
+            GameEvent::Resigned { by: player },
+            GameEvent::GameOver { result },
+        ])
+    }
+
+    // 引き分けを提案する。相手が accept_draw すれば終局、相手が手を確定すれば取り下げ
+    pub fn offer_draw(&mut self, player: Player) -> Result<Vec<GameEvent>, EngineError> {
+        if self.status.is_over() {
+            return Err(EngineError::GameOver);
+        }
+        self.draw_offer = Some(player);
+        Ok(vec![GameEvent::DrawOffered { by: player }])
+    }
+
+    pub fn accept_draw(&mut self, player: Player) -> Result<Vec<GameEvent>, EngineError> {
+        if self.status.is_over() {
+            return Err(EngineError::GameOver);
+        }
+        if self.draw_offer != Some(player.opposite()) {
+            return Err(EngineError::NoDrawOffer);
+        }
+        self.status = GameStatus::Draw {
+            reason: DrawReason::Agreement,
+        };
+        self.draw_offer = None;
+        Ok(vec![
+            GameEvent::DrawAccepted { by: player },
+            GameEvent::GameOver {
+                result: GameResult::Draw,
+            },
+        ])
+    }
+
+    // 複製した局面で全世界線の手を解決し、すべて成功した時だけ差し替える。
+    // 途中の手が不合法なら self は入力済みの手も含めて元のまま。
+    pub fn commit_turn(&mut self) -> Result<Vec<GameEvent>, EngineError> {
+        let mut work = self.clone();
+        let events = work.resolve_turn()?;
+        work.journal.push(JournalEntry {
+            settings: self.settings.clone(),
+            moves: work
+                .record
+                .last()
+                .map(|r| r.moves.clone())
+                .unwrap_or_default(),
+            merges: Vec::new(),
+        });
+        work.redo.clear();
+        *self = work;
+        Ok(events)
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.journal.is_empty() && !self.ended_outside_turn()
+    }
+
+    // 投了・申し立て・合意・時間切れによる終局。journal には残らないので取り消せない
+    fn ended_outside_turn(&self) -> bool {
+        self.status.is_over()
+            && !self.record.last().is_some_and(|r| {
+                r.events
+                    .iter()
+                    .any(|e| matches!(e, GameEvent::GameOver { .. }))
+            })
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    // 直前の同時確定を丸ごと取り消す。分岐でできた世界線や持ち駒、統計も含めて
+    // 戻すため、開始局面から journal を1つ減らして並べ直す (seed が同じなら結果も同じ)。
+    // 入力済みの手は消える。取り消すものがなければ false
+    pub fn undo_turn(&mut self) -> Result<bool, EngineError> {
+        if self.ended_outside_turn() {
+            return Err(EngineError::UndoAfterResult);
+        }
+        let Some(last) = self.journal.last().cloned() else {
+            return Ok(false);
+        };
+        let mut g = self.replay(&self.journal[..self.journal.len() - 1])?;
+        g.time_used = std::mem::take(&mut self.time_used);
+        g.clock = self.clock.take();
+        g.redo = std::mem::take(&mut self.redo);
+        g.redo.push(last);
+        *self = g;
+        Ok(true)
+    }
+
+    // 手番に関係なく、どちらのプレイヤーも申し立てられる。認められれば終局する
+    pub fn claim(&mut self, by: Player, claim: Claim) -> Result<Vec<GameEvent>, ClaimError> {
+        if self.status.is_over() {
+            return Err(ClaimError::GameOver);
+        }
+        self.status = match claim {
+            Claim::Repetition => {
+                let result = self.repetition().ok_or(ClaimError::NoRepetition)?.1;
+                GameStatus::from_result(result, DrawReason::Repetition)
+            }
+            Claim::IllegalMove => {
+                if !self.settings.arbiter_mode {
+                    return Err(ClaimError::ArbiterModeOff);
+                }
+                let last = self
+                    .record
+                    .last()
+                    .filter(|r| r.player == by.opposite())
+                    .ok_or(ClaimError::NoPreviousTurn)?;
+                // 確定後の現在で、相手が指した世界線に相手の王が取られうる形が残っているか
+                let exposed = last.moves.iter().any(|(w, _)| {
+                    self.worlds.get(w).is_some_and(|wl| {
+                        !self.king_attackers(wl.present(), last.player).is_empty()
+                    })
+                });
+                if !exposed {
+                    return Err(ClaimError::NoIllegalMove);
+                }
+                GameStatus::won(by)
+            }
+        };
+        let result = self.status.result().unwrap();
+        self.draw_offer = None;
+        Ok(vec![
+            GameEvent::Claimed { by, claim },
+            GameEvent::GameOver { result },
+        ])
+    }
+
+    // 現在の局面が、同じ手番の過去の局面と合わせて4回現れた世界線
+    pub fn repeated_worlds(&self) -> Vec<WorldId> {
+        self.worlds
+            .keys()
+            .copied()
+            .filter(|w| {
+                self.positions
+                    .get(w)
+                    .is_some_and(|m| Self::repetition_result(m, false).is_some())
+            })
+            .collect()
+    }
+
+    // 千日手になった世界線 (None は全世界線を合わせた状態) と、Settings::repetition_rule による結果
+    pub fn repetition(&self) -> Option<(Option<WorldId>, GameResult)> {
+        let perpetual = self.settings.repetition_rule == RepetitionRule::PerpetualCheckLoses;
+        std::iter::once((None, &self.global_positions))
+            .chain(
+                self.worlds
+                    .keys()
+                    .filter_map(|w| Some((Some(*w), self.positions.get(w)?))),
+            )
+            .find_map(|(w, marks)| Some((w, Self::repetition_result(marks, perpetual)?)))
+    }
+
+    // 最後の局面が4回目なら、1回目から後の手で結果を決める。
+    // 連続王手の扱いでは、その間に指した手がすべて王手だった側の負け
+    fn repetition_result(marks: &[PositionMark], perpetual: bool) -> Option<GameResult> {
+        let last = marks.last()?;
+        let seen: Vec<usize> = marks.iter().positions(|m| m.hash == last.hash).collect();
+        if seen.len() < 4 {
+            return None;
+        }
+        let span = &marks[seen[seen.len() - 4] + 1..];
+        if perpetual {
+            for pl in [Player::Black, Player::White] {
+                // pl が指した直後の局面は相手の手番
+                let mut after = span
+                    .iter()
+                    .filter(|m| m.to_move == pl.opposite())
+                    .peekable();
+                if after.peek().is_some() && after.all(|m| m.in_check) {
+                    return Some(GameResult::Win(pl.opposite()));
+                }
+            }
+        }
+        Some(GameResult::Draw)
+    }
+
+    // 各世界線の現在と、全世界線を合わせた状態を千日手の判定用に記録する
+    fn log_positions(&mut self, to_move: Player) {
+        let mark = |hash: u64, in_check: bool| {
+            let mut h = DefaultHasher::new();
+            (hash, to_move).hash(&mut h);
+            PositionMark {
+                hash: h.finish(),
+                to_move,
+                in_check,
+            }
+        };
+        let mut global = DefaultHasher::new();
+        let mut any_check = false;
+        for (w, wl) in &self.worlds {
+            let s = wl.present();
+            let hash = s.position_hash();
+            let in_check = self.is_in_check(s, to_move);
+            (w, hash).hash(&mut global);
+            any_check |= in_check;
+            self.positions
+                .entry(*w)
+                .or_default()
+                .push(mark(hash, in_check));
+        }
+        self.global_positions.push(mark(global.finish(), any_check));
+    }
+
+    // 手番の側の考慮時間を進める。フロントエンドが計った経過時間を随時渡す。
+    // 時計があり時間切れになれば、その側の負けで終局する
+    pub fn tick_clock(&mut self, elapsed: std::time::Duration) -> Vec<GameEvent> {
+        if self.status.is_over() {
+            return Vec::new();
+        }
+        let player = self.turn;
+        *self.time_used.entry(player).or_default() += elapsed;
+        let Some(clock) = &mut self.clock else {
+            return Vec::new();
+        };
+        if !clock.tick(player, elapsed) {
+            return Vec::new();
+        }
+        let result = GameResult::Win(player.opposite());
+        self.status = GameStatus::won(player.opposite());
+        self.draw_offer = None;
+        vec![
+            GameEvent::Flagged { player },
+            GameEvent::GameOver { result },
+        ]
+    }
+
+    pub fn time_used(&self, player: Player) -> std::time::Duration {
+        self.time_used.get(&player).copied().unwrap_or_default()
+    }
+
+    pub fn clock(&self) -> Option<&Clock> {
+        self.clock.as_ref()
+    }
+
+    // undo_turn で取り消したターンを同じ手と設定で確定し直す
+    pub fn redo_turn(&mut self) -> Result<Option<Vec<GameEvent>>, EngineError> {
+        let Some(entry) = self.redo.last().cloned() else {
+            return Ok(None);
+        };
+        let mut work = self.clone();
+        let events = work.commit_entry(&entry)?;
+        work.redo = std::mem::take(&mut self.redo);
+        work.redo.pop();
+        *self = work;
+        Ok(Some(events))
+    }
+
+    fn replay(&self, entries: &[JournalEntry]) -> Result<Game, EngineError> {
+        let mut g = match &self.start {
+            Some(start) => start.as_ref().clone().place(),
+            None => GameBuilder::new()
+                .settings(self.initial_settings.clone())
+                .seed(self.seed)
+                .build(),
+        };
+        for entry in entries {
+            g.commit_entry(entry)?;
+        }
+        // set_settings で途中から変えた設定は残す (盤の形や駒の配り方は変えられない)
+        g.settings = self.settings.clone();
+        Ok(g)
+    }
+
+    fn commit_entry(&mut self, entry: &JournalEntry) -> Result<Vec<GameEvent>, EngineError> {
+        let current = std::mem::replace(&mut self.settings, entry.settings.clone());
+        self.clear_staged();
+        for (w, pm) in &entry.moves {
+            if let Some(wl) = self.worlds.get_mut(w) {
+                wl.staged = Some(pm.clone());
+            }
+        }
+        let mut result = self.commit_turn();
+        for &(a, b) in &entry.merges {
+            if let Ok(events) = &mut result {
+                match self.merge_worlds(a, b) {
+                    Ok(more) => events.extend(more),
+                    Err(e) => result = Err(e),
+                }
+            }
+        }
+        self.settings = current;
+        result
+    }
+
+    fn resolve_turn(&mut self) -> Result<Vec<GameEvent>, EngineError> {
+        if self.status.is_over() {
+            return Err(EngineError::GameOver);
+        }
+        // 設定は対局中も UI から変えられるので、確定のたびに確かめる
+        self.settings.validate().map_err(EngineError::Settings)?;
+        let world_ids: Vec<WorldId> = self.worlds.keys().copied().collect();
+        self.resolving = self
+            .worlds
+            .iter()
+            .map(|(w, wl)| (*w, wl.history.len() - 1))
+            .collect();
+        let unstaged = |w: &WorldId| {
+            self.worlds
+                .get(w)
+                .and_then(|wl| wl.staged.as_ref())
+                .is_none()
+        };
+        match self.settings.turn_mode {
+            TurnMode::Simultaneous => {
+                if let Some(w) = world_ids.iter().find(|w| unstaged(w)) {
+                    return Err(EngineError::NotStaged(*w));
+                }
+            }
+            TurnMode::Sequential => {
+                if world_ids.iter().all(unstaged) {
+                    return Err(EngineError::NothingStaged);
+                }
+            }
+            TurnMode::RoundRobin => {
+                if let Some(w) = self.acting_world().filter(unstaged) {
+                    return Err(EngineError::NotStaged(w));
+                }
+            }
+        }
+        if let Some(issue) = self.staging_issues().into_iter().next() {
+            return Err(EngineError::Staging(issue));
+        }
+
+        let staged: Vec<(WorldId, PlannedMove)> = world_ids
+            .iter()
+            .filter_map(|w| self.worlds[w].staged.clone().map(|pm| (*w, pm)))
+            .collect();
+
+        let mut moved: HashMap<u64, CollapseCause> = HashMap::new();
+        for (w, pm) in &staged {
+            let Some(s) = self.present(*w) else { continue };
+            let moved_piece = match &pm.kind {
+                MoveKind::Move { from, .. } => {
+                    s.piece_at(*from).map(|p| (p.id, CollapseCause::Movement))
+                }
+                MoveKind::Drop { group, .. } | MoveKind::TeleportDrop { group, .. } => s
+                    .hand_index_of(self.turn, group)
+                    .map(|i| (s.hand(self.turn)[i].id, CollapseCause::Drop)),
+                MoveKind::CollapseWorld { .. } | MoveKind::Pass => None,
+                MoveKind::Observe { at } => {
+                    s.piece_at(*at).map(|p| (p.id, CollapseCause::Observation))
+                }
+            };
+            moved.extend(moved_piece);
+        }
+
+        let is_teleport = |pm: &PlannedMove| matches!(pm.kind, MoveKind::TeleportDrop { .. });
+        if staged.iter().filter(|(_, pm)| is_teleport(pm)).count() > 1 {
+            return Err(EngineError::TeleportDropLimit);
+        }
+
+        // global 持ち駒は局面に手を付ける前に、ターン開始時の在庫で照合する
+        if self.settings.hand_mode == HandMode::Global {
+            let mut stock: HashMap<PieceType, usize> = HashMap::new();
+            for wl in self.worlds.values() {
+                for p in wl.present().hand(self.turn) {
+                    for c in &p.candidates {
+                        *stock.entry(*c).or_default() += 1;
+                    }
+                }
+            }
+            let mut used: HashMap<PieceType, usize> = HashMap::new();
+            for (_, pm) in &staged {
+                if let MoveKind::Drop { group, .. } | MoveKind::TeleportDrop { group, .. } =
+                    &pm.kind
+                {
+                    for c in group {
+                        *used.entry(*c).or_default() += 1;
+                    }
+                }
+            }
+            for (pt, n) in used {
+                if n > stock.get(&pt).copied().unwrap_or(0) {
+                    return Err(EngineError::GlobalHandShortage(pt));
+                }
+            }
+        }
+
+        let mut notes = Vec::new();
+        let mut events = Vec::new();
+
+        // 解決順 (resolution_phase 参照)。同じ段階の中では世界線番号順だが、
+        // 段階 0 の手は自分の世界線の履歴しか読まないので順序に依存しない。
+        // 分岐の起点はターン開始時の履歴で先に決めておく (履歴は追記のみなので添字は変わらない)
+        let bases: BTreeMap<WorldId, (WorldId, TimeIdx)> = staged
+            .iter()
+            .filter(|(_, pm)| pm.delta_w != 0 || pm.delta_t < 0)
+            .filter_map(|(w, pm)| self.branch_base(*w, pm).ok().map(|b| (*w, b)))
+            .collect();
+        let mut ordered: Vec<&(WorldId, PlannedMove)> = staged.iter().collect();
+        ordered.sort_by_key(|(w, pm)| (Self::resolution_phase(pm), *w));
+        for (w, pm) in ordered {
+            let base = bases.get(w).copied();
+            // 分岐先の番号はこの手を解決する時点の空きで決まる
+            let w_new = self.allocate_world(*w, pm.delta_w);
+            self.apply_one_world(*w, pm.clone(), base, &mut notes)
+                .map_err(|error| EngineError::Illegal { w: *w, error })?;
+            if let (Some((from, t_base)), Some(w_new)) = (base, w_new) {
+                events.push(GameEvent::WorldCreated {
+                    w: w_new,
+                    parent: *w,
+                    from,
+                    t_base,
+                });
+                self.stats.record_branch(w_new);
+            }
+        }
+
+        let mut destroyed = BTreeSet::new();
+        for (w, pm) in &staged {
+            let MoveKind::CollapseWorld { target, .. } = pm.kind else {
+                continue;
+            };
+            if target == *w || !world_ids.contains(&target) {
+                return Err(EngineError::InvalidCollapseTarget(target));
+            }
+            let s = self.worlds[&target].present();
+            let mine: Vec<&Piece> = s
+                .pieces()
+                .map(|(_, p)| p)
+                .filter(|p| p.owner == self.turn)
+                .collect();
+            if mine.len() == 1 && mine[0].candidates.contains(&PieceType::King) {
+                return Err(EngineError::CollapseKingOnly(target));
+            }
+            destroyed.insert(target);
+        }
+        if !destroyed.is_empty() && destroyed.len() >= self.worlds.len() {
+            return Err(EngineError::CollapseLastWorld);
+        }
+        for w in destroyed {
+            self.worlds.remove(&w);
+            self.stats.record_lost(w);
+            events.push(GameEvent::WorldDestroyed { w, by: self.turn });
+        }
+
+        if self.settings.strict_commit_check {
+            // このターンに手が入った世界線だけを見る (指していない世界線は直しようがない)
+            let touched: BTreeSet<WorldId> = staged
+                .iter()
+                .flat_map(|(w, pm)| match pm.kind {
+                    MoveKind::TeleportDrop { dest, .. } => vec![*w, dest],
+                    _ => vec![*w],
+                })
+                .chain(events.iter().filter_map(|e| match e {
+                    GameEvent::WorldCreated { w, .. } => Some(*w),
+                    _ => None,
+                }))
+                .collect();
+            let exposed: Vec<String> = touched
+                .iter()
+                .filter_map(|w| self.worlds.get(w))
+                .filter(|wl| !wl.is_decided())
+                .filter_map(|wl| {
+                    let attacks = self.king_attackers(wl.present(), self.turn);
+                    (!attacks.is_empty()).then(|| {
+                        let detail = attacks
+                            .iter()
+                            .map(|(from, types, king)| {
+                                let names: String = types.iter().map(|t| t.short()).collect();
+                                format!(
+                                    "{from}の{names}→{king}",
+                                    from = from.notation(wl.present().files()),
+                                    king = king.notation(wl.present().files())
+                                )
+                            })
+                            .join(", ");
+                        format!("{}: {detail}", wl.w)
+                    })
+                })
+                .collect();
+            if !exposed.is_empty() {
+                return Err(EngineError::KingLeftInCheck(exposed));
+            }
+        }
+
+        for (_, pm) in &staged {
+            self.stats.record_move(pm.delta_w, pm.delta_t);
+        }
+
+        self.resolving.clear();
+
+        self.record_collapses(|id, in_hand| {
+            if in_hand {
+                CollapseCause::Capture
+            } else {
+                moved.get(&id).copied().unwrap_or(CollapseCause::Movement)
+            }
+        });
+
+        self.propagate(&mut notes)?;
+
+        for wl in self.worlds.values_mut() {
+            wl.staged = None;
+            let s = wl.present();
+            let was_lost = wl.lost;
+            wl.lost = Self::king_candidates(s, self.turn).is_empty()
+                || Self::king_candidates(s, self.turn.opposite()).is_empty();
+            if wl.lost && !was_lost {
+                self.stats.record_lost(wl.w);
+                events.push(GameEvent::WorldLost { w: wl.w });
+            }
+        }
+
+        let next = self.turn.opposite();
+        let decided: Vec<(WorldId, Player)> = self
+            .worlds
+            .values()
+            .filter(|wl| !wl.is_decided())
+            .filter_map(|wl| {
+                let s = wl.present();
+                if Self::king_candidates(s, next).is_empty() || self.is_checkmated(s, next) {
+                    Some((wl.w, self.turn))
+                } else if Self::king_candidates(s, self.turn).is_empty() {
+                    Some((wl.w, next))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        for (w, winner) in decided {
+            self.worlds.get_mut(&w).unwrap().winner = Some(winner);
+            events.push(GameEvent::WorldDecided { w, winner });
+        }
+        if self.settings.entering_king && self.settings.variant == Variant::Standard {
+            let impasse: Vec<(WorldId, Option<Player>)> = self
+                .worlds
+                .values()
+                .filter(|wl| !wl.is_decided())
+                .filter(|wl| {
+                    [Player::Black, Player::White]
+                        .iter()
+                        .all(|pl| self.king_entered(wl.present(), *pl))
+                })
+                .map(|wl| (wl.w, self.impasse_winner(wl.present())))
+                .collect();
+            for (w, winner) in impasse {
+                let wl = self.worlds.get_mut(&w).unwrap();
+                events.push(GameEvent::Impasse { w, winner });
+                match winner {
+                    Some(winner) => {
+                        wl.winner = Some(winner);
+                        events.push(GameEvent::WorldDecided { w, winner });
+                    }
+                    None => wl.drawn = true,
+                }
+            }
+        }
+        if self.settings.prune_lost_worlds {
+            let lost: Vec<WorldId> = self
+                .worlds
+                .values()
+                .filter(|wl| wl.lost)
+                .map(|wl| wl.w)
+                .collect();
+            for w in lost {
+                if self.worlds.len() <= 1 {
+                    break;
+                }
+                let wl = self.worlds.remove(&w).unwrap();
+                self.archived.insert(w, wl);
+                events.push(GameEvent::WorldPruned { w });
+            }
+        }
+        if self.settings.auto_merge_worlds {
+            events.extend(self.merge_all()?);
+        }
+        if let Some(result) = self.aggregate_result() {
+            // 勝ちのない引き分けで、持将棋になった世界線があれば持将棋とする
+            let impasse = self
+                .worlds
+                .values()
+                .chain(self.archived.values())
+                .any(|wl| wl.drawn);
+            let reason = if impasse {
+                DrawReason::Impasse
+            } else {
+                DrawReason::Worlds
+            };
+            self.status = GameStatus::from_result(result, reason);
+            events.push(GameEvent::GameOver { result });
+        }
+
+        // 手を指した世界線と、量子トンネル打ちの行き先・推論で候補が変わった世界線を動きありとする
+        let now = self.stats.turns + 1;
+        let touched = staged
+            .iter()
+            .flat_map(|(w, pm)| match pm.kind {
+                MoveKind::TeleportDrop { dest, .. } => vec![*w, dest],
+                _ => vec![*w],
+            })
+            .chain(notes.iter().map(|d| d.world));
+        for w in touched {
+            if let Some(wl) = self.worlds.get_mut(&w) {
+                wl.last_active = now;
+            }
+        }
+        if let Some(idle) = self.settings.archive_idle_turns {
+            let stale: Vec<WorldId> = self
+                .worlds
+                .values()
+                .filter(|wl| now - wl.last_active >= idle)
+                .map(|wl| wl.w)
+                .collect();
+            for w in stale {
+                if self.worlds.len() <= 1 {
+                    break;
+                }
+                let wl = self.worlds.remove(&w).unwrap();
+                self.archived.insert(w, wl);
+                events.push(GameEvent::WorldArchived { w });
+            }
+        }
+        self.log_positions(next);
+        if !self.status.is_over() {
+            if let Some((w, result)) = self.repetition() {
+                self.status = GameStatus::from_result(result, DrawReason::Repetition);
+                events.push(GameEvent::Repetition { w });
+                events.push(GameEvent::GameOver { result });
+            }
+        }
+
+        for d in notes {
+            self.deductions.entry(d.piece_id).or_default().push(d);
+        }
+        let u = self.average_uncertainty();
+        self.collapse_stats.record_uncertainty(u);
+
+        // 提案された側が応じずに指したので取り下げ
+        if self.draw_offer == Some(self.turn.opposite()) || self.status.is_over() {
+            self.draw_offer = None;
+        }
+        self.record.push(TurnRecord {
+            player: self.turn,
+            moves: staged,
+            events: events.clone(),
+        });
+        self.stats.end_turn();
+        splitmix64(&mut self.rng);
+        events.push(GameEvent::TurnCommitted {
+            player: self.turn,
+            turn: self.stats.turns,
+        });
+        if let Some(clock) = &mut self.clock {
+            clock.on_move(self.turn);
+        }
+        self.turn = self.turn.opposite();
+        #[cfg(feature = "strict-checks")]
+        self.debug_assert_invariants();
+        Ok(events)
+    }
+
+    // 凍結中の世界線も母数に含める (破壊された世界線は数えない)
+    fn aggregate_result(&self) -> Option<GameResult> {
+        let all: Vec<&WorldLine> = self.worlds.values().chain(self.archived.values()).collect();
+        let wins = |pl: Player| all.iter().filter(|wl| wl.winner == Some(pl)).count();
+        let (black, white) = (wins(Player::Black), wins(Player::White));
+        let all_decided = all.iter().all(|wl| wl.is_decided());
+        let pick = |b: bool, w: bool| match (b, w) {
+            (true, false) => Some(GameResult::Win(Player::Black)),
+            (false, true) => Some(GameResult::Win(Player::White)),
+            (true, true) => Some(GameResult::Draw),
+            (false, false) if all_decided => Some(GameResult::Draw),
+            (false, false) => None,
+        };
+        match self.settings.win_condition {
+            WinCondition::AnyWorld => pick(black > 0, white > 0),
+            WinCondition::Majority => pick(black * 2 > all.len(), white * 2 > all.len()),
+            WinCondition::AllWorlds => pick(black == all.len(), white == all.len()),
+        }
+    }
+
+    #[cfg(feature = "strict-checks")]
+    pub(crate) fn debug_assert_invariants(&self) {
+        for (w, wl) in &self.worlds {
+            assert_eq!(
+                *w, wl.w,
+                "不変条件違反: 世界線キー {w} と w={} が不一致",
+                wl.w
+            );
+            assert!(!wl.history.is_empty(), "不変条件違反: {w} に局面がない");
+            for (t, s) in wl.history.iter().enumerate() {
+                for (sq, p) in s.pieces() {
+                    assert!(
+                        !p.candidates.is_empty(),
+                        "不変条件違反: {w} t{t} {sq} #{} の候補が空",
+                        p.id,
+                        sq = sq.notation(s.files())
+                    );
+                    assert!(
+                        !p.promoted || p.candidates.iter().all(|c| c.can_promote()),
+                        "不変条件違反: {w} t{t} {sq} #{} は成っているのに成れない候補が残る",
+                        p.id,
+                        sq = sq.notation(s.files())
+                    );
+                }
+                for (pl, hand) in &s.hands {
+                    for p in hand {
+                        assert!(
+                            p.owner == *pl && !p.promoted && !p.candidates.is_empty(),
+                            "不変条件違反: {w} t{t} {}持駒 #{} が不整合",
+                            pl.mark(),
+                            p.id
+                        );
+                    }
+                }
+                // 取った駒は持ち主が変わるので、推論と同じく両者を合わせた総数 (type_budget) で数える
+                for pt in PieceType::all() {
+                    let lim = self.type_budget(pt);
+                    // 過去の自分と重なって同じ id が2つある駒は1枚と数える
+                    let fixed = s
+                        .pieces()
+                        .map(|(_, p)| p)
+                        .chain(s.hand_pieces())
+                        .filter(|p| p.candidates.len() == 1 && p.candidates.contains(&pt))
+                        .map(|p| p.id)
+                        .collect::<BTreeSet<_>>()
+                        .len();
+                    assert!(
+                        fixed <= lim,
+                        "不変条件違反: {w} t{t} {}が{fixed}枚 (上限{lim})",
+                        pt.short()
+                    );
+                }
+            }
+        }
+    }
+
+    fn record_collapses(&mut self, cause: impl Fn(u64, bool) -> CollapseCause) {
+        let turn = self.stats.turns + 1;
+        let mut found = Vec::new();
+        for (_, s) in self.presents() {
+            let on_board = s.pieces().map(|(_, p)| (p, false));
+            let in_hand = s.hand_pieces().map(|p| (p, true));
+            for (p, hand) in on_board.chain(in_hand) {
+                if p.candidates.len() == 1 && !self.collapse_stats.is_collapsed(p.id) {
+                    let pt = *p.candidates.iter().next().unwrap();
+                    found.push((p.id, pt, cause(p.id, hand)));
+                }
+            }
+        }
+        for (id, pt, c) in found {
+            self.collapse_stats.record(turn, id, pt, c);
+        }
+    }
+
+    pub(crate) fn average_uncertainty(&self) -> f64 {
+        let mut total = 0usize;
+        let mut n = 0usize;
+        for (_, s) in self.presents() {
+            for p in s.pieces().map(|(_, p)| p).chain(s.hand_pieces()) {
+                total += p.candidates.len();
+                n += 1;
+            }
+        }
+        if n == 0 {
+            return 0.0;
+        }
+        total as f64 / n as f64
+    }
+
+    // 分岐の起点 (世界線, 時刻)。base_world は祖先か兄弟 (同じ親を持つ) の世界線に限る。
+    // 時刻は起点の世界線の現在から Δt だけ遡った局面。
+    fn branch_base(&self, w: WorldId, pm: &PlannedMove) -> Result<(WorldId, TimeIdx), MoveError> {
+        let base_w = match pm.base_world {
+            Some(b) if b != w => {
+                if !self.settings.cross_world_base {
+                    return Err(MoveError::CrossWorldBaseDisabled);
+                }
+                let parent = self.worlds.get(&w).and_then(|wl| wl.parent);
+                let sibling =
+                    parent.is_some() && self.worlds.get(&b).is_some_and(|bl| bl.parent == parent);
+                if !sibling && !self.ancestors(w).contains(&b) {
+                    return Err(MoveError::UnrelatedBase { base: b, w });
+                }
+                b
+            }
+            _ => w,
+        };
+        let bl = self
+            .worlds
+            .get(&base_w)
+            .ok_or(MoveError::NoSuchBase(base_w))?;
+        let t = bl
+            .present_t()
+            .offset(pm.delta_t)
+            .filter(|t| t.0 < bl.history.len())
+            .ok_or(MoveError::HistoryOutOfRange)?;
+        Ok((base_w, t))
+    }
+
+    // 親をたどった世界線 (凍結中のものも含む)
+    fn ancestors(&self, w: WorldId) -> Vec<WorldId> {
+        let mut out = Vec::new();
+        let mut cur = w;
+        while let Some(p) = self
+            .worlds
+            .get(&cur)
+            .or_else(|| self.archived.get(&cur))
+            .and_then(|wl| wl.parent)
+        {
+            if out.contains(&p) {
+                break;
+            }
+            out.push(p);
+            cur = p;
+        }
+        out
+    }
+
+    // 別の世界線・時刻からの駒は新しい世界線にしか着地しないので、
+    // 既存の局面への利きは同じ局面内の駒だけを見ればよい。
+    // Possible で王の位置が定まっていない間は王手が常態なので、王が確定してから判定する。
+    fn check_self_check(&self, s: &Snapshot, w: WorldId) -> Result<(), MoveError> {
+        if !self.settings.forbid_self_check {
+            return Ok(());
+        }
+        let located = Self::king_candidates(s, self.turn).len() == 1
+            || self.settings.check_attack_mode == CheckAttackMode::Certain;
+        if located && self.is_in_check(s, self.turn) {
+            return Err(MoveError::SelfCheck(w));
+        }
+        Ok(())
+    }
+
+    fn apply_one_world(
+        &mut self,
+        w: WorldId,
+        pm: PlannedMove,
+        base: Option<(WorldId, TimeIdx)>,
+        notes: &mut Vec<Deduction>,
+    ) -> Result<(), MoveError> {
+        let change = self.play_one_world(w, &pm, base, notes)?;
+        if change.summoned {
+            self.record_summon();
+        }
+        self.worlds
+            .get_mut(&w)
+            .unwrap()
+            .history
+            .push(change.present);
+        if let Some((dest, s)) = change.dest {
+            *self
+                .worlds
+                .get_mut(&dest)
+                .unwrap()
+                .history
+                .last_mut()
+                .unwrap() = s;
+        }
+        if let Some(wl) = change.branch {
+            self.worlds.insert(wl.w, wl);
+        }
+        Ok(())
+    }
+
+    // w の手を指した後の局面を作る。Game には手を付けないので、判定だけなら複製はいらない
+    fn play_one_world(
+        &self,
+        w: WorldId,
+        pm: &PlannedMove,
+        base: Option<(WorldId, TimeIdx)>,
+        notes: &mut Vec<Deduction>,
+    ) -> Result<WorldChange, MoveError> {
+        if self.settings.past_only && pm.delta_t > 0 {
+            return Err(MoveError::FutureMoveDisabled);
+        }
+        if pm.delta_t.abs() > self.settings.max_time_jump {
+            return Err(MoveError::TimeJumpTooFar);
+        }
+
+        let branching = pm.delta_w != 0 || pm.delta_t < 0;
+        if !branching && pm.base_world.is_some_and(|b| b != w) {
+            return Err(MoveError::BaseWithoutBranch);
+        }
+        if branching && matches!(pm.kind, MoveKind::CollapseWorld { .. }) {
+            return Err(MoveError::CollapseWithShift);
+        }
+        if branching && matches!(pm.kind, MoveKind::Observe { .. }) {
+            return Err(MoveError::ObserveWithShift);
+        }
+        if let MoveKind::Pass = pm.kind {
+            if pm.delta_w != 0 || pm.delta_t != 0 || pm.summon.is_some() {
+                return Err(MoveError::PassWithShift);
+            }
+            self.check_pass_interval(w)?;
+        }
+        if !branching && pm.summon.is_some() {
+            return Err(MoveError::SummonOnlyOnBranch);
+        }
+        let mut cur = self.worlds[&w].present().clone();
+        if let MoveKind::TeleportDrop { dest, .. } = pm.kind {
+            if !self.settings.teleport_drop || self.settings.hand_mode != HandMode::Global {
+                return Err(MoveError::TeleportDropDisabled);
+            }
+            if branching {
+                return Err(MoveError::TeleportWithShift);
+            }
+            if dest == w || !self.worlds.contains_key(&dest) {
+                return Err(MoveError::InvalidDropWorld(dest));
+            }
+            let mut dest_now = self.worlds[&dest].present().clone();
+            self.execute_move(&mut cur, Some(&mut dest_now), w, pm, notes)?;
+            self.check_self_check(&cur, w)?;
+            self.check_self_check(&dest_now, dest)?;
+            return Ok(WorldChange {
+                present: cur,
+                dest: Some((dest, dest_now)),
+                branch: None,
+                summoned: false,
+            });
+        }
+        if !branching {
+            self.execute_move(&mut cur, None, w, pm, notes)?;
+            self.check_self_check(&cur, w)?;
+            return Ok(WorldChange {
+                present: cur,
+                dest: None,
+                branch: None,
+                summoned: false,
+            });
+        }
+
+        if self.worlds.len() >= self.settings.max_worlds {
+            return Err(MoveError::MaxWorlds);
+        }
+        let w_new = self
+            .allocate_world(w, pm.delta_w)
+            .ok_or(MoveError::WorldCollision)?;
+        let (base_w, base_t) = match base {
+            Some(b) => b,
+            None => self.branch_base(w, pm)?,
+        };
+        let mut new_snap = self.worlds[&base_w].history[base_t.0].clone();
+        self.execute_move(&mut cur, Some(&mut new_snap), w, pm, notes)?;
+        if let Some(sq) = pm.summon {
+            self.summon_into(&mut new_snap, sq)?;
+        }
+        self.check_self_check(&cur, w)?;
+        self.check_self_check(&new_snap, w_new)?;
+        Ok(WorldChange {
+            present: cur,
+            dest: None,
+            branch: Some(WorldLine {
+                w: w_new,
+                history: vec![new_snap],
+                staged: None,
+                lost: false,
+                winner: None,
+                drawn: false,
+                parent: Some(w),
+                branch_point: Some((base_w, base_t)),
+                last_active: self.stats.turns + 1,
+            }),
+            summoned: pm.summon.is_some(),
+        })
+    }
+
+    // Settings::pass_interval。自分の直近 n-1 手番のうちに w でパスしていれば指せない
+    fn check_pass_interval(&self, w: WorldId) -> Result<(), MoveError> {
+        let Some(n) = self.settings.pass_interval else {
+            return Ok(());
+        };
+        let passed = self
+            .record
+            .iter()
+            .rev()
+            .filter(|r| r.player == self.turn)
+            .take(n.saturating_sub(1))
+            .any(|r| {
+                r.moves
+                    .iter()
+                    .any(|(mw, pm)| *mw == w && matches!(pm.kind, MoveKind::Pass))
+            });
+        if passed {
+            return Err(MoveError::PassTooSoon(n));
+        }
+        Ok(())
+    }
+
+    // 分岐先で id の駒を取った時、分岐元の現在の盤上にいる同じ駒を
+    // Settings::capture_across_time に従って扱う
+    fn capture_in_source(&self, source: &mut Snapshot, id: PieceId) {
+        let Some(sq) = source.pieces().find(|(_, p)| p.id == id).map(|(sq, _)| sq) else {
+            return;
+        };
+        match self.settings.capture_across_time {
+            CaptureAcrossTime::Local => {}
+            CaptureAcrossTime::RemoveFromSource => source.put(sq, None),
+            CaptureAcrossTime::Paradoxed => {
+                if let Some(p) = source.piece_mut(sq) {
+                    p.paradoxed = true;
+                }
+            }
+        }
+    }
+
+    // target が None のときは分岐なしで src_present 上でそのまま指す
+    fn execute_move(
+        &self,
+        src_present: &mut Snapshot,
+        target: Option<&mut Snapshot>,
+        w: WorldId,
+        pm: &PlannedMove,
+        notes: &mut Vec<Deduction>,
+    ) -> Result<(), MoveError> {
+        let turn = self.stats.turns + 1;
+        let files = self.files();
+        let mut note =
+            |piece_id: u64, before: &CandidateSet, after: &CandidateSet, reason: String| {
+                let removed: CandidateSet = before.difference(after).copied().collect();
+                if !removed.is_empty() {
+                    notes.push(Deduction {
+                        turn,
+                        world: w,
+                        piece_id,
+                        removed,
+                        reason,
+                    });
+                }
+            };
+        match pm.kind.clone() {
+            MoveKind::Move { from, to, promote } => {
+                let mut piece = src_present.take(from).ok_or(MoveError::EmptySource)?;
+                if piece.owner != self.turn {
+                    return Err(MoveError::NotYourPiece);
+                }
+                if piece.paradoxed {
+                    return Err(MoveError::ParadoxedPiece(piece.id));
+                }
+                let candidates = self.filter_candidates_for_move(
+                    w,
+                    &piece,
+                    from,
+                    to,
+                    pm.delta_w,
+                    pm.delta_t,
+                    src_present,
+                    target.as_deref().unwrap_or(src_present),
+                )?;
+                let shifted = target.is_some();
+                let (target, source) = match target {
+                    Some(t) => (t, Some(src_present)),
+                    None => (src_present, None),
+                };
+                if candidates.is_empty() {
+                    return Err(MoveError::NoCandidates);
+                }
+                let zone_ok = self.can_promote_on(piece.owner, from, to, pm.delta_w, pm.delta_t);
+                if promote && !piece.promoted && !zone_ok {
+                    return Err(MoveError::PromotionOutsideZone);
+                }
+                let mut promotes = promote && !piece.promoted;
+                let mut settled = if piece.promoted {
+                    self.filter_by_promotion(&candidates, piece.owner, to, true)
+                } else {
+                    self.filter_by_promotion(&candidates, piece.owner, to, promotes)
+                };
+                // 不成だと全候補が行き所のない駒になるなら強制的に成る
+                let forced = settled.is_empty() && !promotes && !piece.promoted && zone_ok;
+                if forced {
+                    promotes = true;
+                    settled = self.filter_by_promotion(&candidates, piece.owner, to, true);
+                }
+                if settled.is_empty() {
+                    return Err(MoveError::NoPromotionCandidate);
+                }
+                if !self.admits(piece.id, &settled) {
+                    return Err(MoveError::CannotMoveThatWay);
+                }
+                let (dx, dy) = from.delta_to(to);
+                note(
+                    piece.id,
+                    &piece.candidates,
+                    &candidates,
+                    format!(
+                        "{from}→{to} (Δx{dx:+} Δy{dy:+} Δw{:+} Δt{:+}) と動いた",
+                        pm.delta_w,
+                        pm.delta_t,
+                        from = from.notation(files),
+                        to = to.notation(files)
+                    ),
+                );
+                note(
+                    piece.id,
+                    &candidates,
+                    &settled,
+                    if forced {
+                        format!(
+                            "{to} で成った (行き所がないため強制)",
+                            to = to.notation(files)
+                        )
+                    } else if promotes {
+                        format!("{to} で成った", to = to.notation(files))
+                    } else {
+                        format!(
+                            "{to} で成らなかった (行き所のない駒になる)",
+                            to = to.notation(files)
+                        )
+                    },
+                );
+                piece.candidates = settled;
+                piece.promoted |= promotes;
+                if shifted {
+                    let before = piece.candidates.clone();
+                    self.resolve_paradox(target, &mut piece)?;
+                    note(
+                        piece.id,
+                        &before,
+                        &piece.candidates,
+                        format!("{to} で過去の自分と重なった", to = to.notation(files)),
+                    );
+                }
+
+                if let Some(mut captured) = target.take(to) {
+                    let before = captured.candidates.clone();
+                    captured.candidates.remove(&PieceType::King);
+                    note(
+                        captured.id,
+                        &before,
+                        &captured.candidates,
+                        format!("{to} で捕獲された", to = to.notation(files)),
+                    );
+                    if self.settings.collapse_mode == CollapseMode::RandomOnCapture
+                        && captured.candidates.len() > 1
+                    {
+                        let chosen = self.observe_outcome(target, &captured);
+                        let before =
+                            std::mem::replace(&mut captured.candidates, BTreeSet::from([chosen]));
+                        note(
+                            captured.id,
+                            &before,
+                            &captured.candidates,
+                            format!("{to} で捕獲され観測された", to = to.notation(files)),
+                        );
+                    }
+                    if let Some(source) = source {
+                        self.capture_in_source(source, captured.id);
+                    }
+                    // 王と確定していた駒は持ち駒にせず、盤から除くだけにする
+                    if !captured.candidates.is_empty() {
+                        captured.demote_captured(self.turn, (w, turn));
+                        target.add_to_hand(captured);
+                    }
+                }
+                target.put(to, Some(piece));
+            }
+            MoveKind::Drop { group, to } | MoveKind::TeleportDrop { group, to, .. } => {
+                let dest = target.as_deref().unwrap_or(src_present);
+                if !dest.contains(to) {
+                    return Err(MoveError::OutOfBoard);
+                }
+                if dest.piece_at(to).is_some() {
+                    return Err(MoveError::DropOccupied);
+                }
+                let piece_index = src_present
+                    .hand_index_of(self.turn, &group)
+                    .ok_or(MoveError::NoSuchHandPiece)?;
+                let mut p = Arc::unwrap_or_clone(
+                    src_present
+                        .hands
+                        .get_mut(&self.turn)
+                        .unwrap()
+                        .remove(piece_index),
+                );
+                p.owner = self.turn;
+                p.captured_at = None;
+                let shifted = target.is_some();
+                let target = match target {
+                    Some(t) => t,
+                    None => src_present,
+                };
+                if shifted {
+                    let before = p.candidates.clone();
+                    self.resolve_paradox(target, &mut p)?;
+                    note(
+                        p.id,
+                        &before,
+                        &p.candidates,
+                        format!("{to} で過去の自分と重なった", to = to.notation(files)),
+                    );
+                }
+                let filtered = self.filter_drop_candidates(&p.candidates, to, target, self.turn);
+                note(
+                    p.id,
+                    &p.candidates,
+                    &filtered,
+                    format!(
+                        "{to} に打った (二歩・行き所のない駒・打ち歩詰めの禁則)",
+                        to = to.notation(files)
+                    ),
+                );
+                p.candidates = filtered;
+                if !self.admits(p.id, &p.candidates) {
+                    return Err(MoveError::DropForbidden);
+                }
+                target.put(to, Some(p));
+            }
+            MoveKind::CollapseWorld { sacrifice, .. } => {
+                if !self.settings.world_destruction {
+                    return Err(MoveError::CollapseDisabled);
+                }
+                let piece = src_present.take(sacrifice).ok_or(MoveError::NoSacrifice)?;
+                if piece.owner != self.turn {
+                    return Err(MoveError::NotYourPiece);
+                }
+                if piece.candidates.len() == 1 && piece.candidates.contains(&PieceType::King) {
+                    return Err(MoveError::KingSacrifice);
+                }
+            }
+            MoveKind::Observe { at } => {
+                if self.settings.collapse_mode == CollapseMode::CountOnly {
+                    return Err(MoveError::ObserveDisabled);
+                }
+                let p = src_present
+                    .piece_at(at)
+                    .ok_or(MoveError::NothingToObserve)?;
+                if p.candidates.len() == 1 {
+                    return Err(MoveError::AlreadyObserved);
+                }
+                let (id, before) = (p.id, p.candidates.clone());
+                let chosen = self.observe_outcome(src_present, p);
+                note(
+                    id,
+                    &before,
+                    &BTreeSet::from([chosen]),
+                    format!("{at} で観測された", at = at.notation(files)),
+                );
+                if let Some(p) = src_present.piece_mut(at) {
+                    p.candidates = BTreeSet::from([chosen]);
+                }
+            }
+            MoveKind::Pass => {}
+        }
+        Ok(())
+    }
+
+    // 別の局面へ着地する駒と同じ id の駒が着地先にいる場合の処理 (Settings::paradox_rule)
+    fn resolve_paradox(&self, target: &mut Snapshot, piece: &mut Piece) -> Result<(), MoveError> {
+        if !target.contains_piece(piece.id) {
+            return Ok(());
+        }
+        match self.settings.paradox_rule {
+            ParadoxRule::Forbid => Err(MoveError::Paradox(piece.id)),
+            ParadoxRule::RemovePastSelf => {
+                target.remove_piece(piece.id);
+                Ok(())
+            }
+            ParadoxRule::MergeCandidates => {
+                let mut common = piece.candidates.clone();
+                for p in target.all_pieces_mut().filter(|p| p.id == piece.id) {
+                    common.retain(|t| p.candidates.contains(t));
+                }
+                if common.is_empty() {
+                    return Err(MoveError::CandidatesExhausted(piece.id));
+                }
+                for p in target.all_pieces_mut().filter(|p| p.id == piece.id) {
+                    if p.candidates != common {
+                        Arc::make_mut(p).candidates = common.clone();
+                    }
+                }
+                piece.candidates = common;
+                Ok(())
+            }
+        }
+    }
+
+    // 観測で確定する駒種。hidden なら割り当て済みの駒種を明かし、lazy なら
+    // 枚数上限に達していない候補から、ターンの乱数状態と駒 id で決まる値で選ぶ
+    // (同じ seed から同じ手順を指せば同じ結果になる)
+    fn observe_outcome(&self, s: &Snapshot, p: &Piece) -> PieceType {
+        if let Some(t) = self.hidden.get(&p.id).filter(|t| p.candidates.contains(t)) {
+            return *t;
+        }
+        let settled = |t: PieceType| {
+            s.pieces()
+                .map(|(_, q)| q)
+                .chain(s.hand_pieces())
+                .filter(|q| q.owner == p.owner && q.candidates.len() == 1)
+                .filter(|q| q.candidates.contains(&t))
+                .count()
+        };
+        let open: Vec<PieceType> = p
+            .candidates
+            .iter()
+            .copied()
+            .filter(|t| settled(*t) < self.army_limit(p.owner, *t))
+            .collect();
+        let pool: Vec<PieceType> = if open.is_empty() {
+            p.candidates.iter().copied().collect()
+        } else {
+            open
+        };
+        let mut state = self.rng ^ p.id.rotate_left(32);
+        pool[(splitmix64(&mut state) % pool.len() as u64) as usize]
+    }
+
+    // 敵陣の奥から数えた段 (0 が最奥)
+    fn zone_depth(&self, owner: Player, sq: Square) -> usize {
+        match owner {
+            Player::Black => sq.rank,
+            Player::White => self.settings.variant.board_size().1 - 1 - sq.rank,
+        }
+    }
+
+    // from から to への移動で成れる位置か (Settings::promotion_zone)
+    fn can_promote_on(&self, owner: Player, from: Square, to: Square, dw: i32, dt: i32) -> bool {
+        let camp = self.settings.variant.camp_depth();
+        let in_zone = |sq: Square| self.zone_depth(owner, sq) < camp;
+        match self.settings.promotion_zone {
+            PromotionZone::PerWorld => in_zone(from) || in_zone(to),
+            PromotionZone::PerTimeLayer => in_zone(to) || dw == 0 && dt == 0 && in_zone(from),
+        }
+    }
+
+    // 成れるのは金・玉以外。成らずに行き所がなくなる駒種も除く。
+    fn filter_by_promotion(
+        &self,
+        cands: &CandidateSet,
+        owner: Player,
+        to: Square,
+        promotes: bool,
+    ) -> CandidateSet {
+        let depth = self.zone_depth(owner, to);
+        cands
+            .iter()
+            .copied()
+            .filter(|c| {
+                if promotes {
+                    c.can_promote()
+                } else {
+                    !(depth == 0 && matches!(c, PieceType::Pawn | PieceType::Lance)
+                        || depth <= 1 && *c == PieceType::Knight)
+                }
+            })
+            .collect()
+    }
+
+    // 打ち歩詰め: 打った駒が歩だとしたら直ちに相手が詰むなら、その候補は打てない
+    fn drop_pawn_mates(&self, target: &Snapshot, to: Square, owner: Player) -> bool {
+        let mut next = target.clone();
+        let mut pawn = Piece::new(u64::MAX, owner);
+        pawn.candidates = BTreeSet::from([PieceType::Pawn]);
+        next.put(to, Some(pawn));
+        self.is_checkmated(&next, owner.opposite())
+    }
+
+    // owner が to に打てる候補 (二歩・行き所のない駒・打ち歩詰めを除く)
+    fn filter_drop_candidates(
+        &self,
+        cands: &BTreeSet<PieceType>,
+        to: Square,
+        target: &Snapshot,
+        owner: Player,
+    ) -> BTreeSet<PieceType> {
+        let mut out = BTreeSet::new();
+        let depth = self.zone_depth(owner, to);
+        for c in cands {
+            if *c == PieceType::Pawn {
+                if self.double_pawn_file(target, to.file, owner) {
+                    continue;
+                }
+                if depth == 0 {
+                    continue;
+                }
+                if self.drop_pawn_mates(target, to, owner) {
+                    continue;
+                }
+            }
+            if *c == PieceType::Lance && depth == 0 {
+                continue;
+            }
+            if *c == PieceType::Knight && depth <= 1 {
+                continue;
+            }
+            out.insert(*c);
+        }
+        out
+    }
+
+    // 規則判定でその駒の種類を確定扱いしてよいか。
+    // Lazy では候補が1つに絞られた駒だけ、Hidden では常に真の駒種を使う。
+    fn settled_type(&self, p: &Piece) -> Option<PieceType> {
+        match self.settings.quantum_model {
+            QuantumModel::Lazy => match p.candidates.iter().next() {
+                Some(t) if p.candidates.len() == 1 => Some(*t),
+                _ => None,
+            },
+            QuantumModel::Hidden => self.hidden.get(&p.id).copied(),
+        }
+    }
+
+    // 絞り込んだ候補で指し手を認めてよいか。Hidden では真の駒種が残っている必要がある。
+    fn admits(&self, piece_id: u64, candidates: &CandidateSet) -> bool {
+        match self.settings.quantum_model {
+            QuantumModel::Lazy => !candidates.is_empty(),
+            QuantumModel::Hidden => self
+                .hidden
+                .get(&piece_id)
+                .map_or(!candidates.is_empty(), |t| candidates.contains(t)),
+        }
+    }
+
+    fn double_pawn_file(&self, s: &Snapshot, file: usize, owner: Player) -> bool {
+        (0..s.ranks()).any(|rank| {
+            s.piece_at(Square::new(file, rank))
+                .is_some_and(|p| p.owner == owner && self.settled_type(p) == Some(PieceType::Pawn))
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn filter_candidates_for_move(
+        &self,
+        w: WorldId,
+        piece: &Piece,
+        from: Square,
+        to: Square,
+        dw: i32,
+        dt: i32,
+        src: &Snapshot,
+        target: &Snapshot,
+    ) -> Result<BTreeSet<PieceType>, MoveError> {
+        if !target.contains(to) {
+            return Err(MoveError::OutOfBoard);
+        }
+        if let Some(tp) = target.piece_at(to) {
+            if tp.owner == piece.owner {
+                return Err(MoveError::OwnPieceOnTarget);
+            }
+        }
+        let (dx, dy) = from.delta_to(to);
+        let mut out = BTreeSet::new();
+        for c in &piece.candidates {
+            let origin = Some((w, piece.id));
+            if self.type_can_move(
+                *c,
+                piece.promoted,
+                piece.owner,
+                dx,
+                dy,
+                dw,
+                dt,
+                from,
+                src,
+                origin,
+            )? {
+                out.insert(*c);
+            }
+        }
+        Ok(out)
+    }
+
+    // variant の空の盤の中央から各軸2マスまでで、駒種が動ける変位 (Δx, 前, 前方の世界線, Δt) を列挙する。
+    // ルール表示用。判定は実際の指し手と同じ type_can_move を通す
+    pub fn move_offsets(&self, t: PieceType, promoted: bool) -> Vec<[i32; 4]> {
+        let empty = Snapshot::empty(self.settings.variant.board_size());
+        let center = Square::new(empty.files() / 2, empty.ranks() / 2);
+        let f = Player::Black.forward_sign();
+        (0..4)
+            .map(|_| -2..=2)
+            .multi_cartesian_product()
+            .filter(|d| d.iter().any(|v| *v != 0))
+            .filter(|d| {
+                self.type_can_move(
+                    t,
+                    promoted,
+                    Player::Black,
+                    d[0],
+                    d[1],
+                    d[2],
+                    d[3],
+                    center,
+                    &empty,
+                    None,
+                ) == Ok(true)
+            })
+            .map(|d| [d[0], d[1] * f, d[2] * f, d[3]])
+            .collect()
+    }
+
+    // origin は動かす駒の世界線と id。None なら src 以外の層の駒は数えない
+    #[allow(clippy::too_many_arguments)]
+    fn type_can_move(
+        &self,
+        t: PieceType,
+        promoted: bool,
+        owner: Player,
+        dx: i32,
+        dy: i32,
+        dw: i32,
+        dt: i32,
+        from: Square,
+        src: &Snapshot,
+        origin: Option<(WorldId, PieceId)>,
+    ) -> Result<bool, MoveError> {
+        if self.settings.past_only && dt > 0 {
+            return Ok(false);
+        }
+        // と金・成香・成桂・成銀は金と同じ動き (時空方向の一歩も金に準ずる)
+        let t = match t {
+            PieceType::Pawn | PieceType::Lance | PieceType::Knight | PieceType::Silver
+                if promoted =>
+            {
+                PieceType::Gold
             }
-            let base = self
-                .worlds
-                .get(&w)
-                .unwrap()
-                .history
-                .get(t_base as usize)
-                .cloned()
-                .ok_or_else(|| anyhow::anyhow!("t_base無効"))?;
-            let mut src_now = self
-                .worlds
-                .get(&w)
-                .unwrap()
-                .history
-                .last()
-                .cloned()
-                .unwrap();
-            let mut new_snap = base;
-            self.execute_move(&mut src_now, &mut new_snap, &pm, true, global_cons)?;
-            self.worlds.get_mut(&w).unwrap().history.push(src_now);
-            self.worlds.insert(
-                w_new,
-                WorldLine {
-                    w: w_new,
-                    history: vec![new_snap],
-                    staged: None,
-                    lost: false,
-                },
-            );
+            _ => t,
+        };
+        let king_step = dx.abs().max(dy.abs()).max(dw.abs()).max(dt.abs()) == 1;
+        if matches!(
+            t,
+            PieceType::Pawn | PieceType::Gold | PieceType::Silver | PieceType::King
+        ) && dw.abs() >= 2
+        {
+            return Ok(false);
+        }
+        let f = owner.forward_sign();
+        let ok = match t {
+            PieceType::King => king_step,
+            PieceType::Pawn => {
+                (dy == f && dx == 0 && dw == 0 && dt == 0)
+                    || (dw == f && dx == 0 && dy == 0 && dt == 0)
+                    || (dt == -1 && dx == 0 && dy == 0 && dw == 0)
+            }
+            PieceType::Gold => {
+                let steps = [
+                    (0, f, 0, 0),
+                    (1, 0, 0, 0),
+                    (-1, 0, 0, 0),
+                    (0, -f, 0, 0),
+                    (1, f, 0, 0),
+                    (-1, f, 0, 0),
+                    (0, 0, f, 0),
+                    (0, 0, 0, -1),
+                ];
+                steps.contains(&(dx, dy, dw, dt))
+            }
+            PieceType::Silver => {
+                let steps = [
+                    (0, f, 0, 0),
+                    (1, f, 0, 0),
+                    (-1, f, 0, 0),
+                    (1, -f, 0, 0),
+                    (-1, -f, 0, 0),
+                    (0, 0, f, 0),
+                    (0, 0, 0, -1),
+                ];
+                steps.contains(&(dx, dy, dw, dt))
+            }
+            PieceType::Knight => {
+                let ks = [
+                    (1, 2 * f, 0, 0),
+                    (-1, 2 * f, 0, 0),
+                    (1, 0, 2 * f, 0),
+                    (-1, 0, 2 * f, 0),
+                    (1, 0, 0, -2),
+                    (-1, 0, 0, -2),
+                ];
+                ks.contains(&(dx, dy, dw, dt))
+            }
+            PieceType::Lance => {
+                self.is_linear_clear(from, dx, dy, dw, dt, src, origin)?
+                    && ((dx, dy, dw, dt) != (0, 0, 0, 0))
+                    && ((dx == 0 && dw == 0 && dt == 0 && dy.signum() == f)
+                        || (dx == 0 && dy == 0 && dt == 0 && dw.signum() == f))
+            }
+            // 龍・馬は元の動きに加えて玉の一歩
+            PieceType::Rook if promoted && king_step => true,
+            PieceType::Bishop if promoted && king_step => true,
+            PieceType::Rook => {
+                self.is_linear_clear(from, dx, dy, dw, dt, src, origin)?
+                    && [dx == 0, dy == 0, dw == 0, dt == 0]
+                        .into_iter()
+                        .filter(|v| *v)
+                        .count()
+                        == 3
+            }
+            // 4次元の斜め: 動く軸はどれも同じ距離だけ動き、その数は 2〜bishop_axes
+            PieceType::Bishop => {
+                let moving: Vec<i32> = [dx, dy, dw, dt]
+                    .into_iter()
+                    .filter(|v| *v != 0)
+                    .map(i32::abs)
+                    .collect();
+                (2..=self.settings.bishop_axes).contains(&moving.len())
+                    && moving.iter().all_equal()
+                    && self.is_linear_clear(from, dx, dy, dw, dt, src, origin)?
+            }
+        };
+        Ok(ok)
+    }
+
+    // 途中の点を4次元でたどる。w・t がずれる点は、途中の世界線の現在から Δt ずらした層を見る。
+    // 動かす駒の過去の自分は塞がない。途中の世界線や層がなければ空きとみなす
+    #[allow(clippy::too_many_arguments)]
+    fn is_linear_clear(
+        &self,
+        from: Square,
+        dx: i32,
+        dy: i32,
+        dw: i32,
+        dt: i32,
+        src: &Snapshot,
+        origin: Option<(WorldId, PieceId)>,
+    ) -> Result<bool, MoveError> {
+        let steps = dx.abs().max(dy.abs()).max(dw.abs()).max(dt.abs());
+        if steps <= 1 {
+            return Ok(true);
+        }
+        let (sx, sy, sw, st) = (dx.signum(), dy.signum(), dw.signum(), dt.signum());
+        for i in 1..steps {
+            let sq = from
+                .offset(sx * i, sy * i, (src.files(), src.ranks()))
+                .ok_or(MoveError::PathOutOfRange)?;
+            let blocked = if sw == 0 && st == 0 {
+                src.piece_at(sq).is_some()
+            } else {
+                let Some((w, id)) = origin else { continue };
+                self.layer(w.offset(sw * i), st * i)
+                    .and_then(|s| s.piece_at(sq))
+                    .is_some_and(|p| p.id != id)
+            };
+            if blocked {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // w の世界線の現在から dt ずらした局面。ターンの解決中は解決前の現在を基準にし、
+    // このターンにできた世界線は含めない (解決順で結果が変わらないように)
+    fn layer(&self, w: WorldId, dt: i32) -> Option<&Snapshot> {
+        let wl = self.worlds.get(&w)?;
+        let present = if self.resolving.is_empty() {
+            wl.history.len() - 1
         } else {
-            let mut cur = self
-                .worlds
-                .get(&w)
-                .unwrap()
-                .history
-                .last()
-                .cloned()
-                .unwrap();
-            let mut dummy = cur.clone();
-            self.execute_move(&mut cur, &mut dummy, &pm, false, global_cons)?;
-            self.worlds.get_mut(&w).unwrap().history.push(cur);
+            *self.resolving.get(&w)?
+        };
+        let t = usize::try_from(present as i32 + dt).ok()?;
+        wl.history.get(t)
+    }
+
+    pub fn king_candidates(s: &Snapshot, pl: Player) -> Vec<Square> {
+        s.pieces()
+            .filter(|(_, p)| p.owner == pl && p.candidates.contains(&PieceType::King))
+            .map(|(sq, _)| sq)
+            .collect()
+    }
+
+    // 玉になりうる駒がすべて敵陣にある
+    fn king_entered(&self, s: &Snapshot, pl: Player) -> bool {
+        let camp = self.settings.variant.camp_depth();
+        let kings = Self::king_candidates(s, pl);
+        !kings.is_empty() && kings.iter().all(|sq| self.zone_depth(pl, *sq) < camp)
+    }
+
+    // 27点法の点数 (飛角5点・玉0点・他1点) と、敵陣にある玉以外の駒の数。
+    // 点数は敵陣の駒と持ち駒を数え、駒種が決まっていない駒は候補の平均を使う
+    pub fn impasse_score(&self, s: &Snapshot, pl: Player) -> (f64, usize) {
+        let value = |t: PieceType| match t {
+            PieceType::Rook | PieceType::Bishop => 5.0,
+            PieceType::King => 0.0,
+            _ => 1.0,
+        };
+        let expected = |p: &Piece| match self.settled_type(p) {
+            Some(t) => value(t),
+            None => p.candidates.iter().map(|t| value(*t)).sum::<f64>() / p.candidates.len() as f64,
+        };
+        let camp = self.settings.variant.camp_depth();
+        let in_camp: Vec<&Piece> = s
+            .pieces()
+            .filter(|(sq, p)| p.owner == pl && self.zone_depth(pl, *sq) < camp)
+            .map(|(_, p)| p)
+            .filter(|p| self.settled_type(p) != Some(PieceType::King))
+            .collect();
+        let points = in_camp
+            .iter()
+            .copied()
+            .chain(s.hand(pl).iter().map(Arc::as_ref))
+            .map(expected)
+            .fold(0.0, |a, v| a + v);
+        (points, in_camp.len())
+    }
+
+    // 両者の王が入玉した世界線の判定。敵陣に10枚以上あり、王手されておらず、
+    // 先手28点・後手27点以上の側が勝ち。両方か、どちらも満たさなければ持将棋 (None)
+    fn impasse_winner(&self, s: &Snapshot) -> Option<Player> {
+        let qualifies = |pl: Player| {
+            let (points, count) = self.impasse_score(s, pl);
+            let needed = match pl {
+                Player::Black => 28.0,
+                Player::White => 27.0,
+            };
+            count >= 10 && points >= needed && !self.is_in_check(s, pl)
+        };
+        match (qualifies(Player::Black), qualifies(Player::White)) {
+            (true, false) => Some(Player::Black),
+            (false, true) => Some(Player::White),
+            _ => None,
         }
-        Ok(())
     }
 
-    fn execute_move(
+    // CheckAttackMode で王手に数える駒種
+    fn attacker_types(&self, p: &Piece) -> Vec<PieceType> {
+        match self.settings.check_attack_mode {
+            CheckAttackMode::Possible => p.candidates.iter().copied().collect(),
+            CheckAttackMode::Certain if p.candidates.len() == 1 => {
+                p.candidates.iter().copied().collect()
+            }
+            CheckAttackMode::Certain => Vec::new(),
+        }
+    }
+
+    // player の王の候補を狙う相手駒を (位置, 届く駒種, 狙われる王の候補の位置) で列挙する
+    pub fn king_attackers(
         &self,
-        src_present: &mut Snapshot,
-        target: &mut Snapshot,
+        s: &Snapshot,
+        player: Player,
+    ) -> Vec<(Square, Vec<PieceType>, Square)> {
+        let kings = Self::king_candidates(s, player);
+        let mut out = Vec::new();
+        for (from, p) in s.pieces().filter(|(_, p)| p.owner != player) {
+            let attackers = self.attacker_types(p);
+            for &k in &kings {
+                let (dx, dy) = from.delta_to(k);
+                let hits: Vec<PieceType> = attackers
+                    .iter()
+                    .copied()
+                    .filter(|&t| {
+                        self.type_can_move(t, p.promoted, p.owner, dx, dy, 0, 0, from, s, None)
+                            .unwrap_or(false)
+                    })
+                    .collect();
+                if !hits.is_empty() {
+                    out.push((from, hits, k));
+                }
+            }
+        }
+        out
+    }
+
+    // 王の候補がいずれかの相手駒に取られうるか。
+    // Possible は候補のどれか1つでも届けば王手、Certain は駒種が確定した駒だけを数える。
+    pub fn is_in_check(&self, s: &Snapshot, player: Player) -> bool {
+        let kings = Self::king_candidates(s, player);
+        s.pieces()
+            .filter(|(_, p)| p.owner != player)
+            .any(|(from, p)| {
+                let attackers = self.attacker_types(p);
+                kings.iter().any(|&k| {
+                    let (dx, dy) = from.delta_to(k);
+                    attackers.iter().any(|&t| {
+                        self.type_can_move(t, p.promoted, p.owner, dx, dy, 0, 0, from, s, None)
+                            .unwrap_or(false)
+                    })
+                })
+            })
+    }
+
+    // 王の位置が確定していて王手されており、同じ時空内の移動・打ちでは逃れられない。
+    // 時空を越える手で逃れられるかまでは見ない。
+    fn is_checkmated(&self, s: &Snapshot, pl: Player) -> bool {
+        if Self::king_candidates(s, pl).len() != 1 || !self.is_in_check(s, pl) {
+            return false;
+        }
+        let escapes = |next: &Snapshot| {
+            Self::king_candidates(next, pl).len() == 1 && !self.is_in_check(next, pl)
+        };
+        let squares = board_squares(s);
+        for (from, p) in s.pieces().filter(|(_, p)| p.owner == pl) {
+            for to in squares.clone() {
+                if to == from || s.piece_at(to).is_some_and(|q| q.owner == pl) {
+                    continue;
+                }
+                let (dx, dy) = from.delta_to(to);
+                let movable: CandidateSet = p
+                    .candidates
+                    .iter()
+                    .copied()
+                    .filter(|&t| {
+                        self.type_can_move(t, p.promoted, pl, dx, dy, 0, 0, from, s, None)
+                            .unwrap_or(false)
+                    })
+                    .collect();
+                if movable.is_empty() {
+                    continue;
+                }
+                let mut next = s.clone();
+                let mut piece = next.take(from).unwrap();
+                piece.candidates = movable;
+                next.put(to, Some(piece));
+                if escapes(&next) {
+                    return false;
+                }
+            }
+        }
+        // 持ち駒は候補の組ごとに、打てる候補だけに絞って試す。
+        // 打ち歩詰めの判定は重くて再帰もするので、王手を防げる場所でだけ絞る
+        for group in s.hand_groups(pl).into_keys() {
+            for to in squares.clone().filter(|sq| s.piece_at(*sq).is_none()) {
+                let drop = |candidates: CandidateSet| {
+                    let mut next = s.clone();
+                    let i = next.hand_index_of(pl, &group).unwrap();
+                    let mut piece =
+                        Arc::unwrap_or_clone(next.hands.get_mut(&pl).unwrap().remove(i));
+                    piece.candidates = candidates;
+                    next.put(to, Some(piece));
+                    next
+                };
+                if !escapes(&drop(group.clone())) {
+                    continue;
+                }
+                let kept = self.filter_drop_candidates(&group, to, s, pl);
+                if !kept.is_empty() && escapes(&drop(kept)) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    // 世界線 w で手番側が指せる手を列挙する (召喚の有無は区別しない)。
+    // Δw は ±max_worlds、Δt は -max_time_jump..=0 の範囲で、分岐先の番号が
+    // world_id_policy で決まるものだけ探す。cross_world_base なら起点の世界線も変えてみる。
+    // 候補で絞り込んだ後に、1つの複製へ1手ずつ適用して確かめる。
+    pub fn legal_moves(&self, w: WorldId) -> Vec<PlannedMove> {
+        let Some(wl) = self.worlds.get(&w) else {
+            return Vec::new();
+        };
+        if self.check_turn_mode(w).is_err() {
+            return Vec::new();
+        }
+        let present = wl.present();
+        let plan =
+            |kind: MoveKind, delta_w: i32, delta_t: i32, base_world: Option<WorldId>| PlannedMove {
+                kind,
+                delta_w,
+                delta_t,
+                summon: None,
+                base_world,
+            };
+        // 分岐の起点にする世界線 (None は w 自身)。起点として認められるかは branch_base が決める
+        let mut bases = vec![None];
+        if self.settings.cross_world_base {
+            bases.extend(self.worlds.keys().filter(|b| **b != w).map(|b| Some(*b)));
+        }
+        let max_w = self.settings.max_worlds as i32;
+        let mut shifts: Vec<(i32, i32, Option<WorldId>, &Snapshot)> = vec![(0, 0, None, present)];
+        for (dw, dt) in (-max_w..=max_w).cartesian_product(-self.settings.max_time_jump..=0) {
+            if (dw == 0 && dt == 0) || self.allocate_world(w, dw).is_none() {
+                continue;
+            }
+            for &base_world in &bases {
+                let probe = plan(MoveKind::Pass, dw, dt, base_world);
+                if let Ok((bw, t)) = self.branch_base(w, &probe) {
+                    shifts.push((dw, dt, base_world, &self.worlds[&bw].history[t.0]));
+                }
+            }
+        }
+
+        let mut out = Vec::new();
+        for (from, piece) in present
+            .pieces()
+            .filter(|(_, p)| p.owner == self.turn && !p.paradoxed)
+        {
+            let mut src = present.clone();
+            src.take(from);
+            for &(dw, dt, base, target) in &shifts {
+                for to in board_squares(target) {
+                    let Some(cands) = self
+                        .filter_candidates_for_move(w, piece, from, to, dw, dt, &src, target)
+                        .ok()
+                        .filter(|c| !c.is_empty())
+                    else {
+                        continue;
+                    };
+                    // 不成で行き所がなくなるなら成る手だけ、成れない位置なら不成だけ
+                    let zone_ok =
+                        !piece.promoted && self.can_promote_on(piece.owner, from, to, dw, dt);
+                    let forced = zone_ok
+                        && self
+                            .filter_by_promotion(&cands, piece.owner, to, false)
+                            .is_empty();
+                    for promote in [false, true] {
+                        if promote && !zone_ok || !promote && forced {
+                            continue;
+                        }
+                        out.push(plan(MoveKind::Move { from, to, promote }, dw, dt, base));
+                    }
+                }
+            }
+        }
+        for group in present.hand_groups(self.turn).into_keys() {
+            for &(dw, dt, base, target) in &shifts {
+                for to in board_squares(target).filter(|sq| target.piece_at(*sq).is_none()) {
+                    if !self
+                        .filter_drop_candidates(&group, to, target, self.turn)
+                        .is_empty()
+                    {
+                        let kind = MoveKind::Drop {
+                            group: group.clone(),
+                            to,
+                        };
+                        out.push(plan(kind, dw, dt, base));
+                    }
+                }
+            }
+            if self.settings.teleport_drop && self.settings.hand_mode == HandMode::Global {
+                for (&dest, dl) in self.worlds.iter().filter(|(d, _)| **d != w) {
+                    for to in board_squares(dl.present())
+                        .filter(|sq| dl.present().piece_at(*sq).is_none())
+                    {
+                        let kind = MoveKind::TeleportDrop {
+                            group: group.clone(),
+                            to,
+                            dest,
+                        };
+                        out.push(plan(kind, 0, 0, None));
+                    }
+                }
+            }
+        }
+        for (at, _) in present.pieces().filter(|(_, p)| p.candidates.len() > 1) {
+            out.push(plan(MoveKind::Observe { at }, 0, 0, None));
+        }
+        if self.settings.world_destruction {
+            for &target in self.worlds.keys().filter(|t| **t != w) {
+                for (sacrifice, _) in present.pieces().filter(|(_, p)| p.owner == self.turn) {
+                    out.push(plan(
+                        MoveKind::CollapseWorld { target, sacrifice },
+                        0,
+                        0,
+                        None,
+                    ));
+                }
+            }
+        }
+        out.push(plan(MoveKind::Pass, 0, 0, None));
+        out.retain(|pm| self.play_one_world(w, pm, None, &mut Vec::new()).is_ok());
+        out
+    }
+
+    // 開発用: 手を実際には指さずに、駒種ごとの判定と最終的な合否を列挙する
+    pub fn trace_move(
+        &self,
+        w: WorldId,
         pm: &PlannedMove,
-        branching: bool,
-        global_cons: &mut HashMap<PieceType, usize>,
-    ) -> anyhow::Result<()> {
-        match pm.kind.clone() {
-            MoveKind::Move { from, to, promote } => {
-                let mut piece = src_present.board[from.1][from.0]
-                    .take()
-                    .ok_or_else(|| anyhow::anyhow!("移動元空"))?;
-                if piece.owner != self.turn {
-                    anyhow::bail!("自駒ではない");
+        override_candidates: Option<&CandidateSet>,
+    ) -> Vec<String> {
+        let files = self.files();
+        let mut g = self.clone();
+        let mut lines = Vec::new();
+        if let (Some(cands), MoveKind::Move { from, .. }) = (override_candidates, &pm.kind) {
+            let piece = g
+                .worlds
+                .get_mut(&w)
+                .and_then(|wl| wl.history.last_mut())
+                .and_then(|s| s.piece_mut(*from));
+            if let Some(p) = piece {
+                p.candidates = cands.clone();
+                lines.push(format!(
+                    "{from} の候補を上書き",
+                    from = from.notation(files)
+                ));
+            }
+        }
+        let Some(wl) = g.worlds.get(&w) else {
+            return vec![format!("世界線 {w} が存在しない")];
+        };
+        let branching = pm.delta_w != 0 || pm.delta_t < 0;
+        let base = g
+            .branch_base(w, pm)
+            .ok()
+            .filter(|_| branching)
+            .map(|(bw, t)| &g.worlds[&bw].history[t.0]);
+        let present = wl.present();
+        match &pm.kind {
+            MoveKind::Move { from, to, .. } => {
+                let mut src = present.clone();
+                match src.take(*from) {
+                    Some(piece) => {
+                        let target = base.unwrap_or(&src);
+                        let (dx, dy) = from.delta_to(*to);
+                        lines.push(format!(
+                            "{from}→{to} Δx{dx:+} Δy{dy:+} Δw{:+} Δt{:+} {}",
+                            pm.delta_w,
+                            pm.delta_t,
+                            piece.owner.label(),
+                            from = from.notation(files),
+                            to = to.notation(files)
+                        ));
+                        if let Some(tp) = target.piece_at(*to) {
+                            lines.push(format!("移動先: {}", tp.display_text()));
+                        }
+                        for c in PieceType::all() {
+                            let verdict = if !piece.candidates.contains(&c) {
+                                "候補外".to_string()
+                            } else {
+                                match g.type_can_move(
+                                    c,
+                                    piece.promoted,
+                                    piece.owner,
+                                    dx,
+                                    dy,
+                                    pm.delta_w,
+                                    pm.delta_t,
+                                    *from,
+                                    &src,
+                                    Some((w, piece.id)),
+                                ) {
+                                    Ok(true) => "○".into(),
+                                    Ok(false) => "×".into(),
+                                    Err(e) => format!("エラー: {e}"),
+                                }
+                            };
+                            lines.push(format!("  {}: {verdict}", c.short()));
+                        }
+                    }
+                    None => lines.push(format!("{from} に駒がない", from = from.notation(files))),
                 }
-                let candidates = self.filter_candidates_for_move(
-                    &piece,
-                    from,
-                    to,
-                    pm.delta_w,
-                    pm.delta_t,
-                    src_present,
-                    target,
-                )?;
-                if candidates.is_empty() {
-                    anyhow::bail!("候補なし");
+            }
+            MoveKind::Drop { group, to } | MoveKind::TeleportDrop { group, to, .. } => {
+                let target = match (base, &pm.kind) {
+                    (_, MoveKind::TeleportDrop { dest, .. }) => match g.worlds.get(dest) {
+                        Some(d) => d.present(),
+                        None => present,
+                    },
+                    (Some(b), _) => b,
+                    _ => present,
+                };
+                let kept = g.filter_drop_candidates(group, *to, target, g.turn);
+                lines.push(format!(
+                    "{to} に打つ ({}持駒)",
+                    g.turn.label(),
+                    to = to.notation(files)
+                ));
+                for c in group {
+                    let verdict = if kept.contains(c) { "○" } else { "× 禁則" };
+                    lines.push(format!("  {}: {verdict}", c.short()));
+                }
+            }
+            MoveKind::CollapseWorld { target, sacrifice } => {
+                lines.push(format!(
+                    "{sacrifice} を犠牲に {target} を破壊",
+                    sacrifice = sacrifice.notation(files)
+                ));
+                lines.push("(破壊対象の条件は同時確定時に判定)".into());
+            }
+            MoveKind::Observe { at } => {
+                lines.push(format!("{at} の駒を観測", at = at.notation(files)));
+            }
+            MoveKind::Pass => lines.push("パス".into()),
+        }
+        let mut notes = Vec::new();
+        match g.apply_one_world(w, pm.clone(), None, &mut notes) {
+            Ok(()) => lines.push("結果: 合法".into()),
+            Err(e) => lines.push(format!("結果: 不合法 ({e})")),
+        }
+        for d in notes {
+            let removed: String = d.removed.iter().map(|c| c.short()).collect();
+            lines.push(format!(
+                "  #{} {} ⇒ {removed} ではない",
+                d.piece_id, d.reason
+            ));
+        }
+        lines
+    }
+
+    // 召喚される駒は金で確定している。id は次に払い出す番号で、record_summon で確保する
+    fn summon_into(&self, s: &mut Snapshot, sq: Square) -> Result<(), MoveError> {
+        if !self.summon_available(self.turn) {
+            return Err(MoveError::SummonUnavailable);
+        }
+        if !s.contains(sq) || s.piece_at(sq).is_some() {
+            return Err(MoveError::SummonOccupied(sq));
+        }
+        let mut piece = Piece::new(self.next_id, self.turn);
+        piece.candidates = BTreeSet::from([PieceType::Gold]);
+        s.put(sq, Some(piece));
+        Ok(())
+    }
+
+    fn record_summon(&mut self) {
+        let id = self.alloc_id();
+        if self.settings.quantum_model == QuantumModel::Hidden {
+            self.hidden.insert(id, PieceType::Gold);
+        }
+        let turn = self.stats.turns + 1;
+        self.collapse_stats
+            .record(turn, id, PieceType::Gold, CollapseCause::Summon);
+        self.summon_used.insert(self.turn);
+    }
+
+    // 局面エディタ等で作った配置が variant の規則上ありえるかを調べ、違反を列挙する
+    pub fn validate_position(s: &Snapshot, variant: Variant) -> Vec<PositionViolation> {
+        let army = variant.army();
+        let mut out = Vec::new();
+        let mut seen = BTreeSet::new();
+        for (sq, p) in s.pieces() {
+            let (owner, at) = (p.owner, Some(sq));
+            if !seen.insert(p.id) {
+                out.push(PositionViolation::DuplicateId {
+                    owner,
+                    at,
+                    id: p.id,
+                });
+            }
+            if p.candidates.is_empty() {
+                out.push(PositionViolation::NoCandidates { owner, at });
+            }
+            if p.promoted && p.candidates.iter().any(|c| !c.can_promote()) {
+                out.push(PositionViolation::PromotedUnpromotable(sq));
+            }
+            let last = match p.owner {
+                Player::Black => sq.rank,
+                Player::White => s.ranks() - 1 - sq.rank,
+            };
+            let stuck = |limit: &[PieceType]| {
+                !p.promoted
+                    && !p.candidates.is_empty()
+                    && p.candidates.iter().all(|c| limit.contains(c))
+            };
+            if (last == 0 && stuck(&[PieceType::Pawn, PieceType::Lance, PieceType::Knight]))
+                || (last == 1 && stuck(&[PieceType::Knight]))
+            {
+                out.push(PositionViolation::Stuck(sq));
+            }
+        }
+        for p in s.pieces().map(|(_, p)| p).chain(s.hand_pieces()) {
+            // 一式にない駒種を候補に持つ駒
+            if let Some(&piece) = p
+                .candidates
+                .iter()
+                .find(|t| !army.iter().any(|(a, _)| a == *t))
+            {
+                out.push(PositionViolation::NotInVariant {
+                    id: p.id,
+                    piece,
+                    variant,
+                });
+            }
+        }
+        for pl in [Player::Black, Player::White] {
+            for p in s.hand(pl) {
+                if !seen.insert(p.id) {
+                    out.push(PositionViolation::DuplicateId {
+                        owner: pl,
+                        at: None,
+                        id: p.id,
+                    });
+                }
+                if p.candidates.is_empty() {
+                    out.push(PositionViolation::NoCandidates {
+                        owner: pl,
+                        at: None,
+                    });
+                }
+            }
+            let fixed = |pt: PieceType| {
+                s.pieces()
+                    .map(|(_, p)| p)
+                    .filter(|p| p.owner == pl)
+                    .chain(s.hand(pl).iter().map(Arc::as_ref))
+                    .filter(|p| p.candidates.len() == 1 && p.candidates.contains(&pt))
+                    .count()
+            };
+            // 王になりうる駒がちょうど1枚に決まれる (確定した王は1枚まで)
+            if Self::king_candidates(s, pl).is_empty() {
+                out.push(PositionViolation::NoKing(pl));
+            }
+            let kings = fixed(PieceType::King);
+            if kings > 1 {
+                out.push(PositionViolation::ManyKings {
+                    player: pl,
+                    count: kings,
+                });
+            }
+            for &(pt, limit) in army.iter().filter(|(pt, _)| *pt != PieceType::King) {
+                let count = fixed(pt);
+                if count > limit {
+                    out.push(PositionViolation::TooMany {
+                        player: pl,
+                        piece: pt,
+                        count,
+                        limit,
+                    });
                 }
-                piece.candidates = candidates;
-                piece.promoted = promote;
+            }
+            let pawn_files: Vec<usize> = s
+                .pieces()
+                .filter(|(_, p)| {
+                    p.owner == pl
+                        && !p.promoted
+                        && p.candidates.len() == 1
+                        && p.candidates.contains(&PieceType::Pawn)
+                })
+                .map(|(sq, _)| sq.file)
+                .collect();
+            for file in pawn_files.into_iter().duplicates() {
+                out.push(PositionViolation::DoublePawn { player: pl, file });
+            }
+        }
+        out
+    }
 
-                if let Some(mut captured) = target.board[to.1][to.0].take() {
-                    captured.candidates.remove(&PieceType::King);
-                    target.hands.get_mut(&self.turn).unwrap().push(captured);
+    // 各世界線の現在局面で枚数制約による確定を進め、消えた候補を notes に残す
+    fn settle_counts(&mut self, notes: &mut Vec<Deduction>) -> Result<(), EngineError> {
+        let turn = self.stats.turns + 1;
+        // 召喚された金がある対局では金の総数が世界線ごとに違うので、金は全体の枚数で絞らない
+        let budgets: Vec<(PieceType, usize)> = PieceType::all()
+            .into_iter()
+            .filter(|pt| *pt != PieceType::Gold || self.summon_used.is_empty())
+            .map(|pt| (pt, self.type_budget(pt)))
+            .collect();
+        let army = self.army_limits();
+        for wl in self.worlds.values_mut() {
+            let Some(s) = wl.history.last_mut() else {
+                continue;
+            };
+            let before: HashMap<u64, CandidateSet> = s
+                .pieces()
+                .map(|(_, p)| p)
+                .chain(s.hand_pieces())
+                .map(|p| (p.id, p.candidates.clone()))
+                .collect();
+            Self::collapse_by_count(s, &army, &budgets)
+                .map_err(|error| EngineError::Illegal { w: wl.w, error })?;
+            for p in s.pieces().map(|(_, p)| p).chain(s.hand_pieces()) {
+                let Some(prev) = before.get(&p.id) else {
+                    continue;
+                };
+                let removed: CandidateSet = prev.difference(&p.candidates).copied().collect();
+                if !removed.is_empty() {
+                    notes.push(Deduction {
+                        turn,
+                        world: wl.w,
+                        piece_id: p.id,
+                        removed,
+                        reason: "枚数制約により確定".into(),
+                    });
                 }
+            }
+        }
+        Ok(())
+    }
 
-                if !branching {
-                    target.board[from.1][from.0] = None;
+    // 2つの局面を重ねた局面。駒の id は a の側を残す。
+    // 駒の並び (持ち主・成り) か持ち駒の枚数が食い違うか、候補の共通部分が空になる駒があれば None
+    fn merged_snapshot(a: &Snapshot, b: &Snapshot) -> Option<Snapshot> {
+        let same = |p: &Piece, q: &Piece| p.owner == q.owner && p.promoted == q.promoted;
+        let mut out = a.clone();
+        for (rank, row) in out.board.iter_mut().enumerate() {
+            for (file, cell) in row.iter_mut().enumerate() {
+                match (cell.as_mut(), b.board[rank][file].as_deref()) {
+                    (None, None) => {}
+                    (Some(p), Some(q)) if same(p, q) => {
+                        if p.candidates != q.candidates {
+                            let common: CandidateSet =
+                                p.candidates.intersection(&q.candidates).copied().collect();
+                            if common.is_empty() {
+                                return None;
+                            }
+                            Arc::make_mut(p).candidates = common;
+                        }
+                    }
+                    _ => return None,
                 }
-                target.board[to.1][to.0] = Some(piece);
             }
-            MoveKind::Drop { piece_index, to } => {
-                if target.board[to.1][to.0].is_some() {
-                    anyhow::bail!("打ち先占有");
+        }
+        for pl in [Player::Black, Player::White] {
+            let mut theirs: Vec<&Piece> = b.hand(pl).iter().map(Arc::as_ref).collect();
+            theirs.sort_by(|p, q| p.candidates.cmp(&q.candidates));
+            let Some(hand) = out.hands.get_mut(&pl) else {
+                if theirs.is_empty() {
+                    continue;
                 }
-                let hand = src_present.hands.get_mut(&self.turn).unwrap();
-                if piece_index >= hand.len() {
-                    anyhow::bail!("持ち駒index不正");
+                return None;
+            };
+            if hand.len() != theirs.len() {
+                return None;
+            }
+            // 持ち駒の並び順は打つ駒の選び方に関わるので変えず、候補順に対応させる
+            let mut order: Vec<usize> = (0..hand.len()).collect();
+            order.sort_by(|i, j| hand[*i].candidates.cmp(&hand[*j].candidates));
+            for (i, q) in order.into_iter().zip(theirs) {
+                let p = &mut hand[i];
+                if !same(p, q) {
+                    return None;
                 }
-                let mut p = hand.remove(piece_index);
-                if self.settings.hand_mode == HandMode::Global {
-                    for c in &p.candidates {
-                        *global_cons.entry(*c).or_default() += 1;
-                    }
+                let common: CandidateSet =
+                    p.candidates.intersection(&q.candidates).copied().collect();
+                if common.is_empty() {
+                    return None;
                 }
-                p.owner = self.turn;
-                p.candidates = self.filter_drop_candidates(&p.candidates, to, target);
-                if p.candidates.is_empty() {
-                    anyhow::bail!("禁則により打てない");
+                if common != p.candidates {
+                    Arc::make_mut(p).candidates = common;
                 }
-                target.board[to.1][to.0] = Some(p);
             }
         }
-        Ok(())
+        Some(out)
     }
 
-    fn filter_drop_candidates(
-        &self,
-        cands: &BTreeSet<PieceType>,
-        to: (usize, usize),
-        target: &Snapshot,
-    ) -> BTreeSet<PieceType> {
-        let mut out = BTreeSet::new();
-        for c in cands {
-            if *c == PieceType::Pawn {
-                if self.double_pawn_file(target, to.0, self.turn) {
-                    continue;
-                }
-                if (self.turn == Player::Black && to.1 == 0)
-                    || (self.turn == Player::White && to.1 == 8)
-                {
-                    continue;
-                }
+    // 枚数制約・総数に収まる割り当て・世界線をまたいだ同じ駒 (Settings::entangle_worlds) の
+    // 3つを、どれも候補を消さなくなるまで繰り返す
+    fn propagate(&mut self, notes: &mut Vec<Deduction>) -> Result<(), EngineError> {
+        loop {
+            self.settle_counts(notes)?;
+            let mut changed = self.assignment_pass(notes)?;
+            self.record_collapses(|_, _| CollapseCause::Count);
+            if self.settings.entangle_worlds {
+                changed |= self.entangle_pass(notes)?;
+                self.record_collapses(|_, _| CollapseCause::Entanglement);
             }
-            if *c == PieceType::Lance {
-                if (self.turn == Player::Black && to.1 == 0)
-                    || (self.turn == Player::White && to.1 == 8)
-                {
-                    continue;
+            if !changed {
+                return Ok(());
+            }
+        }
+    }
+
+    // 各世界線の現在局面で、駒種ごとの総数 (両者合わせて飛2枚など) を超えずに全駒へ駒種を
+    // 割り当てる方法を二部マッチングで探し、どの割り当てにも現れない候補を消す。
+    // 1つでも候補が変わったら true
+    fn assignment_pass(&mut self, notes: &mut Vec<Deduction>) -> Result<bool, EngineError> {
+        let turn = self.stats.turns + 1;
+        let budgets: BTreeMap<PieceType, usize> = PieceType::all()
+            .into_iter()
+            .map(|t| (t, self.type_budget(t)))
+            .collect();
+        let capacity = |t: PieceType| budgets[&t];
+        let mut changed = false;
+        for wl in self.worlds.values_mut() {
+            let Some(s) = wl.history.last_mut() else {
+                continue;
+            };
+            // 過去の自分と重なって同じ id が2つある駒は1枚として候補の共通部分を使う
+            let mut cands: BTreeMap<PieceId, CandidateSet> = BTreeMap::new();
+            for p in s.pieces().map(|(_, p)| p).chain(s.hand_pieces()) {
+                cands
+                    .entry(p.id)
+                    .and_modify(|c| c.retain(|t| p.candidates.contains(t)))
+                    .or_insert_with(|| p.candidates.clone());
+            }
+            // どの駒種も候補に持つ駒が総数以下なら、どう割り当てても収まる
+            let tight = PieceType::all()
+                .into_iter()
+                .any(|t| cands.values().filter(|c| c.contains(&t)).count() > capacity(t));
+            if !tight {
+                continue;
+            }
+            let Some(base) = Self::assign_types(&cands, None, &capacity) else {
+                return Err(EngineError::Illegal {
+                    w: wl.w,
+                    error: MoveError::CandidatesExhausted(*cands.keys().next().unwrap()),
+                });
+            };
+            let mut unsupported: BTreeMap<PieceId, CandidateSet> = BTreeMap::new();
+            for (id, c) in &cands {
+                for &t in c {
+                    if base.get(id) == Some(&t)
+                        || Self::assign_types(&cands, Some((*id, t)), &capacity).is_some()
+                    {
+                        continue;
+                    }
+                    unsupported.entry(*id).or_default().insert(t);
                 }
             }
-            if *c == PieceType::Knight {
-                if (self.turn == Player::Black && to.1 <= 1)
-                    || (self.turn == Player::White && to.1 >= 7)
-                {
+            if unsupported.is_empty() {
+                continue;
+            }
+            for (id, removed) in &unsupported {
+                notes.push(Deduction {
+                    turn,
+                    world: wl.w,
+                    piece_id: *id,
+                    removed: removed.clone(),
+                    reason: "駒種ごとの総数に収まる割り当てがない".into(),
+                });
+            }
+            s.retain_candidates(|p, t| unsupported.get(&p.id).is_none_or(|b| !b.contains(&t)))
+                .map_err(|error| EngineError::Illegal { w: wl.w, error })?;
+            changed = true;
+        }
+        Ok(changed)
+    }
+
+    // 各駒に候補の駒種を1つずつ、駒種ごとの容量を超えないように割り当てる (増加路法)。
+    // fixed を与えるとその駒はその駒種に決めて探す。割り当てられなければ None
+    fn assign_types(
+        cands: &BTreeMap<PieceId, CandidateSet>,
+        fixed: Option<(PieceId, PieceType)>,
+        capacity: &impl Fn(PieceType) -> usize,
+    ) -> Option<BTreeMap<PieceId, PieceType>> {
+        fn augment(
+            id: PieceId,
+            cands: &BTreeMap<PieceId, CandidateSet>,
+            slots: &mut BTreeMap<PieceType, Vec<PieceId>>,
+            seen: &mut BTreeSet<PieceType>,
+            pinned: Option<PieceId>,
+            capacity: &impl Fn(PieceType) -> usize,
+        ) -> bool {
+            for &t in &cands[&id] {
+                if !seen.insert(t) {
                     continue;
                 }
+                let holders = slots.entry(t).or_default().clone();
+                if holders.len() < capacity(t) {
+                    slots.get_mut(&t).unwrap().push(id);
+                    return true;
+                }
+                // 埋まっていれば、いま t にいる駒を別の駒種へ移せるか試す
+                for other in holders.into_iter().filter(|o| Some(*o) != pinned) {
+                    if augment(other, cands, slots, seen, pinned, capacity) {
+                        let slot = slots.get_mut(&t).unwrap();
+                        slot.retain(|o| *o != other);
+                        slot.push(id);
+                        return true;
+                    }
+                }
             }
-            out.insert(*c);
+            false
         }
-        out
-    }
 
-    fn double_pawn_file(&self, s: &Snapshot, file: usize, owner: Player) -> bool {
-        (0..9).any(|y| {
-            s.board[y][file].as_ref().is_some_and(|p| {
-                p.owner == owner
-                    && p.candidates.len() == 1
-                    && p.candidates.contains(&PieceType::Pawn)
-            })
-        })
+        let mut slots: BTreeMap<PieceType, Vec<PieceId>> = BTreeMap::new();
+        if let Some((id, t)) = fixed {
+            if capacity(t) == 0 {
+                return None;
+            }
+            slots.entry(t).or_default().push(id);
+        }
+        let pinned = fixed.map(|(id, _)| id);
+        for &id in cands.keys().filter(|id| Some(**id) != pinned) {
+            let mut seen = BTreeSet::new();
+            if !augment(id, cands, &mut slots, &mut seen, pinned, capacity) {
+                return None;
+            }
+        }
+        Some(
+            slots
+                .into_iter()
+                .flat_map(|(t, ids)| ids.into_iter().map(move |id| (id, t)))
+                .collect(),
+        )
     }
 
-    fn filter_candidates_for_move(
-        &self,
-        piece: &Piece,
-        from: (usize, usize),
-        to: (usize, usize),
-        dw: i32,
-        dt: i32,
-        src: &Snapshot,
-        target: &Snapshot,
-    ) -> anyhow::Result<BTreeSet<PieceType>> {
-        if to.0 >= 9 || to.1 >= 9 {
-            anyhow::bail!("盤外");
+    // 同じ id の駒の候補を全世界線の共通部分に揃える (Settings::entangle_worlds)。
+    // 共通部分が空になる駒は矛盾しているので、各世界線の候補をそのまま残す。
+    // 1つでも候補が変わったら true
+    fn entangle_pass(&mut self, notes: &mut Vec<Deduction>) -> Result<bool, EngineError> {
+        let mut shared: HashMap<PieceId, CandidateSet> = HashMap::new();
+        for wl in self.worlds.values() {
+            let s = wl.present();
+            for p in s.pieces().map(|(_, p)| p).chain(s.hand_pieces()) {
+                shared
+                    .entry(p.id)
+                    .and_modify(|c| c.retain(|t| p.candidates.contains(t)))
+                    .or_insert_with(|| p.candidates.clone());
+            }
         }
-        if let Some(tp) = target.board[to.1][to.0].as_ref() {
-            if tp.owner == piece.owner {
-                anyhow::bail!("味方占有");
+        shared.retain(|_, c| !c.is_empty());
+        let turn = self.stats.turns + 1;
+        let mut changed = false;
+        for wl in self.worlds.values_mut() {
+            let Some(s) = wl.history.last_mut() else {
+                continue;
+            };
+            for p in s.pieces().map(|(_, p)| p).chain(s.hand_pieces()) {
+                let Some(c) = shared.get(&p.id) else {
+                    continue;
+                };
+                let removed: CandidateSet = p.candidates.difference(c).copied().collect();
+                if !removed.is_empty() {
+                    notes.push(Deduction {
+                        turn,
+                        world: wl.w,
+                        piece_id: p.id,
+                        removed,
+                        reason: "別の世界線の同じ駒と絡み合い".into(),
+                    });
+                }
             }
+            let ids = s
+                .retain_candidates(|p, t| shared.get(&p.id).is_none_or(|c| c.contains(&t)))
+                .map_err(|error| EngineError::Illegal { w: wl.w, error })?;
+            changed |= !ids.is_empty();
         }
-        let dx = to.0 as i32 - from.0 as i32;
-        let dy = to.1 as i32 - from.1 as i32;
-        let mut out = BTreeSet::new();
-        for c in &piece.candidates {
-            if self.type_can_move(*c, piece.owner, dx, dy, dw, dt, from, src)? {
-                out.insert(*c);
+        Ok(changed)
+    }
+
+    // army は各プレイヤーの駒種ごとの上限 (Game::army_limit)、budgets は両者合わせた総数
+    fn collapse_by_count(
+        s: &mut Snapshot,
+        army: &[(Player, PieceType, usize)],
+        budgets: &[(PieceType, usize)],
+    ) -> Result<(), MoveError> {
+        loop {
+            let mut changed = false;
+            for &(pl, pt, lim) in army {
+                if s.count_candidates(pl, pt) != lim {
+                    continue;
+                }
+                let collapsed = s.retain_candidates(|p, t| {
+                    p.owner != pl || !p.candidates.contains(&pt) || t == pt
+                })?;
+                changed |= !collapsed.is_empty();
+            }
+            // 両者の盤上・持ち駒を合わせた総数 (飛なら2枚)。取られて持ち主が変わった駒も数える
+            for &(pt, budget) in budgets {
+                // 過去の自分と重なって同じ id が2つある駒は1枚と数える
+                let mut holders: BTreeMap<PieceId, bool> = BTreeMap::new();
+                for p in s.pieces().map(|(_, p)| p).chain(s.hand_pieces()) {
+                    if p.candidates.contains(&pt) {
+                        *holders.entry(p.id).or_default() |= p.candidates.len() == 1;
+                    }
+                }
+                let fixed = holders.values().filter(|f| **f).count();
+                let collapsed = if holders.len() == budget {
+                    // 候補に pt を含む駒がちょうど総数だけなら、すべて pt
+                    s.retain_candidates(|p, t| !holders.contains_key(&p.id) || t == pt)?
+                } else if fixed == budget {
+                    // pt と確定した駒が総数に達したら、ほかの駒は pt ではない
+                    s.retain_candidates(|p, t| t != pt || holders.get(&p.id) == Some(&true))?
+                } else {
+                    continue;
+                };
+                changed |= !collapsed.is_empty();
+            }
+            if !changed {
+                return Ok(());
             }
         }
-        Ok(out)
     }
+}
 
-    fn type_can_move(
-        &self,
-        t: PieceType,
-        owner: Player,
-        dx: i32,
-        dy: i32,
-        dw: i32,
-        dt: i32,
-        from: (usize, usize),
-        src: &Snapshot,
-    ) -> anyhow::Result<bool> {
-        if self.settings.past_only && dt > 0 {
-            return Ok(false);
+fn board_squares(s: &Snapshot) -> impl Iterator<Item = Square> + Clone + use<> {
+    let files = s.files();
+    (0..s.ranks()).flat_map(move |rank| (0..files).map(move |file| Square::new(file, rank)))
+}
+
+// 隠れた駒種の割り当て用に、駒 i から増加路を探す。holder[slot] はその駒種を持つ駒の添字
+fn augment_hidden(
+    i: usize,
+    pieces: &[&Piece],
+    types: &[PieceType],
+    holder: &mut [Option<usize>],
+    seen: &mut [bool],
+) -> bool {
+    for slot in 0..types.len() {
+        if seen[slot] || !pieces[i].candidates.contains(&types[slot]) {
+            continue;
         }
-        if matches!(
-            t,
-            PieceType::Pawn | PieceType::Gold | PieceType::Silver | PieceType::King
-        ) && dw.abs() >= 2
-        {
-            return Ok(false);
+        seen[slot] = true;
+        if holder[slot].is_none_or(|k| augment_hidden(k, pieces, types, holder, seen)) {
+            holder[slot] = Some(i);
+            return true;
         }
-        let f = owner.forward_sign();
-        let ok = match t {
-            PieceType::King => dx.abs().max(dy.abs()).max(dw.abs()).max(dt.abs()) == 1,
-            PieceType::Pawn => {
-                (dy == f && dx == 0 && dw == 0 && dt == 0)
-                    || (dw == f && dx == 0 && dy == 0 && dt == 0)
-                    || (dt == -1 && dx == 0 && dy == 0 && dw == 0)
+    }
+    false
+}
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TurnPlan {
+    pub moves: BTreeMap<WorldId, PlannedMove>,
+}
+
+impl TurnPlan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, w: WorldId, mv: PlannedMove) -> Self {
+        self.moves.insert(w, mv);
+        self
+    }
+}
+
+// 1つの世界線の手が指せない理由
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MoveError {
+    GameOver,
+    NoSuchWorld(WorldId),
+    FutureMoveDisabled,
+    TimeJumpTooFar,
+    BaseWithoutBranch,
+    CrossWorldBaseDisabled,
+    UnrelatedBase { base: WorldId, w: WorldId },
+    NoSuchBase(WorldId),
+    HistoryOutOfRange,
+    MaxWorlds,
+    WorldCollision,
+    SummonOnlyOnBranch,
+    SummonUnavailable,
+    SummonOccupied(Square),
+    OutOfBoard,
+    EmptySource,
+    NotYourPiece,
+    OwnPieceOnTarget,
+    PathOutOfRange,
+    NoCandidates,
+    CannotMoveThatWay,
+    PromotionOutsideZone,
+    NoPromotionCandidate,
+    DropOccupied,
+    NoSuchHandPiece,
+    DropForbidden,
+    TeleportDropDisabled,
+    TeleportWithShift,
+    InvalidDropWorld(WorldId),
+    CollapseDisabled,
+    CollapseWithShift,
+    ObserveWithShift,
+    ObserveDisabled,
+    NothingToObserve,
+    AlreadyObserved,
+    NoSacrifice,
+    KingSacrifice,
+    SelfCheck(WorldId),
+    CandidatesExhausted(PieceId),
+    PieceNotFound(PieceId),
+    NotACandidate(PieceId, PieceType),
+    Paradox(PieceId),
+    OtherWorldStaged(WorldId),
+    NotActingWorld(WorldId),
+    PassWithShift,
+    PassTooSoon(usize),
+    ParadoxedPiece(PieceId),
+}
+
+impl std::fmt::Display for MoveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MoveError::GameOver => write!(f, "対局は終了しています"),
+            MoveError::NoSuchWorld(w) => write!(f, "世界線 {w} が存在しない"),
+            MoveError::FutureMoveDisabled => write!(f, "未来移動は無効"),
+            MoveError::TimeJumpTooFar => write!(f, "時間逆行幅が上限超え"),
+            MoveError::BaseWithoutBranch => write!(f, "分岐しない手に起点の世界線は指定できない"),
+            MoveError::CrossWorldBaseDisabled => write!(f, "他の世界線を起点にする分岐は無効"),
+            MoveError::UnrelatedBase { base, w } => {
+                write!(f, "起点 {base} は {w} の祖先でも兄弟でもない")
             }
-            PieceType::Gold => {
-                let steps = [
-                    (0, f, 0, 0),
-                    (1, 0, 0, 0),
-                    (-1, 0, 0, 0),
-                    (0, -f, 0, 0),
-                    (1, f, 0, 0),
-                    (-1, f, 0, 0),
-                    (0, 0, f, 0),
-                    (0, 0, 0, -1),
-                ];
-                steps.contains(&(dx, dy, dw, dt))
+            MoveError::NoSuchBase(b) => write!(f, "起点の世界線 {b} が存在しない"),
+            MoveError::HistoryOutOfRange => write!(f, "履歴範囲外"),
+            MoveError::MaxWorlds => write!(f, "MAX_WORLDS"),
+            MoveError::WorldCollision => write!(f, "world衝突"),
+            MoveError::SummonOnlyOnBranch => write!(f, "召喚は世界線の生成時のみ"),
+            MoveError::SummonUnavailable => write!(f, "召喚できません"),
+            MoveError::SummonOccupied(_) => write!(f, "召喚先が空いていない"),
+            MoveError::OutOfBoard => write!(f, "盤外"),
+            MoveError::EmptySource => write!(f, "移動元空"),
+            MoveError::NotYourPiece => write!(f, "自駒ではない"),
+            MoveError::OwnPieceOnTarget => write!(f, "味方占有"),
+            MoveError::PathOutOfRange => write!(f, "経路範囲外"),
+            MoveError::NoCandidates => write!(f, "候補なし"),
+            MoveError::CannotMoveThatWay => write!(f, "この駒はその動きができない"),
+            MoveError::PromotionOutsideZone => write!(f, "敵陣の外では成れない"),
+            MoveError::NoPromotionCandidate => write!(f, "成り・不成の条件に合う候補なし"),
+            MoveError::DropOccupied => write!(f, "打ち先占有"),
+            MoveError::NoSuchHandPiece => write!(f, "該当する持ち駒なし"),
+            MoveError::DropForbidden => write!(f, "禁則により打てない"),
+            MoveError::TeleportDropDisabled => write!(f, "量子トンネル打ちは無効"),
+            MoveError::TeleportWithShift => write!(f, "量子トンネル打ちは時空移動と併用できない"),
+            MoveError::InvalidDropWorld(w) => write!(f, "打ち先の世界線 {w} が不正"),
+            MoveError::CollapseDisabled => write!(f, "世界線破壊は無効"),
+            MoveError::CollapseWithShift => write!(f, "世界線破壊は時空移動と併用できない"),
+            MoveError::ObserveWithShift => write!(f, "観測は時空移動と併用できない"),
+            MoveError::ObserveDisabled => write!(f, "観測は無効 (COLLAPSE_MODE)"),
+            MoveError::NothingToObserve => write!(f, "観測する駒がない"),
+            MoveError::AlreadyObserved => write!(f, "駒種は確定済み"),
+            MoveError::NoSacrifice => write!(f, "犠牲にする駒がない"),
+            MoveError::KingSacrifice => write!(f, "王は犠牲にできない"),
+            MoveError::SelfCheck(w) => write!(f, "{w} で自玉が取られる形になる"),
+            MoveError::CandidatesExhausted(id) => write!(f, "駒 #{id} の候補がなくなる"),
+            MoveError::PieceNotFound(id) => write!(f, "駒 #{id} はこの局面にない"),
+            MoveError::Paradox(id) => write!(f, "着地先に駒 #{id} の過去の自分がいる"),
+            MoveError::OtherWorldStaged(w) => {
+                write!(f, "このターンは {w} に手を入力済み (1ターン1世界線)")
             }
-            PieceType::Silver => {
-                let steps = [
-                    (0, f, 0, 0),
-                    (1, f, 0, 0),
-                    (-1, f, 0, 0),
-                    (1, -f, 0, 0),
-                    (-1, -f, 0, 0),
-                    (0, 0, f, 0),
-                    (0, 0, 0, -1),
-                ];
-                steps.contains(&(dx, dy, dw, dt))
+            MoveError::NotActingWorld(w) => write!(f, "このターンに指せるのは {w} だけ"),
+            MoveError::PassWithShift => write!(f, "パスは時空移動・召喚と併用できない"),
+            MoveError::PassTooSoon(n) => {
+                write!(f, "同じ世界線でのパスは自分の手番 {n} 回につき1回まで")
             }
-            PieceType::Knight => {
-                let ks = [
-                    (1, 2 * f, 0, 0),
-                    (-1, 2 * f, 0, 0),
-                    (1, 0, 2 * f, 0),
-                    (-1, 0, 2 * f, 0),
-                    (1, 0, 0, -2),
-                    (-1, 0, 0, -2),
-                ];
-                ks.contains(&(dx, dy, dw, dt))
+            MoveError::ParadoxedPiece(id) => {
+                write!(f, "駒 #{id} は過去の世界線で取られているので動かせない")
             }
-            PieceType::Lance => {
-                self.is_linear_clear(from, dx, dy, dw, dt, src)?
-                    && ((dx, dy, dw, dt) != (0, 0, 0, 0))
-                    && ((dx == 0 && dw == 0 && dt == 0 && dy.signum() == f)
-                        || (dx == 0 && dy == 0 && dt == 0 && dw.signum() == f))
+            MoveError::NotACandidate(id, pt) => write!(f, "駒 #{id} は {} になれない", pt.short()),
+        }
+    }
+}
+
+impl std::error::Error for MoveError {}
+
+// 確定前に分かる、入力済みの手の組み合わせの問題 (Game::staging_issues)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StagingIssue {
+    TooManyWorlds {
+        branches: usize,
+        total: usize,
+        max: usize,
+    },
+    TooManyBranches {
+        branches: usize,
+        limit: usize,
+    },
+    ManyWorldsStaged(Vec<WorldId>),
+    NotActingWorld(WorldId),
+    BranchExists {
+        from: Vec<WorldId>,
+        target: WorldId,
+    },
+    BranchesOverlap {
+        from: Vec<WorldId>,
+        target: WorldId,
+    },
+}
+
+impl std::fmt::Display for StagingIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StagingIssue::TooManyWorlds { branches, total, max } => write!(
+                f,
+                "分岐する手が {branches} 件あり、確定すると世界線が {total} 本になる (MAX_WORLDS={max})"
+            ),
+            StagingIssue::TooManyBranches { branches, limit } => {
+                write!(f, "分岐する手が {branches} 件ある (1ターン {limit} 件まで)")
             }
-            PieceType::Rook => {
-                self.is_linear_clear(from, dx, dy, dw, dt, src)?
-                    && [dx == 0, dy == 0, dw == 0, dt == 0]
-                        .into_iter()
-                        .filter(|v| *v)
-                        .count()
-                        == 3
+            StagingIssue::ManyWorldsStaged(staged) => write!(
+                f,
+                "1ターンに指せる世界線は1つだけ ({} に入力がある)",
+                staged.iter().join(", ")
+            ),
+            StagingIssue::NotActingWorld(a) => write!(f, "このターンに指せるのは {a} だけ"),
+            StagingIssue::BranchExists { from, target } => {
+                write!(f, "{} の分岐先 {target} は既に存在する", from.iter().join(", "))
             }
-            PieceType::Bishop => {
-                let non_zero = [dx, dy, dw, dt]
-                    .into_iter()
-                    .filter(|x| *x != 0)
-                    .collect::<Vec<_>>();
-                non_zero.len() >= 2
-                    && non_zero.iter().all(|v| v.abs() == non_zero[0].abs())
-                    && self.is_linear_clear(from, dx, dy, dw, dt, src)?
+            StagingIssue::BranchesOverlap { from, target } => {
+                write!(f, "{} の分岐先が {target} で重なっている", from.iter().join(", "))
             }
-        };
-        Ok(ok)
+        }
     }
+}
 
-    fn is_linear_clear(
-        &self,
-        from: (usize, usize),
-        dx: i32,
-        dy: i32,
-        _dw: i32,
-        _dt: i32,
-        src: &Snapshot,
-    ) -> anyhow::Result<bool> {
-        let steps = dx.abs().max(dy.abs());
-        if steps <= 1 {
-            return Ok(true);
-        }
-        let sx = dx.signum();
-        let sy = dy.signum();
-        for i in 1..steps {
-            let x = from.0 as i32 + sx * i;
-            let y = from.1 as i32 + sy * i;
-            if x < 0 || y < 0 || x >= 9 || y >= 9 {
-                anyhow::bail!("経路範囲外");
+// ターン全体の確定に失敗した理由。世界線ごとの不合法手は Illegal に包む
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EngineError {
+    GameOver,
+    Settings(SettingsError),
+    NotStaged(WorldId),
+    NothingStaged,
+    Staging(StagingIssue),
+    TeleportDropLimit,
+    GlobalHandShortage(PieceType),
+    Illegal { w: WorldId, error: MoveError },
+    InvalidCollapseTarget(WorldId),
+    CollapseKingOnly(WorldId),
+    CollapseLastWorld,
+    MergeUnavailable(WorldId),
+    MergeIncompatible { a: WorldId, b: WorldId },
+    KingLeftInCheck(Vec<String>),
+    NoDrawOffer,
+    UndoAfterResult,
+}
+
+impl std::fmt::Display for EngineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EngineError::GameOver => write!(f, "対局は終了しています"),
+            EngineError::Settings(e) => write!(f, "設定の問題: {e}"),
+            EngineError::NotStaged(w) => write!(f, "世界線 {w} の手が未入力です"),
+            EngineError::NothingStaged => write!(f, "どの世界線にも手が入力されていない"),
+            EngineError::Staging(issue) => write!(f, "{issue}"),
+            EngineError::TeleportDropLimit => write!(f, "量子トンネル打ちは1ターンに1回まで"),
+            EngineError::GlobalHandShortage(pt) => write!(f, "global hand不足: {}", pt.short()),
+            EngineError::Illegal { error, .. } => write!(f, "不合法手: {error}"),
+            EngineError::InvalidCollapseTarget(w) => write!(f, "不合法手: 破壊対象 {w} が不正"),
+            EngineError::CollapseKingOnly(w) => {
+                write!(f, "不合法手: 王だけが残る {w} は破壊できない")
             }
-            if src.board[y as usize][x as usize].is_some() {
-                return Ok(false);
+            EngineError::CollapseLastWorld => write!(f, "不合法手: 最後の世界線は破壊できない"),
+            EngineError::MergeUnavailable(w) => write!(f, "世界線 {w} はまとめられない"),
+            EngineError::MergeIncompatible { a, b } => {
+                write!(f, "{a} と {b} は局面が食い違うためまとめられない")
+            }
+            EngineError::KingLeftInCheck(worlds) => {
+                write!(f, "王が取られうる世界線が残る: {}", worlds.join(" / "))
+            }
+            EngineError::NoDrawOffer => write!(f, "相手から引き分けの提案がない"),
+            EngineError::UndoAfterResult => {
+                write!(
+                    f,
+                    "投了・申し立て・合意・時間切れで終わった対局は取り消せない"
+                )
             }
         }
-        Ok(true)
     }
+}
 
-    pub fn king_candidates(s: &Snapshot, pl: Player) -> Vec<(usize, usize)> {
-        let mut out = Vec::new();
-        for y in 0..9 {
-            for x in 0..9 {
-                if let Some(p) = &s.board[y][x] {
-                    if p.owner == pl && p.candidates.contains(&PieceType::King) {
-                        out.push((x, y));
-                    }
-                }
-            }
+impl std::error::Error for EngineError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EngineError::Settings(e) => Some(e),
+            EngineError::Illegal { error, .. } => Some(error),
+            _ => None,
         }
-        out
     }
+}
 
-    fn collapse_by_count(s: &mut Snapshot) {
-        let limits: Vec<(PieceType, usize)> = vec![
-            (PieceType::King, 1),
-            (PieceType::Rook, 1),
-            (PieceType::Bishop, 1),
-            (PieceType::Gold, 2),
-            (PieceType::Silver, 2),
-            (PieceType::Knight, 2),
-            (PieceType::Lance, 2),
-            (PieceType::Pawn, 9),
-        ];
-        loop {
-            let mut changed = false;
-            for pl in [Player::Black, Player::White] {
-                for (pt, lim) in &limits {
-                    let mut ids = Vec::new();
-                    for row in &s.board {
-                        for p in row.iter().flatten() {
-                            if p.owner == pl && p.candidates.contains(pt) {
-                                ids.push(p.id);
-                            }
-                        }
-                    }
-                    for p in s.hands.get(&pl).into_iter().flatten() {
-                        if p.candidates.contains(pt) {
-                            ids.push(p.id);
-                        }
-                    }
-                    if ids.len() == *lim {
-                        for row in s.board.iter_mut() {
-                            for p in row.iter_mut().flatten() {
-                                if p.owner == pl && ids.contains(&p.id) {
-                                    if !(p.candidates.len() == 1 && p.candidates.contains(pt)) {
-                                        p.candidates.clear();
-                                        p.candidates.insert(*pt);
-                                        changed = true;
-                                    }
-                                }
-                            }
-                        }
-                        for p in s.hands.get_mut(&pl).into_iter().flatten() {
-                            if ids.contains(&p.id) {
-                                if !(p.candidates.len() == 1 && p.candidates.contains(pt)) {
-                                    p.candidates.clear();
-                                    p.candidates.insert(*pt);
-                                    changed = true;
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-            if !changed {
-                break;
+#[derive(Debug)]
+pub struct SimError {
+    pub turn: usize,
+    pub kind: SimErrorKind,
+}
+
+// 手の入力 (stage_move) と確定 (commit_turn) のどちらで止まったか
+#[derive(Debug)]
+pub enum SimErrorKind {
+    Move { w: WorldId, error: MoveError },
+    Engine(EngineError),
+}
+
+impl std::fmt::Display for SimError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            SimErrorKind::Move { w, error } => {
+                write!(f, "{}手目: 世界線 {w}: {error}", self.turn + 1)
             }
+            SimErrorKind::Engine(e) => write!(f, "{}手目: {e}", self.turn + 1),
+        }
+    }
+}
+
+impl std::error::Error for SimError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            SimErrorKind::Move { error, .. } => Some(error),
+            SimErrorKind::Engine(e) => Some(e),
+        }
+    }
+}
+
+pub fn simulate(settings: Settings, turns: &[TurnPlan]) -> Result<Game, SimError> {
+    let mut game = Game::new(settings);
+    for (turn, plan) in turns.iter().enumerate() {
+        for (w, mv) in &plan.moves {
+            game.stage_move(*w, mv.clone()).map_err(|error| SimError {
+                turn,
+                kind: SimErrorKind::Move { w: *w, error },
+            })?;
         }
+        game.commit_turn().map_err(|e| SimError {
+            turn,
+            kind: SimErrorKind::Engine(e),
+        })?;
     }
+    Ok(game)
 }
@@ -0,0 +1,36 @@
+use crate::engine::{Piece, PieceType, Player, Snapshot};
+
+pub fn piece_value(t: PieceType) -> f64 {
+    match t {
+        PieceType::Pawn => 1.0,
+        PieceType::Lance => 3.0,
+        PieceType::Knight => 4.0,
+        PieceType::Silver => 5.0,
+        PieceType::Gold => 6.0,
+        PieceType::Bishop => 8.0,
+        PieceType::Rook => 10.0,
+        PieceType::King => 0.0,
+    }
+}
+
+// 候補が一様に確からしいとみなした期待駒価値
+pub fn expected_value(p: &Piece) -> f64 {
+    if p.candidates().is_empty() {
+        return 0.0;
+    }
+    p.candidates().iter().map(|c| piece_value(*c)).sum::<f64>() / p.candidates().len() as f64
+}
+
+// 先手から見た駒得（正なら先手有利）
+pub fn material_balance(s: &Snapshot) -> f64 {
+    let sign = |pl: Player| if pl == Player::Black { 1.0 } else { -1.0 };
+    let board: f64 = s
+        .pieces()
+        .map(|(_, p)| sign(p.owner()) * expected_value(p))
+        .sum();
+    let hands: f64 = [Player::Black, Player::White]
+        .into_iter()
+        .map(|pl| sign(pl) * s.hand(pl).iter().map(|p| expected_value(p)).sum::<f64>())
+        .sum();
+    board + hands
+}
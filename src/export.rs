@@ -0,0 +1,967 @@
+use std::fmt::Write;
+
+use crate::coords::{Square, TimeIdx, WorldId, RANK_KANJI};
+use crate::engine::{
+    CandidateSet, CaptureAcrossTime, CheckAttackMode, ClockMode, CollapseMode, Game, GameEvent,
+    GameResult, HandMode, Handicap, MoveKind, ParadoxRule, Piece, PieceType, Player, PromotionZone,
+    QuantumModel, RepetitionRule, Snapshot, StartPosition, TurnMode, TurnRecord, Variant,
+    WinCondition, WorldIdPolicy,
+};
+
+#[derive(Clone, Debug, Default)]
+pub struct KifuSheetInfo {
+    pub black_name: String,
+    pub white_name: String,
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn board_table(out: &mut String, s: &Snapshot) {
+    out.push_str("<table class=\"board\"><tr><th></th>");
+    for file in 0..s.files() {
+        let _ = write!(out, "<th>{}</th>", s.files() - file);
+    }
+    out.push_str("</tr>");
+    for (rank, kanji) in RANK_KANJI.iter().take(s.ranks()).enumerate() {
+        let _ = write!(out, "<tr><th>{kanji}</th>");
+        for file in 0..s.files() {
+            let cell = s
+                .piece_at(Square::new(file, rank))
+                .map(|p| p.display_text())
+                .unwrap_or_default();
+            let _ = write!(out, "<td>{}</td>", escape_html(&cell));
+        }
+        out.push_str("</tr>");
+    }
+    out.push_str("</table>");
+}
+
+// 1局面の図。GUI を起動せずに資料や投稿用の画像を作るのに使う
+pub fn board_svg(s: &Snapshot, title: &str) -> String {
+    const CELL: usize = 44;
+    const LEFT: usize = 28;
+    const TOP: usize = 76;
+    let (files, ranks) = (s.files(), s.ranks());
+    let width = LEFT * 2 + CELL * files;
+    let height = TOP + CELL * ranks + 56;
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" \
+         font-family=\"sans-serif\" text-anchor=\"middle\">\
+         <rect width=\"100%\" height=\"100%\" fill=\"#fff\"/>\
+         <text x=\"{}\" y=\"22\" font-size=\"16\">{}</text>\
+         <text x=\"{}\" y=\"46\" font-size=\"13\">{}: {}</text>",
+        width / 2,
+        escape_html(title),
+        width / 2,
+        Player::White.label(),
+        escape_html(&hand_line(s, Player::White)),
+    );
+    for file in 0..files {
+        let _ = write!(
+            out,
+            "<text x=\"{}\" y=\"{}\" font-size=\"12\">{}</text>",
+            LEFT + file * CELL + CELL / 2,
+            TOP - 6,
+            files - file
+        );
+    }
+    for (rank, kanji) in RANK_KANJI.iter().take(ranks).enumerate() {
+        let y = TOP + rank * CELL;
+        let _ = write!(
+            out,
+            "<text x=\"{}\" y=\"{}\" font-size=\"12\">{kanji}</text>",
+            LEFT + CELL * files + 14,
+            y + CELL / 2 + 4
+        );
+        for file in 0..files {
+            let x = LEFT + file * CELL;
+            let _ = write!(
+                out,
+                "<rect x=\"{x}\" y=\"{y}\" width=\"{CELL}\" height=\"{CELL}\" \
+                 fill=\"#f3d9a4\" stroke=\"#333\"/>"
+            );
+            if let Some(p) = s.piece_at(Square::new(file, rank)) {
+                let color = match p.owner {
+                    Player::Black => "#000",
+                    Player::White => "#b00",
+                };
+                let _ = write!(
+                    out,
+                    "<text x=\"{}\" y=\"{}\" font-size=\"14\" fill=\"{color}\">{}</text>",
+                    x + CELL / 2,
+                    y + CELL / 2 + 5,
+                    escape_html(&p.display_text())
+                );
+            }
+        }
+    }
+    let _ = write!(
+        out,
+        "<text x=\"{}\" y=\"{}\" font-size=\"13\">{}: {}</text></svg>",
+        width / 2,
+        TOP + CELL * ranks + 30,
+        Player::Black.label(),
+        escape_html(&hand_line(s, Player::Black)),
+    );
+    out
+}
+
+fn hand_line(s: &Snapshot, player: Player) -> String {
+    let groups = s.hand_groups(player);
+    if groups.is_empty() {
+        return "なし".into();
+    }
+    groups
+        .iter()
+        .map(|(g, ids)| {
+            let cands: String = g.iter().map(|c| c.short()).collect();
+            format!("{cands}×{}", ids.len())
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn kifu_html(game: &Game, info: &KifuSheetInfo) -> String {
+    let settings = game.settings();
+    let mut out = String::new();
+    out.push_str(
+        "<!DOCTYPE html>\n<html lang=\"ja\"><head><meta charset=\"utf-8\">\
+         <title>量子時空将棋 棋譜</title><style>\
+         body{font-family:serif;margin:2em}\
+         table.board{border-collapse:collapse;margin:0.5em 0}\
+         table.board td{border:1px solid #000;width:2.6em;height:2em;text-align:center}\
+         .world{display:inline-block;vertical-align:top;margin:0 1.5em 1.5em 0;page-break-inside:avoid}\
+         @media print{body{margin:0}}\
+         </style></head><body>\n",
+    );
+    out.push_str("<h1>量子時空将棋 棋譜</h1>\n");
+    let _ = writeln!(
+        out,
+        "<p>{} {} ／ {} {}</p>",
+        Player::Black.mark(),
+        escape_html(&info.black_name),
+        Player::White.mark(),
+        escape_html(&info.white_name)
+    );
+    let _ = writeln!(
+        out,
+        "<p>設定: MAX_WORLDS={} MAX_TIME_JUMP={} HAND_MODE={} CHECK_ATTACK_MODE={} past_only={}</p>",
+        settings.max_worlds,
+        settings.max_time_jump,
+        match settings.hand_mode {
+            HandMode::PerWorld => "per_world",
+            HandMode::Global => "global",
+        },
+        match settings.check_attack_mode {
+            CheckAttackMode::Possible => "possible",
+            CheckAttackMode::Certain => "certain",
+        },
+        settings.past_only
+    );
+    let lost: Vec<String> = game
+        .worlds()
+        .values()
+        .filter(|wl| wl.is_lost())
+        .map(|wl| wl.w().to_string())
+        .collect();
+    let _ = writeln!(
+        out,
+        "<p>結果: {}</p>",
+        if game.status().is_over() {
+            game.status().label()
+        } else if lost.is_empty() {
+            format!("対局中（{}番）", game.turn().label())
+        } else {
+            format!("王喪失: {}", lost.join(", "))
+        }
+    );
+
+    out.push_str("<h2>指し手</h2>\n<ol>\n");
+    for turn in game.record() {
+        let moves: Vec<String> = turn
+            .moves
+            .iter()
+            .map(|(w, pm)| format!("{w}: {}", pm.notation(game.files())))
+            .collect();
+        let _ = writeln!(
+            out,
+            "<li>{}{}</li>",
+            turn.player.mark(),
+            escape_html(&moves.join(" ／ "))
+        );
+    }
+    out.push_str("</ol>\n<h2>実況</h2>\n<ol>\n");
+    for line in narration(game) {
+        let _ = writeln!(out, "<li>{}</li>", escape_html(&line));
+    }
+    out.push_str("</ol>\n<h2>最終局面</h2>\n");
+
+    let active = game.worlds().values().map(|wl| (wl, ""));
+    let archived = game.archived().values().map(|wl| (wl, " (凍結)"));
+    for (wl, note) in active.chain(archived) {
+        let s = wl.present();
+        let _ = write!(
+            out,
+            "<div class=\"world\"><h3>{} {}{}{note}</h3>",
+            wl.w(),
+            wl.present_t(),
+            if wl.is_lost() { " (王喪失)" } else { "" }
+        );
+        let _ = write!(
+            out,
+            "<p>{}持駒: {}</p>",
+            Player::White.mark(),
+            hand_line(s, Player::White)
+        );
+        board_table(&mut out, s);
+        let _ = writeln!(
+            out,
+            "<p>{}持駒: {}</p></div>",
+            Player::Black.mark(),
+            hand_line(s, Player::Black)
+        );
+    }
+    out.push_str("</body></html>\n");
+    out
+}
+
+// 既知の駒は CSA 風に "+FU"、未確定の駒は "+?5" (候補数) で表す
+fn ascii_code(cands: &CandidateSet, promoted: bool) -> String {
+    match cands.iter().next() {
+        Some(pt) if cands.len() == 1 => pt.csa(promoted).to_string(),
+        _ => format!("?{}", cands.len()),
+    }
+}
+
+fn ascii_cell(p: &Piece) -> String {
+    let sign = match p.owner() {
+        Player::Black => '+',
+        Player::White => '-',
+    };
+    format!("{sign}{}", ascii_code(p.candidates(), p.is_promoted()))
+}
+
+fn ascii_hand(s: &Snapshot, player: Player) -> String {
+    let groups = s.hand_groups(player);
+    if groups.is_empty() {
+        return "-".into();
+    }
+    groups
+        .iter()
+        .map(|(g, ids)| format!("{}x{}", ascii_code(g, false), ids.len()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl Snapshot {
+    pub fn to_ascii(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "hand-: {}", ascii_hand(self, Player::White));
+        out.push(' ');
+        for file in 0..self.files() {
+            let _ = write!(out, "  {}", self.files() - file);
+        }
+        out.push('\n');
+        for rank in 0..self.ranks() {
+            out.push(' ');
+            for file in 0..self.files() {
+                let cell = self
+                    .piece_at(Square::new(file, rank))
+                    .map(ascii_cell)
+                    .unwrap_or_else(|| " * ".into());
+                out.push_str(&cell);
+            }
+            let _ = writeln!(out, " {}", rank + 1);
+        }
+        let _ = writeln!(out, "hand+: {}", ascii_hand(self, Player::Black));
+        out
+    }
+}
+
+impl Game {
+    pub fn to_ascii(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "turn {} {}",
+            self.stats().turns + 1,
+            match self.turn() {
+                Player::Black => '+',
+                Player::White => '-',
+            }
+        );
+        for wl in self.worlds().values() {
+            let _ = writeln!(
+                out,
+                "{} {}{}",
+                wl.w(),
+                wl.present_t(),
+                if wl.is_lost() { " lost" } else { "" }
+            );
+            out.push_str(&wl.present().to_ascii());
+        }
+        out
+    }
+}
+
+enum Json {
+    Null,
+    Bool(bool),
+    Num(i128),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(&'static str, Json)>),
+}
+
+impl Json {
+    fn str(s: impl ToString) -> Self {
+        Self::Str(s.to_string())
+    }
+
+    fn write(&self, out: &mut String, indent: usize) {
+        let pad = |out: &mut String, n: usize| out.push_str(&"  ".repeat(n));
+        match self {
+            Self::Null => out.push_str("null"),
+            Self::Bool(b) => {
+                let _ = write!(out, "{b}");
+            }
+            Self::Num(n) => {
+                let _ = write!(out, "{n}");
+            }
+            Self::Str(s) => {
+                out.push('"');
+                for c in s.chars() {
+                    match c {
+                        '"' => out.push_str("\\\""),
+                        '\\' => out.push_str("\\\\"),
+                        '\n' => out.push_str("\\n"),
+                        c if (c as u32) < 0x20 => {
+                            let _ = write!(out, "\\u{:04x}", c as u32);
+                        }
+                        c => out.push(c),
+                    }
+                }
+                out.push('"');
+            }
+            Self::Arr(items) if items.is_empty() => out.push_str("[]"),
+            Self::Arr(items) => {
+                out.push_str("[\n");
+                for (i, v) in items.iter().enumerate() {
+                    pad(out, indent + 1);
+                    v.write(out, indent + 1);
+                    out.push_str(if i + 1 < items.len() { ",\n" } else { "\n" });
+                }
+                pad(out, indent);
+                out.push(']');
+            }
+            Self::Obj(fields) if fields.is_empty() => out.push_str("{}"),
+            Self::Obj(fields) => {
+                out.push_str("{\n");
+                for (i, (k, v)) in fields.iter().enumerate() {
+                    pad(out, indent + 1);
+                    let _ = write!(out, "\"{k}\": ");
+                    v.write(out, indent + 1);
+                    out.push_str(if i + 1 < fields.len() { ",\n" } else { "\n" });
+                }
+                pad(out, indent);
+                out.push('}');
+            }
+        }
+    }
+}
+
+fn player_json(p: Player) -> Json {
+    Json::str(match p {
+        Player::Black => "black",
+        Player::White => "white",
+    })
+}
+
+fn candidates_json(cands: &CandidateSet) -> Json {
+    Json::Arr(cands.iter().map(|c| Json::str(format!("{c:?}"))).collect())
+}
+
+// files は盤の筋数 (square の表記に使う)
+fn piece_json(p: &Piece, sq: Option<Square>, files: usize) -> Json {
+    let mut fields = Vec::new();
+    if let Some(sq) = sq {
+        fields.push(("square", Json::str(sq.notation(files))));
+        fields.push(("file", Json::Num(sq.file as i128)));
+        fields.push(("rank", Json::Num(sq.rank as i128)));
+    }
+    fields.extend([
+        ("id", Json::Num(p.id() as i128)),
+        ("owner", player_json(p.owner())),
+        ("promoted", Json::Bool(p.is_promoted())),
+        ("candidates", candidates_json(p.candidates())),
+    ]);
+    if let Some((w, turn)) = p.captured_at() {
+        fields.push((
+            "captured_at",
+            Json::Obj(vec![
+                ("w", Json::Num(w.0 as i128)),
+                ("turn", Json::Num(turn as i128)),
+            ]),
+        ));
+    }
+    Json::Obj(fields)
+}
+
+impl Game {
+    pub fn debug_json(&self) -> String {
+        let settings = self.settings();
+        let worlds = self
+            .worlds()
+            .values()
+            .map(|wl| {
+                let s = wl.present();
+                let hands = [Player::Black, Player::White].map(|pl| {
+                    Json::Arr(
+                        s.hand(pl)
+                            .iter()
+                            .map(|p| piece_json(p, None, s.files()))
+                            .collect(),
+                    )
+                });
+                let [black, white] = hands;
+                Json::Obj(vec![
+                    ("w", Json::Num(wl.w().0 as i128)),
+                    ("present_t", Json::Num(wl.present_t().0 as i128)),
+                    ("lost", Json::Bool(wl.is_lost())),
+                    ("winner", wl.winner().map_or(Json::Null, player_json)),
+                    ("drawn", Json::Bool(wl.is_drawn())),
+                    (
+                        "parent",
+                        wl.parent().map_or(Json::Null, |p| Json::Num(p.0 as i128)),
+                    ),
+                    (
+                        "staged",
+                        wl.staged()
+                            .map(|pm| Json::str(pm.notation(s.files())))
+                            .unwrap_or(Json::Null),
+                    ),
+                    (
+                        "board",
+                        Json::Arr(
+                            s.pieces()
+                                .map(|(sq, p)| piece_json(p, Some(sq), s.files()))
+                                .collect(),
+                        ),
+                    ),
+                    ("hands", Json::Obj(vec![("black", black), ("white", white)])),
+                ])
+            })
+            .collect();
+        let root = Json::Obj(vec![
+            ("turn", Json::Num(self.stats().turns as i128 + 1)),
+            ("player", player_json(self.turn())),
+            ("seed", Json::Num(self.seed() as i128)),
+            ("rng", Json::Num(self.rng_state() as i128)),
+            (
+                "result",
+                match self.result() {
+                    None => Json::Null,
+                    Some(GameResult::Win(p)) => player_json(p),
+                    Some(GameResult::Draw) => Json::str("draw"),
+                },
+            ),
+            ("status", Json::str(format!("{:?}", self.status()))),
+            (
+                "draw_offer",
+                self.draw_offer().map_or(Json::Null, player_json),
+            ),
+            (
+                "clock",
+                self.clock().map_or(Json::Null, |c| {
+                    Json::Obj(vec![
+                        ("black", Json::str(c.label(Player::Black))),
+                        ("white", Json::str(c.label(Player::White))),
+                    ])
+                }),
+            ),
+            (
+                "settings",
+                Json::Obj(vec![
+                    ("max_worlds", Json::Num(settings.max_worlds as i128)),
+                    ("max_time_jump", Json::Num(settings.max_time_jump as i128)),
+                    ("bishop_axes", Json::Num(settings.bishop_axes as i128)),
+                    ("hand_mode", Json::str(format!("{:?}", settings.hand_mode))),
+                    (
+                        "check_attack_mode",
+                        Json::str(format!("{:?}", settings.check_attack_mode)),
+                    ),
+                    ("past_only", Json::Bool(settings.past_only)),
+                    ("summon_variant", Json::Bool(settings.summon_variant)),
+                    ("world_destruction", Json::Bool(settings.world_destruction)),
+                    ("teleport_drop", Json::Bool(settings.teleport_drop)),
+                    ("cross_world_base", Json::Bool(settings.cross_world_base)),
+                    ("forbid_self_check", Json::Bool(settings.forbid_self_check)),
+                    ("entering_king", Json::Bool(settings.entering_king)),
+                    ("arbiter_mode", Json::Bool(settings.arbiter_mode)),
+                    (
+                        "time_control",
+                        settings
+                            .time_control
+                            .map_or(Json::Null, |tc| Json::str(format!("{tc:?}"))),
+                    ),
+                    (
+                        "strict_commit_check",
+                        Json::Bool(settings.strict_commit_check),
+                    ),
+                    ("entangle_worlds", Json::Bool(settings.entangle_worlds)),
+                    ("auto_merge_worlds", Json::Bool(settings.auto_merge_worlds)),
+                    ("prune_lost_worlds", Json::Bool(settings.prune_lost_worlds)),
+                    (
+                        "pass_interval",
+                        settings
+                            .pass_interval
+                            .map_or(Json::Null, |n| Json::Num(n as i128)),
+                    ),
+                    (
+                        "max_branches_per_turn",
+                        settings
+                            .max_branches_per_turn
+                            .map_or(Json::Null, |n| Json::Num(n as i128)),
+                    ),
+                    (
+                        "quantum_model",
+                        Json::str(format!("{:?}", settings.quantum_model)),
+                    ),
+                    ("variant", Json::str(format!("{:?}", settings.variant))),
+                    (
+                        "start_position",
+                        Json::str(format!("{:?}", settings.start_position)),
+                    ),
+                    ("handicap", Json::str(format!("{:?}", settings.handicap))),
+                    (
+                        "promotion_zone",
+                        Json::str(format!("{:?}", settings.promotion_zone)),
+                    ),
+                    (
+                        "win_condition",
+                        Json::str(format!("{:?}", settings.win_condition)),
+                    ),
+                    (
+                        "repetition_rule",
+                        Json::str(format!("{:?}", settings.repetition_rule)),
+                    ),
+                    (
+                        "collapse_mode",
+                        Json::str(format!("{:?}", settings.collapse_mode)),
+                    ),
+                    (
+                        "paradox_rule",
+                        Json::str(format!("{:?}", settings.paradox_rule)),
+                    ),
+                    ("turn_mode", Json::str(format!("{:?}", settings.turn_mode))),
+                    (
+                        "capture_across_time",
+                        Json::str(format!("{:?}", settings.capture_across_time)),
+                    ),
+                    (
+                        "world_id_policy",
+                        Json::str(format!("{:?}", settings.world_id_policy)),
+                    ),
+                    (
+                        "archive_idle_turns",
+                        settings
+                            .archive_idle_turns
+                            .map_or(Json::Null, |n| Json::Num(n as i128)),
+                    ),
+                ]),
+            ),
+            ("worlds", Json::Arr(worlds)),
+            (
+                "archived",
+                Json::Arr(
+                    self.archived()
+                        .keys()
+                        .map(|w| Json::Num(w.0 as i128))
+                        .collect(),
+                ),
+            ),
+        ]);
+        let mut out = String::new();
+        root.write(&mut out, 0);
+        out.push('\n');
+        out
+    }
+}
+
+// 観戦者向けに、1ターンの同時着手と起きた出来事を文章にする
+// files は盤の筋数 (Game::files)
+pub fn narrate_turn(rec: &TurnRecord, files: usize) -> String {
+    let created: Vec<(WorldId, WorldId, WorldId, TimeIdx)> = rec
+        .events
+        .iter()
+        .filter_map(|e| match e {
+            GameEvent::WorldCreated {
+                w,
+                parent,
+                from,
+                t_base,
+            } => Some((*w, *parent, *from, *t_base)),
+            _ => None,
+        })
+        .collect();
+    let group_name = |g: &CandidateSet| match g.iter().next() {
+        Some(t) if g.len() == 1 => t.short().to_string(),
+        _ => "持ち駒".to_string(),
+    };
+    let parts: Vec<String> = rec
+        .moves
+        .iter()
+        .map(|(w, pm)| {
+            let what = match &pm.kind {
+                MoveKind::Move { from, to, promote } => {
+                    format!(
+                        "{from}→{to}{}",
+                        if *promote { "成" } else { "" },
+                        from = from.notation(files),
+                        to = to.notation(files)
+                    )
+                }
+                MoveKind::Drop { group, to } => {
+                    format!("{to}に{}を打ち", group_name(group), to = to.notation(files))
+                }
+                MoveKind::TeleportDrop { group, to, dest } => {
+                    return format!(
+                        "{w} から {dest} の{to}へ{}をトンネル打ち",
+                        group_name(group),
+                        to = to.notation(files)
+                    );
+                }
+                MoveKind::CollapseWorld { target, sacrifice } => {
+                    return format!(
+                        "{w} で{sacrifice}の駒を犠牲に {target} の破壊を試み",
+                        sacrifice = sacrifice.notation(files)
+                    );
+                }
+                MoveKind::Observe { at } => {
+                    return format!("{w} で{at}の駒を観測", at = at.notation(files))
+                }
+                MoveKind::Pass => return format!("{w} ではパス"),
+            };
+            let branch = created.iter().find(|(_, parent, _, _)| parent == w);
+            match branch {
+                Some((new, _, from, t_base)) if pm.delta_t < 0 => format!(
+                    "{w} から {what} で{}手前の {from}{t_base} へ駒を送り {new} を創出",
+                    -pm.delta_t
+                ),
+                Some((new, _, from, _)) => {
+                    format!("{w} から {what} で {from} を起点に {new} を創出")
+                }
+                None => format!("{w} で {what}"),
+            }
+        })
+        .collect();
+    let mut out = format!("{}は {}。", rec.player.label(), parts.join("、同時に "));
+    for e in &rec.events {
+        let line = match e {
+            GameEvent::WorldCreated { .. } | GameEvent::TurnCommitted { .. } => continue,
+            GameEvent::WorldLost { w } => format!("{w} では王が失われた。"),
+            GameEvent::WorldArchived { w } => format!("{w} は動きがなく凍結された。"),
+            GameEvent::WorldPruned { w } => format!("{w} は王を失ったため盤から外された。"),
+            GameEvent::WorldDestroyed { w, by } => {
+                format!("{w} は{}によって消滅した。", by.label())
+            }
+            GameEvent::WorldDecided { w, winner } => {
+                format!("{w} は{}の勝ちで決着。", winner.label())
+            }
+            GameEvent::WorldsMerged { kept, removed } => {
+                format!("{removed} は {kept} と同じ局面になり、ひとつにまとまった。")
+            }
+            GameEvent::Impasse { w, winner } => match winner {
+                Some(p) => format!("{w} は両者が入玉し、点数で{}が上回った。", p.label()),
+                None => format!("{w} は両者が入玉し、持将棋になった。"),
+            },
+            GameEvent::Repetition { w: Some(w) } => format!("{w} で同じ局面が4回現れた。"),
+            GameEvent::Repetition { w: None } => {
+                "全世界線を合わせて同じ局面が4回現れた。".to_string()
+            }
+            GameEvent::Claimed { by, claim } => {
+                format!("{}が{}を申し立て、認められた。", by.label(), claim.label())
+            }
+            GameEvent::Flagged { player } => format!("{}が時間切れになった。", player.label()),
+            GameEvent::Resigned { by } => format!("{}が投了した。", by.label()),
+            GameEvent::DrawOffered { by } => format!("{}が引き分けを提案した。", by.label()),
+            GameEvent::DrawAccepted { by } => format!("{}が引き分けに応じた。", by.label()),
+            GameEvent::GameOver { result } => match result {
+                GameResult::Win(p) => format!("対局は{}の勝ちで終わった。", p.label()),
+                GameResult::Draw => "対局は引き分けで終わった。".to_string(),
+            },
+        };
+        out.push_str(&line);
+    }
+    out
+}
+
+pub fn narration(game: &Game) -> Vec<String> {
+    game.record()
+        .iter()
+        .enumerate()
+        .map(|(i, rec)| format!("{}手目: {}", i + 1, narrate_turn(rec, game.files())))
+        .collect()
+}
+
+fn offset_label(d: [i32; 4], slide: bool) -> String {
+    let axis = |v: i32| {
+        if v == 0 {
+            "0".to_string()
+        } else {
+            format!("{v:+}")
+        }
+    };
+    let dots = if slide { "…" } else { "" };
+    format!(
+        "({},{},{},{}){dots}",
+        axis(d[0]),
+        axis(d[1]),
+        axis(d[2]),
+        axis(d[3])
+    )
+}
+
+// 駒の動きを、一歩ずつの変位と走り (… 付き) に分けて並べる
+fn piece_moves(game: &Game, t: PieceType, promoted: bool) -> String {
+    let offsets = game.move_offsets(t, promoted);
+    let unit = |d: &[i32; 4]| d.iter().all(|v| v % 2 == 0) && offsets.contains(&d.map(|v| v / 2));
+    let mut parts: Vec<String> = offsets
+        .iter()
+        .filter(|d| !unit(d))
+        .map(|d| offset_label(*d, offsets.contains(&d.map(|v| v * 2))))
+        .collect();
+    if parts.is_empty() {
+        parts.push("なし".to_string());
+    }
+    parts.join(" ")
+}
+
+// ルール早見表。動きの表と現在の設定から組み立てるので、変則ルールを選ぶとその内容が反映される
+pub fn rules_reference(game: &Game) -> Vec<(&'static str, Vec<String>)> {
+    let st = game.settings();
+    let mut moves =
+        vec!["変位は (横, 前, 前方の世界線, 時間) で、… はその方向に走れることを表す".to_string()];
+    for t in PieceType::all() {
+        moves.push(format!("{}: {}", t.short(), piece_moves(game, t, false)));
+        if !matches!(t, PieceType::Gold | PieceType::King) {
+            moves.push(format!("{}(成): {}", t.short(), piece_moves(game, t, true)));
+        }
+    }
+    moves.push(format!(
+        "角の斜め: 動く軸はどれも同じ距離で、同時に動く軸は2〜{}本",
+        st.bishop_axes
+    ));
+
+    let mut drops = vec![
+        "二歩・行き所のない駒・打ち歩詰めは禁止".to_string(),
+        format!(
+            "持ち駒: {}",
+            match st.hand_mode {
+                HandMode::PerWorld => "世界線ごと",
+                HandMode::Global => "全世界線で共有",
+            }
+        ),
+    ];
+    if st.teleport_drop {
+        drops.push(
+            "量子トンネル打ち: 共有の持ち駒を別の世界線の現在へ打てる (1ターン1回)".to_string(),
+        );
+    }
+
+    let mut branching = vec![
+        format!(
+            "1ターンに指す世界線: {}",
+            match st.turn_mode {
+                TurnMode::Simultaneous => "全世界線で1手ずつ同時に確定",
+                TurnMode::Sequential => "好きな世界線を1つ",
+                TurnMode::RoundRobin => "世界線番号順に1つずつ (先手・後手で1巡)",
+            }
+        ),
+        format!("世界線の上限: {}", st.max_worlds),
+        format!("時間跳躍の上限: {}", st.max_time_jump),
+        if st.past_only {
+            "時間方向は過去へのみ移動できる".to_string()
+        } else {
+            "時間方向は未来へも移動できる".to_string()
+        },
+        match st.max_branches_per_turn {
+            Some(n) => format!("1ターンの分岐: {n} 手まで"),
+            None => "1ターンの分岐: 制限なし".to_string(),
+        },
+    ];
+    branching.push(format!(
+        "過去の自分と重なった駒: {}",
+        match st.paradox_rule {
+            ParadoxRule::RemovePastSelf => "過去の自分が消える",
+            ParadoxRule::MergeCandidates => "両方残り、候補を共通部分にそろえる",
+            ParadoxRule::Forbid => "そのような手は指せない",
+        }
+    ));
+    branching.push(format!(
+        "過去での捕獲: {}",
+        match st.capture_across_time {
+            CaptureAcrossTime::Local => "取った世界線だけの出来事 (分岐元の現在には残る)",
+            CaptureAcrossTime::RemoveFromSource => "分岐元の現在の盤上からも消える",
+            CaptureAcrossTime::Paradoxed => "分岐元の現在に残るが動かせなくなる (※)",
+        }
+    ));
+    branching.push(format!(
+        "分岐先の番号: {}",
+        match st.world_id_policy {
+            WorldIdPolicy::Exact => "w+Δw ちょうど (使われていれば分岐できない)",
+            WorldIdPolicy::NearestFree => "w+Δw に最も近い空き番号",
+            WorldIdPolicy::SignedDirection => "w+Δw から Δw の向きに最初の空き番号",
+            WorldIdPolicy::Tree => "親 w の子として 3w±1 から空き番号",
+        }
+    ));
+    branching.push(match st.pass_interval {
+        Some(n) => format!("パス: 同じ世界線では自分の手番 {n} 回につき1回まで"),
+        None => "パス: 何もしない世界線を選べる (制限なし)".to_string(),
+    });
+    if st.cross_world_base {
+        branching.push("分岐の起点に祖先・兄弟の世界線を選べる".to_string());
+    }
+    if st.summon_variant {
+        branching.push("分岐時の召喚: 各プレイヤー1局1回".to_string());
+    }
+    if st.world_destruction {
+        branching.push("世界線の消滅: 自駒を犠牲に他の世界線を潰せる".to_string());
+    }
+    if st.auto_merge_worlds {
+        branching.push("局面が食い違わない世界線はターンの終わりにまとめられる".to_string());
+    }
+    if st.prune_lost_worlds {
+        branching.push("王を失った世界線は凍結され、上限に数えない".to_string());
+    }
+    if let Some(n) = st.archive_idle_turns {
+        branching.push(format!("{n} ターン動きのない世界線は凍結される"));
+    }
+
+    let mut victory = vec![
+        format!(
+            "勝利条件: {}",
+            match st.win_condition {
+                WinCondition::AllWorlds => "全世界線で勝つ",
+                WinCondition::Majority => "過半数の世界線で勝つ",
+                WinCondition::AnyWorld => "どれか1つの世界線で勝つ",
+            }
+        ),
+        format!(
+            "王手の判定: {}",
+            match st.check_attack_mode {
+                CheckAttackMode::Possible => "候補のどれかで取れれば王手",
+                CheckAttackMode::Certain => "駒種が確定した駒だけが王手をかける",
+            }
+        ),
+        format!(
+            "成り: {}",
+            match st.promotion_zone {
+                PromotionZone::PerWorld => "移動元か移動先が敵陣",
+                PromotionZone::PerTimeLayer => "時空移動は到着した時間層の位置で判定",
+            }
+        ),
+        format!(
+            "盤: {}",
+            match st.variant {
+                Variant::Standard => "本将棋 (9×9、敵陣3段)",
+                Variant::Minishogi => "5五将棋 (5×5、敵陣1段、王飛角金銀歩が1枚ずつ)",
+            }
+        ),
+        format!(
+            "開始局面: {}",
+            match (st.start_position, st.variant) {
+                (StartPosition::FullQuantum, Variant::Standard) => {
+                    "自陣3段すべてに、どの駒種にもなりうる駒"
+                }
+                (StartPosition::FullQuantum, _) => "初形の升に、どの駒種にもなりうる駒",
+                (StartPosition::Classical, _) => "初形 (駒種は確定)",
+                (StartPosition::RowConstrained, Variant::Standard) => {
+                    "本将棋の初形の升に、1段目は香桂銀金王・2段目は飛角・3段目は歩の候補"
+                }
+                (StartPosition::RowConstrained, _) => {
+                    "初形の升に、1段目は王金銀角飛・2段目は歩の候補"
+                }
+            }
+        ),
+        match st.handicap {
+            Handicap::None => "手合: 平手 (先手から指す)".to_string(),
+            h => format!("手合: {} (上手は後手の側に並び、先に指す)", h.label()),
+        },
+        format!(
+            "駒種: {}",
+            match st.quantum_model {
+                QuantumModel::Lazy => "観測で候補が絞られた時に決まる",
+                QuantumModel::Hidden => "開始時に決まっていて観測で明かされる",
+            }
+        ),
+        format!(
+            "乱数による確定: {}",
+            match st.collapse_mode {
+                CollapseMode::CountOnly => "なし (観測の手も指せない)",
+                CollapseMode::RandomOnObserve => "観測の手",
+                CollapseMode::RandomOnCapture => "観測の手と捕獲",
+            }
+        ),
+    ];
+    if st.entering_king && st.variant == Variant::Standard {
+        victory.push(
+            "両者の王が敵陣に入った世界線は27点法で判定 (飛角5点・他1点、決まっていない駒は候補の平均)。\
+             敵陣に10枚以上・王手なし・先手28点/後手27点以上の側の勝ち、どちらでもなければ持将棋"
+                .to_string(),
+        );
+    }
+    if st.forbid_self_check {
+        victory.push("自玉が取られる形になる手は指せない".to_string());
+    }
+    if st.strict_commit_check {
+        victory.push("確定後に自玉が取られうる世界線が残る入力は確定できない".to_string());
+    }
+    if let Some(tc) = st.time_control {
+        let after = match tc.mode {
+            ClockMode::Byoyomi {
+                secs: _,
+                periods: 0,
+            } => "使い切ると負け".to_string(),
+            ClockMode::Byoyomi { secs, periods } => {
+                format!("使い切った後は1手 {secs} 秒の秒読みを {periods} 回まで")
+            }
+            ClockMode::Fischer { increment_secs } => {
+                format!("1手ごとに {increment_secs} 秒加算、使い切ると負け")
+            }
+        };
+        victory.push(format!(
+            "持ち時間 {} 秒。{after}。時間切れの側は負け",
+            tc.main_secs
+        ));
+    }
+    victory.push(
+        match st.repetition_rule {
+            RepetitionRule::Draw => {
+                "どれかの世界線か全世界線を合わせた状態で同じ局面が4回現れたら千日手 (引き分け)"
+            }
+            RepetitionRule::PerpetualCheckLoses => {
+                "どれかの世界線か全世界線を合わせた状態で同じ局面が4回現れたら千日手 (引き分け)。\
+                 その間ずっと王手をかけ続けた側は負け"
+            }
+        }
+        .to_string(),
+    );
+    if st.arbiter_mode {
+        victory.push("裁定モード: 王手放置の手も確定し、相手が反則を申告すると負け".to_string());
+    }
+
+    vec![
+        ("駒の動き", moves),
+        ("打つ手", drops),
+        ("分岐", branching),
+        ("勝敗", victory),
+    ]
+}
@@ -0,0 +1,581 @@
+use std::collections::BTreeMap;
+
+use crate::coords::{Square, WorldId};
+use crate::engine::{
+    Claim, Game, GameEvent, GameResult, MoveError, MoveKind, Piece, PieceType, PlannedMove, Player,
+};
+
+// フロントエンドからエンジンへの操作
+#[derive(Clone, Debug)]
+pub enum Command {
+    Stage { w: WorldId, mv: PlannedMove },
+    ClearStaged,
+    Commit,
+    Undo,
+    Redo,
+    Merge { a: WorldId, b: WorldId },
+    Claim { by: Player, claim: Claim },
+    Resign { by: Player },
+    OfferDraw { by: Player },
+    AcceptDraw { by: Player },
+}
+
+// 描画と入力は各フロントエンドが持ち、エンジンへの操作とその結果の通知はここを通す
+pub trait Frontend {
+    fn on_events(&mut self, game: &Game, events: &[GameEvent]);
+    fn on_error(&mut self, game: &Game, message: &str);
+}
+
+pub fn dispatch(game: &mut Game, cmd: Command, fe: &mut dyn Frontend) {
+    match cmd {
+        Command::Stage { w, mv } => {
+            if let Err(e) = game.stage_move(w, mv) {
+                fe.on_error(game, &format!("{w}: {e}"));
+            }
+        }
+        Command::ClearStaged => game.clear_staged(),
+        Command::Commit => match game.commit_turn() {
+            Ok(events) => fe.on_events(game, &events),
+            Err(e) => fe.on_error(game, &e.to_string()),
+        },
+        Command::Undo => match game.undo_turn() {
+            Ok(true) => fe.on_events(game, &[]),
+            Ok(false) => fe.on_error(game, "取り消す手がない"),
+            Err(e) => fe.on_error(game, &e.to_string()),
+        },
+        Command::Redo => match game.redo_turn() {
+            Ok(Some(events)) => fe.on_events(game, &events),
+            Ok(None) => fe.on_error(game, "やり直す手がない"),
+            Err(e) => fe.on_error(game, &e.to_string()),
+        },
+        Command::Merge { a, b } => match game.merge_worlds(a, b) {
+            Ok(events) => fe.on_events(game, &events),
+            Err(e) => fe.on_error(game, &e.to_string()),
+        },
+        Command::Claim { by, claim } => match game.claim(by, claim) {
+            Ok(events) => fe.on_events(game, &events),
+            Err(e) => fe.on_error(game, &format!("{}の申告は認められない: {e}", claim.label())),
+        },
+        Command::Resign { by } => match game.resign(by) {
+            Ok(events) => fe.on_events(game, &events),
+            Err(e) => fe.on_error(game, &e.to_string()),
+        },
+        Command::OfferDraw { by } => match game.offer_draw(by) {
+            Ok(events) => fe.on_events(game, &events),
+            Err(e) => fe.on_error(game, &e.to_string()),
+        },
+        Command::AcceptDraw { by } => match game.accept_draw(by) {
+            Ok(events) => fe.on_events(game, &events),
+            Err(e) => fe.on_error(game, &e.to_string()),
+        },
+    }
+}
+
+// 盤上の駒の見せ方。描画側はこれを受け取って文字か図形で駒を描く
+pub trait GlyphSet {
+    fn piece(&self, t: PieceType) -> &'static str;
+    fn owner(&self, p: Player) -> &'static str;
+    // 候補が n 通り残っている駒
+    fn unknown(&self, n: usize) -> String;
+    // 文字ではなく図形で描いてほしい場合は true
+    fn shaped(&self) -> bool {
+        false
+    }
+    fn cell(&self, p: &Piece) -> String {
+        let body = match p.candidates().iter().next() {
+            Some(t) if p.candidates().len() == 1 => self.piece(*t).to_string(),
+            _ => self.unknown(p.candidates().len()),
+        };
+        format!("{}{}", self.owner(p.owner()), body)
+    }
+}
+
+pub struct KanjiGlyphs;
+
+impl GlyphSet for KanjiGlyphs {
+    fn piece(&self, t: PieceType) -> &'static str {
+        t.short()
+    }
+    fn owner(&self, p: Player) -> &'static str {
+        p.mark()
+    }
+    fn unknown(&self, n: usize) -> String {
+        format!("{n}候補")
+    }
+}
+
+// 漢字を読まない人向け。英語圏の将棋表記の頭文字
+pub struct LatinGlyphs;
+
+impl GlyphSet for LatinGlyphs {
+    fn piece(&self, t: PieceType) -> &'static str {
+        match t {
+            PieceType::Pawn => "P",
+            PieceType::Lance => "L",
+            PieceType::Knight => "N",
+            PieceType::Silver => "S",
+            PieceType::Gold => "G",
+            PieceType::Rook => "R",
+            PieceType::Bishop => "B",
+            PieceType::King => "K",
+        }
+    }
+    fn owner(&self, p: Player) -> &'static str {
+        match p {
+            Player::Black => "b",
+            Player::White => "w",
+        }
+    }
+    fn unknown(&self, n: usize) -> String {
+        format!("?{n}")
+    }
+}
+
+// 文字に頼らない記号。GUI では同じ形を図形として描く
+pub struct SymbolGlyphs;
+
+impl GlyphSet for SymbolGlyphs {
+    fn piece(&self, t: PieceType) -> &'static str {
+        match t {
+            PieceType::Pawn => "•",
+            PieceType::Lance => "↑",
+            PieceType::Knight => "▲",
+            PieceType::Silver => "◇",
+            PieceType::Gold => "◆",
+            PieceType::Rook => "✚",
+            PieceType::Bishop => "✖",
+            PieceType::King => "◉",
+        }
+    }
+    fn owner(&self, p: Player) -> &'static str {
+        match p {
+            Player::Black => "+",
+            Player::White => "-",
+        }
+    }
+    fn unknown(&self, n: usize) -> String {
+        format!("?{n}")
+    }
+    fn shaped(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GlyphStyle {
+    #[default]
+    Kanji,
+    Latin,
+    Symbols,
+}
+
+impl GlyphStyle {
+    pub const ALL: [GlyphStyle; 3] = [Self::Kanji, Self::Latin, Self::Symbols];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Kanji => "漢字",
+            Self::Latin => "Latin",
+            Self::Symbols => "記号",
+        }
+    }
+
+    pub fn glyphs(self) -> &'static dyn GlyphSet {
+        match self {
+            Self::Kanji => &KanjiGlyphs,
+            Self::Latin => &LatinGlyphs,
+            Self::Symbols => &SymbolGlyphs,
+        }
+    }
+}
+
+pub fn result_label(r: GameResult) -> String {
+    match r {
+        GameResult::Win(p) => format!("{}の勝ち", p.label()),
+        GameResult::Draw => "引き分け".into(),
+    }
+}
+
+pub fn describe(e: &GameEvent) -> String {
+    match e {
+        GameEvent::WorldCreated {
+            w, from, t_base, ..
+        } => {
+            format!("{w} 生成 ({from} の {t_base} から分岐)")
+        }
+        GameEvent::WorldLost { w } => format!("{w} 王喪失"),
+        GameEvent::WorldArchived { w } => format!("{w} 凍結"),
+        GameEvent::WorldPruned { w } => format!("{w} 王喪失のため凍結"),
+        GameEvent::WorldDestroyed { w, by } => format!("{w} を{}が破壊", by.label()),
+        GameEvent::WorldDecided { w, winner } => format!("{w} は{}の勝ち", winner.label()),
+        GameEvent::WorldsMerged { kept, removed } => format!("{removed} を {kept} に統合"),
+        GameEvent::Impasse { w, winner: Some(p) } => format!("{w} 入玉 ({}の点数勝ち)", p.label()),
+        GameEvent::Impasse { w, winner: None } => format!("{w} 持将棋"),
+        GameEvent::Repetition { w: Some(w) } => format!("{w} で千日手"),
+        GameEvent::Repetition { w: None } => "全世界線で千日手".to_string(),
+        GameEvent::Claimed { by, claim } => format!("{}が{}を申告", by.label(), claim.label()),
+        GameEvent::Flagged { player } => format!("{}の時間切れ", player.label()),
+        GameEvent::Resigned { by } => format!("{}が投了", by.label()),
+        GameEvent::DrawOffered { by } => format!("{}が引き分けを提案", by.label()),
+        GameEvent::DrawAccepted { by } => format!("{}が引き分けに合意", by.label()),
+        GameEvent::GameOver { result } => format!("終局: {}", result_label(*result)),
+        GameEvent::TurnCommitted { player, turn } => {
+            format!("{}手目 {} 同時確定しました", turn, player.label())
+        }
+    }
+}
+
+// ネット対戦でこの端末が受け持つ側。player が None ならローカル対局で両方を受け持つ。
+// 相手の番の間は手を登録させず、先行入力として預かって自分の番になった時に登録する
+#[derive(Clone, Debug, Default)]
+pub struct Seat {
+    player: Option<Player>,
+    premoves: BTreeMap<WorldId, PlannedMove>,
+}
+
+impl Seat {
+    pub fn new(player: Option<Player>) -> Self {
+        Self {
+            player,
+            premoves: BTreeMap::new(),
+        }
+    }
+    pub fn player(&self) -> Option<Player> {
+        self.player
+    }
+    // 受け持つ側を変えると預かっていた先行入力は捨てる
+    pub fn set_player(&mut self, player: Option<Player>) {
+        if self.player != player {
+            self.premoves.clear();
+        }
+        self.player = player;
+    }
+    pub fn premoves(&self) -> &BTreeMap<WorldId, PlannedMove> {
+        &self.premoves
+    }
+    pub fn cancel_premove(&mut self, w: WorldId) -> Option<PlannedMove> {
+        self.premoves.remove(&w)
+    }
+
+    // 手の登録・確定・取り消しなど盤を動かす操作ができるか
+    pub fn can_act(&self, game: &Game) -> bool {
+        !game.status().is_over() && self.player.is_none_or(|p| p == game.turn())
+    }
+
+    // w の世界線の手番表示
+    pub fn turn_label(&self, game: &Game, w: WorldId) -> String {
+        let Some(wl) = game.world(w) else {
+            return "凍結".into();
+        };
+        if let Some(p) = wl.winner() {
+            return format!("{}勝で決着", p.label());
+        }
+        if wl.is_drawn() {
+            return "持将棋".into();
+        }
+        let turn = game.turn();
+        let whose = match self.player {
+            None => format!("{}の番", turn.label()),
+            Some(p) if p == turn => format!("自分の番 ({})", turn.label()),
+            Some(_) => format!("相手の番 ({})", turn.label()),
+        };
+        if self.premoves.contains_key(&w) {
+            format!("{whose} 先行入力あり")
+        } else {
+            whose
+        }
+    }
+
+    // 自分の番なら登録して Ok(true)、相手の番なら先行入力として預けて Ok(false)
+    pub fn submit(
+        &mut self,
+        game: &mut Game,
+        w: WorldId,
+        pm: PlannedMove,
+    ) -> Result<bool, MoveError> {
+        if self.can_act(game) {
+            game.stage_move(w, pm)?;
+            return Ok(true);
+        }
+        if game.status().is_over() {
+            return Err(MoveError::GameOver);
+        }
+        if game.world(w).is_none() {
+            return Err(MoveError::NoSuchWorld(w));
+        }
+        self.premoves.insert(w, pm);
+        Ok(false)
+    }
+
+    // 自分の番になっていれば預かった手をまとめて登録する。登録できなかった手は理由と共に返す
+    pub fn flush(&mut self, game: &mut Game) -> Vec<(WorldId, MoveError)> {
+        if self.premoves.is_empty() || !self.can_act(game) {
+            return Vec::new();
+        }
+        std::mem::take(&mut self.premoves)
+            .into_iter()
+            .filter_map(|(w, pm)| game.stage_move(w, pm).err().map(|e| (w, e)))
+            .collect()
+    }
+}
+
+// 入力補助で欄ごとに出す判定。None はその欄が今の手の種類では使われないか、まだ判定できないもの
+#[derive(Clone, Debug, Default)]
+pub struct FieldChecks {
+    pub from: Option<Result<(), MoveError>>,
+    pub to: Option<Result<(), MoveError>>,
+    pub delta_w: Option<Result<(), MoveError>>,
+    pub delta_t: Option<Result<(), MoveError>>,
+}
+
+// 組み立て途中の手を欄ごとに調べる。手全体の判定 (Game::validate_move) と違い、
+// 他の欄が誤っていてもそれぞれの欄だけで分かる問題を返す
+pub fn check_fields(game: &Game, w: WorldId, pm: &PlannedMove) -> FieldChecks {
+    let Some(wl) = game.world(w) else {
+        return FieldChecks::default();
+    };
+    let st = game.settings();
+    let turn = game.turn();
+    let present = wl.present();
+    let branching = pm.delta_w != 0 || pm.delta_t < 0;
+
+    let base = game.world(pm.base_world.unwrap_or(w)).unwrap_or(wl);
+    let base_t = base
+        .present_t()
+        .offset(pm.delta_t)
+        .filter(|t| t.0 < base.history().len());
+    let delta_t = if st.past_only && pm.delta_t > 0 {
+        Err(MoveError::FutureMoveDisabled)
+    } else if pm.delta_t.abs() > st.max_time_jump {
+        Err(MoveError::TimeJumpTooFar)
+    } else if base_t.is_none() {
+        Err(MoveError::HistoryOutOfRange)
+    } else {
+        Ok(())
+    };
+    let delta_w = if !branching {
+        Ok(())
+    } else if game.worlds().len() >= st.max_worlds {
+        Err(MoveError::MaxWorlds)
+    } else if game.allocate_world(w, pm.delta_w).is_none() {
+        Err(MoveError::WorldCollision)
+    } else {
+        Ok(())
+    };
+
+    // 着地する局面。分岐なら起点の世界線の過去、量子トンネル打ちなら行き先の現在
+    let target = match &pm.kind {
+        MoveKind::TeleportDrop { dest, .. } => game.present(*dest),
+        _ if branching => base_t.map(|t| &base.history()[t.0]),
+        _ => Some(present),
+    };
+    let from = match &pm.kind {
+        MoveKind::Move { from, .. } => Some(match present.piece_at(*from) {
+            None => Err(MoveError::EmptySource),
+            Some(p) if p.owner() != turn => Err(MoveError::NotYourPiece),
+            Some(_) => Ok(()),
+        }),
+        MoveKind::Observe { at } => Some(match present.piece_at(*at) {
+            None => Err(MoveError::NothingToObserve),
+            Some(p) if p.candidates().len() == 1 => Err(MoveError::AlreadyObserved),
+            Some(_) => Ok(()),
+        }),
+        MoveKind::CollapseWorld { sacrifice, .. } => Some(match present.piece_at(*sacrifice) {
+            Some(p) if p.owner() == turn => Ok(()),
+            _ => Err(MoveError::NoSacrifice),
+        }),
+        _ => None,
+    };
+    let to = match &pm.kind {
+        MoveKind::Move { to, .. } => target.map(|s| match s.piece_at(*to) {
+            Some(p) if p.owner() == turn => Err(MoveError::OwnPieceOnTarget),
+            _ => Ok(()),
+        }),
+        MoveKind::Drop { to, .. } | MoveKind::TeleportDrop { to, .. } => {
+            target.map(|s| match s.piece_at(*to) {
+                Some(_) => Err(MoveError::DropOccupied),
+                None => Ok(()),
+            })
+        }
+        _ => None,
+    };
+    FieldChecks {
+        from,
+        to,
+        delta_w: Some(delta_w),
+        delta_t: Some(delta_t),
+    }
+}
+
+// 表示と同じ「筋段」の2桁 (例: 76 は 7六)。筋は盤の筋数 files から数える
+pub fn parse_square(s: &str, (files, ranks): (usize, usize)) -> Option<Square> {
+    let mut digits = s.chars().map(|c| c.to_digit(10));
+    let (Some(Some(file)), Some(Some(rank)), None) = (digits.next(), digits.next(), digits.next())
+    else {
+        return None;
+    };
+    let (file, rank) = (file as usize, rank as usize);
+    if !(1..=files).contains(&file) || !(1..=ranks).contains(&rank) {
+        return None;
+    }
+    Some(Square::new(files - file, rank - 1))
+}
+
+// コマンド欄と同じ書式の手順を1行ずつ実行する。行頭に w2 のように世界線を書ける (省略時は w0)。
+// 空行と # で始まる行は読み飛ばす
+pub fn run_script(game: &mut Game, script: &str) -> anyhow::Result<()> {
+    for (i, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut run = || -> anyhow::Result<()> {
+            let (w, rest) = match line.split_once(' ') {
+                Some((head, rest)) if head.starts_with('w') => {
+                    let n: i32 = head[1..]
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("世界線の指定が不正: {head}"))?;
+                    (WorldId(n), rest)
+                }
+                _ => (WorldId(0), line),
+            };
+            match parse_command(rest, game, w)? {
+                Command::Stage { w, mv } => game.stage_move(w, mv)?,
+                Command::ClearStaged => game.clear_staged(),
+                Command::Commit => {
+                    game.commit_turn()?;
+                }
+                Command::Undo => {
+                    game.undo_turn()?;
+                }
+                Command::Redo => {
+                    game.redo_turn()?;
+                }
+                Command::Merge { a, b } => {
+                    game.merge_worlds(a, b)?;
+                }
+                Command::Claim { by, claim } => {
+                    game.claim(by, claim)?;
+                }
+                Command::Resign { by } => {
+                    game.resign(by)?;
+                }
+                Command::OfferDraw { by } => {
+                    game.offer_draw(by)?;
+                }
+                Command::AcceptDraw { by } => {
+                    game.accept_draw(by)?;
+                }
+            }
+            Ok(())
+        };
+        run().map_err(|e| anyhow::anyhow!("{}行目: {e}", i + 1))?;
+    }
+    Ok(())
+}
+
+// コマンド欄の書式:
+//   mv <元> <先> [Δw] [Δt] [+]    例: mv 77 76 / mv 28 24 1 0 +
+//   drop <持ち駒番号> <先> [Δw] [Δt]
+//   obs <マス>
+//   pass
+//   merge <残す世界線> <消す世界線>  例: merge 0 1
+//   claim <rep|illegal> [b|w]        申告する側を省略すると手番の側
+//   resign / draw / accept [b|w]     投了・引き分けの提案・提案への合意
+//   clear / commit / undo / redo
+pub fn parse_command(line: &str, game: &Game, w: WorldId) -> anyhow::Result<Command> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let square = |i: usize| {
+        let s = words.get(i).copied().unwrap_or_default();
+        parse_square(s, game.settings().variant.board_size())
+            .ok_or_else(|| anyhow::anyhow!("マスの指定が不正: {s}"))
+    };
+    let delta = |i: usize| -> anyhow::Result<i32> {
+        match words.get(i) {
+            Some(s) if *s != "+" => s.parse().map_err(|_| anyhow::anyhow!("Δの指定が不正: {s}")),
+            _ => Ok(0),
+        }
+    };
+    let kind = match words.first().copied() {
+        Some("commit") => return Ok(Command::Commit),
+        Some("clear") => return Ok(Command::ClearStaged),
+        Some("undo") => return Ok(Command::Undo),
+        Some("redo") => return Ok(Command::Redo),
+        Some("merge") => {
+            let world = |i: usize| -> anyhow::Result<WorldId> {
+                let s = words.get(i).copied().unwrap_or_default();
+                s.trim_start_matches('w')
+                    .parse()
+                    .map(WorldId)
+                    .map_err(|_| anyhow::anyhow!("世界線の指定が不正: {s}"))
+            };
+            return Ok(Command::Merge {
+                a: world(1)?,
+                b: world(2)?,
+            });
+        }
+        Some("claim") => {
+            let claim = match words.get(1).copied() {
+                Some("rep") => Claim::Repetition,
+                Some("illegal") => Claim::IllegalMove,
+                other => anyhow::bail!("申告の種類が不正: {}", other.unwrap_or_default()),
+            };
+            let by = match words.get(2).copied() {
+                None => game.turn(),
+                Some("b") => Player::Black,
+                Some("w") => Player::White,
+                Some(s) => anyhow::bail!("申告する側の指定が不正: {s}"),
+            };
+            return Ok(Command::Claim { by, claim });
+        }
+        Some(cmd @ ("resign" | "draw" | "accept")) => {
+            let by = match words.get(1).copied() {
+                None => game.turn(),
+                Some("b") => Player::Black,
+                Some("w") => Player::White,
+                Some(s) => anyhow::bail!("対局者の指定が不正: {s}"),
+            };
+            return Ok(match cmd {
+                "resign" => Command::Resign { by },
+                "draw" => Command::OfferDraw { by },
+                _ => Command::AcceptDraw { by },
+            });
+        }
+        Some("mv") => MoveKind::Move {
+            from: square(1)?,
+            to: square(2)?,
+            promote: words.last() == Some(&"+"),
+        },
+        Some("obs") => MoveKind::Observe { at: square(1)? },
+        Some("pass") => MoveKind::Pass,
+        Some("drop") => {
+            let idx: usize = words
+                .get(1)
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| anyhow::anyhow!("持ち駒番号が不正"))?;
+            let snap = game
+                .present(w)
+                .ok_or_else(|| anyhow::anyhow!("世界線 {w} が存在しない"))?;
+            let group = snap
+                .hand_groups(game.turn())
+                .into_keys()
+                .nth(idx)
+                .ok_or_else(|| anyhow::anyhow!("持ち駒 {idx} がない"))?;
+            MoveKind::Drop {
+                group,
+                to: square(2)?,
+            }
+        }
+        _ => anyhow::bail!("不明なコマンド: {line}"),
+    };
+    Ok(Command::Stage {
+        w,
+        mv: PlannedMove {
+            kind,
+            delta_w: delta(3)?,
+            delta_t: delta(4)?,
+            summon: None,
+            base_world: None,
+        },
+    })
+}
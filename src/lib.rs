@@ -0,0 +1,23 @@
+pub mod coords;
+pub mod engine;
+pub mod eval;
+pub mod export;
+pub mod frontend;
+pub mod stats;
+#[cfg(feature = "widgets")]
+pub mod widgets;
+
+pub mod prelude {
+    pub use crate::coords::{Square, TimeIdx, WorldId};
+    pub use crate::engine::{
+        simulate, Board, CandidateSet, CaptureAcrossTime, CheckAttackMode, Claim, ClaimError,
+        Clock, ClockMode, CollapseMode, Deduction, DrawReason, EngineError, Game, GameBuilder,
+        GameEvent, GameResult, GameStatus, HandMode, Handicap, MoveError, MoveKind, ParadoxRule,
+        Piece, PieceId, PieceType, PlannedMove, Player, PositionBuilder, PositionError,
+        PositionViolation, PromotionZone, QuantumModel, RepetitionRule, Settings, SettingsError,
+        SimError, SimErrorKind, Snapshot, StagingIssue, StartPosition, TimeControl, TurnMode,
+        TurnPlan, TurnRecord, Variant, WinCondition, WorldIdPolicy, WorldLine,
+    };
+    pub use crate::export::KifuSheetInfo;
+    pub use crate::stats::{CollapseCause, CollapseEvent, CollapseStats, WorldUsageStats};
+}
@@ -1,11 +1,20 @@
-mod engine;
-
 use eframe::egui;
-use engine::{CheckAttackMode, Game, HandMode, MoveKind, PieceType, PlannedMove, Player, Settings};
+use quantum_spacetime_shogi::export;
+use quantum_spacetime_shogi::frontend::{
+    check_fields, describe, dispatch, Command, FieldChecks, Frontend, GlyphStyle, Seat,
+};
+use quantum_spacetime_shogi::prelude::*;
+use quantum_spacetime_shogi::widgets::{self, GameView};
 
 #[derive(Default, Clone)]
 struct MoveInput {
     mode_drop: bool,
+    mode_destroy: bool,
+    mode_observe: bool,
+    mode_pass: bool,
+    destroy_w: i32,
+    teleport: bool,
+    teleport_w: i32,
     from_x: usize,
     from_y: usize,
     to_x: usize,
@@ -14,205 +23,1086 @@ struct MoveInput {
     hand_idx: usize,
     delta_w: i32,
     delta_t: i32,
+    summon: bool,
+    summon_x: usize,
+    summon_y: usize,
+    use_base: bool,
+    base_w: i32,
+}
+
+impl MoveInput {
+    fn planned_move(
+        &self,
+        snap: &Snapshot,
+        player: Player,
+        summon_ok: bool,
+        base_ok: bool,
+    ) -> PlannedMove {
+        let kind = if self.mode_pass {
+            MoveKind::Pass
+        } else if self.mode_observe {
+            MoveKind::Observe {
+                at: Square::new(self.from_x, self.from_y),
+            }
+        } else if self.mode_destroy {
+            MoveKind::CollapseWorld {
+                target: WorldId(self.destroy_w),
+                sacrifice: Square::new(self.from_x, self.from_y),
+            }
+        } else if self.mode_drop {
+            let group = snap
+                .hand_groups(player)
+                .into_keys()
+                .nth(self.hand_idx)
+                .unwrap_or_default();
+            let to = Square::new(self.to_x, self.to_y);
+            if self.teleport {
+                MoveKind::TeleportDrop {
+                    group,
+                    to,
+                    dest: WorldId(self.teleport_w),
+                }
+            } else {
+                MoveKind::Drop { group, to }
+            }
+        } else {
+            MoveKind::Move {
+                from: Square::new(self.from_x, self.from_y),
+                to: Square::new(self.to_x, self.to_y),
+                promote: self.promote,
+            }
+        };
+        PlannedMove {
+            kind,
+            delta_w: self.delta_w,
+            delta_t: self.delta_t,
+            summon: (self.summon && summon_ok).then(|| Square::new(self.summon_x, self.summon_y)),
+            base_world: (self.use_base && base_ok).then_some(WorldId(self.base_w)),
+        }
+    }
+}
+
+// 表示・入力まわりの状態。エンジン側 (Game) には持たせない。
+#[derive(Default)]
+struct UiState {
+    selected_world: WorldId,
+    message: String,
+    inputs: std::collections::BTreeMap<WorldId, MoveInput>,
+    // 登録を拒否された世界線ごとの理由。次に登録できるか確定するまで残す
+    stage_errors: std::collections::BTreeMap<WorldId, String>,
+    // 入力中の手を欄ごとに検査して○×を出す
+    assisted_input: bool,
+    show_stats: bool,
+    show_narration: bool,
+    show_rules: bool,
+    show_export: bool,
+    kifu_info: KifuSheetInfo,
+    inspected: Option<u64>,
+    // 盤に表示する過去の時間。None は現在の局面
+    view_time: Option<TimeIdx>,
+    show_sandbox: bool,
+    glyph_style: GlyphStyle,
+    sandbox_override: bool,
+    sandbox_candidates: CandidateSet,
+    sandbox_trace: Vec<String>,
+    game_no: usize,
+    recorder: SessionRecorder,
+    auto_commit: bool,
+    // 自動確定の予定時刻。取り消した場合は入力が揃い直すまで再開しない
+    auto_commit_at: Option<std::time::Instant>,
+    auto_commit_cancelled: bool,
+    // ネット対戦で受け持つ側と、相手の番の間に預かった先行入力
+    seat: Seat,
+    // 世界線一覧の表示中のページ
+    world_page: usize,
+    // 手番の考慮時間を計り始めた時刻。確定や申告の時に Game へ加算する
+    turn_started: Option<std::time::Instant>,
+}
+
+// オプトインの操作記録。UI のどこで迷うかを後から追うためのもの。
+#[derive(Default)]
+struct SessionRecorder {
+    enabled: bool,
+    started: Option<(std::time::Instant, u64)>,
+    entries: Vec<(f64, String)>,
+}
+
+impl SessionRecorder {
+    fn log(&mut self, entry: impl Into<String>) {
+        if !self.enabled {
+            return;
+        }
+        let (started, _) = *self.started.get_or_insert_with(|| {
+            let unix = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            (std::time::Instant::now(), unix)
+        });
+        self.entries
+            .push((started.elapsed().as_secs_f64(), entry.into()));
+    }
+
+    // 匿名版は開始時刻と対局者名を含めない
+    fn export(&self, info: &KifuSheetInfo, anonymize: bool) -> String {
+        let mut out = String::new();
+        if !anonymize {
+            let unix = self.started.map_or(0, |(_, u)| u);
+            out.push_str(&format!(
+                "# started={unix} black={} white={}\n",
+                info.black_name, info.white_name
+            ));
+        }
+        for (secs, entry) in &self.entries {
+            let mut entry = entry.clone();
+            if anonymize {
+                for (name, label) in [
+                    (&info.black_name, Player::Black.label()),
+                    (&info.white_name, Player::White.label()),
+                ] {
+                    if !name.is_empty() {
+                        entry = entry.replace(name.as_str(), label);
+                    }
+                }
+            }
+            out.push_str(&format!("{secs:.1}\t{entry}\n"));
+        }
+        out
+    }
 }
 
 struct App {
     game: Game,
-    inputs: std::collections::BTreeMap<i32, MoveInput>,
+    ui: UiState,
 }
 
 impl Default for App {
     fn default() -> Self {
         Self {
-            game: Game::new(Settings::default()),
-            inputs: std::collections::BTreeMap::new(),
+            game: Game::builder().settings(Settings::default()).build(),
+            ui: UiState::default(),
         }
     }
 }
 
+const AUTO_COMMIT_DELAY: std::time::Duration = std::time::Duration::from_secs(3);
+
+impl App {
+    // 計っている考慮時間を手番の側の時計に渡す。時間切れなら終局を知らせる
+    fn charge_time(&mut self) {
+        if let Some(started) = self.ui.turn_started.take() {
+            let events = self.game.tick_clock(started.elapsed());
+            if !events.is_empty() {
+                self.ui.on_events(&self.game, &events);
+                self.ui.recorder.log(format!("時計 → {}", self.ui.message));
+            }
+        }
+    }
+
+    fn commit(&mut self) {
+        self.ui.auto_commit_at = None;
+        self.charge_time();
+        dispatch(&mut self.game, Command::Commit, &mut self.ui);
+        self.ui
+            .recorder
+            .log(format!("同時確定 → {}", self.ui.message));
+    }
+
+    fn tick_auto_commit(&mut self, ctx: &egui::Context) {
+        if !self.ui.auto_commit
+            || !self.ui.seat.can_act(&self.game)
+            || !self.game.all_staged()
+            || !self.game.staging_issues().is_empty()
+            || self.game.status().is_over()
+        {
+            self.ui.auto_commit_at = None;
+            self.ui.auto_commit_cancelled = false;
+            return;
+        }
+        if self.ui.auto_commit_cancelled {
+            return;
+        }
+        let at = *self
+            .ui
+            .auto_commit_at
+            .get_or_insert_with(|| std::time::Instant::now() + AUTO_COMMIT_DELAY);
+        if std::time::Instant::now() >= at {
+            self.commit();
+        } else {
+            ctx.request_repaint_after(std::time::Duration::from_millis(100));
+        }
+    }
+
+    // 設定はそのまま引き継ぎ、対局者名を入れ替えて新しい対局を始める
+    fn rematch(&mut self) {
+        self.game = Game::builder()
+            .settings(self.game.settings().clone())
+            .seed(self.game.seed())
+            .build();
+        let info = &mut self.ui.kifu_info;
+        std::mem::swap(&mut info.black_name, &mut info.white_name);
+        self.ui.selected_world = WorldId(0);
+        self.ui.inputs.clear();
+        self.ui.stage_errors.clear();
+        self.ui.inspected = None;
+        self.ui.sandbox_trace.clear();
+        self.ui.seat = Seat::new(self.ui.seat.player());
+        self.ui.turn_started = None;
+        self.ui.game_no += 1;
+        self.ui.message = format!("第{}局 先後を入れ替えました", self.ui.game_no + 1);
+        self.ui
+            .recorder
+            .log(format!("再戦 第{}局", self.ui.game_no + 1));
+    }
+}
+
+// 入力補助の判定を欄の横に出す
+fn field_mark(ui: &mut egui::Ui, check: &Option<Result<(), MoveError>>) {
+    match check {
+        Some(Ok(())) => {
+            ui.colored_label(egui::Color32::from_rgb(40, 160, 60), "○");
+        }
+        Some(Err(e)) => {
+            ui.colored_label(egui::Color32::RED, format!("× {e}"));
+        }
+        None => {}
+    }
+}
+
+impl Frontend for UiState {
+    fn on_events(&mut self, _game: &Game, events: &[GameEvent]) {
+        self.stage_errors.clear();
+        self.message = events.iter().map(describe).collect::<Vec<_>>().join(" / ");
+    }
+
+    fn on_error(&mut self, _game: &Game, message: &str) {
+        // 失敗した入力のまま自動確定を繰り返さない
+        self.auto_commit_cancelled = true;
+        self.message = message.to_string();
+    }
+}
+
 impl eframe::App for App {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        for (w, e) in self.ui.seat.flush(&mut self.game) {
+            self.ui.recorder.log(format!("先行入力の登録拒否 {w}: {e}"));
+            self.ui.stage_errors.insert(w, format!("先行入力: {e}"));
+        }
+        // 時計は毎フレーム進める
+        self.charge_time();
+        if !self.game.status().is_over() && self.ui.turn_started.is_none() {
+            self.ui.turn_started = Some(std::time::Instant::now());
+        }
+        self.tick_auto_commit(ctx);
+        let w = self.ui.selected_world;
+        if !self.game.worlds().contains_key(&w) && !self.game.archived().contains_key(&w) {
+            self.ui.selected_world = *self.game.worlds().keys().next().unwrap();
+        }
+
         egui::TopBottomPanel::top("top").show(ctx, |ui| {
             ui.horizontal(|ui| {
                 ui.heading("量子時空将棋 プロトタイプ");
                 ui.separator();
-                ui.label(format!("手番: {}", self.game.turn.label()));
-                ui.label(&self.game.message);
+                ui.label(format!("手番: {}", self.game.turn().label()));
+                if let Some(clock) = self.game.clock() {
+                    ui.label(format!(
+                        "残り 先手 {} / 後手 {}",
+                        clock.label(Player::Black),
+                        clock.label(Player::White)
+                    ));
+                    ctx.request_repaint_after(std::time::Duration::from_millis(200));
+                }
+                let mut seat = self.ui.seat.player();
+                egui::ComboBox::from_label("担当")
+                    .selected_text(seat.map_or("両方", |p| p.label()))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut seat, None, "両方");
+                        for p in [Player::Black, Player::White] {
+                            ui.selectable_value(&mut seat, Some(p), p.label());
+                        }
+                    });
+                if seat != self.ui.seat.player() {
+                    self.ui.seat.set_player(seat);
+                    self.ui
+                        .recorder
+                        .log(format!("担当 {}", seat.map_or("両方", |p| p.label())));
+                }
+                if !self.ui.seat.can_act(&self.game) && !self.game.status().is_over() {
+                    ui.colored_label(
+                        egui::Color32::from_rgb(220, 70, 60),
+                        "相手の番 (先行入力のみ)",
+                    );
+                }
+                ui.label(&self.ui.message);
+                ui.separator();
+                ui.toggle_value(&mut self.ui.show_stats, "統計");
+                ui.toggle_value(&mut self.ui.show_narration, "実況");
+                ui.toggle_value(&mut self.ui.show_export, "棋譜シート");
+                ui.toggle_value(&mut self.ui.show_sandbox, "ルール検証");
+                ui.toggle_value(&mut self.ui.show_rules, "ルール表");
+                egui::ComboBox::from_id_source("glyph_style")
+                    .selected_text(self.ui.glyph_style.label())
+                    .show_ui(ui, |ui| {
+                        for g in GlyphStyle::ALL {
+                            ui.selectable_value(&mut self.ui.glyph_style, g, g.label());
+                        }
+                    });
+                if ui.button("再戦 (先後入替)").clicked() {
+                    self.rematch();
+                }
+                ui.checkbox(&mut self.ui.recorder.enabled, "操作記録");
+                if !self.ui.recorder.entries.is_empty() {
+                    for (anonymize, path, label) in [
+                        (false, "session.log", "記録を保存"),
+                        (true, "session_anon.log", "匿名で保存"),
+                    ] {
+                        if ui.button(label).clicked() {
+                            let text = self.ui.recorder.export(&self.ui.kifu_info, anonymize);
+                            self.ui.message = match std::fs::write(path, text) {
+                                Ok(()) => format!("{path} に出力しました"),
+                                Err(e) => format!("出力失敗: {e}"),
+                            };
+                        }
+                    }
+                }
+                if ui.button("状態をJSONで書き出す").clicked() {
+                    self.ui.message = match std::fs::write("state.json", self.game.debug_json()) {
+                        Ok(()) => "state.json に出力しました".into(),
+                        Err(e) => format!("出力失敗: {e}"),
+                    };
+                }
             });
+            let mut settings = self.game.settings().clone();
             ui.horizontal(|ui| {
                 ui.label("MAX_WORLDS");
-                ui.add(
-                    egui::DragValue::new(&mut self.game.settings.max_worlds).clamp_range(1..=20),
-                );
+                ui.add(egui::DragValue::new(&mut settings.max_worlds).clamp_range(1..=20));
                 ui.label("MAX_TIME_JUMP");
-                ui.add(
-                    egui::DragValue::new(&mut self.game.settings.max_time_jump).clamp_range(1..=20),
-                );
+                ui.add(egui::DragValue::new(&mut settings.max_time_jump).clamp_range(1..=20));
+                ui.label("BISHOP_AXES");
+                ui.add(egui::DragValue::new(&mut settings.bishop_axes).clamp_range(2..=4));
                 egui::ComboBox::from_label("HAND_MODE")
-                    .selected_text(match self.game.settings.hand_mode {
+                    .selected_text(match settings.hand_mode {
                         HandMode::PerWorld => "per_world",
                         HandMode::Global => "global",
                     })
                     .show_ui(ui, |ui| {
                         ui.selectable_value(
-                            &mut self.game.settings.hand_mode,
+                            &mut settings.hand_mode,
                             HandMode::PerWorld,
                             "per_world",
                         );
-                        ui.selectable_value(
-                            &mut self.game.settings.hand_mode,
-                            HandMode::Global,
-                            "global",
-                        );
+                        ui.selectable_value(&mut settings.hand_mode, HandMode::Global, "global");
                     });
                 egui::ComboBox::from_label("CHECK_ATTACK_MODE")
-                    .selected_text(match self.game.settings.check_attack_mode {
+                    .selected_text(match settings.check_attack_mode {
                         CheckAttackMode::Possible => "possible",
                         CheckAttackMode::Certain => "certain",
                     })
                     .show_ui(ui, |ui| {
                         ui.selectable_value(
-                            &mut self.game.settings.check_attack_mode,
+                            &mut settings.check_attack_mode,
                             CheckAttackMode::Possible,
                             "possible",
                         );
                         ui.selectable_value(
-                            &mut self.game.settings.check_attack_mode,
+                            &mut settings.check_attack_mode,
                             CheckAttackMode::Certain,
                             "certain",
                         );
                     });
-                ui.checkbox(&mut self.game.settings.past_only, "past_only");
+                ui.checkbox(&mut settings.past_only, "past_only");
+                ui.checkbox(&mut settings.summon_variant, "召喚ルール");
+                ui.checkbox(&mut settings.world_destruction, "世界線破壊");
+                ui.checkbox(&mut settings.teleport_drop, "量子トンネル打ち");
+                ui.checkbox(&mut settings.cross_world_base, "他の世界線を起点に分岐");
+                ui.checkbox(&mut settings.forbid_self_check, "自玉を取られる手を禁止");
+                ui.checkbox(&mut settings.entering_king, "入玉を27点法で判定");
+                ui.checkbox(&mut settings.strict_commit_check, "王手放置の確定を拒否");
+                ui.checkbox(
+                    &mut settings.entangle_worlds,
+                    "世界線間で同じ駒の候補を共有",
+                );
+                ui.checkbox(
+                    &mut settings.auto_merge_worlds,
+                    "同じ局面の世界線を自動でまとめる",
+                );
+                ui.checkbox(&mut settings.prune_lost_worlds, "王を失った世界線を凍結");
+                egui::ComboBox::from_label("WIN_CONDITION")
+                    .selected_text(match settings.win_condition {
+                        WinCondition::AllWorlds => "all",
+                        WinCondition::Majority => "majority",
+                        WinCondition::AnyWorld => "any",
+                    })
+                    .show_ui(ui, |ui| {
+                        for (c, label) in [
+                            (WinCondition::AllWorlds, "all"),
+                            (WinCondition::Majority, "majority"),
+                            (WinCondition::AnyWorld, "any"),
+                        ] {
+                            ui.selectable_value(&mut settings.win_condition, c, label);
+                        }
+                    });
+                egui::ComboBox::from_label("REPETITION_RULE")
+                    .selected_text(match settings.repetition_rule {
+                        RepetitionRule::Draw => "draw",
+                        RepetitionRule::PerpetualCheckLoses => "perpetual_check_loses",
+                    })
+                    .show_ui(ui, |ui| {
+                        for (r, label) in [
+                            (RepetitionRule::Draw, "draw"),
+                            (RepetitionRule::PerpetualCheckLoses, "perpetual_check_loses"),
+                        ] {
+                            ui.selectable_value(&mut settings.repetition_rule, r, label);
+                        }
+                    });
+                egui::ComboBox::from_label("COLLAPSE_MODE")
+                    .selected_text(match settings.collapse_mode {
+                        CollapseMode::CountOnly => "count_only",
+                        CollapseMode::RandomOnObserve => "random_on_observe",
+                        CollapseMode::RandomOnCapture => "random_on_capture",
+                    })
+                    .show_ui(ui, |ui| {
+                        for (m, label) in [
+                            (CollapseMode::CountOnly, "count_only"),
+                            (CollapseMode::RandomOnObserve, "random_on_observe"),
+                            (CollapseMode::RandomOnCapture, "random_on_capture"),
+                        ] {
+                            ui.selectable_value(&mut settings.collapse_mode, m, label);
+                        }
+                    });
+                egui::ComboBox::from_label("PARADOX_RULE")
+                    .selected_text(match settings.paradox_rule {
+                        ParadoxRule::RemovePastSelf => "remove_past_self",
+                        ParadoxRule::MergeCandidates => "merge_candidates",
+                        ParadoxRule::Forbid => "forbid",
+                    })
+                    .show_ui(ui, |ui| {
+                        for (r, label) in [
+                            (ParadoxRule::RemovePastSelf, "remove_past_self"),
+                            (ParadoxRule::MergeCandidates, "merge_candidates"),
+                            (ParadoxRule::Forbid, "forbid"),
+                        ] {
+                            ui.selectable_value(&mut settings.paradox_rule, r, label);
+                        }
+                    });
+                egui::ComboBox::from_label("TURN_MODE")
+                    .selected_text(match settings.turn_mode {
+                        TurnMode::Simultaneous => "simultaneous",
+                        TurnMode::Sequential => "sequential",
+                        TurnMode::RoundRobin => "round_robin",
+                    })
+                    .show_ui(ui, |ui| {
+                        for (m, label) in [
+                            (TurnMode::Simultaneous, "simultaneous"),
+                            (TurnMode::Sequential, "sequential"),
+                            (TurnMode::RoundRobin, "round_robin"),
+                        ] {
+                            ui.selectable_value(&mut settings.turn_mode, m, label);
+                        }
+                    });
+                egui::ComboBox::from_label("CAPTURE_ACROSS_TIME")
+                    .selected_text(match settings.capture_across_time {
+                        CaptureAcrossTime::Local => "local",
+                        CaptureAcrossTime::RemoveFromSource => "remove_from_source",
+                        CaptureAcrossTime::Paradoxed => "paradoxed",
+                    })
+                    .show_ui(ui, |ui| {
+                        for (c, label) in [
+                            (CaptureAcrossTime::Local, "local"),
+                            (CaptureAcrossTime::RemoveFromSource, "remove_from_source"),
+                            (CaptureAcrossTime::Paradoxed, "paradoxed"),
+                        ] {
+                            ui.selectable_value(&mut settings.capture_across_time, c, label);
+                        }
+                    });
+                egui::ComboBox::from_label("WORLD_ID_POLICY")
+                    .selected_text(match settings.world_id_policy {
+                        WorldIdPolicy::Exact => "exact",
+                        WorldIdPolicy::NearestFree => "nearest_free",
+                        WorldIdPolicy::SignedDirection => "signed_direction",
+                        WorldIdPolicy::Tree => "tree",
+                    })
+                    .show_ui(ui, |ui| {
+                        for (p, label) in [
+                            (WorldIdPolicy::Exact, "exact"),
+                            (WorldIdPolicy::NearestFree, "nearest_free"),
+                            (WorldIdPolicy::SignedDirection, "signed_direction"),
+                            (WorldIdPolicy::Tree, "tree"),
+                        ] {
+                            ui.selectable_value(&mut settings.world_id_policy, p, label);
+                        }
+                    });
+                egui::ComboBox::from_label("PROMOTION_ZONE")
+                    .selected_text(match settings.promotion_zone {
+                        PromotionZone::PerWorld => "per_world",
+                        PromotionZone::PerTimeLayer => "per_time_layer",
+                    })
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(
+                            &mut settings.promotion_zone,
+                            PromotionZone::PerWorld,
+                            "per_world",
+                        );
+                        ui.selectable_value(
+                            &mut settings.promotion_zone,
+                            PromotionZone::PerTimeLayer,
+                            "per_time_layer",
+                        );
+                    });
+                let mut model = settings.quantum_model;
+                let label = |m: QuantumModel| match m {
+                    QuantumModel::Lazy => "lazy",
+                    QuantumModel::Hidden => "hidden",
+                };
+                ui.add_enabled_ui(self.game.record().is_empty(), |ui| {
+                    egui::ComboBox::from_label("QUANTUM_MODEL")
+                        .selected_text(label(model))
+                        .show_ui(ui, |ui| {
+                            for m in [QuantumModel::Lazy, QuantumModel::Hidden] {
+                                ui.selectable_value(&mut model, m, label(m));
+                            }
+                        });
+                });
+                let mut variant = settings.variant;
+                ui.add_enabled_ui(self.game.record().is_empty(), |ui| {
+                    egui::ComboBox::from_label("VARIANT")
+                        .selected_text(variant.label())
+                        .show_ui(ui, |ui| {
+                            for v in [Variant::Standard, Variant::Minishogi] {
+                                ui.selectable_value(&mut variant, v, v.label());
+                            }
+                        });
+                });
+                let mut start = settings.start_position;
+                let start_label = |p: StartPosition| match p {
+                    StartPosition::FullQuantum => "full_quantum",
+                    StartPosition::Classical => "classical",
+                    StartPosition::RowConstrained => "row_constrained",
+                };
+                ui.add_enabled_ui(self.game.record().is_empty(), |ui| {
+                    egui::ComboBox::from_label("START_POSITION")
+                        .selected_text(start_label(start))
+                        .show_ui(ui, |ui| {
+                            for p in [
+                                StartPosition::FullQuantum,
+                                StartPosition::Classical,
+                                StartPosition::RowConstrained,
+                            ] {
+                                ui.selectable_value(&mut start, p, start_label(p));
+                            }
+                        });
+                });
+                let mut handicap = settings.handicap;
+                let standard = variant == Variant::Standard;
+                ui.add_enabled_ui(self.game.record().is_empty() && standard, |ui| {
+                    egui::ComboBox::from_label("HANDICAP")
+                        .selected_text(handicap.label())
+                        .show_ui(ui, |ui| {
+                            for h in [
+                                Handicap::None,
+                                Handicap::Lance,
+                                Handicap::Bishop,
+                                Handicap::Rook,
+                                Handicap::TwoPiece,
+                                Handicap::FourPiece,
+                                Handicap::SixPiece,
+                            ] {
+                                ui.selectable_value(&mut handicap, h, h.label());
+                            }
+                        });
+                });
+                // 時計は対局の開始時に作るので、持ち時間の設定も開始前だけ変えられる
+                let mut time_control = settings.time_control;
+                ui.add_enabled_ui(self.game.record().is_empty(), |ui| {
+                    let mut timed = time_control.is_some();
+                    if ui.checkbox(&mut timed, "持ち時間").changed() {
+                        time_control = timed.then(TimeControl::default);
+                    }
+                    let Some(tc) = &mut time_control else {
+                        return;
+                    };
+                    ui.horizontal(|ui| {
+                        ui.add(
+                            egui::DragValue::new(&mut tc.main_secs)
+                                .clamp_range(1..=36000)
+                                .suffix("秒"),
+                        );
+                        let byoyomi = matches!(tc.mode, ClockMode::Byoyomi { .. });
+                        egui::ComboBox::from_label("CLOCK_MODE")
+                            .selected_text(if byoyomi { "byoyomi" } else { "fischer" })
+                            .show_ui(ui, |ui| {
+                                if ui.selectable_label(byoyomi, "byoyomi").clicked() && !byoyomi {
+                                    tc.mode = ClockMode::Byoyomi {
+                                        secs: 30,
+                                        periods: 1,
+                                    };
+                                }
+                                if ui.selectable_label(!byoyomi, "fischer").clicked() && byoyomi {
+                                    tc.mode = ClockMode::Fischer { increment_secs: 10 };
+                                }
+                            });
+                        match &mut tc.mode {
+                            ClockMode::Byoyomi { secs, periods } => {
+                                ui.add(
+                                    egui::DragValue::new(secs)
+                                        .clamp_range(0..=600)
+                                        .prefix("秒読み ")
+                                        .suffix("秒"),
+                                );
+                                ui.add(
+                                    egui::DragValue::new(periods)
+                                        .clamp_range(0..=10)
+                                        .prefix("×")
+                                        .suffix("回"),
+                                );
+                            }
+                            ClockMode::Fischer { increment_secs } => {
+                                ui.add(
+                                    egui::DragValue::new(increment_secs)
+                                        .clamp_range(0..=600)
+                                        .prefix("+")
+                                        .suffix("秒/手"),
+                                );
+                            }
+                        }
+                    });
+                });
+                if model != settings.quantum_model
+                    || time_control != settings.time_control
+                    || variant != settings.variant
+                    || start != settings.start_position
+                    || handicap != settings.handicap
+                {
+                    // 駒の配置と駒種の割り当ては開始時に行うので、対局を作り直す
+                    settings.quantum_model = model;
+                    settings.variant = variant;
+                    settings.time_control = time_control;
+                    settings.start_position = start;
+                    settings.handicap = handicap;
+                    settings.clamp();
+                    let seed = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map_or(0, |d| d.as_nanos() as u64);
+                    self.game = Game::builder()
+                        .settings(settings.clone())
+                        .seed(seed)
+                        .build();
+                }
+                let mut limit = settings.max_branches_per_turn.is_some();
+                if ui.checkbox(&mut limit, "分岐数上限").changed() {
+                    settings.max_branches_per_turn = limit.then_some(1);
+                }
+                if let Some(n) = &mut settings.max_branches_per_turn {
+                    ui.add(
+                        egui::DragValue::new(n)
+                            .clamp_range(0..=20)
+                            .suffix("/ターン"),
+                    );
+                }
+                let mut pass = settings.pass_interval.is_some();
+                if ui.checkbox(&mut pass, "パス間隔").changed() {
+                    settings.pass_interval = pass.then_some(2);
+                }
+                if let Some(n) = &mut settings.pass_interval {
+                    ui.add(
+                        egui::DragValue::new(n)
+                            .clamp_range(1..=20)
+                            .suffix("手番に1回"),
+                    );
+                }
+                ui.checkbox(&mut settings.arbiter_mode, "裁定モード");
+                let mut archive = settings.archive_idle_turns.is_some();
+                if ui.checkbox(&mut archive, "無風の世界線を凍結").changed() {
+                    settings.archive_idle_turns = archive.then_some(10);
+                }
+                if let Some(n) = &mut settings.archive_idle_turns {
+                    ui.add(
+                        egui::DragValue::new(n)
+                            .clamp_range(1..=100)
+                            .suffix("ターン"),
+                    );
+                }
             });
+            // 画面では写しを編集し、変わった時だけ検証を通して対局に反映する
+            if settings != *self.game.settings() {
+                if let Err(e) = self.game.set_settings(settings) {
+                    self.ui.message = format!("設定の問題: {e}");
+                }
+            }
         });
 
+        if self.game.worlds().len() > widgets::WORLDS_PER_PAGE {
+            egui::TopBottomPanel::top("summary").show(ctx, |ui| {
+                let view = GameView::new(&self.game, self.ui.selected_world);
+                if let Some(w) = widgets::world_summary(ui, &view) {
+                    self.ui.selected_world = w;
+                    self.ui.view_time = None;
+                    self.ui.world_page = widgets::page_of(&self.game, w).unwrap_or(0);
+                    self.ui.recorder.log(format!("選択 {w}"));
+                }
+            });
+        }
+
         egui::SidePanel::left("worlds").show(ctx, |ui| {
             ui.heading("世界線一覧");
-            for (w, wl) in &self.game.worlds {
-                let snap = wl.history.last().unwrap();
-                let my_king = engine::Game::king_candidates(snap, self.game.turn).len();
-                let text = format!(
-                    "w={w} t={} king?={}{}",
-                    wl.history.len() - 1,
-                    my_king == 1,
-                    if wl.staged.is_some() {
-                        " [入力済]"
-                    } else {
-                        ""
+            let view = GameView::new(&self.game, self.ui.selected_world);
+            let errors = &self.ui.stage_errors;
+            let seat = &self.ui.seat;
+            let game = &self.game;
+            let clicked = widgets::world_list(ui, &view, &mut self.ui.world_page, &|wl| {
+                let mut s = format!(" [{}]", seat.turn_label(game, wl.w()));
+                if game.acting_world() == Some(wl.w()) {
+                    s.push_str(" [この手番の世界線]");
+                }
+                if errors.contains_key(&wl.w()) {
+                    s.push_str(" [登録拒否]");
+                }
+                s
+            });
+            let can_act = self.ui.seat.can_act(&self.game);
+            let pairs = self.game.mergeable_pairs();
+            if !pairs.is_empty() {
+                ui.separator();
+                ui.label("まとめられる世界線");
+                for (a, b) in pairs {
+                    if ui
+                        .add_enabled(can_act, egui::Button::new(format!("{b} を {a} に統合")))
+                        .clicked()
+                    {
+                        self.ui.recorder.log(format!("統合 {a} {b}"));
+                        dispatch(&mut self.game, Command::Merge { a, b }, &mut self.ui);
                     }
-                );
+                }
+            }
+            if let Some(w) = clicked {
+                self.ui.selected_world = w;
+                self.ui.view_time = None;
+                self.ui.recorder.log(format!("選択 {w}"));
+            }
+            if ui
+                .add_enabled(can_act, egui::Button::new("全入力クリア"))
+                .clicked()
+            {
+                self.game.clear_staged();
+                self.ui.recorder.log("全入力クリア");
+            }
+            ui.horizontal(|ui| {
+                if ui
+                    .add_enabled(
+                        can_act && self.game.can_undo(),
+                        egui::Button::new("一手戻す"),
+                    )
+                    .clicked()
+                {
+                    self.ui.auto_commit_at = None;
+                    match self.game.undo_turn() {
+                        Ok(_) => self.ui.message = "直前の同時確定を取り消しました".into(),
+                        Err(e) => self.ui.on_error(&self.game, &e.to_string()),
+                    }
+                    self.ui.stage_errors.clear();
+                    self.ui.recorder.log("一手戻す");
+                }
                 if ui
-                    .selectable_label(*w == self.game.selected_world, text)
+                    .add_enabled(
+                        can_act && self.game.can_redo(),
+                        egui::Button::new("やり直す"),
+                    )
                     .clicked()
                 {
-                    self.game.selected_world = *w;
+                    dispatch(&mut self.game, Command::Redo, &mut self.ui);
+                    self.ui
+                        .recorder
+                        .log(format!("やり直す → {}", self.ui.message));
                 }
+            });
+            let issues = self.game.staging_issues();
+            for issue in &issues {
+                ui.colored_label(egui::Color32::from_rgb(220, 70, 60), issue.to_string());
             }
-            if ui.button("全入力クリア").clicked() {
-                self.game.clear_staged();
+            if self.game.status().is_over() {
+                ui.heading(format!("終局: {}", self.game.status().label()));
+            } else {
+                ui.horizontal(|ui| {
+                    ui.label("申告");
+                    for claim in [Claim::Repetition, Claim::IllegalMove] {
+                        if ui.button(claim.label()).clicked() {
+                            let by = self.ui.seat.player().unwrap_or(self.game.turn());
+                            self.charge_time();
+                            dispatch(&mut self.game, Command::Claim { by, claim }, &mut self.ui);
+                            self.ui.recorder.log(format!(
+                                "{}が{}を申告 → {}",
+                                by.label(),
+                                claim.label(),
+                                self.ui.message
+                            ));
+                        }
+                    }
+                });
+                ui.horizontal(|ui| {
+                    // 担当が決まっていなければ手番の側として操作する
+                    let me = self.ui.seat.player().unwrap_or(self.game.turn());
+                    if ui.button("投了").clicked() {
+                        dispatch(&mut self.game, Command::Resign { by: me }, &mut self.ui);
+                        self.ui
+                            .recorder
+                            .log(format!("{}が投了 → {}", me.label(), self.ui.message));
+                    }
+                    match self.game.draw_offer() {
+                        Some(by) if by != me || self.ui.seat.player().is_none() => {
+                            ui.label(format!("{}から引き分けの提案", by.label()));
+                            if ui.button("合意").clicked() {
+                                let to = by.opposite();
+                                dispatch(
+                                    &mut self.game,
+                                    Command::AcceptDraw { by: to },
+                                    &mut self.ui,
+                                );
+                                self.ui.recorder.log(format!(
+                                    "{}が引き分けに合意 → {}",
+                                    to.label(),
+                                    self.ui.message
+                                ));
+                            }
+                        }
+                        Some(_) => {
+                            ui.label("引き分けを提案中");
+                        }
+                        None => {
+                            if ui.button("引き分けを提案").clicked() {
+                                dispatch(
+                                    &mut self.game,
+                                    Command::OfferDraw { by: me },
+                                    &mut self.ui,
+                                );
+                                self.ui.recorder.log(format!(
+                                    "{}が引き分けを提案 → {}",
+                                    me.label(),
+                                    self.ui.message
+                                ));
+                            }
+                        }
+                    }
+                });
             }
-            if ui.button("同時確定").clicked() {
-                self.game.commit_turn();
+            let commit =
+                ui.add_enabled(can_act && issues.is_empty(), egui::Button::new("同時確定"));
+            let hint = if self.game.status().is_over() {
+                "対局は終了しています"
+            } else if !can_act {
+                "相手の番です"
+            } else {
+                "分岐先の重複や分岐数の超過など、上に出ている入力の問題を解消してください"
+            };
+            if commit.on_disabled_hover_text(hint).clicked() {
+                self.commit();
+            }
+            ui.checkbox(&mut self.ui.auto_commit, "全入力で自動確定");
+            if let Some(at) = self.ui.auto_commit_at {
+                let left = at.saturating_duration_since(std::time::Instant::now());
+                ui.horizontal(|ui| {
+                    ui.label(format!("{:.1}秒後に確定", left.as_secs_f32()));
+                    if ui.button("取消").clicked() {
+                        self.ui.auto_commit_at = None;
+                        self.ui.auto_commit_cancelled = true;
+                        self.ui.recorder.log("自動確定を取消");
+                    }
+                });
             }
         });
 
         egui::CentralPanel::default().show(ctx, |ui| {
-            if let Some(wl) = self.game.worlds.get(&self.game.selected_world) {
-                let snap = wl.history.last().unwrap();
-                ui.heading(format!("盤面 w={}", self.game.selected_world));
-                egui::Grid::new("board").spacing([4.0, 4.0]).show(ui, |ui| {
-                    for y in 0..9 {
-                        for x in 0..9 {
-                            let txt = if let Some(p) = &snap.board[y][x] {
-                                let owner = if p.owner == Player::Black {
-                                    "▲"
-                                } else {
-                                    "△"
-                                };
-                                let body = if p.candidates.len() == 1 {
-                                    p.candidates.iter().next().unwrap().short().to_string()
-                                } else {
-                                    format!("{}候補", p.candidates.len())
-                                };
-                                format!("{}{}", owner, body)
-                            } else {
-                                "・".to_string()
-                            };
-                            ui.label(txt);
-                        }
-                        ui.end_row();
+            if let Some(snap) = self.game.present(self.ui.selected_world).cloned() {
+                ui.horizontal(|ui| {
+                    ui.heading(format!("盤面 {}", self.ui.selected_world));
+                    if ui.button("テキストでコピー").clicked() {
+                        ui.output_mut(|o| o.copied_text = snap.to_ascii());
                     }
                 });
+                let view = GameView::new(&self.game, self.ui.selected_world)
+                    .time(self.ui.view_time)
+                    .glyphs(self.ui.glyph_style.glyphs());
+                widgets::timeline(ui, &view, &mut self.ui.view_time);
+                let view = view.time(self.ui.view_time);
+                if let Some(sq) = widgets::board(ui, &view) {
+                    self.ui.inspected =
+                        view.snapshot().and_then(|s| s.piece_at(sq)).map(|p| p.id());
+                }
+                if let Some(t) = self.ui.view_time {
+                    ui.label(format!("{t} の局面を表示中 (手は現在の局面に入力)"));
+                }
 
                 ui.separator();
-                ui.label("手入力（この世界線）");
-                let input = self.inputs.entry(self.game.selected_world).or_default();
+                ui.horizontal(|ui| {
+                    ui.label("手入力（この世界線）");
+                    ui.checkbox(&mut self.ui.assisted_input, "入力補助");
+                });
+                let hand_groups: Vec<(CandidateSet, Vec<u64>)> =
+                    snap.hand_groups(self.game.turn()).into_iter().collect();
+                let input = self.ui.inputs.entry(self.ui.selected_world).or_default();
+                let turn = self.game.turn();
+                let (fmax, rmax) = (snap.files() - 1, snap.ranks() - 1);
+                let current = input.planned_move(
+                    &snap,
+                    turn,
+                    self.game.summon_available(turn),
+                    self.game.settings().cross_world_base,
+                );
+                let checks = if self.ui.assisted_input {
+                    check_fields(&self.game, self.ui.selected_world, &current)
+                } else {
+                    FieldChecks::default()
+                };
+                if self.game.settings().world_destruction {
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut input.mode_destroy, "世界線破壊");
+                        if input.mode_destroy {
+                            ui.label("対象 w");
+                            ui.add(
+                                egui::DragValue::new(&mut input.destroy_w).clamp_range(-20..=20),
+                            );
+                            ui.label("犠牲 x,y (from)");
+                        }
+                    });
+                }
+                ui.checkbox(&mut input.mode_pass, "パス (この世界線では何もしない)");
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut input.mode_observe, "観測");
+                    if input.mode_observe {
+                        ui.label("観測する駒 x,y (from)");
+                    }
+                });
                 ui.checkbox(&mut input.mode_drop, "打つ");
                 ui.horizontal(|ui| {
                     if input.mode_drop {
                         ui.label("hand_idx");
                         ui.add(egui::DragValue::new(&mut input.hand_idx).clamp_range(0..=99));
+                        if self.game.settings().teleport_drop
+                            && self.game.settings().hand_mode == HandMode::Global
+                        {
+                            ui.checkbox(&mut input.teleport, "別世界線へ");
+                            if input.teleport {
+                                ui.add(
+                                    egui::DragValue::new(&mut input.teleport_w)
+                                        .clamp_range(-20..=20)
+                                        .prefix("w"),
+                                );
+                            }
+                        }
                     } else {
                         ui.label("from x,y");
-                        ui.add(egui::DragValue::new(&mut input.from_x).clamp_range(0..=8));
-                        ui.add(egui::DragValue::new(&mut input.from_y).clamp_range(0..=8));
+                        ui.add(egui::DragValue::new(&mut input.from_x).clamp_range(0..=fmax));
+                        ui.add(egui::DragValue::new(&mut input.from_y).clamp_range(0..=rmax));
+                        ui.label(
+                            Square::new(input.from_x, input.from_y).notation(self.game.files()),
+                        );
+                        field_mark(ui, &checks.from);
                         ui.checkbox(&mut input.promote, "成り");
                     }
                     ui.label("to x,y");
-                    ui.add(egui::DragValue::new(&mut input.to_x).clamp_range(0..=8));
-                    ui.add(egui::DragValue::new(&mut input.to_y).clamp_range(0..=8));
+                    ui.add(egui::DragValue::new(&mut input.to_x).clamp_range(0..=fmax));
+                    ui.add(egui::DragValue::new(&mut input.to_y).clamp_range(0..=rmax));
+                    ui.label(Square::new(input.to_x, input.to_y).notation(self.game.files()));
+                    field_mark(ui, &checks.to);
                 });
                 ui.horizontal(|ui| {
                     ui.label("Δw");
                     ui.add(egui::DragValue::new(&mut input.delta_w).clamp_range(-20..=20));
+                    field_mark(ui, &checks.delta_w);
                     ui.label("Δt");
                     ui.add(egui::DragValue::new(&mut input.delta_t).clamp_range(-20..=20));
+                    field_mark(ui, &checks.delta_t);
+                    if self.game.settings().cross_world_base {
+                        ui.checkbox(&mut input.use_base, "起点の世界線");
+                        ui.add_enabled(
+                            input.use_base,
+                            egui::DragValue::new(&mut input.base_w)
+                                .clamp_range(-20..=20)
+                                .prefix("w"),
+                        );
+                    }
                 });
+                if self.game.summon_available(self.game.turn()) {
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut input.summon, "新世界線に金を召喚");
+                        ui.add(egui::DragValue::new(&mut input.summon_x).clamp_range(0..=fmax));
+                        ui.add(egui::DragValue::new(&mut input.summon_y).clamp_range(0..=rmax));
+                        ui.label(
+                            Square::new(input.summon_x, input.summon_y).notation(self.game.files()),
+                        );
+                    });
+                }
 
-                if ui.button("この世界線の手を登録").clicked() {
-                    let kind = if input.mode_drop {
-                        MoveKind::Drop {
-                            piece_index: input.hand_idx,
-                            to: (input.to_x, input.to_y),
+                let w = self.ui.selected_world;
+                let label = if self.ui.seat.can_act(&self.game) {
+                    "この世界線の手を登録"
+                } else {
+                    "この世界線の先行入力を予約"
+                };
+                if ui.button(label).clicked() {
+                    let turn = self.game.turn();
+                    let pm = input.planned_move(
+                        &snap,
+                        turn,
+                        self.game.summon_available(turn),
+                        self.game.settings().cross_world_base,
+                    );
+                    self.ui.recorder.log(format!(
+                        "登録 {}: {}",
+                        self.ui.selected_world,
+                        pm.notation(self.game.files())
+                    ));
+                    match self.ui.seat.submit(&mut self.game, w, pm) {
+                        Ok(staged) => {
+                            self.ui.stage_errors.remove(&w);
+                            if !staged {
+                                self.ui.recorder.log(format!("先行入力 {w}"));
+                            }
                         }
-                    } else {
-                        MoveKind::Move {
-                            from: (input.from_x, input.from_y),
-                            to: (input.to_x, input.to_y),
-                            promote: input.promote,
+                        Err(e) => {
+                            self.ui.recorder.log(format!("登録拒否 {w}: {e}"));
+                            self.ui.stage_errors.insert(w, e.to_string());
                         }
-                    };
-                    self.game.stage_move(
-                        self.game.selected_world,
-                        PlannedMove {
-                            kind,
-                            delta_w: input.delta_w,
-                            delta_t: input.delta_t,
-                        },
-                    );
+                    }
+                }
+                if let Some(pm) = self.ui.seat.premoves().get(&w) {
+                    let text = format!("先行入力: {}", pm.notation(self.game.files()));
+                    ui.horizontal(|ui| {
+                        ui.label(text);
+                        if ui.button("取消").clicked() {
+                            self.ui.seat.cancel_premove(w);
+                            self.ui.recorder.log(format!("先行入力を取消 {w}"));
+                        }
+                    });
+                }
+                if let Some(reason) = self.ui.stage_errors.get(&self.ui.selected_world) {
+                    ui.colored_label(egui::Color32::RED, format!("登録できない: {reason}"));
+                }
+                if self.ui.assisted_input {
+                    let whole = self.game.validate_move(self.ui.selected_world, &current);
+                    ui.horizontal(|ui| {
+                        ui.label("手全体");
+                        field_mark(ui, &Some(whole));
+                    });
                 }
 
                 ui.separator();
-                let hand = snap.hands.get(&self.game.turn).unwrap();
+                let hand = snap.hand(self.game.turn());
                 ui.label(format!("現在手番の持ち駒数: {}", hand.len()));
-                for (i, p) in hand.iter().enumerate() {
-                    let cands = p
-                        .candidates
+                for (i, (group, ids)) in hand_groups.iter().enumerate() {
+                    let cands = group
                         .iter()
                         .map(|c| c.short())
                         .collect::<Vec<_>>()
                         .join(",");
-                    ui.label(format!("[{i}] {cands}"));
+                    ui.label(format!("[{i}] {cands} ×{}", ids.len()));
                 }
 
-                if self.game.settings.hand_mode == HandMode::Global {
+                if self.game.settings().hand_mode == HandMode::Global {
                     ui.separator();
                     let mut cnt: std::collections::BTreeMap<PieceType, usize> =
                         std::collections::BTreeMap::new();
-                    for wl in self.game.worlds.values() {
-                        let s = wl.history.last().unwrap();
-                        for p in s.hands.get(&self.game.turn).into_iter().flatten() {
-                            for c in &p.candidates {
+                    for (_, s) in self.game.presents() {
+                        for p in s.hand(self.game.turn()) {
+                            for c in p.candidates() {
                                 *cnt.entry(*c).or_default() += 1;
                             }
                         }
@@ -221,9 +1111,216 @@ impl eframe::App for App {
                     for (k, v) in cnt {
                         ui.label(format!("{}: {}", k.short(), v));
                     }
+                    ui.collapsing("持ち駒の出自", |ui| {
+                        let turn = self.game.turn();
+                        let mut worlds_of: std::collections::BTreeMap<u64, Vec<WorldId>> =
+                            std::collections::BTreeMap::new();
+                        for (w, s) in self.game.presents() {
+                            for p in s.hand(turn) {
+                                worlds_of.entry(p.id()).or_default().push(w);
+                            }
+                        }
+                        for (w, s) in self.game.presents() {
+                            for p in s.hand(turn) {
+                                let cands: String =
+                                    p.candidates().iter().map(|c| c.short()).collect();
+                                let origin = p
+                                    .captured_at()
+                                    .map(|(cw, t)| format!("{cw} {t}手目で捕獲"))
+                                    .unwrap_or_default();
+                                let dup = if worlds_of[&p.id()].len() > 1 {
+                                    " (他の世界線にも同じ駒)"
+                                } else {
+                                    ""
+                                };
+                                ui.label(format!("{w}: #{} {cands} ← {origin}{dup}", p.id()));
+                            }
+                        }
+                    });
+                }
+            } else if let Some(wl) = self.game.archived().get(&self.ui.selected_world) {
+                ui.heading(format!("盤面 {} (凍結)", wl.w()));
+                let view = GameView::new(&self.game, wl.w())
+                    .time(self.ui.view_time)
+                    .glyphs(self.ui.glyph_style.glyphs());
+                widgets::timeline(ui, &view, &mut self.ui.view_time);
+                let view = view.time(self.ui.view_time);
+                if let Some(sq) = widgets::board(ui, &view) {
+                    self.ui.inspected =
+                        view.snapshot().and_then(|s| s.piece_at(sq)).map(|p| p.id());
                 }
+                ui.label(format!(
+                    "{}ターン目以降動きがなく凍結されました。閲覧のみ可能です。",
+                    wl.last_active()
+                ));
             }
         });
+
+        egui::Window::new("実況")
+            .open(&mut self.ui.show_narration)
+            .show(ctx, |ui| {
+                egui::ScrollArea::vertical()
+                    .stick_to_bottom(true)
+                    .show(ui, |ui| {
+                        for line in export::narration(&self.game) {
+                            ui.label(line);
+                        }
+                    });
+            });
+
+        egui::Window::new("ルール早見表")
+            .open(&mut self.ui.show_rules)
+            .show(ctx, |ui| {
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    for (title, lines) in export::rules_reference(&self.game) {
+                        egui::CollapsingHeader::new(title)
+                            .default_open(true)
+                            .show(ui, |ui| {
+                                for line in lines {
+                                    ui.label(line);
+                                }
+                            });
+                    }
+                });
+            });
+
+        egui::Window::new("統計")
+            .open(&mut self.ui.show_stats)
+            .show(ctx, |ui| {
+                for line in self.game.stats().summary_lines() {
+                    ui.label(line);
+                }
+                ui.separator();
+                for line in self.game.collapse_stats().summary_lines() {
+                    ui.label(line);
+                }
+                ui.collapsing("確定履歴", |ui| {
+                    for e in &self.game.collapse_stats().events {
+                        ui.label(format!(
+                            "t={} #{} {} ({})",
+                            e.turn,
+                            e.piece_id,
+                            e.piece_type.short(),
+                            e.cause.label()
+                        ));
+                    }
+                });
+            });
+
+        if let Some(id) = self.ui.inspected {
+            let mut open = true;
+            egui::Window::new("候補消去の根拠")
+                .open(&mut open)
+                .show(ctx, |ui| {
+                    let current = self.game.present(self.ui.selected_world).and_then(|s| {
+                        s.pieces()
+                            .map(|(_, p)| p)
+                            .chain(s.hand_pieces())
+                            .find(|p| p.id() == id)
+                    });
+                    match current {
+                        Some(p) => {
+                            let cands: String = p.candidate_labels().concat();
+                            ui.label(format!("#{id} {} 現在の候補: {cands}", p.owner().mark()));
+                        }
+                        None => {
+                            ui.label(format!("#{id} (この世界線には存在しない)"));
+                        }
+                    }
+                    ui.separator();
+                    let trail = self.game.deductions(id);
+                    if trail.is_empty() {
+                        ui.label("まだ候補は消去されていません");
+                    }
+                    for d in trail {
+                        let removed = d
+                            .removed
+                            .iter()
+                            .map(|c| c.short())
+                            .collect::<Vec<_>>()
+                            .join("/");
+                        ui.label(format!(
+                            "{}手目 {}: {} ⇒ {} ではない",
+                            d.turn, d.world, d.reason, removed
+                        ));
+                    }
+                });
+            if !open {
+                self.ui.inspected = None;
+            }
+        }
+
+        egui::Window::new("ルール検証")
+            .open(&mut self.ui.show_sandbox)
+            .show(ctx, |ui| {
+                let w = self.ui.selected_world;
+                ui.label(format!("{w} の入力中の手を、指さずに判定します"));
+                ui.checkbox(&mut self.ui.sandbox_override, "移動元の候補を上書き");
+                if self.ui.sandbox_override {
+                    ui.horizontal(|ui| {
+                        for c in PieceType::all() {
+                            let mut on = self.ui.sandbox_candidates.contains(&c);
+                            if ui.checkbox(&mut on, c.short()).changed() {
+                                if on {
+                                    self.ui.sandbox_candidates.insert(c);
+                                } else {
+                                    self.ui.sandbox_candidates.remove(&c);
+                                }
+                            }
+                        }
+                    });
+                }
+                if ui.button("判定").clicked() {
+                    let turn = self.game.turn();
+                    self.ui.sandbox_trace = match self.game.present(w) {
+                        Some(snap) => {
+                            let input = self.ui.inputs.get(&w).cloned().unwrap_or_default();
+                            let pm = input.planned_move(
+                                snap,
+                                turn,
+                                self.game.summon_available(turn),
+                                self.game.settings().cross_world_base,
+                            );
+                            let mut lines = vec![pm.notation(self.game.files())];
+                            lines.extend(
+                                self.game.trace_move(
+                                    w,
+                                    &pm,
+                                    self.ui
+                                        .sandbox_override
+                                        .then_some(&self.ui.sandbox_candidates),
+                                ),
+                            );
+                            lines
+                        }
+                        None => Vec::new(),
+                    };
+                }
+                ui.separator();
+                for line in &self.ui.sandbox_trace {
+                    ui.monospace(line);
+                }
+            });
+
+        egui::Window::new("棋譜シート")
+            .open(&mut self.ui.show_export)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("先手");
+                    ui.text_edit_singleline(&mut self.ui.kifu_info.black_name);
+                });
+                ui.horizontal(|ui| {
+                    ui.label("後手");
+                    ui.text_edit_singleline(&mut self.ui.kifu_info.white_name);
+                });
+                if ui.button("HTML出力 (kifu.html)").clicked() {
+                    let html = export::kifu_html(&self.game, &self.ui.kifu_info);
+                    self.ui.message = match std::fs::write("kifu.html", html) {
+                        Ok(()) => "kifu.html に出力しました".into(),
+                        Err(e) => format!("出力失敗: {e}"),
+                    };
+                }
+            });
     }
 }
 
@@ -0,0 +1,229 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::coords::WorldId;
+use crate::engine::PieceType;
+
+#[derive(Clone, Debug, Default)]
+pub struct WorldUsageStats {
+    pub turns: usize,
+    pub moves: usize,
+    pub spacetime_moves: usize,
+    pub branches: usize,
+    pub delta_w_hist: BTreeMap<i32, usize>,
+    pub delta_t_hist: BTreeMap<i32, usize>,
+    // w -> (生成ターン, 終了ターン)。敗北・破壊・統合のどれでも寿命はそこで終わる
+    pub world_spans: BTreeMap<WorldId, (usize, Option<usize>)>,
+    // 統合で消えた世界線。寿命は終わるが敗北には数えない
+    pub merged: BTreeSet<WorldId>,
+}
+
+impl WorldUsageStats {
+    pub(crate) fn new() -> Self {
+        let mut s = Self::default();
+        s.world_spans.insert(WorldId(0), (0, None));
+        s
+    }
+
+    pub(crate) fn record_move(&mut self, delta_w: i32, delta_t: i32) {
+        self.moves += 1;
+        if delta_w != 0 || delta_t != 0 {
+            self.spacetime_moves += 1;
+        }
+        *self.delta_w_hist.entry(delta_w).or_default() += 1;
+        *self.delta_t_hist.entry(delta_t).or_default() += 1;
+    }
+
+    pub(crate) fn record_branch(&mut self, w_new: WorldId) {
+        self.branches += 1;
+        self.world_spans.insert(w_new, (self.turns + 1, None));
+    }
+
+    pub(crate) fn record_lost(&mut self, w: WorldId) {
+        let now = self.turns + 1;
+        if let Some((_, end)) = self.world_spans.get_mut(&w) {
+            end.get_or_insert(now);
+        }
+    }
+
+    pub(crate) fn record_merged(&mut self, w: WorldId) {
+        self.record_lost(w);
+        self.merged.insert(w);
+    }
+
+    // 敗北か破壊で終わった世界線の数
+    pub fn lost_worlds(&self) -> usize {
+        self.world_spans
+            .iter()
+            .filter(|(w, (_, end))| end.is_some() && !self.merged.contains(w))
+            .count()
+    }
+
+    pub(crate) fn end_turn(&mut self) {
+        self.turns += 1;
+    }
+
+    pub fn spacetime_ratio(&self) -> f64 {
+        if self.moves == 0 {
+            return 0.0;
+        }
+        self.spacetime_moves as f64 / self.moves as f64
+    }
+
+    pub fn average_world_lifetime(&self) -> f64 {
+        if self.world_spans.is_empty() {
+            return 0.0;
+        }
+        let total: usize = self
+            .world_spans
+            .values()
+            .map(|(born, end)| end.unwrap_or(self.turns) - born)
+            .sum();
+        total as f64 / self.world_spans.len() as f64
+    }
+
+    pub fn summary_lines(&self) -> Vec<String> {
+        let hist = |h: &BTreeMap<i32, usize>| {
+            h.iter()
+                .map(|(d, n)| format!("{d:+}:{n}"))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        vec![
+            format!("確定ターン数: {}", self.turns),
+            format!("総手数: {}", self.moves),
+            format!(
+                "Δw/Δt使用手: {} ({:.1}%)",
+                self.spacetime_moves,
+                self.spacetime_ratio() * 100.0
+            ),
+            format!("分岐数: {}", self.branches),
+            format!(
+                "敗北した世界線: {} / 統合された世界線: {}",
+                self.lost_worlds(),
+                self.merged.len()
+            ),
+            format!(
+                "平均世界線寿命: {:.2} ターン",
+                self.average_world_lifetime()
+            ),
+            format!("Δw分布: {}", hist(&self.delta_w_hist)),
+            format!("Δt分布: {}", hist(&self.delta_t_hist)),
+        ]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CollapseCause {
+    Movement,
+    Drop,
+    Capture,
+    Count,
+    Summon,
+    Observation,
+    Entanglement,
+}
+
+impl CollapseCause {
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Movement => "移動",
+            Self::Drop => "打ち",
+            Self::Capture => "捕獲",
+            Self::Count => "枚数制約",
+            Self::Summon => "召喚",
+            Self::Observation => "観測",
+            Self::Entanglement => "絡み合い",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CollapseEvent {
+    pub turn: usize,
+    pub piece_id: u64,
+    pub piece_type: PieceType,
+    pub cause: CollapseCause,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CollapseStats {
+    pub events: Vec<CollapseEvent>,
+    // ターンごとの1駒あたり平均候補数（index 0 は初期局面）
+    pub uncertainty: Vec<f64>,
+    collapsed: BTreeSet<u64>,
+}
+
+impl CollapseStats {
+    pub(crate) fn is_collapsed(&self, id: u64) -> bool {
+        self.collapsed.contains(&id)
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        turn: usize,
+        piece_id: u64,
+        piece_type: PieceType,
+        cause: CollapseCause,
+    ) {
+        if self.collapsed.insert(piece_id) {
+            self.events.push(CollapseEvent {
+                turn,
+                piece_id,
+                piece_type,
+                cause,
+            });
+        }
+    }
+
+    pub(crate) fn record_uncertainty(&mut self, avg: f64) {
+        self.uncertainty.push(avg);
+    }
+
+    pub fn average_collapse_turn_by_type(&self) -> BTreeMap<PieceType, f64> {
+        let mut acc: BTreeMap<PieceType, (usize, usize)> = BTreeMap::new();
+        for e in &self.events {
+            let a = acc.entry(e.piece_type).or_default();
+            a.0 += e.turn;
+            a.1 += 1;
+        }
+        acc.into_iter()
+            .map(|(pt, (sum, n))| (pt, sum as f64 / n as f64))
+            .collect()
+    }
+
+    pub fn summary_lines(&self) -> Vec<String> {
+        let mut out = vec![format!("確定駒数: {}", self.events.len())];
+        let mut causes: BTreeMap<&'static str, usize> = BTreeMap::new();
+        for e in &self.events {
+            *causes.entry(e.cause.label()).or_default() += 1;
+        }
+        out.push(format!(
+            "確定要因: {}",
+            causes
+                .iter()
+                .map(|(c, n)| format!("{c}:{n}"))
+                .collect::<Vec<_>>()
+                .join(" ")
+        ));
+        let mut order: Vec<(PieceType, f64)> =
+            self.average_collapse_turn_by_type().into_iter().collect();
+        order.sort_by(|a, b| a.1.total_cmp(&b.1));
+        out.push(format!(
+            "確定の早い駒種: {}",
+            order
+                .iter()
+                .map(|(pt, t)| format!("{}({t:.1})", pt.short()))
+                .collect::<Vec<_>>()
+                .join(" ")
+        ));
+        out.push(format!(
+            "平均候補数の推移: {}",
+            self.uncertainty
+                .iter()
+                .map(|u| format!("{u:.2}"))
+                .collect::<Vec<_>>()
+                .join(" → ")
+        ));
+        out
+    }
+}
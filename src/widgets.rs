@@ -0,0 +1,350 @@
+use crate::coords::{Square, TimeIdx, WorldId};
+use crate::engine::{Game, Piece, PieceType, Player, Snapshot, WorldLine};
+use crate::eval;
+use crate::frontend::{GlyphSet, KanjiGlyphs};
+
+// 他の egui アプリに盤を埋め込むための部品 (feature "widgets")。
+// 部品は GameView を読んで描くだけで Game は書き換えない。クリックなどの結果は戻り値で返す
+#[derive(Clone, Copy)]
+pub struct GameView<'a> {
+    game: &'a Game,
+    world: WorldId,
+    time: Option<TimeIdx>,
+    glyphs: &'a dyn GlyphSet,
+}
+
+impl<'a> GameView<'a> {
+    pub fn new(game: &'a Game, world: WorldId) -> Self {
+        Self {
+            game,
+            world,
+            time: None,
+            glyphs: &KanjiGlyphs,
+        }
+    }
+    // None なら現在の局面
+    pub fn time(mut self, time: Option<TimeIdx>) -> Self {
+        self.time = time;
+        self
+    }
+    pub fn glyphs(mut self, glyphs: &'a dyn GlyphSet) -> Self {
+        self.glyphs = glyphs;
+        self
+    }
+    pub fn game(&self) -> &'a Game {
+        self.game
+    }
+    pub fn world(&self) -> WorldId {
+        self.world
+    }
+    // 凍結中の世界線も見られる
+    pub fn world_line(&self) -> Option<&'a WorldLine> {
+        self.game
+            .world(self.world)
+            .or_else(|| self.game.archived().get(&self.world))
+    }
+    // 表示する局面。time が履歴の範囲外なら現在の局面
+    pub fn snapshot(&self) -> Option<&'a Snapshot> {
+        let wl = self.world_line()?;
+        Some(
+            self.time
+                .and_then(|t| wl.history().get(t.0))
+                .unwrap_or(wl.present()),
+        )
+    }
+}
+
+// 盤 (局面の広さのまま)。クリックされたマスを返す
+pub fn board(ui: &mut egui::Ui, view: &GameView) -> Option<Square> {
+    let Some(snap) = view.snapshot() else {
+        ui.label(format!("世界線 {} がない", view.world));
+        return None;
+    };
+    let glyphs = view.glyphs;
+    let mut clicked = None;
+    egui::Grid::new(("board", view.world))
+        .spacing([4.0, 4.0])
+        .show(ui, |ui| {
+            for y in 0..snap.ranks() {
+                for x in 0..snap.files() {
+                    let sq = Square::new(x, y);
+                    let piece = snap.piece_at(sq);
+                    let cell = if glyphs.shaped() {
+                        let (rect, cell) =
+                            ui.allocate_exact_size(egui::vec2(22.0, 22.0), egui::Sense::click());
+                        match piece {
+                            Some(p) => paint_piece(ui.painter(), rect, p, glyphs),
+                            None => {
+                                ui.painter()
+                                    .circle_filled(rect.center(), 1.5, egui::Color32::GRAY);
+                            }
+                        }
+                        match piece {
+                            Some(p) => cell.on_hover_text(glyphs.cell(p)),
+                            None => cell,
+                        }
+                    } else {
+                        let txt = piece
+                            .map(|p| glyphs.cell(p))
+                            .unwrap_or_else(|| "・".to_string());
+                        ui.add(egui::Label::new(txt).sense(egui::Sense::click()))
+                    };
+                    if cell.clicked() {
+                        clicked = Some(sq);
+                    }
+                }
+                ui.end_row();
+            }
+        });
+    clicked
+}
+
+// 記号表示で駒を図形として描く。先手は青、後手は赤
+pub fn paint_piece(painter: &egui::Painter, rect: egui::Rect, p: &Piece, glyphs: &dyn GlyphSet) {
+    let color = player_color(p.owner());
+    let c = rect.center();
+    let r = rect.width() * 0.4;
+    let stroke = egui::Stroke::new(2.0, color);
+    let pt = |dx: f32, dy: f32| c + egui::vec2(dx * r, dy * r);
+    let kind = p
+        .candidates()
+        .iter()
+        .next()
+        .filter(|_| p.candidates().len() == 1);
+    match kind {
+        None => {
+            painter.circle_stroke(c, r, egui::Stroke::new(1.0, color));
+            painter.text(
+                c,
+                egui::Align2::CENTER_CENTER,
+                glyphs.unknown(p.candidates().len()),
+                egui::FontId::proportional(10.0),
+                color,
+            );
+        }
+        Some(PieceType::King) => {
+            painter.circle_stroke(c, r, stroke);
+            painter.circle_filled(c, r * 0.5, color);
+        }
+        Some(PieceType::Rook) => {
+            painter.line_segment([pt(-1.0, 0.0), pt(1.0, 0.0)], stroke);
+            painter.line_segment([pt(0.0, -1.0), pt(0.0, 1.0)], stroke);
+        }
+        Some(PieceType::Bishop) => {
+            painter.line_segment([pt(-0.8, -0.8), pt(0.8, 0.8)], stroke);
+            painter.line_segment([pt(-0.8, 0.8), pt(0.8, -0.8)], stroke);
+        }
+        Some(t @ (PieceType::Gold | PieceType::Silver)) => {
+            let diamond = vec![pt(0.0, -1.0), pt(1.0, 0.0), pt(0.0, 1.0), pt(-1.0, 0.0)];
+            let fill = if *t == PieceType::Gold {
+                color
+            } else {
+                egui::Color32::TRANSPARENT
+            };
+            painter.add(egui::Shape::convex_polygon(diamond, fill, stroke));
+        }
+        Some(PieceType::Knight) => {
+            let f = p.owner().forward_sign() as f32;
+            let tri = vec![pt(0.0, f), pt(0.9, -0.7 * f), pt(-0.9, -0.7 * f)];
+            painter.add(egui::Shape::convex_polygon(tri, color, stroke));
+        }
+        Some(PieceType::Lance) => {
+            let f = p.owner().forward_sign() as f32;
+            painter.line_segment([pt(0.0, -f), pt(0.0, f)], stroke);
+            painter.line_segment([pt(0.0, f), pt(-0.5, 0.4 * f)], stroke);
+            painter.line_segment([pt(0.0, f), pt(0.5, 0.4 * f)], stroke);
+        }
+        Some(PieceType::Pawn) => {
+            painter.circle_filled(c, r * 0.35, color);
+        }
+    }
+}
+
+fn player_color(p: Player) -> egui::Color32 {
+    match p {
+        Player::Black => egui::Color32::from_rgb(60, 110, 220),
+        Player::White => egui::Color32::from_rgb(220, 70, 60),
+    }
+}
+
+// 一覧の1ページに並べる世界線の数。これを超えるとページ送りと概要の帯を使う
+pub const WORLDS_PER_PAGE: usize = 8;
+
+// 稼働中と凍結中の世界線の一覧。view の世界線を選択中として表示し、クリックされた世界線を返す。
+// annotate は各行の末尾に足す文字列 (埋め込む側の入力状態など)。
+// 稼働中の世界線が WORLDS_PER_PAGE を超えると page 番目のページだけを表示する
+pub fn world_list(
+    ui: &mut egui::Ui,
+    view: &GameView,
+    page: &mut usize,
+    annotate: &dyn Fn(&WorldLine) -> String,
+) -> Option<WorldId> {
+    let game = view.game;
+    let turn = game.turn();
+    let mut clicked = None;
+    let pages = game.worlds().len().div_ceil(WORLDS_PER_PAGE).max(1);
+    *page = (*page).min(pages - 1);
+    if pages > 1 {
+        ui.horizontal(|ui| {
+            if ui.add_enabled(*page > 0, egui::Button::new("◀")).clicked() {
+                *page -= 1;
+            }
+            ui.label(format!("{}/{}", *page + 1, pages));
+            if ui
+                .add_enabled(*page + 1 < pages, egui::Button::new("▶"))
+                .clicked()
+            {
+                *page += 1;
+            }
+        });
+    }
+    for (w, wl) in game
+        .worlds()
+        .iter()
+        .skip(*page * WORLDS_PER_PAGE)
+        .take(WORLDS_PER_PAGE)
+    {
+        let my_king = Game::king_candidates(wl.present(), turn).len();
+        let check = game.is_in_check(wl.present(), turn);
+        let decided = match wl.winner() {
+            Some(p) => format!(" [{}勝]", p.label()),
+            None if wl.is_drawn() => " [持将棋]".to_string(),
+            None => String::new(),
+        };
+        let text = format!(
+            "{} {}{} king?={}{}{}{}{}",
+            wl.w(),
+            wl.present_t(),
+            branch_label(wl),
+            my_king == 1,
+            if check { " 王手" } else { "" },
+            decided,
+            if wl.staged().is_some() {
+                " [入力済]"
+            } else {
+                ""
+            },
+            annotate(wl)
+        );
+        ui.horizontal(|ui| {
+            let score = eval::material_balance(wl.present());
+            let color = if score > 0.5 {
+                player_color(Player::Black)
+            } else if score < -0.5 {
+                player_color(Player::White)
+            } else {
+                egui::Color32::GRAY
+            };
+            ui.label(
+                egui::RichText::new(format!("{score:+.1}"))
+                    .color(color)
+                    .monospace(),
+            );
+            if ui.selectable_label(*w == view.world, text).clicked() {
+                clicked = Some(*w);
+            }
+        });
+    }
+    if !game.archived().is_empty() {
+        ui.separator();
+        ui.label("凍結世界線");
+        for (w, wl) in game.archived() {
+            let text = format!(
+                "{} {} ({}){}",
+                w,
+                wl.present_t(),
+                if wl.is_lost() { "王喪失" } else { "凍結" },
+                branch_label(wl)
+            );
+            if ui.selectable_label(*w == view.world, text).clicked() {
+                clicked = Some(*w);
+            }
+        }
+    }
+    clicked
+}
+
+// 全世界線を1行ずつの小さな札にまとめた帯。王手は赤、入力済は緑、決着済は灰で示す。
+// クリックされた世界線を返す
+pub fn world_summary(ui: &mut egui::Ui, view: &GameView) -> Option<WorldId> {
+    let game = view.game;
+    let turn = game.turn();
+    let mut clicked = None;
+    ui.horizontal_wrapped(|ui| {
+        for (w, wl) in game.worlds() {
+            let check = game.is_in_check(wl.present(), turn);
+            let (mark, color) = if wl.is_decided() {
+                ("済", egui::Color32::GRAY)
+            } else if check {
+                ("!", egui::Color32::from_rgb(220, 70, 60))
+            } else if wl.staged().is_some() {
+                ("✓", egui::Color32::from_rgb(60, 160, 80))
+            } else {
+                ("・", ui.visuals().text_color())
+            };
+            let chip = egui::RichText::new(format!("{w}{mark}"))
+                .color(color)
+                .monospace();
+            let hover = format!(
+                "{} {}{}{}",
+                w,
+                wl.present_t(),
+                if check { " 王手" } else { "" },
+                if wl.staged().is_some() {
+                    " 入力済"
+                } else {
+                    ""
+                }
+            );
+            if ui
+                .selectable_label(*w == view.world, chip)
+                .on_hover_text(hover)
+                .clicked()
+            {
+                clicked = Some(*w);
+            }
+        }
+    });
+    clicked
+}
+
+// w が一覧の何ページ目にあるか。稼働中でなければ None
+pub fn page_of(game: &Game, w: WorldId) -> Option<usize> {
+    game.worlds()
+        .keys()
+        .position(|k| *k == w)
+        .map(|i| i / WORLDS_PER_PAGE)
+}
+
+// 分岐の起点。最初の世界線は空文字列
+fn branch_label(wl: &WorldLine) -> String {
+    wl.branch_point()
+        .map(|(w, t)| format!(" ({w} {t} から)"))
+        .unwrap_or_default()
+}
+
+// view の世界線の履歴をたどるスライダー。None は現在の局面。値が変わったら true
+pub fn timeline(ui: &mut egui::Ui, view: &GameView, time: &mut Option<TimeIdx>) -> bool {
+    let Some(wl) = view.world_line() else {
+        return false;
+    };
+    let present = wl.present_t().0;
+    let mut t = time.map_or(present, |t| t.0.min(present));
+    ui.horizontal(|ui| {
+        ui.label("時間");
+        ui.add_enabled(
+            present > 0,
+            egui::Slider::new(&mut t, 0..=present).prefix("t"),
+        );
+        if ui
+            .add_enabled(t != present, egui::Button::new("現在"))
+            .clicked()
+        {
+            t = present;
+        }
+    });
+    let next = (t != present).then_some(TimeIdx(t));
+    let changed = next != *time;
+    *time = next;
+    changed
+}
@@ -0,0 +1,2001 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use quantum_spacetime_shogi::prelude::*;
+
+const W0: WorldId = WorldId(0);
+
+fn mv(from: (usize, usize), to: (usize, usize)) -> PlannedMove {
+    PlannedMove {
+        kind: MoveKind::Move {
+            from: Square::new(from.0, from.1),
+            to: Square::new(to.0, to.1),
+            promote: false,
+        },
+        delta_w: 0,
+        delta_t: 0,
+        summon: None,
+        base_world: None,
+    }
+}
+
+fn one(pt: PieceType) -> BTreeSet<PieceType> {
+    BTreeSet::from([pt])
+}
+
+fn play(g: &mut Game, w: WorldId, pm: PlannedMove) -> Result<Vec<GameEvent>, EngineError> {
+    g.stage_move(w, pm)
+        .map_err(|error| EngineError::Illegal { w, error })?;
+    g.commit_turn()
+}
+
+fn classical() -> Settings {
+    Settings {
+        start_position: StartPosition::Classical,
+        ..Settings::default()
+    }
+}
+
+// 後手玉 (4,0) を先手の金 (4,1) が取れる局面
+fn king_capture_position() -> PositionBuilder {
+    PositionBuilder::new()
+        .piece(W0, Square::new(4, 8), Player::Black, one(PieceType::King))
+        .piece(W0, Square::new(4, 0), Player::White, one(PieceType::King))
+        .piece(W0, Square::new(4, 1), Player::Black, one(PieceType::Gold))
+}
+
+#[test]
+fn capturing_a_known_king_commits() {
+    let mut g = Game::from_position(king_capture_position()).unwrap();
+    assert!(play(&mut g, W0, mv((4, 1), (4, 0))).is_ok());
+    assert_eq!(g.record().len(), 1);
+}
+
+#[test]
+fn captured_king_leaves_play_instead_of_entering_hand() {
+    let mut g = Game::from_position(king_capture_position()).unwrap();
+    play(&mut g, W0, mv((4, 1), (4, 0))).unwrap();
+    let s = g.present(W0).unwrap();
+    assert!(s.hand(Player::Black).is_empty());
+    assert!(s.hand_pieces().all(|p| !p.candidates().is_empty()));
+    assert!(Game::king_candidates(s, Player::White).is_empty());
+}
+
+#[test]
+fn capturing_the_only_king_candidate_ends_the_game() {
+    let b = PositionBuilder::new()
+        .piece(W0, Square::new(4, 8), Player::Black, one(PieceType::King))
+        .piece(
+            W0,
+            Square::new(4, 0),
+            Player::White,
+            BTreeSet::from([PieceType::King, PieceType::Gold]),
+        )
+        .piece(W0, Square::new(4, 1), Player::Black, one(PieceType::Gold));
+    let mut g = Game::from_position(b).unwrap();
+    let events = play(&mut g, W0, mv((4, 1), (4, 0))).unwrap();
+    assert!(events.contains(&GameEvent::GameOver {
+        result: GameResult::Win(Player::Black)
+    }));
+    assert_eq!(g.status(), GameStatus::BlackWins);
+    assert!(g.status().is_over());
+    let _ = g.stage_move(W0, mv((4, 8), (4, 7)));
+    assert!(g.commit_turn().is_err());
+}
+
+#[test]
+fn capturing_a_known_king_ends_the_game() {
+    let mut g = Game::from_position(king_capture_position()).unwrap();
+    play(&mut g, W0, mv((4, 1), (4, 0))).unwrap();
+    assert_eq!(g.status(), GameStatus::BlackWins);
+    assert_eq!(g.world(W0).unwrap().winner(), Some(Player::Black));
+}
+
+#[test]
+fn undo_replays_from_the_settings_the_game_started_with() {
+    let mut g = Game::new(classical());
+    play(&mut g, W0, mv((6, 6), (6, 5))).unwrap();
+    g.set_settings(Settings {
+        max_worlds: 2,
+        past_only: true,
+        ..classical()
+    })
+    .unwrap();
+    assert!(g.undo_turn().unwrap());
+    let s = g.present(W0).unwrap();
+    assert_eq!(s.pieces().count(), 40);
+    assert!(s.piece_at(Square::new(6, 6)).is_some());
+    assert_eq!(g.settings().max_worlds, 2);
+}
+
+#[test]
+fn settings_changes_mid_game_are_validated() {
+    let mut g = Game::new(classical());
+    play(&mut g, W0, mv((6, 6), (6, 5))).unwrap();
+    let cases = [
+        (
+            Settings {
+                max_worlds: 0,
+                ..classical()
+            },
+            SettingsError::NoWorlds,
+        ),
+        (
+            Settings {
+                teleport_drop: true,
+                ..classical()
+            },
+            SettingsError::TeleportNeedsGlobalHand,
+        ),
+        (
+            Settings {
+                variant: Variant::Minishogi,
+                ..classical()
+            },
+            SettingsError::FixedAtStart,
+        ),
+    ];
+    for (settings, error) in cases {
+        assert_eq!(g.set_settings(settings), Err(error));
+    }
+    assert!(g.settings() == &classical());
+    g.set_settings(Settings {
+        turn_mode: TurnMode::Sequential,
+        ..classical()
+    })
+    .unwrap();
+    assert_eq!(g.settings().turn_mode, TurnMode::Sequential);
+}
+
+#[test]
+fn undo_and_redo_restore_the_same_turn() {
+    let mut g = Game::new(Settings::default());
+    play(&mut g, W0, mv((6, 6), (6, 5))).unwrap();
+    let after = g.present(W0).unwrap().position_hash();
+    assert!(g.undo_turn().unwrap());
+    assert_eq!(g.turn(), Player::Black);
+    assert!(g.record().is_empty());
+    assert!(g.redo_turn().unwrap().is_some());
+    assert_eq!(g.turn(), Player::White);
+    assert_eq!(g.present(W0).unwrap().position_hash(), after);
+}
+
+#[test]
+fn undo_is_refused_after_resignation() {
+    let mut g = Game::new(Settings::default());
+    play(&mut g, W0, mv((6, 6), (6, 5))).unwrap();
+    g.resign(Player::White).unwrap();
+    assert!(!g.can_undo());
+    assert!(matches!(g.undo_turn(), Err(EngineError::UndoAfterResult)));
+    assert_eq!(g.status(), GameStatus::BlackWins);
+}
+
+#[test]
+fn undo_reopens_a_game_ended_by_the_last_turn() {
+    let mut g = Game::from_position(king_capture_position()).unwrap();
+    play(&mut g, W0, mv((4, 1), (4, 0))).unwrap();
+    assert!(g.can_undo());
+    assert!(g.undo_turn().unwrap());
+    assert_eq!(g.status(), GameStatus::Ongoing);
+}
+
+fn legal(g: &Game, from: (usize, usize), to: (usize, usize)) -> bool {
+    play(&mut g.clone(), W0, mv(from, to)).is_ok()
+}
+
+#[test]
+fn hidden_types_are_drawn_from_each_piece_candidates() {
+    use PieceType::*;
+    // 後手は一式すべて、先手は王と、飛角銀を候補の重なる3枚に配る
+    let settings = Settings {
+        variant: Variant::Minishogi,
+        quantum_model: QuantumModel::Hidden,
+        ..Settings::default()
+    };
+    for seed in 0..16 {
+        let b = PositionBuilder::new()
+            .settings(settings.clone())
+            .seed(seed)
+            .piece(W0, Square::new(4, 4), Player::Black, one(King))
+            .piece(W0, Square::new(0, 0), Player::White, one(King))
+            .piece(W0, Square::new(1, 0), Player::White, one(Rook))
+            .piece(W0, Square::new(4, 0), Player::White, one(Bishop))
+            .piece(W0, Square::new(0, 1), Player::White, one(Gold))
+            .piece(W0, Square::new(1, 1), Player::White, one(Silver))
+            .piece(W0, Square::new(2, 1), Player::White, one(Pawn))
+            .piece(
+                W0,
+                Square::new(2, 3),
+                Player::Black,
+                BTreeSet::from([Rook, Bishop]),
+            )
+            .piece(
+                W0,
+                Square::new(0, 3),
+                Player::Black,
+                BTreeSet::from([Rook, Silver]),
+            )
+            .piece(
+                W0,
+                Square::new(4, 2),
+                Player::Black,
+                BTreeSet::from([Bishop, Silver]),
+            );
+        let g = Game::from_position(b).unwrap();
+        // どの駒も、候補の片方だけが指せる2手のうちちょうど1手が指せる
+        let pairs = [
+            (((2, 3), (2, 2)), ((2, 3), (1, 2))),
+            (((0, 3), (1, 3)), ((0, 3), (1, 2))),
+            (((4, 2), (4, 1)), ((4, 2), (2, 0))),
+        ];
+        for ((f1, t1), (f2, t2)) in pairs {
+            assert_ne!(legal(&g, f1, t1), legal(&g, f2, t2), "seed {seed}: {f1:?}");
+        }
+    }
+}
+
+#[test]
+fn worlds_with_ordinary_moves_are_not_archived_as_idle() {
+    let settings = Settings {
+        archive_idle_turns: Some(2),
+        ..Settings::default()
+    };
+    let mut g = Game::new(settings);
+    let branch = PlannedMove {
+        delta_w: 1,
+        ..mv((4, 6), (4, 5))
+    };
+    play(&mut g, W0, branch).unwrap();
+    let worlds: Vec<WorldId> = g.worlds().keys().copied().collect();
+    assert_eq!(worlds.len(), 2);
+    // 両方の世界線で端の駒を1マスずつ進め続ける
+    for i in 0..4 {
+        for &w in &worlds {
+            let pm = if g.turn() == Player::Black {
+                mv((0, 6 - i / 2), (0, 5 - i / 2))
+            } else {
+                mv((8, 2 + i / 2), (8, 3 + i / 2))
+            };
+            g.stage_move(w, pm).unwrap();
+        }
+        g.commit_turn().unwrap();
+    }
+    assert_eq!(g.worlds().len(), 2);
+    assert!(g.archived().is_empty());
+}
+
+#[test]
+fn squares_are_labelled_from_the_board_width() {
+    let mini = Variant::Minishogi.board_size();
+    assert_eq!(Square::new(0, 0).notation(mini.0), "5一");
+    assert_eq!(Square::new(4, 4).notation(mini.0), "1五");
+    assert_eq!(Square::new(2, 6).notation(9), "7七");
+    assert!(!Square::new(5, 0).is_on_board(mini));
+    assert_eq!(Square::new(4, 4).offset(1, 0, mini), None);
+    assert_eq!(
+        quantum_spacetime_shogi::frontend::parse_square("55", mini),
+        Some(Square::new(0, 4))
+    );
+    assert_eq!(
+        quantum_spacetime_shogi::frontend::parse_square("65", mini),
+        None
+    );
+
+    let settings = Settings {
+        variant: Variant::Minishogi,
+        ..classical()
+    };
+    let mut g = Game::new(settings);
+    let pm = mv((0, 3), (0, 2));
+    assert_eq!(pm.notation(g.files()), "5四→5三");
+    play(&mut g, W0, pm).unwrap();
+    let narration = quantum_spacetime_shogi::export::narration(&g);
+    assert!(narration[0].contains("5四→5三"), "{narration:?}");
+    // ルール表の変位も5×5の盤の中央から数える
+    let standard = Game::new(classical());
+    for pt in [PieceType::Rook, PieceType::Knight] {
+        assert_eq!(g.move_offsets(pt, false), standard.move_offsets(pt, false));
+    }
+}
+
+fn violations(b: PositionBuilder) -> Vec<PositionViolation> {
+    match Game::from_position(b) {
+        Err(PositionError::Invalid { problems, .. }) => problems,
+        other => panic!("{:?}", other.err()),
+    }
+}
+
+#[test]
+fn position_violations_name_each_broken_rule() {
+    use PieceType::*;
+    let b = PositionBuilder::new()
+        .piece(W0, Square::new(4, 8), Player::Black, one(King))
+        .piece(W0, Square::new(0, 8), Player::Black, one(King))
+        .piece(
+            W0,
+            Square::new(4, 0),
+            Player::White,
+            BTreeSet::from([Gold, Silver]),
+        )
+        .piece(W0, Square::new(1, 0), Player::Black, one(Pawn))
+        .piece(W0, Square::new(2, 6), Player::Black, one(Pawn))
+        .piece(W0, Square::new(2, 5), Player::Black, one(Pawn));
+    let v = violations(b);
+    for expected in [
+        PositionViolation::ManyKings {
+            player: Player::Black,
+            count: 2,
+        },
+        PositionViolation::NoKing(Player::White),
+        PositionViolation::Stuck(Square::new(1, 0)),
+        PositionViolation::DoublePawn {
+            player: Player::Black,
+            file: 2,
+        },
+    ] {
+        assert!(v.contains(&expected), "{expected:?} not in {v:?}");
+    }
+    assert_eq!(v.len(), 4, "{v:?}");
+}
+
+#[test]
+fn positions_are_checked_against_the_variant_army() {
+    let settings = Settings {
+        variant: Variant::Minishogi,
+        ..Settings::default()
+    };
+    // 5五将棋の一式に金は1枚だけ
+    let b = PositionBuilder::new()
+        .settings(settings)
+        .piece(W0, Square::new(2, 4), Player::Black, one(PieceType::King))
+        .piece(W0, Square::new(2, 0), Player::White, one(PieceType::King))
+        .piece(W0, Square::new(0, 3), Player::Black, one(PieceType::Gold))
+        .piece(W0, Square::new(4, 3), Player::Black, one(PieceType::Gold))
+        .piece(W0, Square::new(1, 3), Player::Black, one(PieceType::Lance));
+    let v = violations(b);
+    assert!(v.contains(&PositionViolation::TooMany {
+        player: Player::Black,
+        piece: PieceType::Gold,
+        count: 2,
+        limit: 1,
+    }));
+    assert!(v.iter().any(|p| matches!(
+        p,
+        PositionViolation::NotInVariant {
+            piece: PieceType::Lance,
+            ..
+        }
+    )));
+}
+
+fn small_minishogi() -> Settings {
+    Settings {
+        variant: Variant::Minishogi,
+        max_worlds: 2,
+        max_time_jump: 1,
+        ..classical()
+    }
+}
+
+// 盤上の駒の移動を総当たりし、stage_move が受け付けるものを集める
+fn brute_force_moves(g: &Game, w: WorldId) -> BTreeSet<String> {
+    let s = g.present(w).unwrap();
+    let (files, ranks) = (s.files(), s.ranks());
+    let max_w = g.settings().max_worlds as i32;
+    let mut out = BTreeSet::new();
+    for (from, p) in s.pieces() {
+        if p.owner() != g.turn() {
+            continue;
+        }
+        for (file, rank) in (0..files).flat_map(|f| (0..ranks).map(move |r| (f, r))) {
+            for promote in [false, true] {
+                for dw in -max_w..=max_w {
+                    for dt in -g.settings().max_time_jump..=0 {
+                        let pm = PlannedMove {
+                            kind: MoveKind::Move {
+                                from,
+                                to: Square::new(file, rank),
+                                promote,
+                            },
+                            delta_w: dw,
+                            delta_t: dt,
+                            summon: None,
+                            base_world: None,
+                        };
+                        if g.clone().stage_move(w, pm.clone()).is_ok() {
+                            out.insert(format!("{pm:?}"));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    out
+}
+
+#[test]
+fn legal_moves_match_what_stage_and_commit_accept() {
+    let mut g = Game::new(small_minishogi());
+    for _ in 0..2 {
+        let legal = g.legal_moves(W0);
+        let listed: BTreeSet<String> = legal
+            .iter()
+            .filter(|pm| matches!(pm.kind, MoveKind::Move { .. }))
+            .map(|pm| format!("{pm:?}"))
+            .collect();
+        assert_eq!(listed, brute_force_moves(&g, W0));
+        for pm in &legal {
+            let mut h = g.clone();
+            assert!(play(&mut h, W0, pm.clone()).is_ok(), "{pm:?}");
+        }
+        let first = legal
+            .into_iter()
+            .find(|pm| pm.delta_w == 0 && pm.delta_t == 0)
+            .unwrap();
+        play(&mut g, W0, first).unwrap();
+    }
+}
+
+#[test]
+fn legal_moves_follow_the_world_id_policy() {
+    let branch_to_w1 = |policy: WorldIdPolicy| {
+        let settings = Settings {
+            world_id_policy: policy,
+            max_worlds: 4,
+            ..small_minishogi()
+        };
+        let mut g = Game::new(settings);
+        let branch = g
+            .legal_moves(W0)
+            .into_iter()
+            .find(|pm| pm.delta_w == 1)
+            .unwrap();
+        play(&mut g, W0, branch).unwrap();
+        assert!(g.world(WorldId(1)).is_some());
+        // w1 は使用中なので、Δw+1 の分岐は番号の決め方しだい
+        g.legal_moves(W0).iter().any(|pm| pm.delta_w == 1)
+    };
+    assert!(!branch_to_w1(WorldIdPolicy::Exact));
+    assert!(branch_to_w1(WorldIdPolicy::SignedDirection));
+}
+
+#[test]
+fn legal_moves_offer_cross_world_bases() {
+    let settings = Settings {
+        cross_world_base: true,
+        world_id_policy: WorldIdPolicy::SignedDirection,
+        max_worlds: 4,
+        ..small_minishogi()
+    };
+    let mut g = Game::new(settings);
+    let branch = g
+        .legal_moves(W0)
+        .into_iter()
+        .find(|pm| pm.delta_w == 1)
+        .unwrap();
+    play(&mut g, W0, branch).unwrap();
+    let w1 = WorldId(1);
+    let crossed: Vec<PlannedMove> = g
+        .legal_moves(w1)
+        .into_iter()
+        .filter(|pm| pm.base_world == Some(W0))
+        .collect();
+    assert!(!crossed.is_empty());
+    // 同時に確定するので w0 にも分岐しない手を入れる
+    let stay = g
+        .legal_moves(W0)
+        .into_iter()
+        .find(|pm| pm.delta_w == 0 && pm.delta_t == 0)
+        .unwrap();
+    for pm in crossed {
+        let mut h = g.clone();
+        h.stage_move(W0, stay.clone()).unwrap();
+        let r = play(&mut h, w1, pm.clone());
+        assert!(r.is_ok(), "{pm:?} {r:?}");
+    }
+}
+
+#[test]
+fn running_out_of_time_ends_the_game_on_the_clock() {
+    let secs = std::time::Duration::from_secs;
+    let mut g = Game::new(Settings {
+        time_control: Some(TimeControl {
+            main_secs: 10,
+            mode: ClockMode::Byoyomi {
+                secs: 5,
+                periods: 1,
+            },
+        }),
+        ..classical()
+    });
+    // 持ち時間を使い切っても秒読みが残っていれば続く。確定すると秒読みは読み直し
+    assert!(g.tick_clock(secs(12)).is_empty());
+    assert_eq!(g.clock().unwrap().period_left(Player::Black), secs(3));
+    play(&mut g, W0, mv((0, 6), (0, 5))).unwrap();
+    assert_eq!(g.clock().unwrap().period_left(Player::Black), secs(5));
+    let events = g.tick_clock(secs(16));
+    assert!(events.contains(&GameEvent::Flagged {
+        player: Player::White
+    }));
+    assert_eq!(g.status(), GameStatus::BlackWins);
+    // 終局後は時計も止まり、申告もできない
+    assert!(g.tick_clock(secs(1)).is_empty());
+    assert_eq!(
+        g.claim(Player::White, Claim::Repetition),
+        Err(ClaimError::GameOver)
+    );
+}
+
+#[test]
+fn builder_start_position_wins_over_settings_in_any_order() {
+    let minishogi = Settings {
+        variant: Variant::Minishogi,
+        ..Settings::default()
+    };
+    let g = GameBuilder::new()
+        .start_position(StartPosition::Classical)
+        .settings(minishogi)
+        .seed(3)
+        .build();
+    assert_eq!(g.settings().start_position, StartPosition::Classical);
+    let s = g.present(W0).unwrap();
+    assert_eq!(s.pieces().count(), 12);
+    assert!(s.pieces().all(|(_, p)| p.candidates().len() == 1));
+}
+
+fn still(kind: MoveKind) -> PlannedMove {
+    PlannedMove {
+        kind,
+        ..mv((0, 0), (0, 0))
+    }
+}
+
+// その世界線に留まる、from の駒の移動先
+fn destinations(g: &Game, from: Square) -> BTreeSet<Square> {
+    g.legal_moves(W0)
+        .into_iter()
+        .filter(|pm| pm.delta_w == 0 && pm.delta_t == 0)
+        .filter_map(|pm| match pm.kind {
+            MoveKind::Move { from: f, to, .. } if f == from => Some(to),
+            _ => None,
+        })
+        .collect()
+}
+
+fn quantum_minishogi(model: QuantumModel, seed: u64) -> Game {
+    GameBuilder::new()
+        .settings(Settings {
+            variant: Variant::Minishogi,
+            quantum_model: model,
+            ..Settings::default()
+        })
+        .seed(seed)
+        .build()
+}
+
+#[test]
+fn hidden_model_admits_only_moves_of_the_true_type() {
+    let mut narrower = false;
+    for seed in 0..8 {
+        let lazy = quantum_minishogi(QuantumModel::Lazy, seed);
+        let hidden = quantum_minishogi(QuantumModel::Hidden, seed);
+        let s = hidden.present(W0).unwrap();
+        for (sq, p) in s.pieces().filter(|(_, p)| p.owner() == Player::Black) {
+            assert!(p.candidates().len() > 1);
+            let (l, h) = (destinations(&lazy, sq), destinations(&hidden, sq));
+            assert!(h.is_subset(&l), "seed {seed}: {sq:?}");
+            narrower |= h.len() < l.len();
+            // 観測で明かされた駒種でも、指せる手は変わらない
+            let mut seen = hidden.clone();
+            play(&mut seen, W0, still(MoveKind::Observe { at: sq })).unwrap();
+            play(&mut seen, W0, still(MoveKind::Pass)).unwrap();
+            assert_eq!(destinations(&seen, sq), h, "seed {seed}: {sq:?}");
+        }
+    }
+    assert!(narrower);
+}
+
+#[test]
+fn hidden_observation_reveals_the_same_type_whenever_it_is_made() {
+    let observed = |g: &mut Game, sq: Square| {
+        play(g, W0, still(MoveKind::Observe { at: sq })).unwrap();
+        g.present(W0)
+            .unwrap()
+            .piece_at(sq)
+            .unwrap()
+            .candidates()
+            .clone()
+    };
+    for seed in 0..8 {
+        let g = quantum_minishogi(QuantumModel::Hidden, seed);
+        let squares: Vec<Square> = g
+            .present(W0)
+            .unwrap()
+            .pieces()
+            .filter(|(_, p)| p.owner() == Player::Black)
+            .map(|(sq, _)| sq)
+            .collect();
+        for sq in squares {
+            let now = observed(&mut g.clone(), sq);
+            let mut later = g.clone();
+            play(&mut later, W0, still(MoveKind::Pass)).unwrap();
+            play(&mut later, W0, still(MoveKind::Pass)).unwrap();
+            assert_eq!(now.len(), 1);
+            assert_eq!(observed(&mut later, sq), now, "seed {seed}: {sq:?}");
+        }
+    }
+}
+
+// 先手の飛車が4筋と5筋を往復して王手をかけ続け、後手玉が逃げ続ける
+fn chase(rule: RepetitionRule) -> Game {
+    let settings = Settings {
+        repetition_rule: rule,
+        ..Settings::default()
+    };
+    let mut g = Game::from_position(
+        PositionBuilder::new()
+            .settings(settings)
+            .piece(W0, Square::new(8, 8), Player::Black, one(PieceType::King))
+            .piece(W0, Square::new(4, 0), Player::White, one(PieceType::King))
+            .piece(W0, Square::new(3, 5), Player::Black, one(PieceType::Rook)),
+    )
+    .unwrap();
+    let cycle = [
+        mv((3, 5), (4, 5)),
+        mv((4, 0), (3, 0)),
+        mv((4, 5), (3, 5)),
+        mv((3, 0), (4, 0)),
+    ];
+    for pm in cycle.into_iter().cycle().take(12) {
+        assert!(!g.status().is_over());
+        play(&mut g, W0, pm).unwrap();
+    }
+    g
+}
+
+#[test]
+fn fourth_repetition_ends_the_game_as_a_draw() {
+    let g = chase(RepetitionRule::Draw);
+    assert_eq!(
+        g.status(),
+        GameStatus::Draw {
+            reason: DrawReason::Repetition
+        }
+    );
+}
+
+#[test]
+fn perpetual_check_loses_the_repetition() {
+    assert_eq!(
+        chase(RepetitionRule::PerpetualCheckLoses).status(),
+        GameStatus::WhiteWins
+    );
+}
+
+// 両者入玉。後手は一式すべてを先手陣に置き、飛を除けば点数が足りない
+fn impasse(with_rook: bool) -> Game {
+    use PieceType::*;
+    let settings = Settings {
+        entering_king: true,
+        ..Settings::default()
+    };
+    let mut b = PositionBuilder::new()
+        .settings(settings)
+        .piece(W0, Square::new(4, 1), Player::Black, one(King))
+        .piece(W0, Square::new(4, 8), Player::White, one(King));
+    let mut white = vec![
+        ((2, 8), Bishop),
+        ((3, 8), Gold),
+        ((5, 8), Gold),
+        ((6, 8), Silver),
+        ((7, 8), Silver),
+        ((0, 6), Knight),
+        ((8, 6), Knight),
+        ((1, 7), Lance),
+        ((7, 7), Lance),
+        ((0, 7), Pawn),
+        ((8, 7), Pawn),
+    ];
+    white.extend((1..8).map(|f| ((f, 6), Pawn)));
+    if with_rook {
+        white.push(((1, 8), Rook));
+    }
+    for ((f, r), pt) in white {
+        b = b.piece(W0, Square::new(f, r), Player::White, one(pt));
+    }
+    let mut g = Game::from_position(b).unwrap();
+    play(&mut g, W0, mv((4, 1), (4, 2))).unwrap();
+    g
+}
+
+#[test]
+fn entering_kings_are_scored_by_the_27_point_rule() {
+    let g = impasse(true);
+    let s = g.present(W0).unwrap();
+    assert_eq!(g.impasse_score(s, Player::White), (27.0, 19));
+    assert_eq!(g.status(), GameStatus::WhiteWins);
+
+    let g = impasse(false);
+    let s = g.present(W0).unwrap();
+    assert_eq!(g.impasse_score(s, Player::White), (22.0, 18));
+    assert_eq!(
+        g.status(),
+        GameStatus::Draw {
+            reason: DrawReason::Impasse
+        }
+    );
+}
+
+#[test]
+fn resignation_ends_the_game_for_the_other_side() {
+    let mut g = Game::new(classical());
+    let events = g.resign(Player::Black).unwrap();
+    assert!(events.contains(&GameEvent::Resigned { by: Player::Black }));
+    assert_eq!(g.status(), GameStatus::WhiteWins);
+    assert_eq!(g.resign(Player::White), Err(EngineError::GameOver));
+    assert_eq!(g.offer_draw(Player::White), Err(EngineError::GameOver));
+    assert!(g.stage_move(W0, mv((0, 6), (0, 5))).is_err());
+}
+
+#[test]
+fn a_draw_needs_the_other_side_to_accept_before_moving() {
+    let mut g = Game::new(classical());
+    assert_eq!(g.accept_draw(Player::White), Err(EngineError::NoDrawOffer));
+    g.offer_draw(Player::Black).unwrap();
+    assert_eq!(g.draw_offer(), Some(Player::Black));
+    // 自分の提案には合意できない
+    assert_eq!(g.accept_draw(Player::Black), Err(EngineError::NoDrawOffer));
+    play(&mut g, W0, mv((0, 6), (0, 5))).unwrap();
+    assert_eq!(g.draw_offer(), Some(Player::Black));
+    // 後手が受けずに指せば提案は取り下げ
+    play(&mut g, W0, mv((0, 2), (0, 3))).unwrap();
+    assert_eq!(g.draw_offer(), None);
+    assert_eq!(g.accept_draw(Player::White), Err(EngineError::NoDrawOffer));
+
+    g.offer_draw(Player::Black).unwrap();
+    g.accept_draw(Player::White).unwrap();
+    assert_eq!(
+        g.status(),
+        GameStatus::Draw {
+            reason: DrawReason::Agreement
+        }
+    );
+    assert_eq!(g.status().result(), Some(GameResult::Draw));
+}
+
+// w0 を写した w1 の同じマスに、候補の違う駒を置く
+fn twin_worlds(w1_square: (usize, usize)) -> Game {
+    use PieceType::*;
+    let w1 = WorldId(1);
+    let (f, r) = w1_square;
+    let b = king_capture_position()
+        .copy_world(W0, w1)
+        .piece(
+            W0,
+            Square::new(2, 6),
+            Player::Black,
+            BTreeSet::from([Gold, Silver]),
+        )
+        .piece(
+            w1,
+            Square::new(f, r),
+            Player::Black,
+            BTreeSet::from([Silver, Bishop]),
+        );
+    Game::from_position(b).unwrap()
+}
+
+#[test]
+fn merging_matching_worlds_keeps_the_common_candidates() {
+    let mut g = twin_worlds((2, 6));
+    let events = g.merge_worlds(W0, WorldId(1)).unwrap();
+    assert_eq!(
+        events,
+        vec![GameEvent::WorldsMerged {
+            kept: W0,
+            removed: WorldId(1)
+        }]
+    );
+    assert_eq!(g.worlds().len(), 1);
+    let p = g.present(W0).unwrap().piece_at(Square::new(2, 6)).unwrap();
+    assert_eq!(p.candidates(), &one(PieceType::Silver));
+    // 統合は寿命を終えるが、敗北には数えない
+    assert!(g.stats().merged.contains(&WorldId(1)));
+    assert_eq!(g.stats().world_spans[&WorldId(1)].1, Some(1));
+    assert_eq!(g.stats().lost_worlds(), 0);
+}
+
+#[test]
+fn merging_is_refused_for_differing_or_missing_worlds() {
+    let mut g = twin_worlds((3, 6));
+    let w1 = WorldId(1);
+    assert_eq!(
+        g.merge_worlds(W0, w1),
+        Err(EngineError::MergeIncompatible { a: W0, b: w1 })
+    );
+    assert_eq!(
+        g.merge_worlds(W0, W0),
+        Err(EngineError::MergeUnavailable(W0))
+    );
+    assert_eq!(
+        g.merge_worlds(W0, WorldId(5)),
+        Err(EngineError::MergeUnavailable(WorldId(5)))
+    );
+    assert_eq!(g.worlds().len(), 2);
+}
+
+// 先手の飛車が (8,3) から (8,0) へ回って、隅の後手玉に横から王手をかける。
+// 逃げ道は金が押さえていて、間のどの筋にも後手の歩があるので歩は二歩になって打てない
+fn rank_check(hand: &[PieceType]) -> Game {
+    let mut b = PositionBuilder::new()
+        .piece(W0, Square::new(4, 8), Player::Black, one(PieceType::King))
+        .piece(W0, Square::new(0, 0), Player::White, one(PieceType::King))
+        .piece(W0, Square::new(0, 2), Player::Black, one(PieceType::Gold))
+        .piece(W0, Square::new(8, 3), Player::Black, one(PieceType::Rook));
+    for f in 1..8 {
+        b = b.piece(W0, Square::new(f, 2), Player::White, one(PieceType::Pawn));
+    }
+    for pt in hand {
+        b = b.hand(W0, Player::White, one(*pt));
+    }
+    let mut g = Game::from_position(b).unwrap();
+    play(&mut g, W0, mv((8, 3), (8, 0))).unwrap();
+    g
+}
+
+#[test]
+fn a_check_only_shielded_by_an_illegal_drop_is_mate() {
+    assert_eq!(
+        rank_check(&[PieceType::Pawn]).status(),
+        GameStatus::BlackWins
+    );
+}
+
+#[test]
+fn any_hand_piece_that_can_shield_the_king_avoids_mate() {
+    let g = rank_check(&[PieceType::Pawn, PieceType::Silver]);
+    assert_eq!(g.status(), GameStatus::Ongoing);
+}
+
+#[test]
+fn staging_rejects_illegal_moves_without_touching_the_game() {
+    let mut g = GameBuilder::new()
+        .settings(Settings {
+            summon_variant: true,
+            ..classical()
+        })
+        .build();
+    let shift = g
+        .legal_moves(W0)
+        .into_iter()
+        .find(|pm| pm.delta_w != 0 && pm.summon.is_none())
+        .unwrap();
+    let branch = |summon: Option<Square>| PlannedMove {
+        summon,
+        ..shift.clone()
+    };
+    let cases = [
+        (
+            WorldId(5),
+            mv((0, 6), (0, 5)),
+            MoveError::NoSuchWorld(WorldId(5)),
+        ),
+        (
+            W0,
+            PlannedMove {
+                delta_w: 1,
+                ..still(MoveKind::Pass)
+            },
+            MoveError::PassWithShift,
+        ),
+        (
+            W0,
+            PlannedMove {
+                summon: Some(Square::new(4, 4)),
+                ..mv((0, 6), (0, 5))
+            },
+            MoveError::SummonOnlyOnBranch,
+        ),
+        (
+            W0,
+            PlannedMove {
+                base_world: Some(WorldId(1)),
+                ..mv((0, 6), (0, 5))
+            },
+            MoveError::BaseWithoutBranch,
+        ),
+        (
+            W0,
+            branch(Some(Square::new(4, 6))),
+            MoveError::SummonOccupied(Square::new(4, 6)),
+        ),
+    ];
+    for (w, pm, error) in cases {
+        assert_eq!(g.stage_move(w, pm), Err(error));
+    }
+    assert!(!g.all_staged());
+    assert_eq!(g.worlds().len(), 1);
+    assert!(g.summon_available(Player::Black));
+
+    // 通った召喚は確定した時に初めて使われ、新しい id の金になる
+    let at = Square::new(4, 4);
+    play(&mut g, W0, branch(Some(at))).unwrap();
+    assert!(!g.summon_available(Player::Black));
+    let w1 = g.worlds().keys().find(|w| **w != W0).unwrap();
+    let s = g.present(*w1).unwrap();
+    let gold = s.piece_at(at).unwrap();
+    assert_eq!(gold.candidates(), &one(PieceType::Gold));
+    assert_eq!(s.pieces().filter(|(_, p)| p.id() == gold.id()).count(), 1);
+}
+
+#[test]
+fn simulation_errors_keep_the_typed_cause() {
+    use std::error::Error;
+    let settings = Settings {
+        max_branches_per_turn: Some(0),
+        ..classical()
+    };
+    let err = simulate(
+        settings.clone(),
+        &[TurnPlan::new().with(W0, mv((0, 6), (0, 2)))],
+    )
+    .err()
+    .unwrap();
+    assert_eq!(err.turn, 0);
+    assert!(matches!(
+        err.kind,
+        SimErrorKind::Move {
+            w: W0,
+            error: MoveError::NoCandidates
+        }
+    ));
+    assert!(err.source().is_some());
+
+    let shift = Game::new(settings.clone())
+        .legal_moves(W0)
+        .into_iter()
+        .find(|pm| pm.delta_w != 0)
+        .unwrap();
+    let err = simulate(
+        settings,
+        &[
+            TurnPlan::new().with(W0, mv((0, 6), (0, 5))),
+            TurnPlan::new().with(W0, mv((0, 2), (0, 3))),
+            TurnPlan::new().with(W0, shift),
+        ],
+    )
+    .err()
+    .unwrap();
+    assert_eq!(err.turn, 2);
+    match err.kind {
+        SimErrorKind::Engine(EngineError::Staging(issue)) => assert_eq!(
+            issue,
+            StagingIssue::TooManyBranches {
+                branches: 1,
+                limit: 0
+            }
+        ),
+        other => panic!("{other:?}"),
+    }
+}
+
+#[test]
+fn world_usage_counts_each_shift_and_branch() {
+    let mut g = Game::new(classical());
+    let shift = g
+        .legal_moves(W0)
+        .into_iter()
+        .find(|pm| pm.delta_w == 1 && pm.delta_t == 0)
+        .unwrap();
+    play(&mut g, W0, shift).unwrap();
+    let w1 = *g.worlds().keys().find(|w| **w != W0).unwrap();
+    for w in [W0, w1] {
+        g.stage_move(w, still(MoveKind::Pass)).unwrap();
+    }
+    g.commit_turn().unwrap();
+
+    let stats = g.stats();
+    assert_eq!((stats.turns, stats.moves, stats.branches), (2, 3, 1));
+    assert_eq!(stats.spacetime_moves, 1);
+    assert_eq!(stats.delta_w_hist, BTreeMap::from([(0, 2), (1, 1)]));
+    assert_eq!(stats.delta_t_hist, BTreeMap::from([(0, 3)]));
+    assert_eq!(stats.world_spans[&w1], (1, None));
+    assert_eq!(stats.lost_worlds(), 0);
+}
+
+#[test]
+fn legal_moves_offer_promotion_only_where_it_is_a_choice() {
+    let b = PositionBuilder::new()
+        .piece(W0, Square::new(4, 8), Player::Black, one(PieceType::King))
+        .piece(W0, Square::new(8, 0), Player::White, one(PieceType::King))
+        .piece(W0, Square::new(0, 1), Player::Black, one(PieceType::Pawn))
+        .piece(W0, Square::new(2, 3), Player::Black, one(PieceType::Pawn))
+        .piece(W0, Square::new(4, 5), Player::Black, one(PieceType::Pawn));
+    let g = Game::from_position(b).unwrap();
+    let promotions = |from: (usize, usize)| {
+        let mut out: Vec<bool> = g
+            .legal_moves(W0)
+            .into_iter()
+            .filter(|pm| pm.delta_w == 0 && pm.delta_t == 0)
+            .filter_map(|pm| match pm.kind {
+                MoveKind::Move {
+                    from: f, promote, ..
+                } if f == Square::new(from.0, from.1) => Some(promote),
+                _ => None,
+            })
+            .collect();
+        out.sort();
+        out
+    };
+    // 1段目へは成るしかなく、3段目へは選べ、敵陣の外では成れない
+    assert_eq!(promotions((0, 1)), [true]);
+    assert_eq!(promotions((2, 3)), [false, true]);
+    assert_eq!(promotions((4, 5)), [false]);
+}
+
+// 両者の王と、at にある先手の駒1枚だけの局面
+fn lone_piece(at: (usize, usize), candidates: &[PieceType]) -> Game {
+    Game::from_position(
+        PositionBuilder::new()
+            .piece(W0, Square::new(4, 8), Player::Black, one(PieceType::King))
+            .piece(W0, Square::new(8, 0), Player::White, one(PieceType::King))
+            .piece(
+                W0,
+                Square::new(at.0, at.1),
+                Player::Black,
+                candidates.iter().copied().collect(),
+            ),
+    )
+    .unwrap()
+}
+
+fn candidates_at(g: &Game, at: (usize, usize)) -> CandidateSet {
+    g.present(W0)
+        .unwrap()
+        .piece_at(Square::new(at.0, at.1))
+        .unwrap()
+        .candidates()
+        .clone()
+}
+
+#[test]
+fn a_diagonal_retreat_collapses_to_silver_with_its_reason() {
+    let mut g = lone_piece((4, 4), &[PieceType::Gold, PieceType::Silver]);
+    let id = g
+        .present(W0)
+        .unwrap()
+        .piece_at(Square::new(4, 4))
+        .unwrap()
+        .id();
+    play(&mut g, W0, mv((4, 4), (3, 5))).unwrap();
+    assert_eq!(candidates_at(&g, (3, 5)), one(PieceType::Silver));
+    // 候補の消えた理由は手と一緒に残り、確定の統計にも数えられる
+    let trail = g.deductions(id);
+    assert_eq!(trail.len(), 1);
+    assert_eq!(trail[0].removed, one(PieceType::Gold));
+    assert_eq!(trail[0].turn, 1);
+    let events: Vec<_> = g
+        .collapse_stats()
+        .events
+        .iter()
+        .filter(|e| e.piece_id == id)
+        .map(|e| (e.turn, e.piece_type, e.cause))
+        .collect();
+    assert_eq!(events, [(1, PieceType::Silver, CollapseCause::Movement)]);
+    assert_eq!(g.collapse_stats().uncertainty.len(), 2);
+}
+
+fn promoting(from: (usize, usize), to: (usize, usize)) -> PlannedMove {
+    let mut pm = mv(from, to);
+    if let MoveKind::Move { promote, .. } = &mut pm.kind {
+        *promote = true;
+    }
+    pm
+}
+
+#[test]
+fn promoting_leaves_only_promotable_candidates() {
+    use PieceType::*;
+    let mut g = lone_piece((4, 3), &[Gold, Silver]);
+    play(&mut g, W0, promoting((4, 3), (4, 2))).unwrap();
+    assert_eq!(candidates_at(&g, (4, 2)), one(Silver));
+    assert!(g
+        .present(W0)
+        .unwrap()
+        .piece_at(Square::new(4, 2))
+        .unwrap()
+        .is_promoted());
+}
+
+#[test]
+fn declining_promotion_where_a_pawn_would_be_stuck_rules_it_out() {
+    use PieceType::*;
+    let mut g = lone_piece((4, 1), &[Pawn, Silver]);
+    play(&mut g, W0, mv((4, 1), (4, 0))).unwrap();
+    assert_eq!(candidates_at(&g, (4, 0)), one(Silver));
+}
+
+#[test]
+fn promotion_is_checked_against_the_zone_and_forced_when_stuck() {
+    let mut g = lone_piece((4, 5), &[PieceType::Pawn]);
+    assert_eq!(
+        g.stage_move(W0, promoting((4, 5), (4, 4))),
+        Err(MoveError::PromotionOutsideZone)
+    );
+    let mut g = lone_piece((4, 1), &[PieceType::Pawn]);
+    play(&mut g, W0, mv((4, 1), (4, 0))).unwrap();
+    let p = g.present(W0).unwrap().piece_at(Square::new(4, 0)).unwrap();
+    assert!(p.is_promoted());
+}
+
+#[test]
+fn promoted_minor_pieces_move_like_gold_and_major_pieces_gain_steps() {
+    let g = Game::new(classical());
+    let gold = g.move_offsets(PieceType::Gold, false);
+    for pt in [
+        PieceType::Pawn,
+        PieceType::Lance,
+        PieceType::Knight,
+        PieceType::Silver,
+    ] {
+        assert_eq!(g.move_offsets(pt, true), gold, "{pt:?}");
+    }
+    let king = g.move_offsets(PieceType::King, false);
+    for pt in [PieceType::Rook, PieceType::Bishop] {
+        let plain = g.move_offsets(pt, false);
+        let promoted = g.move_offsets(pt, true);
+        assert!(plain.iter().all(|o| promoted.contains(o)), "{pt:?}");
+        assert!(king.iter().all(|o| promoted.contains(o)), "{pt:?}");
+    }
+}
+
+fn drop(group: CandidateSet, to: (usize, usize)) -> PlannedMove {
+    still(MoveKind::Drop {
+        group,
+        to: Square::new(to.0, to.1),
+    })
+}
+
+#[test]
+fn hand_pieces_are_grouped_by_candidates_and_dropped_by_group() {
+    use PieceType::*;
+    // 角は一式に1枚なので、3枚とも角の候補なら枚数からは決まらない
+    let pb = BTreeSet::from([Pawn, Bishop]);
+    let mut b = PositionBuilder::new()
+        .piece(W0, Square::new(4, 8), Player::Black, one(King))
+        .piece(W0, Square::new(8, 0), Player::White, one(King))
+        .hand(W0, Player::Black, one(Pawn));
+    for _ in 0..3 {
+        b = b.hand(W0, Player::Black, pb.clone());
+    }
+    let mut g = Game::from_position(b).unwrap();
+    let sizes = |g: &Game| -> BTreeMap<CandidateSet, usize> {
+        g.present(W0)
+            .unwrap()
+            .hand_groups(Player::Black)
+            .into_iter()
+            .map(|(c, ids)| (c, ids.len()))
+            .collect()
+    };
+    assert_eq!(sizes(&g), BTreeMap::from([(one(Pawn), 1), (pb.clone(), 3)]));
+    play(&mut g, W0, drop(pb.clone(), (4, 4))).unwrap();
+    assert_eq!(sizes(&g), BTreeMap::from([(one(Pawn), 1), (pb.clone(), 2)]));
+    assert_eq!(candidates_at(&g, (4, 4)), pb);
+}
+
+fn with_rule(settings: Settings, b: PositionBuilder) -> Game {
+    Game::from_position(b.settings(settings)).unwrap()
+}
+
+#[test]
+fn check_needs_a_certain_attacker_only_in_certain_mode() {
+    use PieceType::*;
+    // 後手玉の筋にいる先手の駒は、飛車なら王手、金なら届かない
+    let b = PositionBuilder::new()
+        .piece(W0, Square::new(0, 8), Player::Black, one(King))
+        .piece(W0, Square::new(4, 0), Player::White, one(King))
+        .piece(
+            W0,
+            Square::new(4, 4),
+            Player::Black,
+            BTreeSet::from([Rook, Gold]),
+        );
+    for (mode, check) in [
+        (CheckAttackMode::Possible, true),
+        (CheckAttackMode::Certain, false),
+    ] {
+        let g = with_rule(
+            Settings {
+                check_attack_mode: mode,
+                ..Settings::default()
+            },
+            b.clone(),
+        );
+        assert_eq!(
+            g.is_in_check(g.present(W0).unwrap(), Player::White),
+            check,
+            "{mode:?}"
+        );
+    }
+}
+
+#[test]
+fn a_pawn_drop_that_mates_is_refused() {
+    use PieceType::*;
+    // 隅の後手玉の前に歩を打つと、金が歩を守っていて逃げ道は後手の駒がふさいでいる
+    let b = |guarded: bool| {
+        let b = PositionBuilder::new()
+            .piece(W0, Square::new(4, 8), Player::Black, one(King))
+            .piece(W0, Square::new(0, 0), Player::White, one(King))
+            .piece(W0, Square::new(1, 0), Player::White, one(Lance))
+            .piece(W0, Square::new(1, 1), Player::White, one(Pawn))
+            .hand(W0, Player::Black, one(Pawn));
+        if guarded {
+            b.piece(W0, Square::new(1, 2), Player::Black, one(Gold))
+        } else {
+            b
+        }
+    };
+    let mut g = Game::from_position(b(true)).unwrap();
+    assert_eq!(
+        g.stage_move(W0, drop(one(Pawn), (0, 1))),
+        Err(MoveError::DropForbidden)
+    );
+    let mut g = Game::from_position(b(false)).unwrap();
+    assert!(play(&mut g, W0, drop(one(Pawn), (0, 1))).is_ok());
+}
+
+#[test]
+fn moving_a_pinned_piece_is_refused_when_self_check_is_forbidden() {
+    use PieceType::*;
+    let b = PositionBuilder::new()
+        .piece(W0, Square::new(4, 8), Player::Black, one(King))
+        .piece(W0, Square::new(4, 7), Player::Black, one(Gold))
+        .piece(W0, Square::new(0, 0), Player::White, one(King))
+        .piece(W0, Square::new(4, 0), Player::White, one(Rook));
+    for (forbid, expected) in [(true, Err(MoveError::SelfCheck(W0))), (false, Ok(()))] {
+        let mut g = with_rule(
+            Settings {
+                forbid_self_check: forbid,
+                ..Settings::default()
+            },
+            b.clone(),
+        );
+        assert_eq!(g.stage_move(W0, mv((4, 7), (3, 7))), expected);
+    }
+}
+
+#[test]
+fn branches_beyond_the_per_turn_limit_block_the_commit() {
+    let mut g = Game::new(Settings {
+        max_branches_per_turn: Some(0),
+        ..classical()
+    });
+    let shift = g
+        .legal_moves(W0)
+        .into_iter()
+        .find(|pm| pm.delta_w != 0)
+        .unwrap();
+    g.stage_move(W0, shift).unwrap();
+    let issue = StagingIssue::TooManyBranches {
+        branches: 1,
+        limit: 0,
+    };
+    assert_eq!(g.staging_issues(), std::slice::from_ref(&issue));
+    assert!(matches!(
+        g.commit_turn(),
+        Err(EngineError::Staging(i)) if i == issue
+    ));
+    assert_eq!(g.record().len(), 0);
+}
+
+#[test]
+fn start_positions_narrow_candidates_by_row() {
+    use PieceType::*;
+    let g = Game::new(classical());
+    let s = g.present(W0).unwrap();
+    assert!(s.pieces().all(|(_, p)| p.candidates().len() == 1));
+    let g = Game::new(Settings {
+        start_position: StartPosition::RowConstrained,
+        ..Settings::default()
+    });
+    let s = g.present(W0).unwrap();
+    let rows = [
+        BTreeSet::from([Lance, Knight, Silver, Gold, King]),
+        BTreeSet::from([Rook, Bishop]),
+        one(Pawn),
+    ];
+    for (sq, p) in s.pieces() {
+        let row = if p.owner() == Player::Black {
+            8 - sq.rank
+        } else {
+            sq.rank
+        };
+        assert!(p.candidates().is_subset(&rows[row]), "{sq:?}");
+    }
+    assert_eq!(s.pieces().count(), 40);
+}
+
+#[test]
+fn handicap_removes_the_upper_side_pieces_from_board_and_army() {
+    let g = Game::new(Settings {
+        handicap: Handicap::TwoPiece,
+        ..classical()
+    });
+    let s = g.present(W0).unwrap();
+    assert_eq!(s.pieces().count(), 38);
+    assert!(s.piece_at(Square::new(1, 1)).is_none());
+    assert!(s.piece_at(Square::new(7, 1)).is_none());
+    assert_eq!(g.army_limit(Player::White, PieceType::Rook), 0);
+    assert_eq!(g.army_limit(Player::Black, PieceType::Rook), 1);
+}
+
+#[test]
+fn bishop_axes_choose_how_many_axes_a_diagonal_may_span() {
+    for axes in [2, 3, 4] {
+        let g = Game::new(Settings {
+            bishop_axes: axes,
+            ..Settings::default()
+        });
+        let spans: BTreeSet<usize> = g
+            .move_offsets(PieceType::Bishop, false)
+            .iter()
+            .map(|d| d.iter().filter(|v| **v != 0).count())
+            .collect();
+        assert_eq!(spans, (2..=axes).collect(), "{axes}");
+    }
+}
+
+#[test]
+fn observation_fixes_the_piece_to_one_of_its_candidates() {
+    use PieceType::*;
+    let gs = BTreeSet::from([Gold, Silver]);
+    let b = PositionBuilder::new()
+        .piece(W0, Square::new(4, 8), Player::Black, one(King))
+        .piece(W0, Square::new(8, 0), Player::White, one(King))
+        .piece(W0, Square::new(4, 4), Player::Black, gs.clone());
+    let mut g = with_rule(Settings::default(), b.clone());
+    let observe = |f, r| {
+        still(MoveKind::Observe {
+            at: Square::new(f, r),
+        })
+    };
+    assert_eq!(
+        g.stage_move(W0, observe(4, 5)),
+        Err(MoveError::NothingToObserve)
+    );
+    assert_eq!(
+        g.stage_move(
+            W0,
+            PlannedMove {
+                delta_t: -1,
+                ..observe(4, 4)
+            }
+        ),
+        Err(MoveError::ObserveWithShift)
+    );
+    play(&mut g, W0, observe(4, 4)).unwrap();
+    let c = candidates_at(&g, (4, 4));
+    assert_eq!(c.len(), 1);
+    assert!(c.is_subset(&gs));
+    let mut g = with_rule(
+        Settings {
+            collapse_mode: CollapseMode::CountOnly,
+            ..Settings::default()
+        },
+        b,
+    );
+    assert_eq!(
+        g.stage_move(W0, observe(4, 4)),
+        Err(MoveError::ObserveDisabled)
+    );
+}
+
+fn ordered(turn_mode: TurnMode) -> Game {
+    let b = king_capture_position().copy_world(W0, WorldId(1));
+    with_rule(
+        Settings {
+            turn_mode,
+            ..Settings::default()
+        },
+        b,
+    )
+}
+
+#[test]
+fn turn_modes_decide_which_worlds_may_move() {
+    let w1 = WorldId(1);
+    let mut g = ordered(TurnMode::Sequential);
+    g.stage_move(W0, mv((4, 8), (4, 7))).unwrap();
+    assert_eq!(
+        g.stage_move(w1, mv((4, 8), (4, 7))),
+        Err(MoveError::OtherWorldStaged(W0))
+    );
+    g.commit_turn().unwrap();
+    assert_eq!(g.record().len(), 1);
+    let mut g = ordered(TurnMode::RoundRobin);
+    assert_eq!(g.acting_world(), Some(W0));
+    assert_eq!(
+        g.stage_move(w1, mv((4, 8), (4, 7))),
+        Err(MoveError::NotActingWorld(W0))
+    );
+}
+
+#[test]
+fn passing_is_limited_by_the_pass_interval() {
+    let mut g = with_rule(
+        Settings {
+            pass_interval: Some(2),
+            ..Settings::default()
+        },
+        king_capture_position(),
+    );
+    play(&mut g, W0, still(MoveKind::Pass)).unwrap();
+    play(&mut g, W0, still(MoveKind::Pass)).unwrap();
+    assert_eq!(
+        g.stage_move(W0, still(MoveKind::Pass)),
+        Err(MoveError::PassTooSoon(2))
+    );
+    assert_eq!(
+        g.stage_move(
+            W0,
+            PlannedMove {
+                delta_w: 1,
+                ..still(MoveKind::Pass)
+            }
+        ),
+        Err(MoveError::PassWithShift)
+    );
+}
+
+// 両者の王と先手の金を置いた w0 を w1 に写した局面
+fn two_worlds(settings: Settings) -> PositionBuilder {
+    PositionBuilder::new()
+        .piece(W0, Square::new(4, 8), Player::Black, one(PieceType::King))
+        .piece(W0, Square::new(4, 0), Player::White, one(PieceType::King))
+        .piece(W0, Square::new(0, 8), Player::Black, one(PieceType::Gold))
+        .copy_world(W0, WorldId(1))
+        .settings(settings)
+}
+
+#[test]
+fn sacrificing_a_piece_destroys_another_world() {
+    let w1 = WorldId(1);
+    let settings = Settings {
+        world_destruction: true,
+        ..Settings::default()
+    };
+    let collapse = |target| {
+        still(MoveKind::CollapseWorld {
+            target,
+            sacrifice: Square::new(0, 8),
+        })
+    };
+    let mut g = Game::from_position(two_worlds(settings.clone())).unwrap();
+    g.stage_move(W0, collapse(WorldId(7))).unwrap();
+    g.stage_move(w1, still(MoveKind::Pass)).unwrap();
+    assert_eq!(
+        g.commit_turn(),
+        Err(EngineError::InvalidCollapseTarget(WorldId(7)))
+    );
+    let mut g = Game::from_position(two_worlds(settings)).unwrap();
+    g.stage_move(W0, collapse(w1)).unwrap();
+    g.stage_move(w1, still(MoveKind::Pass)).unwrap();
+    g.commit_turn().unwrap();
+    assert_eq!(g.worlds().keys().collect::<Vec<_>>(), [&W0]);
+    assert!(g.present(W0).unwrap().piece_at(Square::new(0, 8)).is_none());
+}
+
+fn global_hand(teleport_drop: bool) -> Settings {
+    Settings {
+        hand_mode: HandMode::Global,
+        teleport_drop,
+        ..Settings::default()
+    }
+}
+
+#[test]
+fn a_global_hand_piece_can_be_dropped_into_another_world() {
+    use PieceType::*;
+    let w1 = WorldId(1);
+    let teleport = |to, dest| {
+        still(MoveKind::TeleportDrop {
+            group: one(Pawn),
+            to: Square::new(to, 4),
+            dest,
+        })
+    };
+    let b = two_worlds(global_hand(true)).hand(W0, Player::Black, one(Pawn));
+    let mut g = Game::from_position(b.clone()).unwrap();
+    g.stage_move(W0, teleport(4, w1)).unwrap();
+    g.stage_move(w1, still(MoveKind::Pass)).unwrap();
+    g.commit_turn().unwrap();
+    let s = g.present(w1).unwrap();
+    assert_eq!(
+        s.piece_at(Square::new(4, 4)).unwrap().candidates(),
+        &one(Pawn)
+    );
+    assert!(g.present(W0).unwrap().piece_at(Square::new(4, 4)).is_none());
+    let mut g = Game::from_position(b.hand(w1, Player::Black, one(Pawn))).unwrap();
+    g.stage_move(W0, teleport(4, w1)).unwrap();
+    g.stage_move(w1, teleport(3, W0)).unwrap();
+    assert_eq!(g.commit_turn(), Err(EngineError::TeleportDropLimit));
+}
+
+#[test]
+fn a_failed_commit_leaves_the_game_as_it_was() {
+    let w1 = WorldId(1);
+    let mut g = Game::from_position(two_worlds(Settings {
+        world_destruction: true,
+        ..Settings::default()
+    }))
+    .unwrap();
+    let hashes = |g: &Game| -> Vec<u64> {
+        g.worlds()
+            .values()
+            .map(|wl| wl.present().position_hash())
+            .collect()
+    };
+    let before = hashes(&g);
+    // w1 の手を解決した後で、w0 の破壊対象が不正だと分かる
+    g.stage_move(
+        W0,
+        still(MoveKind::CollapseWorld {
+            target: WorldId(7),
+            sacrifice: Square::new(0, 8),
+        }),
+    )
+    .unwrap();
+    g.stage_move(w1, mv((0, 8), (0, 7))).unwrap();
+    assert_eq!(
+        g.commit_turn(),
+        Err(EngineError::InvalidCollapseTarget(WorldId(7)))
+    );
+    assert_eq!(hashes(&g), before);
+    assert_eq!(g.record().len(), 0);
+    assert_eq!(g.turn(), Player::Black);
+    assert!(g.worlds().values().all(|wl| wl.history().len() == 1));
+}
+
+#[test]
+fn the_same_piece_captured_in_two_worlds_stays_two_hand_pieces() {
+    use PieceType::*;
+    let w1 = WorldId(1);
+    let b = two_worlds(global_hand(false))
+        .piece(W0, Square::new(2, 3), Player::White, one(Pawn))
+        .piece(W0, Square::new(2, 4), Player::Black, one(Silver))
+        .copy_world(W0, w1);
+    let mut g = Game::from_position(b).unwrap();
+    g.stage_move(W0, mv((2, 4), (2, 3))).unwrap();
+    g.stage_move(w1, mv((2, 4), (2, 3))).unwrap();
+    g.commit_turn().unwrap();
+    let hand: Vec<&Piece> = g
+        .worlds()
+        .values()
+        .flat_map(|wl| wl.present().hand(Player::Black))
+        .map(|p| p.as_ref())
+        .collect();
+    let origins: Vec<_> = hand.iter().map(|p| p.captured_at()).collect();
+    assert_eq!(origins, [Some((W0, 1)), Some((w1, 1))]);
+    assert_eq!(hand[0].id(), hand[1].id());
+}
+
+#[test]
+fn a_piece_is_found_by_id_in_every_world_it_lives_in() {
+    let g = Game::from_position(two_worlds(Settings::default())).unwrap();
+    let gold = g
+        .present(W0)
+        .unwrap()
+        .piece_at(Square::new(0, 8))
+        .unwrap()
+        .id();
+    assert_eq!(
+        g.locate_piece(gold),
+        [
+            (W0, Some(Square::new(0, 8))),
+            (WorldId(1), Some(Square::new(0, 8)))
+        ]
+    );
+}
+
+#[test]
+fn global_hand_drops_in_several_worlds_are_checked_against_the_stock() {
+    use PieceType::*;
+    let w1 = WorldId(1);
+    let b = two_worlds(global_hand(false))
+        .hand(W0, Player::Black, one(Pawn))
+        .hand(w1, Player::Black, one(Pawn));
+    let mut g = Game::from_position(b).unwrap();
+    g.stage_move(W0, drop(one(Pawn), (4, 4))).unwrap();
+    g.stage_move(w1, drop(one(Pawn), (3, 4))).unwrap();
+    g.commit_turn().unwrap();
+    assert!(g
+        .worlds()
+        .values()
+        .all(|wl| wl.present().hand(Player::Black).is_empty()));
+}
+
+#[test]
+fn entangled_worlds_share_what_is_learned_about_a_piece() {
+    use PieceType::*;
+    let w1 = WorldId(1);
+    for (entangle, expected) in [(true, one(Silver)), (false, BTreeSet::from([Gold, Silver]))] {
+        let b = PositionBuilder::new()
+            .piece(W0, Square::new(4, 8), Player::Black, one(King))
+            .piece(W0, Square::new(8, 0), Player::White, one(King))
+            .piece(
+                W0,
+                Square::new(2, 6),
+                Player::Black,
+                BTreeSet::from([Gold, Silver]),
+            )
+            .copy_world(W0, w1);
+        let mut g = with_rule(
+            Settings {
+                entangle_worlds: entangle,
+                ..Settings::default()
+            },
+            b,
+        );
+        // 斜め後ろへ下がれるのは銀だけ
+        g.stage_move(W0, mv((2, 6), (1, 7))).unwrap();
+        g.stage_move(w1, still(MoveKind::Pass)).unwrap();
+        g.commit_turn().unwrap();
+        let s = g.present(w1).unwrap();
+        assert_eq!(
+            s.piece_at(Square::new(2, 6)).unwrap().candidates(),
+            &expected,
+            "{entangle}"
+        );
+    }
+}
+
+// 角が (4,5) から (3,4) へ進み、後手が1手指した後で、その角を1手前の (3,3) へ送る。
+// 1手前の局面では過去の角がまだ (3,4) にいる
+fn back_onto_past_self(rule: ParadoxRule) -> (Game, Result<(), MoveError>) {
+    let mut g = with_rule(
+        Settings {
+            paradox_rule: rule,
+            world_id_policy: WorldIdPolicy::NearestFree,
+            ..Settings::default()
+        },
+        PositionBuilder::new()
+            .piece(W0, Square::new(4, 8), Player::Black, one(PieceType::King))
+            .piece(W0, Square::new(8, 0), Player::White, one(PieceType::King))
+            .piece(W0, Square::new(4, 5), Player::Black, one(PieceType::Bishop)),
+    );
+    play(&mut g, W0, mv((4, 5), (3, 4))).unwrap();
+    play(&mut g, W0, mv((8, 0), (8, 1))).unwrap();
+    let staged = g.stage_move(
+        W0,
+        PlannedMove {
+            delta_t: -1,
+            ..mv((3, 4), (3, 3))
+        },
+    );
+    (g, staged)
+}
+
+#[test]
+fn a_piece_sent_onto_its_past_self_follows_the_paradox_rule() {
+    let (g, staged) = back_onto_past_self(ParadoxRule::Forbid);
+    let bishop = g
+        .present(W0)
+        .unwrap()
+        .piece_at(Square::new(3, 4))
+        .unwrap()
+        .id();
+    assert_eq!(staged, Err(MoveError::Paradox(bishop)));
+    let (mut g, staged) = back_onto_past_self(ParadoxRule::RemovePastSelf);
+    staged.unwrap();
+    g.commit_turn().unwrap();
+    let (_, branch) = g.worlds().iter().find(|(w, _)| **w != W0).unwrap();
+    let copies = branch
+        .present()
+        .pieces()
+        .filter(|(_, p)| p.id() == bishop)
+        .count();
+    assert_eq!(copies, 1);
+}
+
+#[test]
+fn lost_worlds_are_archived_when_pruning_is_on() {
+    let w1 = WorldId(1);
+    for prune in [true, false] {
+        let mut g = with_rule(
+            Settings {
+                prune_lost_worlds: prune,
+                ..Settings::default()
+            },
+            king_capture_position().copy_world(W0, w1),
+        );
+        g.stage_move(W0, mv((4, 1), (4, 0))).unwrap();
+        g.stage_move(w1, mv((4, 8), (4, 7))).unwrap();
+        g.commit_turn().unwrap();
+        assert_eq!(g.world(W0).is_none(), prune);
+        assert_eq!(g.archived().contains_key(&W0), prune);
+        assert!(g.world(w1).is_some());
+    }
+}
+
+#[test]
+fn a_slide_through_time_is_blocked_by_what_stood_in_between() {
+    use PieceType::*;
+    // 角が2手前の局面へ斜めに戻る。通り道の (3,4) には1手前まで後手の歩がいた
+    for (pawn, clear) in [((3, 4), false), ((6, 4), true)] {
+        let mut g = with_rule(
+            Settings {
+                world_id_policy: WorldIdPolicy::NearestFree,
+                ..Settings::default()
+            },
+            PositionBuilder::new()
+                .piece(W0, Square::new(4, 8), Player::Black, one(King))
+                .piece(W0, Square::new(8, 0), Player::White, one(King))
+                .piece(W0, Square::new(2, 4), Player::Black, one(Bishop))
+                .piece(W0, Square::new(pawn.0, pawn.1), Player::White, one(Pawn)),
+        );
+        play(&mut g, W0, mv((4, 8), (4, 7))).unwrap();
+        play(&mut g, W0, mv(pawn, (pawn.0, pawn.1 + 1))).unwrap();
+        let staged = g.stage_move(
+            W0,
+            PlannedMove {
+                delta_t: -2,
+                ..mv((2, 4), (4, 4))
+            },
+        );
+        if clear {
+            staged.unwrap();
+        } else {
+            assert_eq!(staged, Err(MoveError::NoCandidates));
+        }
+    }
+}
+
+// 先手の角が1手前の局面へ斜めに戻り、そこにいた後手の歩を取る
+fn capture_in_the_past(rule: CaptureAcrossTime) -> Game {
+    use PieceType::*;
+    let mut g = with_rule(
+        Settings {
+            capture_across_time: rule,
+            world_id_policy: WorldIdPolicy::NearestFree,
+            ..Settings::default()
+        },
+        PositionBuilder::new()
+            .piece(W0, Square::new(4, 8), Player::Black, one(King))
+            .piece(W0, Square::new(8, 0), Player::White, one(King))
+            .piece(W0, Square::new(4, 4), Player::Black, one(Bishop))
+            .piece(W0, Square::new(4, 3), Player::White, one(Pawn)),
+    );
+    play(&mut g, W0, mv((4, 8), (3, 8))).unwrap();
+    play(&mut g, W0, mv((8, 0), (8, 1))).unwrap();
+    play(
+        &mut g,
+        W0,
+        PlannedMove {
+            delta_t: -1,
+            ..mv((4, 4), (4, 3))
+        },
+    )
+    .unwrap();
+    g
+}
+
+#[test]
+fn a_capture_in_the_past_reaches_the_source_world_by_rule() {
+    for (rule, stays, paradoxed) in [
+        (CaptureAcrossTime::Local, true, false),
+        (CaptureAcrossTime::RemoveFromSource, false, false),
+        (CaptureAcrossTime::Paradoxed, true, true),
+    ] {
+        let g = capture_in_the_past(rule);
+        let pawn = g.present(W0).unwrap().piece_at(Square::new(4, 3));
+        assert_eq!(pawn.is_some(), stays, "{rule:?}");
+        assert_eq!(
+            pawn.is_some_and(|p| p.is_paradoxed()),
+            paradoxed,
+            "{rule:?}"
+        );
+    }
+}
+
+#[test]
+fn piece_budgets_count_both_sides_together() {
+    use PieceType::*;
+    // 飛は両者で2枚。先手と後手に1枚ずつ確定しているので、残る駒は飛ではない
+    let b = PositionBuilder::new()
+        .piece(W0, Square::new(4, 8), Player::Black, one(King))
+        .piece(W0, Square::new(4, 0), Player::White, one(King))
+        .piece(W0, Square::new(0, 6), Player::Black, one(Rook))
+        .piece(W0, Square::new(8, 2), Player::White, one(Rook))
+        .piece(
+            W0,
+            Square::new(2, 6),
+            Player::Black,
+            BTreeSet::from([Rook, Gold]),
+        );
+    let mut g = Game::from_position(b).unwrap();
+    play(&mut g, W0, mv((4, 8), (4, 7))).unwrap();
+    assert_eq!(candidates_at(&g, (2, 6)), one(Gold));
+}
+
+#[test]
+fn propagation_rules_out_types_no_full_assignment_can_give() {
+    use PieceType::*;
+    let rb = BTreeSet::from([Rook, Bishop]);
+    // 後手の飛角が確定しているので、先手の飛角は1枚ずつ。2枚の飛角候補で埋まる
+    let b = PositionBuilder::new()
+        .piece(W0, Square::new(4, 8), Player::Black, one(King))
+        .piece(W0, Square::new(4, 0), Player::White, one(King))
+        .piece(W0, Square::new(8, 2), Player::White, one(Rook))
+        .piece(W0, Square::new(8, 1), Player::White, one(Bishop))
+        .piece(W0, Square::new(0, 6), Player::Black, rb.clone())
+        .piece(W0, Square::new(2, 6), Player::Black, rb.clone())
+        .piece(
+            W0,
+            Square::new(6, 6),
+            Player::Black,
+            BTreeSet::from([Rook, Bishop, Gold]),
+        );
+    let mut g = Game::from_position(b).unwrap();
+    let id = g
+        .present(W0)
+        .unwrap()
+        .piece_at(Square::new(6, 6))
+        .unwrap()
+        .id();
+    let notes = g.propagate_constraints().unwrap();
+    assert!(notes.iter().any(|d| d.piece_id == id));
+    assert_eq!(candidates_at(&g, (6, 6)), one(Gold));
+    assert_eq!(candidates_at(&g, (0, 6)), rb);
+    assert!(!g.deductions(id).is_empty());
+}
+
+#[test]
+fn the_kifu_sheet_lists_players_moves_and_result() {
+    use quantum_spacetime_shogi::export::kifu_html;
+    let mut g = Game::new(classical());
+    play(&mut g, W0, mv((6, 6), (6, 5))).unwrap();
+    g.resign(Player::White).unwrap();
+    let html = kifu_html(
+        &g,
+        &KifuSheetInfo {
+            black_name: "<先手>".into(),
+            white_name: "後手さん".into(),
+        },
+    );
+    assert!(html.contains("&lt;先手&gt;"));
+    assert!(html.contains("後手さん"));
+    assert!(html.contains(&mv((6, 6), (6, 5)).notation(9)));
+    assert!(html.contains(&g.status().label()));
+}
+
+#[test]
+fn a_summon_places_a_gold_in_the_new_world_once_per_game() {
+    let mut g = Game::new(Settings {
+        summon_variant: true,
+        ..classical()
+    });
+    let shift = g
+        .legal_moves(W0)
+        .into_iter()
+        .find(|pm| pm.delta_w != 0)
+        .unwrap();
+    let sq = Square::new(4, 4);
+    let events = play(
+        &mut g,
+        W0,
+        PlannedMove {
+            summon: Some(sq),
+            ..shift
+        },
+    )
+    .unwrap();
+    let created = events
+        .iter()
+        .find_map(|e| match e {
+            GameEvent::WorldCreated { w, .. } => Some(*w),
+            _ => None,
+        })
+        .unwrap();
+    let gold = g.present(created).unwrap().piece_at(sq).unwrap();
+    assert_eq!(gold.owner(), Player::Black);
+    assert_eq!(gold.candidates(), &one(PieceType::Gold));
+    assert!(g.present(W0).unwrap().piece_at(sq).is_none());
+    assert!(!g.summon_available(Player::Black));
+    assert!(g.summon_available(Player::White));
+}
+
+#[test]
+fn branch_bases_from_other_worlds_need_the_rule_and_a_related_world() {
+    let mut g = Game::new(Settings {
+        cross_world_base: true,
+        world_id_policy: WorldIdPolicy::SignedDirection,
+        max_worlds: 4,
+        ..small_minishogi()
+    });
+    let branch = g
+        .legal_moves(W0)
+        .into_iter()
+        .find(|pm| pm.delta_w == 1)
+        .unwrap();
+    play(&mut g, W0, branch).unwrap();
+    let w1 = WorldId(1);
+    let crossed = g
+        .legal_moves(w1)
+        .into_iter()
+        .find(|pm| pm.base_world == Some(W0))
+        .unwrap();
+    let missing = PlannedMove {
+        base_world: Some(WorldId(9)),
+        ..crossed.clone()
+    };
+    assert_eq!(
+        g.validate_move(w1, &missing),
+        Err(MoveError::UnrelatedBase {
+            base: WorldId(9),
+            w: w1
+        })
+    );
+    g.set_settings(Settings {
+        cross_world_base: false,
+        ..g.settings().clone()
+    })
+    .unwrap();
+    assert_eq!(
+        g.validate_move(w1, &crossed),
+        Err(MoveError::CrossWorldBaseDisabled)
+    );
+}
+
+#[test]
+fn world_id_policies_pick_the_number_of_a_new_branch() {
+    let w1 = WorldId(1);
+    for (policy, expected) in [
+        (WorldIdPolicy::Exact, None),
+        (WorldIdPolicy::NearestFree, Some(WorldId(-1))),
+        (WorldIdPolicy::SignedDirection, Some(WorldId(-1))),
+        (WorldIdPolicy::Tree, Some(WorldId(2))),
+    ] {
+        let g = Game::from_position(two_worlds(Settings {
+            world_id_policy: policy,
+            ..Settings::default()
+        }))
+        .unwrap();
+        assert_eq!(g.allocate_world(w1, -1), expected, "{policy:?}");
+    }
+    let mut g = Game::new(small_minishogi());
+    let branch = g
+        .legal_moves(W0)
+        .into_iter()
+        .find(|pm| pm.delta_w == 1 && pm.delta_t == 0)
+        .unwrap();
+    play(&mut g, W0, branch).unwrap();
+    let wl = g.world(w1).unwrap();
+    assert_eq!(wl.parent(), Some(W0));
+    assert_eq!(wl.branch_point(), Some((W0, TimeIdx(0))));
+}
+
+#[test]
+fn strict_commits_reject_a_turn_that_leaves_the_king_attacked() {
+    use PieceType::*;
+    let b = PositionBuilder::new()
+        .piece(W0, Square::new(4, 8), Player::Black, one(King))
+        .piece(W0, Square::new(8, 0), Player::White, one(King))
+        .piece(W0, Square::new(3, 0), Player::White, one(Rook));
+    for strict in [true, false] {
+        let mut g = with_rule(
+            Settings {
+                strict_commit_check: strict,
+                forbid_self_check: false,
+                ..Settings::default()
+            },
+            b.clone(),
+        );
+        g.stage_move(W0, mv((4, 8), (3, 8))).unwrap();
+        let r = g.commit_turn();
+        assert_eq!(
+            matches!(r, Err(EngineError::KingLeftInCheck(_))),
+            strict,
+            "{r:?}"
+        );
+    }
+}